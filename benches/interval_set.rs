@@ -0,0 +1,58 @@
+//! Interval set operations over years of 15-minute intervals, the size of
+//! state a long-running world with a fine-grained schedule builds up.
+
+use chrono::{Duration, TimeZone, Utc};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use waterfall::interval::Interval;
+use waterfall::interval_set::IntervalSet;
+
+/// Every other 15-minute interval over `years`, offset by `offset`
+/// minutes, so the set doesn't coalesce into one interval
+fn quarter_hours(years: i64, offset: i64) -> IntervalSet {
+    let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap() + Duration::minutes(offset);
+    let count = years * 365 * 24 * 4;
+    let intervals: Vec<Interval> = (0..count)
+        .step_by(2)
+        .map(|i| {
+            let at = start + Duration::minutes(15 * i);
+            Interval::new(at, at + Duration::minutes(15))
+        })
+        .collect();
+    IntervalSet::from(intervals)
+}
+
+fn bench_interval_set(c: &mut Criterion) {
+    let a = quarter_hours(3, 0);
+    let b = quarter_hours(3, 5);
+    let middle = a[a.len() / 2];
+    let probe = Interval::new(middle.start, middle.start + Duration::minutes(10));
+
+    c.bench_function("has_subset", |bench| {
+        bench.iter(|| black_box(&a).has_subset(black_box(probe)))
+    });
+    c.bench_function("contains", |bench| {
+        bench.iter(|| black_box(&a).contains(black_box(probe.end)))
+    });
+    c.bench_function("is_disjoint", |bench| {
+        bench.iter(|| black_box(&a).is_disjoint(black_box(&b)))
+    });
+    c.bench_function("intersection", |bench| {
+        bench.iter(|| black_box(&a).intersection(black_box(&b)))
+    });
+    c.bench_function("union", |bench| {
+        bench.iter(|| black_box(&a).union(black_box(&b)))
+    });
+    c.bench_function("difference", |bench| {
+        bench.iter(|| black_box(&a).difference(black_box(&b)))
+    });
+    c.bench_function("insert", |bench| {
+        bench.iter_batched_ref(
+            || a.clone(),
+            |set| set.insert(black_box(probe)),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_interval_set);
+criterion_main!(benches);
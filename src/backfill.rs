@@ -0,0 +1,144 @@
+//! A throttle on catching up. Bringing up a world with a year of history,
+//! or forcing down a long stretch of it, makes thousands of intervals
+//! runnable at once, and launching them all in the first minute saturates
+//! whatever they load into. Intervals that ended a while ago are backfill,
+//! and only so many of them are launched a minute, oldest first, while
+//! intervals that just ended run as soon as they're ready.
+
+use super::*;
+use std::collections::VecDeque;
+
+fn default_older_than_seconds() -> i64 {
+    3600
+}
+
+/// The longest an interval can have ended before it counts as backfill,
+/// a hundred years
+const MAX_OLDER_THAN_SECONDS: i64 = 36500 * 86400;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BackfillConfig {
+    /// The most backfill actions launched within any minute
+    pub max_launches_per_minute: usize,
+
+    /// Intervals that ended longer ago than this are backfill
+    #[serde(default = "default_older_than_seconds")]
+    pub older_than_seconds: i64,
+}
+
+impl BackfillConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_launches_per_minute == 0 {
+            return Err(Error::Validation(
+                "Backfill must allow at least one launch a minute".to_owned(),
+            ));
+        }
+        if !(0..=MAX_OLDER_THAN_SECONDS).contains(&self.older_than_seconds) {
+            return Err(Error::Validation(format!(
+                "Backfill older_than_seconds of {} must be between 0 and {}",
+                self.older_than_seconds, MAX_OLDER_THAN_SECONDS
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BackfillThrottle {
+    config: BackfillConfig,
+
+    /// Recent backfill launches, oldest first
+    launches: VecDeque<DateTime<Utc>>,
+}
+
+impl BackfillThrottle {
+    pub fn new(config: BackfillConfig) -> Self {
+        BackfillThrottle {
+            config,
+            launches: VecDeque::new(),
+        }
+    }
+
+    /// Changes the limits, keeping track of recent launches
+    pub fn reconfigure(&mut self, config: BackfillConfig) {
+        self.config = config;
+    }
+
+    pub fn is_backfill(&self, interval: Interval, now: DateTime<Utc>) -> bool {
+        Duration::try_seconds(self.config.older_than_seconds)
+            .and_then(|age| interval.end.checked_add_signed(age))
+            .is_some_and(|cutoff| cutoff < now)
+    }
+
+    /// Whether an action for the interval may launch now. Launches of
+    /// backfill are counted against the limit.
+    pub fn admit(&mut self, interval: Interval, now: DateTime<Utc>) -> bool {
+        if !self.is_backfill(interval, now) {
+            return true;
+        }
+        let window_start = now - Duration::try_minutes(1).unwrap();
+        while self.launches.front().is_some_and(|t| *t <= window_start) {
+            self.launches.pop_front();
+        }
+        if self.launches.len() >= self.config.max_launches_per_minute {
+            return false;
+        }
+        self.launches.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_throttle() {
+        let mut throttle = BackfillThrottle::new(
+            serde_json::from_str(r#"{ "max_launches_per_minute": 2 }"#).unwrap(),
+        );
+        let start = Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap();
+        let at = |secs| start + Duration::try_seconds(secs).unwrap();
+        let ending = |end| Interval::new(end - Duration::try_days(1).unwrap(), end);
+        let old = ending(start - Duration::try_days(30).unwrap());
+
+        // Only so much backfill a minute
+        assert!(throttle.admit(old, at(0)));
+        assert!(throttle.admit(old, at(10)));
+        assert!(!throttle.admit(old, at(20)));
+
+        // Intervals that just ended aren't held back
+        assert!(!throttle.is_backfill(ending(at(-60)), at(20)));
+        assert!(throttle.admit(ending(at(-60)), at(20)));
+
+        // The budget frees up as launches age out of the window
+        assert!(!throttle.admit(old, at(59)));
+        assert!(throttle.admit(old, at(60)));
+        assert!(!throttle.admit(old, at(61)));
+        assert!(throttle.admit(old, at(70)));
+    }
+
+    #[test]
+    fn check_validate() {
+        let config = |json| serde_json::from_str::<BackfillConfig>(json).unwrap();
+        assert!(config(r#"{ "max_launches_per_minute": 2 }"#)
+            .validate()
+            .is_ok());
+        assert!(config(r#"{ "max_launches_per_minute": 0 }"#)
+            .validate()
+            .is_err());
+        let huge = config(
+            r#"{ "max_launches_per_minute": 2, "older_than_seconds": 9223372036854775807 }"#,
+        );
+        assert!(huge.validate().is_err());
+
+        // Even unchecked, a huge age doesn't panic
+        let throttle = BackfillThrottle::new(huge);
+        let end = Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap();
+        assert!(!throttle.is_backfill(
+            Interval::new(end - Duration::try_days(1).unwrap(), end),
+            end
+        ));
+    }
+}
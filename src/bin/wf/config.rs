@@ -59,9 +59,9 @@ pub struct GlobalConfigSpec {
 
 #[derive(Clone)]
 pub struct GlobalConfig {
-    pub pools: HashMap<String, mpsc::UnboundedSender<ExecutorMessage>>,
-    pub storage: mpsc::UnboundedSender<StorageMessage>,
-    pub runner: mpsc::UnboundedSender<RunnerMessage>,
+    pub pools: HashMap<String, mpsc::Sender<ExecutorMessage>>,
+    pub storage: mpsc::Sender<StorageMessage>,
+    pub runner: mpsc::Sender<RunnerMessage>,
     pub default_pool: String,
     pub spec: GlobalConfigSpec,
 }
@@ -72,7 +72,7 @@ impl GlobalConfig {
 
         use PoolConfig::*;
         for (pool, pool_spec) in spec.pools.iter() {
-            let (tx, rx) = mpsc::unbounded_channel();
+            let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
             match pool_spec {
                 Local { workers } => {
                     local_executor::start(*workers, rx);
@@ -95,14 +95,14 @@ impl GlobalConfig {
         }
 
         // Storage
-        let (tracker, trx) = mpsc::unbounded_channel();
+        let (tracker, trx) = mpsc::channel(CHANNEL_CAPACITY);
         use StorageConfig::*;
         match spec.tracker {
             Memory => memory_tracker::start(trx),
         }
 
         // Runner
-        let (runner, rrx) = mpsc::unbounded_channel();
+        let (runner, rrx) = mpsc::channel(CHANNEL_CAPACITY);
         let rtx = runner.clone();
         runner::start(rtx, rrx);
 
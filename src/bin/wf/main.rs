@@ -1,56 +1,202 @@
 use clap::Parser;
 
-use log::*;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
-use waterfall;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
 use waterfall::prelude::*;
 
+mod status;
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case", deny_unknown_fields, tag = "type")]
 enum StorageConfig {
-    Redis { url: String, prefix: String },
+    /// One of `url`, `sentinel`, or `cluster_nodes` says where redis is
+    Redis {
+        #[serde(default)]
+        url: Option<String>,
+
+        #[serde(default)]
+        sentinel: Option<waterfall::storage::redis::SentinelConfig>,
+
+        #[serde(default)]
+        cluster_nodes: Option<Vec<String>>,
+
+        prefix: String,
+
+        /// How long attempts are kept after they finish
+        #[serde(default)]
+        attempt_ttl_seconds: Option<u64>,
+
+        /// Periodically copy the state, keeping a rolling window of copies
+        #[serde(default)]
+        snapshots: Option<SnapshotConfig>,
+    },
+    /// JSON files under a directory, created if it doesn't exist
+    File { directory: std::path::PathBuf },
+    /// Nothing kept past the run, for trying out worlds
+    Memory {
+        /// Attempts kept for each task
+        #[serde(default = "default_max_attempts")]
+        max_attempts: usize,
+    },
+    /// A local database file, created if it doesn't exist
+    #[cfg(feature = "sqlite-storage")]
+    Sqlite { path: String },
+    /// Objects in an S3-compatible bucket
+    #[cfg(feature = "s3-storage")]
+    S3 {
+        bucket: String,
+        #[serde(default)]
+        prefix: String,
+        #[serde(default)]
+        endpoint: Option<String>,
+        #[serde(default)]
+        region: Option<String>,
+
+        #[serde(default)]
+        snapshots: Option<SnapshotConfig>,
+    },
+}
+
+fn default_max_attempts() -> usize {
+    waterfall::storage::memory::DEFAULT_MAX_ATTEMPTS
 }
 
 impl StorageConfig {
-    fn start(
-        &self,
-    ) -> (
-        mpsc::UnboundedSender<StorageMessage>,
-        tokio::task::JoinHandle<()>,
-    ) {
-        let (tx, rx) = mpsc::unbounded_channel();
+    fn start(&self) -> (mpsc::Sender<StorageMessage>, tokio::task::JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
         match self {
-            StorageConfig::Redis { url, prefix } => (
-                tx,
-                waterfall::storage::redis::start(rx, url.clone(), prefix.clone()),
-            ),
+            StorageConfig::Redis {
+                url,
+                sentinel,
+                cluster_nodes,
+                prefix,
+                attempt_ttl_seconds,
+                snapshots,
+            } => {
+                if let Some(config) = snapshots {
+                    waterfall::storage::snapshots::schedule(tx.clone(), config.clone());
+                }
+                let config = waterfall::storage::redis::RedisConfig {
+                    url: url.clone(),
+                    sentinel: sentinel.clone(),
+                    cluster_nodes: cluster_nodes.clone(),
+                    prefix: prefix.clone(),
+                    attempt_ttl_seconds: *attempt_ttl_seconds,
+                };
+                (tx, waterfall::storage::redis::start(rx, config))
+            }
+            StorageConfig::File { directory } => {
+                (tx, waterfall::storage::file::start(rx, directory.clone()))
+            }
+            StorageConfig::Memory { max_attempts } => {
+                let storage =
+                    waterfall::storage::memory::MemoryStorage::with_max_attempts(*max_attempts);
+                (tx, waterfall::storage::start(storage, rx))
+            }
+            #[cfg(feature = "sqlite-storage")]
+            StorageConfig::Sqlite { path } => {
+                (tx, waterfall::storage::sqlite::start(rx, path.clone()))
+            }
+            #[cfg(feature = "s3-storage")]
+            StorageConfig::S3 {
+                bucket,
+                prefix,
+                endpoint,
+                region,
+                snapshots,
+            } => {
+                if let Some(config) = snapshots {
+                    waterfall::storage::snapshots::schedule(tx.clone(), config.clone());
+                }
+                let config = waterfall::storage::s3::S3Config {
+                    bucket: bucket.clone(),
+                    prefix: prefix.clone(),
+                    endpoint: endpoint.clone(),
+                    region: region.clone(),
+                };
+                (tx, waterfall::storage::s3::start(rx, config))
+            }
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::large_enum_variant)]
 #[serde(rename_all = "snake_case", deny_unknown_fields, tag = "type")]
 enum ExecutorConfig {
     Local {
         workers: usize,
+
+        #[serde(default)]
+        environment: local_executor::EnvironmentConfig,
+
+        #[serde(default)]
+        output_sink: Option<OutputSink>,
+
+        /// The machine's capacity, which tasks' resources are held against
+        #[serde(default)]
+        resources: Option<TaskResources>,
+
+        /// cgroup and niceness limits placed on tasks' processes
+        #[serde(default)]
+        enforcement: Option<local_executor::EnforcementConfig>,
+
+        /// Where `${secret:NAME}` references in tasks are looked up
+        #[serde(default)]
+        secrets: Option<SecretsConfig>,
     },
     Agent {
         targets: Vec<agent_executor::AgentTarget>,
+
+        /// How tasks are spread over the targets with capacity for them
+        #[serde(default)]
+        selection: agent_executor::Selection,
+
+        /// How often the targets are checked in on, 30 seconds by default
+        #[serde(default)]
+        heartbeat_seconds: Option<u64>,
     },
 }
 
 impl ExecutorConfig {
-    fn start(
-        &self,
-    ) -> (
-        mpsc::UnboundedSender<ExecutorMessage>,
-        tokio::task::JoinHandle<()>,
-    ) {
-        let (tx, rx) = mpsc::unbounded_channel();
+    fn start(&self) -> (mpsc::Sender<ExecutorMessage>, tokio::task::JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
         match self {
-            ExecutorConfig::Local { workers } => (tx, local_executor::start(*workers, rx)),
-            ExecutorConfig::Agent { targets } => (tx, agent_executor::start(targets.clone(), rx)),
+            ExecutorConfig::Local {
+                workers,
+                environment,
+                output_sink,
+                resources,
+                enforcement,
+                secrets,
+            } => {
+                let config = local_executor::LocalExecutorConfig {
+                    environment: environment.clone(),
+                    output_sink: output_sink.clone(),
+                    resources: resources.clone(),
+                    enforcement: enforcement.clone(),
+                    secrets: secrets.clone(),
+                };
+                (tx, local_executor::start_with_config(*workers, config, rx))
+            }
+            ExecutorConfig::Agent {
+                targets,
+                selection,
+                heartbeat_seconds,
+            } => {
+                let mut config = agent_executor::AgentExecutorConfig {
+                    selection: *selection,
+                    ..Default::default()
+                };
+                if let Some(seconds) = heartbeat_seconds {
+                    config.heartbeat_seconds = *seconds;
+                }
+                (
+                    tx,
+                    agent_executor::start_with_config(targets.clone(), config, rx),
+                )
+            }
         }
     }
 }
@@ -62,24 +208,90 @@ struct Config {
     executor: ExecutorConfig,
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Check the world for problems without running it, listing every one
+    /// found. Exits nonzero if there are any. With a config, each task's
+    /// commands are also checked against its executor.
+    Validate,
+    /// List the actions running the world would queue, and why, without
+    /// running anything. Uses the stored state, or none with
+    /// --force-recheck.
+    Plan {
+        /// Plan for the state due by this time instead of now, e.g.
+        /// 2022-01-05T22:00:00Z
+        #[clap(short, long)]
+        until: Option<DateTime<Utc>>,
+    },
+    /// Print the coverage and gaps of each resource of a running wfd, and
+    /// its running, errored, and failed actions
+    Status {
+        /// Base URL of the wfd API
+        #[clap(short, long, default_value = "http://localhost:2503/api/v1")]
+        url: String,
+
+        /// How many hours back to list actions, up to a century
+        #[clap(long, default_value_t = 24, value_parser = clap::value_parser!(i64).range(1..=876_000))]
+        hours: i64,
+
+        /// Comma separated resources to limit the report to
+        #[clap(short, long)]
+        resources: Option<String>,
+    },
+    /// Re-execute the most recent failed attempt of a task interval, using
+    /// the variables it originally ran with
+    Replay {
+        /// Name of the task
+        #[clap(short, long)]
+        task: String,
+
+        /// End of the interval, e.g. 2022-01-05T22:00:00Z
+        #[clap(short, long)]
+        end: DateTime<Utc>,
+    },
+    /// Attach a note to a task interval, e.g. why it was forced up
+    Annotate {
+        /// Name of the task
+        #[clap(short, long)]
+        task: String,
+
+        /// End of the interval, e.g. 2022-01-05T22:00:00Z
+        #[clap(short, long)]
+        end: DateTime<Utc>,
+
+        /// Who is writing the note
+        #[clap(short, long)]
+        author: Option<String>,
+
+        text: String,
+    },
+}
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Configuration File
-    #[clap(short, long, default_value = "")]
+    #[clap(short, long, default_value = "", global = true)]
     config: String,
 
     /// Configuration File
-    #[clap(short, long, default_value = "")]
+    #[clap(short, long, default_value = "", global = true)]
     world: String,
 
-    /// Enable verbose logging
-    #[clap(short, long)]
-    verbose: bool,
+    #[clap(flatten)]
+    log: waterfall::telemetry::LogArgs,
 
     /// Force a full re-check
     #[clap(short, long)]
     force_recheck: bool,
+
+    /// Reload the world whenever the world file changes
+    #[cfg(feature = "watch")]
+    #[clap(long)]
+    watch: bool,
 }
 
 /*
@@ -94,36 +306,233 @@ struct Args {
         "executor": {
             "type": "local",
             "workers": 10,
+            "environment": {
+                "inherit": { "only": [ "PATH", "HOME" ] },
+                "set": { "JAVA_HOME": "/opt/java" }
+            }
         }
     }
 */
 
+/// Every problem with the world in `args`, checking commands against the
+/// executor if there's a config
+async fn validate(args: &Args) -> Vec<String> {
+    let world_def: WorldDefinition = match waterfall::config_file::load(&args.world) {
+        Ok(world_def) => world_def,
+        Err(e) => return vec![format!("Unable to load {}: {}", args.world, e)],
+    };
+    for warning in world_def.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    let mut problems: Vec<String> = world_def.problems().iter().map(|e| e.to_string()).collect();
+    let Ok(taskset) = world_def.unchecked_taskset() else {
+        return problems;
+    };
+    if args.config.is_empty() {
+        eprintln!("No config given, so commands weren't checked against an executor");
+        return problems;
+    }
+    let config: Config = match waterfall::config_file::load(&args.config) {
+        Ok(config) => config,
+        Err(e) => {
+            problems.push(format!("Unable to load {}: {}", args.config, e));
+            return problems;
+        }
+    };
+
+    let (exe_tx, exe_handle) = config.executor.start();
+    let mut tasks: Vec<_> = taskset.iter().collect();
+    tasks.sort_by_key(|task| &task.name);
+    for task in tasks {
+        for (role, details) in task.commands() {
+            let (response, rx) = oneshot::channel();
+            exe_tx
+                .send(ExecutorMessage::ValidateTask {
+                    details: details.clone(),
+                    response,
+                })
+                .await
+                .unwrap();
+            let result = rx.await.unwrap_or_else(|e| Err(e.into()));
+            if let Err(e) = result {
+                problems.push(format!(
+                    "Task {} has a bad {} command: {}",
+                    task.name, role, e
+                ));
+            }
+        }
+    }
+    exe_tx.send(ExecutorMessage::Stop {}).await.unwrap();
+    exe_handle.await.unwrap();
+    problems
+}
+
+/// Prints the actions the runner would queue for `world_def`
+async fn plan(
+    args: &Args,
+    world_def: &WorldDefinition,
+    storage: StorageConfig,
+    until: Option<DateTime<Utc>>,
+) {
+    let tasks = world_def
+        .taskset()
+        .unwrap_or_else(|e| panic!("Invalid world {}: {}", args.world, e));
+
+    let current = if args.force_recheck {
+        waterfall::resource_interval::ResourceInterval::new()
+    } else {
+        let (storage_tx, storage_handle) = storage.start();
+        let (response, rx) = oneshot::channel();
+        storage_tx
+            .send(StorageMessage::LoadState { response })
+            .await
+            .unwrap();
+        let current = rx.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage_handle.await.unwrap();
+        current
+    };
+
+    let actions = waterfall::simulate::plan(&tasks, &current, until.unwrap_or_else(Utc::now))
+        .unwrap_or_else(|e| panic!("Unable to plan {}: {}", args.world, e));
+    for action in &actions {
+        println!("{}", action);
+    }
+    let count = |status| actions.iter().filter(|a| a.status == status).count();
+    println!(
+        "{} action(s): {} ready, {} after other planned actions, {} blocked",
+        actions.len(),
+        count(PlanStatus::Ready),
+        count(PlanStatus::Waiting),
+        count(PlanStatus::Blocked)
+    );
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let args = Args::parse();
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let _telemetry = waterfall::telemetry::init(&args.log, "wf")
+        .unwrap_or_else(|e| panic!("Unable to set up logging: {}", e));
+
+    if let Some(Command::Validate) = &args.command {
+        let problems = validate(&args).await;
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        if !problems.is_empty() {
+            eprintln!("{} problem(s) found in {}", problems.len(), args.world);
+            std::process::exit(1);
+        }
+        println!("{} is valid", args.world);
+        return Ok(());
+    }
+
+    if let Some(Command::Status {
+        url,
+        hours,
+        resources,
+    }) = &args.command
+    {
+        status::status(url, *hours, resources.as_deref()).await;
+        return Ok(());
+    }
 
     // Parse the config
-    let world_json = std::fs::read_to_string(&args.world)
-        .expect(&format!("Unable to open {} for reading", args.config));
-    let world_def: WorldDefinition =
-        serde_json::from_str(&world_json).expect("Unable to parse world definition");
+    let world_def: WorldDefinition = waterfall::config_file::load(&args.world)
+        .unwrap_or_else(|e| panic!("Unable to load world definition from {}: {}", args.world, e));
 
     // Parse the config
-    let config_json = std::fs::read_to_string(&args.config)
-        .expect(&format!("Unable to open {} for reading", args.config));
-    let config: Config =
-        serde_json::from_str(&config_json).expect("Unable to parse config definition");
+    let config: Config = waterfall::config_file::load(&args.config)
+        .unwrap_or_else(|e| panic!("Unable to load config from {}: {}", args.config, e));
+
+    if let Some(Command::Plan { until }) = &args.command {
+        plan(&args, &world_def, config.storage, *until).await;
+        return Ok(());
+    }
 
     // Start the config
     let (exe_tx, exe_handle) = config.executor.start();
     let (storage_tx, storage_handle) = config.storage.start();
 
     let tasks = world_def.taskset().unwrap();
+    for warning in world_def.warnings() {
+        warn!("{}", warning);
+    }
 
     debug!("Config: {:?}", args);
 
-    let (_runner_tx, runner_rx) = mpsc::unbounded_channel();
+    if let Some(Command::Annotate {
+        task,
+        end,
+        author,
+        text,
+    }) = &args.command
+    {
+        let interval = tasks
+            .iter()
+            .find(|t| t.name == *task)
+            .unwrap_or_else(|| panic!("No such task {}", task))
+            .schedule
+            .interval(*end, 0);
+        let (response, rx) = oneshot::channel();
+        storage_tx
+            .send(StorageMessage::StoreAnnotation {
+                annotation: IntervalAnnotation {
+                    task_name: task.clone(),
+                    interval,
+                    annotation: Annotation {
+                        time: Utc::now(),
+                        author: author.clone(),
+                        text: text.clone(),
+                    },
+                },
+                response,
+            })
+            .await
+            .unwrap();
+        let res = rx.await.unwrap();
+
+        exe_tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        exe_handle.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage_handle.await.unwrap();
+
+        res.unwrap_or_else(|e| panic!("Unable to annotate {}: {}", task, e));
+        return Ok(());
+    }
+
+    if let Some(Command::Replay { task, end }) = &args.command {
+        let interval = tasks
+            .iter()
+            .find(|t| t.name == *task)
+            .unwrap_or_else(|| panic!("No such task {}", task))
+            .schedule
+            .interval(*end, 0);
+        let res = waterfall::replay::replay(
+            task,
+            interval,
+            &exe_tx,
+            &storage_tx,
+            world_def.output_options,
+        )
+        .await;
+
+        exe_tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        exe_handle.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage_handle.await.unwrap();
+
+        let attempt = res.unwrap_or_else(|e| panic!("Unable to replay {}: {}", task, e));
+        println!("{}", serde_json::to_string_pretty(&attempt).unwrap());
+        if !attempt.succeeded {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // The sender is only kept for watching
+    #[cfg_attr(not(feature = "watch"), allow(unused_variables))]
+    let (runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
     let mut runner = Runner::new(
         tasks,
         world_def.variables,
@@ -135,13 +544,36 @@ async fn main() -> std::io::Result<()> {
     )
     .await
     .unwrap();
+    if let Some(breaker) = world_def.circuit_breaker {
+        runner.set_circuit_breaker(breaker);
+    }
+    if let Some(limit) = world_def.max_running {
+        runner.set_max_running(limit);
+    }
+    if let Some(config) = world_def.backfill {
+        runner.set_backfill(config);
+    }
+    if let Some(days) = world_def.action_history_days {
+        runner.set_action_history(chrono::Duration::try_days(days).unwrap());
+    }
+    runner.set_resource_aliases(world_def.resource_aliases);
 
-    runner.run(false).await;
+    // Watching keeps the runner up for future edits
+    #[cfg(feature = "watch")]
+    let watching = args.watch;
+    #[cfg(not(feature = "watch"))]
+    let watching = false;
+    #[cfg(feature = "watch")]
+    if watching {
+        waterfall::watch::watch_world(&args.world, runner_tx)
+            .unwrap_or_else(|e| panic!("Unable to watch {}: {}", args.world, e));
+    }
+    runner.run(watching).await;
 
-    exe_tx.send(ExecutorMessage::Stop {}).unwrap();
+    exe_tx.send(ExecutorMessage::Stop {}).await.unwrap();
     exe_handle.await.unwrap();
 
-    storage_tx.send(StorageMessage::Stop {}).unwrap();
+    storage_tx.send(StorageMessage::Stop {}).await.unwrap();
     storage_handle.await.unwrap();
 
     Ok(())
@@ -0,0 +1,203 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use waterfall::interval_set::IntervalSet;
+use waterfall::prelude::*;
+use waterfall::runner::RunnerState;
+
+/// An action, as listed by wfd's `/details`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimelineInterval {
+    time_range: [DateTime<Utc>; 2],
+    val: ActionState,
+}
+
+#[derive(Deserialize)]
+struct TimelineLabel {
+    label: String,
+    data: Vec<TimelineInterval>,
+}
+
+#[derive(Deserialize)]
+struct TimelineGroup {
+    group: String,
+    data: Vec<TimelineLabel>,
+}
+
+/// An unfinished or unhealthy action, and the resources it provides
+struct Outstanding {
+    state: ActionState,
+    interval: Interval,
+    resources: Vec<String>,
+}
+
+fn time(dt: DateTime<Utc>) -> String {
+    dt.format("%Y-%m-%d %H:%M").to_string()
+}
+
+fn interval(interval: &Interval) -> String {
+    format!("({}, {}]", time(interval.start), time(interval.end))
+}
+
+/// Prints `rows` in columns wide enough for their widest cell
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+async fn fetch_state(client: &reqwest::Client, url: &str) -> reqwest::Result<RunnerState> {
+    client
+        .get(format!("{}/state", url))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+/// Every page of the actions over `span`
+async fn fetch_details(
+    client: &reqwest::Client,
+    url: &str,
+    span: Interval,
+    resources: Option<&str>,
+) -> reqwest::Result<Vec<TimelineGroup>> {
+    let mut groups = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut query: Vec<(&str, &str)> = Vec::new();
+        if let Some(resources) = resources {
+            query.push(("resources", resources));
+        }
+        if let Some(cursor) = &cursor {
+            query.push(("cursor", cursor));
+        }
+        let response = client
+            .post(format!("{}/details", url))
+            .query(&query)
+            .json(&span)
+            .send()
+            .await?
+            .error_for_status()?;
+        cursor = response
+            .headers()
+            .get("Next-Cursor")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        groups.extend(response.json::<Vec<TimelineGroup>>().await?);
+        if cursor.is_none() {
+            return Ok(groups);
+        }
+    }
+}
+
+/// Prints the coverage and gaps of each resource of the wfd at `url`,
+/// then its running, errored, and failed actions over the last `hours`
+pub async fn status(url: &str, hours: i64, resources: Option<&str>) {
+    let url = url.trim_end_matches('/');
+    let client = reqwest::Client::new();
+    let now = Utc::now();
+
+    let state = fetch_state(&client, url)
+        .await
+        .unwrap_or_else(|e| panic!("Unable to query {}: {}", url, e));
+    let wanted: Option<HashSet<&str>> =
+        resources.map(|r| r.split(',').map(str::trim).collect::<HashSet<&str>>());
+
+    let due = IntervalSet::from(Interval::new(DateTime::<Utc>::MIN_UTC, now));
+    let mut rows = Vec::new();
+    let names: BTreeSet<&String> = state.coverage.keys().collect();
+    for resource in names {
+        if wanted
+            .as_ref()
+            .is_some_and(|w| !w.contains(resource.as_str()))
+        {
+            continue;
+        }
+        let current = state.current.get(resource).cloned().unwrap_or_default();
+        let gaps = state.coverage[resource]
+            .intersection(&due)
+            .difference(&current);
+        let failed = state.failed.get(resource).map_or(0, |is| is.len());
+        rows.push(vec![
+            resource.clone(),
+            current.end().map_or("-".to_owned(), time),
+            gaps.len().to_string(),
+            gaps.first().map_or("-".to_owned(), interval),
+            failed.to_string(),
+        ]);
+    }
+    print_table(
+        &["RESOURCE", "COVERED TO", "GAPS", "FIRST GAP", "FAILED"],
+        &rows,
+    );
+
+    let span = Interval::new(now - Duration::hours(hours), now);
+    let groups = fetch_details(&client, url, span, resources)
+        .await
+        .unwrap_or_else(|e| panic!("Unable to query {}: {}", url, e));
+
+    // A task providing several resources is listed under each of them
+    let mut actions: BTreeMap<(DateTime<Utc>, String), Outstanding> = BTreeMap::new();
+    for group in groups {
+        for label in group.data {
+            for action in label.data {
+                if !matches!(
+                    action.val,
+                    ActionState::Running | ActionState::Errored | ActionState::Failed
+                ) {
+                    continue;
+                }
+                let [start, end] = action.time_range;
+                actions
+                    .entry((end, label.label.clone()))
+                    .or_insert_with(|| Outstanding {
+                        state: action.val,
+                        interval: Interval::new(start, end),
+                        resources: Vec::new(),
+                    })
+                    .resources
+                    .push(group.group.clone());
+            }
+        }
+    }
+
+    println!();
+    if actions.is_empty() {
+        println!(
+            "No running, errored, or failed actions in the last {} hours",
+            hours
+        );
+        return;
+    }
+    let rows: Vec<Vec<String>> = actions
+        .into_iter()
+        .map(|((_, task), mut action)| {
+            action.resources.sort();
+            vec![
+                format!("{:?}", action.state),
+                task,
+                interval(&action.interval),
+                action.resources.join(","),
+            ]
+        })
+        .collect();
+    print_table(&["STATE", "TASK", "INTERVAL", "RESOURCES"], &rows);
+}
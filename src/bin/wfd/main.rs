@@ -1,8 +1,9 @@
 use actix_cors::Cors;
 use actix_web::{error, middleware::Logger, web, App, HttpResponse, HttpServer, Responder};
 use clap::Parser;
-use log::*;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::{info, warn};
 
 use tokio::sync::{mpsc, oneshot};
 use waterfall::prelude::*;
@@ -31,48 +32,247 @@ impl Default for ServerConfig {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case", deny_unknown_fields, tag = "type")]
 enum StorageConfig {
-    Redis { url: String, prefix: String },
+    /// One of `url`, `sentinel`, or `cluster_nodes` says where redis is
+    Redis {
+        #[serde(default)]
+        url: Option<String>,
+
+        #[serde(default)]
+        sentinel: Option<waterfall::storage::redis::SentinelConfig>,
+
+        #[serde(default)]
+        cluster_nodes: Option<Vec<String>>,
+
+        prefix: String,
+
+        /// How long attempts are kept after they finish
+        #[serde(default)]
+        attempt_ttl_seconds: Option<u64>,
+
+        /// Periodically copy the state, keeping a rolling window of copies
+        #[serde(default)]
+        snapshots: Option<SnapshotConfig>,
+    },
+    /// JSON files under a directory, created if it doesn't exist
+    File { directory: std::path::PathBuf },
+    /// Objects in an S3-compatible bucket
+    #[cfg(feature = "s3-storage")]
+    S3 {
+        bucket: String,
+        #[serde(default)]
+        prefix: String,
+        #[serde(default)]
+        endpoint: Option<String>,
+        #[serde(default)]
+        region: Option<String>,
+
+        #[serde(default)]
+        snapshots: Option<SnapshotConfig>,
+    },
 }
 
 impl StorageConfig {
-    fn start(
-        &self,
-    ) -> (
-        mpsc::UnboundedSender<StorageMessage>,
-        tokio::task::JoinHandle<()>,
-    ) {
-        let (tx, rx) = mpsc::unbounded_channel();
+    fn start(&self) -> (mpsc::Sender<StorageMessage>, tokio::task::JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
         match self {
-            StorageConfig::Redis { url, prefix } => (
-                tx,
-                waterfall::storage::redis::start(rx, url.clone(), prefix.clone()),
-            ),
+            StorageConfig::Redis {
+                url,
+                sentinel,
+                cluster_nodes,
+                prefix,
+                attempt_ttl_seconds,
+                snapshots,
+            } => {
+                if let Some(config) = snapshots {
+                    waterfall::storage::snapshots::schedule(tx.clone(), config.clone());
+                }
+                let config = waterfall::storage::redis::RedisConfig {
+                    url: url.clone(),
+                    sentinel: sentinel.clone(),
+                    cluster_nodes: cluster_nodes.clone(),
+                    prefix: prefix.clone(),
+                    attempt_ttl_seconds: *attempt_ttl_seconds,
+                };
+                (tx, waterfall::storage::redis::start(rx, config))
+            }
+            StorageConfig::File { directory } => {
+                (tx, waterfall::storage::file::start(rx, directory.clone()))
+            }
+            #[cfg(feature = "s3-storage")]
+            StorageConfig::S3 {
+                bucket,
+                prefix,
+                endpoint,
+                region,
+                snapshots,
+            } => {
+                if let Some(config) = snapshots {
+                    waterfall::storage::snapshots::schedule(tx.clone(), config.clone());
+                }
+                let config = waterfall::storage::s3::S3Config {
+                    bucket: bucket.clone(),
+                    prefix: prefix.clone(),
+                    endpoint: endpoint.clone(),
+                    region: region.clone(),
+                };
+                (tx, waterfall::storage::s3::start(rx, config))
+            }
+        }
+    }
+}
+
+impl StorageConfig {
+    /// The storage of a namespace, defaulting to a prefix of its own
+    fn namespaced(&self, namespace: &str, prefix: Option<&String>) -> StorageConfig {
+        match self {
+            StorageConfig::Redis {
+                url,
+                sentinel,
+                cluster_nodes,
+                prefix: base,
+                attempt_ttl_seconds,
+                snapshots,
+            } => StorageConfig::Redis {
+                url: url.clone(),
+                sentinel: sentinel.clone(),
+                cluster_nodes: cluster_nodes.clone(),
+                prefix: prefix
+                    .cloned()
+                    .unwrap_or_else(|| format!("{}/{}", base, namespace)),
+                attempt_ttl_seconds: *attempt_ttl_seconds,
+                snapshots: snapshots.clone(),
+            },
+            StorageConfig::File { directory } => StorageConfig::File {
+                directory: match prefix {
+                    Some(prefix) => directory.join(prefix),
+                    None => directory.join(namespace),
+                },
+            },
+            #[cfg(feature = "s3-storage")]
+            StorageConfig::S3 {
+                bucket,
+                prefix: base,
+                endpoint,
+                region,
+                snapshots,
+            } => StorageConfig::S3 {
+                bucket: bucket.clone(),
+                prefix: prefix
+                    .cloned()
+                    .unwrap_or_else(|| format!("{}/{}", base, namespace)),
+                endpoint: endpoint.clone(),
+                region: region.clone(),
+                snapshots: snapshots.clone(),
+            },
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::large_enum_variant)]
 #[serde(rename_all = "snake_case", deny_unknown_fields, tag = "type")]
 enum ExecutorConfig {
     Local {
         workers: usize,
+
+        #[serde(default)]
+        environment: local_executor::EnvironmentConfig,
+
+        #[serde(default)]
+        output_sink: Option<OutputSink>,
+
+        /// The machine's capacity, which tasks' resources are held against
+        #[serde(default)]
+        resources: Option<TaskResources>,
+
+        /// cgroup and niceness limits placed on tasks' processes
+        #[serde(default)]
+        enforcement: Option<local_executor::EnforcementConfig>,
+
+        /// Where `${secret:NAME}` references in tasks are looked up
+        #[serde(default)]
+        secrets: Option<SecretsConfig>,
     },
     Agent {
         targets: Vec<agent_executor::AgentTarget>,
+
+        /// How tasks are spread over the targets with capacity for them
+        #[serde(default)]
+        selection: agent_executor::Selection,
+
+        /// How often the targets are checked in on, 30 seconds by default
+        #[serde(default)]
+        heartbeat_seconds: Option<u64>,
     },
 }
 
 impl ExecutorConfig {
-    fn start(
-        &self,
-    ) -> (
-        mpsc::UnboundedSender<ExecutorMessage>,
-        tokio::task::JoinHandle<()>,
-    ) {
-        let (tx, rx) = mpsc::unbounded_channel();
+    fn start(&self) -> (mpsc::Sender<ExecutorMessage>, tokio::task::JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        match self {
+            ExecutorConfig::Local {
+                workers,
+                environment,
+                output_sink,
+                resources,
+                enforcement,
+                secrets,
+            } => {
+                let config = local_executor::LocalExecutorConfig {
+                    environment: environment.clone(),
+                    output_sink: output_sink.clone(),
+                    resources: resources.clone(),
+                    enforcement: enforcement.clone(),
+                    secrets: secrets.clone(),
+                };
+                (tx, local_executor::start_with_config(*workers, config, rx))
+            }
+            ExecutorConfig::Agent {
+                targets,
+                selection,
+                heartbeat_seconds,
+            } => {
+                let mut config = agent_executor::AgentExecutorConfig {
+                    selection: *selection,
+                    ..Default::default()
+                };
+                if let Some(seconds) = heartbeat_seconds {
+                    config.heartbeat_seconds = *seconds;
+                }
+                (
+                    tx,
+                    agent_executor::start_with_config(targets.clone(), config, rx),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "notifications")]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum NotifierConfig {
+    Webhook(waterfall::notifications::webhook::WebhookConfig),
+    Slack(waterfall::notifications::slack::SlackConfig),
+    #[cfg(feature = "email")]
+    Email(waterfall::notifications::email::EmailConfig),
+}
+
+#[cfg(feature = "notifications")]
+impl NotifierConfig {
+    fn sink(&self) -> Box<dyn waterfall::notifications::Sink> {
         match self {
-            ExecutorConfig::Local { workers } => (tx, local_executor::start(*workers, rx)),
-            ExecutorConfig::Agent { targets } => (tx, agent_executor::start(targets.clone(), rx)),
+            NotifierConfig::Webhook(config) => Box::new(
+                waterfall::notifications::webhook::WebhookSink::new(config.clone()),
+            ),
+            NotifierConfig::Slack(config) => Box::new(
+                waterfall::notifications::slack::SlackSink::new(config.clone()),
+            ),
+            #[cfg(feature = "email")]
+            NotifierConfig::Email(config) => Box::new(
+                waterfall::notifications::email::EmailSink::new(config.clone())
+                    .unwrap_or_else(|e| panic!("Unable to set up email notifications: {}", e)),
+            ),
         }
     }
 }
@@ -83,6 +283,61 @@ struct Config {
     storage: StorageConfig,
     executor: ExecutorConfig,
     server: ServerConfig,
+
+    /// Run only part of the world, sharing state with the instances running
+    /// the rest of it through storage
+    #[serde(default)]
+    shard: Option<Shard>,
+
+    /// Additional executors namespaces can be assigned to, by name
+    #[serde(default)]
+    executor_pools: HashMap<String, ExecutorConfig>,
+
+    /// Additional worlds hosted alongside the main one
+    #[serde(default)]
+    namespaces: HashMap<String, NamespaceConfig>,
+
+    /// Bearer token required to add or remove agents at runtime. Without
+    /// one, agents can only be changed in the config. Falls back to
+    /// `WFD_REGISTRATION_TOKEN`.
+    #[serde(default)]
+    registration_token: Option<String>,
+
+    /// Where runner events, like failures and missed deadlines, are sent
+    #[cfg(feature = "notifications")]
+    #[serde(default)]
+    notifications: Vec<NotifierConfig>,
+}
+
+/// Names that would shadow the main world's routes
+const RESERVED_NAMESPACES: [&str; 8] = [
+    "state",
+    "events",
+    "details",
+    "tasks",
+    "resources",
+    "actions",
+    "world",
+    "executor",
+];
+
+/// A world hosted alongside the main one, isolated under its own storage
+/// prefix and served under `/api/v1/{namespace}/...`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct NamespaceConfig {
+    /// The world definition file
+    world: String,
+
+    /// Storage prefix, defaulting to the main prefix followed by
+    /// `/{namespace}`. With file storage, the subdirectory of the main
+    /// directory to use, defaulting to the namespace.
+    #[serde(default)]
+    prefix: Option<String>,
+
+    /// The executor pool to run on, defaulting to the main executor
+    #[serde(default)]
+    executor: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -90,22 +345,73 @@ struct SimpleError {
     error: String,
 }
 
-async fn get_state(state: web::Data<AppState>) -> impl Responder {
-    let (response, rx) = oneshot::channel();
+/// Sends `message` and waits for the answer on `rx`
+async fn ask<M, T>(
+    tx: &mpsc::Sender<M>,
+    message: M,
+    rx: oneshot::Receiver<T>,
+) -> Result<T, WaterfallError> {
+    tx.send(message).await?;
+    Ok(rx.await?)
+}
 
-    state
-        .runner_tx
-        .send(RunnerMessage::GetState { response })
-        .unwrap();
+/// A runner, storage, or executor that has shut down, as a 503
+fn unavailable(error: WaterfallError) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(SimpleError {
+        error: error.to_string(),
+    })
+}
 
-    match rx.await {
+async fn get_state(state: web::Data<AppState>) -> impl Responder {
+    match state.runner.state().await {
         Ok(world) => HttpResponse::Ok().json(world),
         Err(error) => HttpResponse::BadRequest().json(SimpleError {
-            error: format!("{:?}", error),
+            error: error.to_string(),
         }),
     }
 }
 
+/// How often an idle event stream sends a comment, so proxies between it
+/// and the client don't time it out
+const EVENT_KEEPALIVE_SECONDS: u64 = 15;
+
+/// Streams the runner's events as server-sent events, named by their kind.
+/// A client that falls too far behind receives a `lagged` event with the
+/// number of events it missed, and should refetch what it displays.
+async fn get_events(state: web::Data<AppState>) -> impl Responder {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let events = match state.runner.subscribe().await {
+        Ok(events) => events,
+        Err(error) => {
+            return HttpResponse::InternalServerError().json(SimpleError {
+                error: error.to_string(),
+            })
+        }
+    };
+
+    let keepalive = std::time::Duration::from_secs(EVENT_KEEPALIVE_SECONDS);
+    let stream = futures::stream::unfold(events, move |mut events| async move {
+        let chunk = match tokio::time::timeout(keepalive, events.recv()).await {
+            Err(_) => ": keepalive\n\n".to_owned(),
+            Ok(Ok(event)) => format!(
+                "event: {}\ndata: {}\n\n",
+                event.kind(),
+                serde_json::to_string(&event).unwrap()
+            ),
+            Ok(Err(RecvError::Lagged(missed))) => {
+                format!("event: lagged\ndata: {{\"missed\":{}}}\n\n", missed)
+            }
+            Ok(Err(RecvError::Closed)) => return None,
+        };
+        Some((Ok::<_, error::Error>(web::Bytes::from(chunk)), events))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
+
 /*
   Generates the data structure for [timelines-chart](https://github.com/vasturiano/timelines-chart)
 
@@ -115,6 +421,7 @@ async fn get_state(state: web::Data<AppState>) -> impl Responder {
         "data": [
             {
                 label: "task_name",
+                "group": "task_group",
                 "data": [
                     {
                         "timeRange": [ "start", "end" ],
@@ -132,11 +439,15 @@ async fn get_state(state: web::Data<AppState>) -> impl Responder {
 struct TimelineInterval {
     time_range: [DateTime<Utc>; 2],
     val: ActionState,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    annotations: Vec<Annotation>,
 }
 
 #[derive(Serialize)]
 struct TimelineLabel {
     label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
     data: Vec<TimelineInterval>,
 }
 
@@ -150,50 +461,115 @@ struct TimelineGroup {
 struct DetailedTimelineOptions {
     #[serde(default)]
     max_intervals: Option<usize>,
+
+    /// Comma separated resources to limit the timeline to
+    #[serde(default)]
+    resources: Option<String>,
+
+    /// Comma separated task groups to limit the timeline to
+    #[serde(default)]
+    groups: Option<String>,
+
+    #[serde(default)]
+    bucket_seconds: Option<i64>,
+
+    #[serde(default)]
+    limit: Option<usize>,
+
+    /// The `Next-Cursor` of the previous page
+    #[serde(default)]
+    cursor: Option<String>,
 }
 
+/// The timeline of the actions over an interval. With `limit`, it's paged,
+/// and the `Next-Cursor` header of each page but the last is passed as
+/// `cursor` to fetch the next one.
 async fn get_detailed_timeline(
     options: web::Query<DetailedTimelineOptions>,
     span: web::Json<Interval>,
     state: web::Data<AppState>,
 ) -> impl Responder {
     let interval = span.into_inner();
-    let max_intervals = options.into_inner().max_intervals;
+    let options = options.into_inner();
+    let cursor = match options.cursor.as_deref().map(str::parse).transpose() {
+        Ok(cursor) => cursor,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(SimpleError {
+                error: format!("{}", error),
+            })
+        }
+    };
+    let options = DetailsOptions {
+        resources: options
+            .resources
+            .map(|r| r.split(',').map(|r| r.trim().to_owned()).collect()),
+        groups: options
+            .groups
+            .map(|g| g.split(',').map(|g| g.trim().to_owned()).collect()),
+        max_intervals: options.max_intervals,
+        bucket_seconds: options.bucket_seconds,
+        limit: options.limit,
+        cursor,
+    };
 
-    let (response, rx) = oneshot::channel();
-    state
-        .runner_tx
-        .send(RunnerMessage::GetResourceStateDetails {
-            interval,
+    let (response, annotations_rx) = oneshot::channel();
+    if let Err(error) = state
+        .storage_tx
+        .send(StorageMessage::GetAnnotations {
+            span: interval,
             response,
-            max_intervals,
         })
-        .unwrap();
+        .await
+    {
+        return unavailable(error.into());
+    }
+    let page = state.runner.details_page(interval, options).await;
+    let mut annotations: HashMap<String, Vec<IntervalAnnotation>> = HashMap::new();
+    for annotation in annotations_rx.await.unwrap_or_default() {
+        annotations
+            .entry(annotation.task_name.clone())
+            .or_default()
+            .push(annotation);
+    }
 
-    match rx.await {
-        Ok(actions) => {
+    match page {
+        Ok(DetailsPage {
+            details,
+            groups,
+            next_cursor,
+        }) => {
             let mut timeline = Vec::new();
             info!(
                 "Querying for actions over {}, got {} responses.",
                 interval,
-                actions.len()
+                details.len()
             );
 
-            for (resource, tasks) in actions {
+            for (resource, tasks) in details {
                 let mut group = TimelineGroup {
                     group: resource.clone(),
                     data: Vec::new(),
                 };
                 for (task_name, intervals) in tasks.into_iter() {
+                    let task_annotations = annotations.get(&task_name);
                     let data = intervals
                         .into_iter()
                         .map(|a| TimelineInterval {
                             time_range: [a.interval.start, a.interval.end],
                             val: a.state,
+                            // Coalesced actions collect the annotations of
+                            // every interval they span
+                            annotations: task_annotations
+                                .iter()
+                                .flat_map(|x| x.iter())
+                                .filter(|x| a.interval.has_subset(x.interval))
+                                .map(|x| x.annotation.clone())
+                                .collect(),
                         })
                         .collect();
 
                     group.data.push(TimelineLabel {
+                        group: groups.get(&task_name).cloned(),
                         label: task_name,
                         data,
                     });
@@ -201,17 +577,707 @@ async fn get_detailed_timeline(
                 timeline.push(group);
             }
 
-            HttpResponse::Ok().json(timeline)
+            let mut response = HttpResponse::Ok();
+            if let Some(cursor) = next_cursor {
+                response.insert_header(("Next-Cursor", cursor.to_string()));
+            }
+            response.json(timeline)
         }
         Err(error) => HttpResponse::BadRequest().json(SimpleError {
-            error: format!("{:?}", error),
+            error: error.to_string(),
+        }),
+    }
+}
+
+fn default_overview_intervals() -> usize {
+    10
+}
+
+fn default_overview_attempts() -> usize {
+    20
+}
+
+#[derive(Deserialize)]
+struct TaskOverviewOptions {
+    /// Maximum number of upcoming and pending intervals to return
+    #[serde(default = "default_overview_intervals")]
+    intervals: usize,
+
+    /// Maximum number of recent attempts to return
+    #[serde(default = "default_overview_attempts")]
+    attempts: usize,
+}
+
+/// Run time statistics over the successful attempts, in seconds
+#[derive(Serialize)]
+struct DurationStats {
+    count: usize,
+    min: f64,
+    max: f64,
+    mean: f64,
+}
+
+impl DurationStats {
+    fn from_attempts(attempts: &[TaskAttempt]) -> Option<Self> {
+        let durations: Vec<f64> = attempts
+            .iter()
+            .filter(|a| a.succeeded)
+            .map(|a| (a.stop_time - a.start_time).num_milliseconds() as f64 / 1000.0)
+            .collect();
+        if durations.is_empty() {
+            return None;
+        }
+        Some(DurationStats {
+            count: durations.len(),
+            min: durations.iter().cloned().fold(f64::INFINITY, f64::min),
+            max: durations.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            mean: durations.iter().sum::<f64>() / durations.len() as f64,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct TaskOverviewResponse {
+    #[serde(flatten)]
+    overview: TaskOverview,
+    recent_attempts: Vec<TaskAttempt>,
+    durations: Option<DurationStats>,
+}
+
+/// Consolidated view of a task: upcoming schedule, what is blocking
+/// outstanding intervals, and recent attempt history
+async fn get_task_overview(
+    path: web::Path<String>,
+    options: web::Query<TaskOverviewOptions>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let task_name = path.into_inner();
+    let options = options.into_inner();
+
+    let overview = match state
+        .runner
+        .task_overview(&task_name, options.intervals)
+        .await
+    {
+        Ok(Some(overview)) => overview,
+        Ok(None) => {
+            return HttpResponse::NotFound().json(SimpleError {
+                error: format!("No such task {}", task_name),
+            })
+        }
+        Err(error) => {
+            return HttpResponse::BadRequest().json(SimpleError {
+                error: error.to_string(),
+            })
+        }
+    };
+
+    let (response, rx) = oneshot::channel();
+    let message = StorageMessage::GetRecentAttempts {
+        task_name,
+        max_attempts: options.attempts,
+        response,
+    };
+    let recent_attempts = match ask(&state.storage_tx, message, rx).await {
+        Ok(attempts) => attempts,
+        Err(error) => return unavailable(error),
+    };
+
+    HttpResponse::Ok().json(TaskOverviewResponse {
+        durations: DurationStats::from_attempts(&recent_attempts),
+        overview,
+        recent_attempts,
+    })
+}
+
+#[derive(Deserialize)]
+struct ResultOptions {
+    /// Maximum number of recent attempts to look through
+    #[serde(default = "default_overview_attempts")]
+    attempts: usize,
+}
+
+/// A result reported by an attempt
+#[derive(Serialize)]
+struct AttemptResult {
+    scheduled_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+    succeeded: bool,
+    result: serde_json::Value,
+}
+
+/// The results reported by a task's recent attempts, newest first
+async fn get_results(
+    path: web::Path<String>,
+    options: web::Query<ResultOptions>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (response, rx) = oneshot::channel();
+    let message = StorageMessage::GetRecentAttempts {
+        task_name: path.into_inner(),
+        max_attempts: options.attempts,
+        response,
+    };
+    match ask(&state.storage_tx, message, rx).await {
+        Ok(attempts) => HttpResponse::Ok().json(
+            attempts
+                .into_iter()
+                .filter_map(|attempt| {
+                    Some(AttemptResult {
+                        scheduled_time: attempt.scheduled_time,
+                        stop_time: attempt.stop_time,
+                        succeeded: attempt.succeeded,
+                        result: attempt.result?,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        ),
+        Err(error) => unavailable(error),
+    }
+}
+
+/// Pauses or resumes dispatching a task's actions
+async fn set_paused(task_name: String, paused: bool, state: &AppState) -> HttpResponse {
+    let res = if paused {
+        state.runner.pause_task(&task_name).await
+    } else {
+        state.runner.resume_task(&task_name).await
+    };
+    match res {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(error @ Error::Channel(_)) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::NotFound().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+async fn pause_task(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    set_paused(path.into_inner(), true, &state).await
+}
+
+async fn resume_task(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    set_paused(path.into_inner(), false, &state).await
+}
+
+async fn set_group_paused(group: String, paused: bool, state: &AppState) -> HttpResponse {
+    let res = if paused {
+        state.runner.pause_group(&group).await
+    } else {
+        state.runner.resume_group(&group).await
+    };
+    match res {
+        Ok(tasks) => HttpResponse::Ok().json(tasks),
+        Err(error @ Error::Channel(_)) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::NotFound().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+/// Pauses every task in a group, responding with their names
+async fn pause_group(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    set_group_paused(path.into_inner(), true, &state).await
+}
+
+async fn resume_group(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    set_group_paused(path.into_inner(), false, &state).await
+}
+
+/// Kills a running action, leaving it errored until it's retried
+async fn kill_action(path: web::Path<ActionId>, state: web::Data<AppState>) -> impl Responder {
+    match state.runner.kill_action(path.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(error @ Error::Channel(_)) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::NotFound().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+/// Requeues an errored, failed, or skipped action immediately, rather than
+/// waiting for its next retry
+async fn retry_action(path: web::Path<ActionId>, state: web::Data<AppState>) -> impl Responder {
+    match state.runner.retry(path.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(error @ Error::Channel(_)) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::BadRequest().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum RetryState {
+    Errored,
+    Failed,
+    Skipped,
+}
+
+#[derive(Deserialize)]
+struct RetryOptions {
+    /// Which actions to retry, errored ones by default
+    state: Option<RetryState>,
+
+    /// Only retry the actions of tasks in this group
+    group: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Retried {
+    action_ids: Vec<ActionId>,
+}
+
+/// Requeues every action in a state, e.g. `?state=failed&group=pricing`
+async fn retry_actions(
+    options: web::Query<RetryOptions>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let action_state = match options.state.unwrap_or(RetryState::Errored) {
+        RetryState::Errored => ActionState::Errored,
+        RetryState::Failed => ActionState::Failed,
+        RetryState::Skipped => ActionState::Skipped,
+    };
+    let res = match &options.group {
+        Some(group) => state.runner.retry_group(action_state, group).await,
+        None => state.runner.retry_all(action_state).await,
+    };
+    match res {
+        Ok(action_ids) => HttpResponse::Ok().json(Retried { action_ids }),
+        Err(error @ Error::Channel(_)) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::BadRequest().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+struct LogOptions {
+    /// Keep streaming output until the action's command finishes
+    #[serde(default)]
+    follow: bool,
+}
+
+/// The recent output of a running action's up command as JSON lines, one
+/// per chunk, wherever the command is running
+async fn get_action_logs(
+    path: web::Path<ActionId>,
+    options: web::Query<LogOptions>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    match state.runner.output(path.into_inner()).await {
+        Ok(output) => {
+            let lines = futures::StreamExt::map(output.json_lines(options.follow), |line| {
+                Ok::<_, error::Error>(web::Bytes::from(line))
+            });
+            HttpResponse::Ok()
+                .content_type("application/x-ndjson")
+                .streaming(lines)
+        }
+        Err(error @ Error::Channel(_)) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::NotFound().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+/// Gives up on an action, leaving its interval down until it's retried
+async fn skip_action(path: web::Path<ActionId>, state: web::Data<AppState>) -> impl Responder {
+    match state.runner.skip(path.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(error @ Error::Channel(_)) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::BadRequest().json(SimpleError {
+            error: error.to_string(),
         }),
     }
 }
 
+#[derive(Deserialize)]
+struct AttemptsOptions {
+    /// End of the interval whose attempts are returned
+    end: DateTime<Utc>,
+}
+
+/// Every stored attempt of a task interval, oldest first
+async fn get_attempts(
+    path: web::Path<String>,
+    options: web::Query<AttemptsOptions>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    match state.runner.attempts(&path.into_inner(), options.end).await {
+        Ok(attempts) => HttpResponse::Ok().json(attempts),
+        Err(error @ Error::Validation(_)) => HttpResponse::NotFound().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+struct ArtifactOptions {
+    /// End of the interval whose artifact is returned
+    end: DateTime<Utc>,
+
+    /// The artifact's path, relative to the task's working directory
+    path: String,
+}
+
+/// The contents of an artifact collected from a task interval
+async fn get_artifact(
+    path: web::Path<String>,
+    options: web::Query<ArtifactOptions>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let options = options.into_inner();
+    match state
+        .runner
+        .artifact(&path.into_inner(), options.end, &options.path)
+        .await
+    {
+        Ok(Some(data)) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(data),
+        Ok(None) => HttpResponse::NotFound().json(SimpleError {
+            error: format!("No artifact {}", options.path),
+        }),
+        Err(error @ Error::Validation(_)) => HttpResponse::NotFound().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+struct StoredOutputOptions {
+    /// End of the interval whose output is returned
+    end: DateTime<Utc>,
+}
+
+/// The output kept of the latest attempt of a task interval as JSON lines,
+/// one per chunk, whether or not the attempt finished
+async fn get_stored_output(
+    path: web::Path<String>,
+    options: web::Query<StoredOutputOptions>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    match state
+        .runner
+        .stored_output(&path.into_inner(), options.end)
+        .await
+    {
+        Ok(chunks) => HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .body(
+                chunks
+                    .iter()
+                    .map(|chunk| format!("{}\n", serde_json::to_string(chunk).unwrap()))
+                    .collect::<String>(),
+            ),
+        Err(error @ Error::Validation(_)) => HttpResponse::NotFound().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+struct ReplayOptions {
+    /// End of the interval whose attempt is replayed
+    end: DateTime<Utc>,
+}
+
+/// Re-executes the most recent failed attempt of a task interval with the
+/// variables it originally ran with
+async fn replay_attempt(
+    path: web::Path<String>,
+    options: web::Query<ReplayOptions>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    match state.runner.replay(&path.into_inner(), options.end).await {
+        Ok(attempt) => HttpResponse::Ok().json(attempt),
+        Err(error @ Error::Channel(_)) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::BadRequest().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+struct AnnotationRequest {
+    interval: Interval,
+
+    #[serde(default)]
+    author: Option<String>,
+
+    text: String,
+}
+
+/// Replaces the running world without a restart. The body is a world
+/// definition, validated before anything changes.
+async fn put_world(body: web::Bytes, state: web::Data<AppState>) -> impl Responder {
+    let definition: WorldDefinition = match serde_json::from_slice(&body) {
+        Ok(definition) => definition,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(SimpleError {
+                error: error.to_string(),
+            })
+        }
+    };
+    match state.runner.reload_world(definition).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(error @ Error::Channel(_)) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => HttpResponse::BadRequest().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+/// Attaches a note to a task interval
+async fn annotate(
+    path: web::Path<String>,
+    request: web::Json<AnnotationRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let (response, rx) = oneshot::channel();
+    let message = StorageMessage::StoreAnnotation {
+        annotation: IntervalAnnotation {
+            task_name: path.into_inner(),
+            interval: request.interval,
+            annotation: Annotation {
+                time: Utc::now(),
+                author: request.author,
+                text: request.text,
+            },
+        },
+        response,
+    };
+    match ask(&state.storage_tx, message, rx).await {
+        Ok(Ok(())) => HttpResponse::Ok().finish(),
+        Ok(Err(error)) => HttpResponse::BadRequest().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => unavailable(error),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForceRequest {
+    resources: HashSet<String>,
+    interval: Interval,
+
+    /// Who is forcing the resources, for the audit trail
+    #[serde(default)]
+    author: Option<String>,
+
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Forces resources up or down, logging who did it and annotating every
+/// task interval it affected, so the timeline shows why
+async fn force(
+    request: web::Json<ForceRequest>,
+    state: web::Data<AppState>,
+    up: bool,
+) -> impl Responder {
+    let ForceRequest {
+        resources,
+        interval,
+        author,
+        reason,
+    } = request.into_inner();
+    let res = if up {
+        state.runner.force_up(resources.clone(), interval).await
+    } else {
+        state.runner.force_down(resources.clone(), interval).await
+    };
+    let forced = match res {
+        Ok(forced) => forced,
+        Err(error @ Error::Channel(_)) => {
+            return HttpResponse::InternalServerError().json(SimpleError {
+                error: error.to_string(),
+            })
+        }
+        Err(error) => {
+            return HttpResponse::BadRequest().json(SimpleError {
+                error: error.to_string(),
+            })
+        }
+    };
+
+    let direction = if up { "up" } else { "down" };
+    info!(
+        target: "audit",
+        ?resources,
+        %interval,
+        author = author.as_deref().unwrap_or("unknown"),
+        reason = reason.as_deref().unwrap_or(""),
+        "Forced {}",
+        direction
+    );
+    let text = match &reason {
+        Some(reason) => format!("Forced {}: {}", direction, reason),
+        None => format!("Forced {}", direction),
+    };
+    for ForcedInterval {
+        task_name,
+        interval,
+    } in &forced
+    {
+        let (response, rx) = oneshot::channel();
+        let message = StorageMessage::StoreAnnotation {
+            annotation: IntervalAnnotation {
+                task_name: task_name.clone(),
+                interval: *interval,
+                annotation: Annotation {
+                    time: Utc::now(),
+                    author: author.clone(),
+                    text: text.clone(),
+                },
+            },
+            response,
+        };
+        // The resources are already forced, and the audit log has it, so a
+        // missing annotation doesn't fail the request
+        match ask(&state.storage_tx, message, rx).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!(task_name, "Unable to annotate forced interval: {}", e),
+            Err(e) => warn!(task_name, "Unable to annotate forced interval: {}", e),
+        }
+    }
+    HttpResponse::Ok().json(forced)
+}
+
+async fn force_up(request: web::Json<ForceRequest>, state: web::Data<AppState>) -> impl Responder {
+    force(request, state, true).await
+}
+
+async fn force_down(
+    request: web::Json<ForceRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    force(request, state, false).await
+}
+
+fn default_gantt_depth() -> usize {
+    10
+}
+
+#[derive(Deserialize)]
+struct GanttOptions {
+    /// How many requirements upstream of the target to follow
+    #[serde(default = "default_gantt_depth")]
+    max_depth: usize,
+}
+
+/// A bar in the Gantt chart, spanning all attempts of a task interval
+#[derive(Serialize)]
+struct GanttRow {
+    id: usize,
+    task_name: String,
+    interval: Interval,
+    state: Option<ActionState>,
+    start: Option<DateTime<Utc>>,
+    stop: Option<DateTime<Utc>>,
+    attempts: usize,
+    requires: Vec<usize>,
+    critical: bool,
+}
+
+/// Generates the upstream chain of task intervals for the resource at the
+/// end of the interval, with the actual run times of each, and flags the
+/// critical path that delayed the target.
+async fn get_gantt(
+    path: web::Path<String>,
+    options: web::Query<GanttOptions>,
+    span: web::Json<Interval>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let resource = path.into_inner();
+    let interval = span.into_inner();
+
+    let nodes = match state
+        .runner
+        .upstream(&resource, interval, options.max_depth)
+        .await
+    {
+        Ok(Some(nodes)) => nodes,
+        Ok(None) => {
+            return HttpResponse::NotFound().json(SimpleError {
+                error: format!("No task provides {} over {}", resource, interval),
+            })
+        }
+        Err(error) => {
+            return HttpResponse::BadRequest().json(SimpleError {
+                error: error.to_string(),
+            })
+        }
+    };
+
+    let mut rows = Vec::new();
+    for (id, node) in nodes.iter().enumerate() {
+        let (response, rx) = oneshot::channel();
+        let message = StorageMessage::GetAttempts {
+            task_name: node.task_name.clone(),
+            interval: node.interval,
+            response,
+        };
+        let attempts = match ask(&state.storage_tx, message, rx).await {
+            Ok(attempts) => attempts,
+            Err(error) => return unavailable(error),
+        };
+        rows.push(GanttRow {
+            id,
+            task_name: node.task_name.clone(),
+            interval: node.interval,
+            state: node.state,
+            start: attempts.iter().map(|a| a.start_time).min(),
+            stop: attempts.iter().map(|a| a.stop_time).max(),
+            attempts: attempts.len(),
+            requires: node.requires.clone(),
+            critical: false,
+        });
+    }
+
+    let finished: Vec<Option<DateTime<Utc>>> = rows.iter().map(|r| r.stop).collect();
+    for id in waterfall::upstream::critical_path(&nodes, &finished) {
+        rows[id].critical = true;
+    }
+
+    HttpResponse::Ok().json(rows)
+}
+
 /// Retrieve all data about a segment, including:
 ///     What resources it relies on
 ///     Last attempt (if any)
+#[allow(dead_code)]
 async fn get_segment_details(
     _max_intervals: web::Query<Option<usize>>,
     _span: web::Json<Interval>,
@@ -283,6 +1349,128 @@ async fn stop_run(path: web::Path<RunID>, state: web::Data<AppState>) -> impl Re
 }
 */
 
+/// What the executor is running, and the health of its agents
+async fn get_executor_status(state: web::Data<AppState>) -> impl Responder {
+    let (response, rx) = oneshot::channel();
+    match ask(&state.exe_tx, ExecutorMessage::GetStatus { response }, rx).await {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(error) => unavailable(error),
+    }
+}
+
+/// How backed up the runner, storage, and executor queues are
+async fn get_queue_depths(state: web::Data<AppState>) -> impl Responder {
+    match state.runner.queue_depths().await {
+        Ok(depths) => HttpResponse::Ok().json(depths),
+        Err(error) => HttpResponse::InternalServerError().json(SimpleError {
+            error: error.to_string(),
+        }),
+    }
+}
+
+/// The storage backend's health, as 503 while it can't be reached
+async fn get_storage_health(state: web::Data<AppState>) -> impl Responder {
+    let (response, rx) = oneshot::channel();
+    match ask(
+        &state.storage_tx,
+        StorageMessage::GetHealth { response },
+        rx,
+    )
+    .await
+    {
+        Ok(health) if health.available => HttpResponse::Ok().json(health),
+        Ok(health) => HttpResponse::ServiceUnavailable().json(health),
+        Err(error) => unavailable(error),
+    }
+}
+
+/// Refuses changes to the agent pool without the registration token
+fn check_registration(req: &actix_web::HttpRequest, state: &AppState) -> Option<HttpResponse> {
+    let Some(expected) = &state.registration_token else {
+        return Some(HttpResponse::Forbidden().json(SimpleError {
+            error: "Agents can't be registered without a registration_token".to_owned(),
+        }));
+    };
+    let given = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if agent_executor::tokens_match(given.as_bytes(), expected.as_bytes()) {
+        None
+    } else {
+        warn!(peer = ?req.peer_addr(), "Refused unauthenticated agent registration");
+        Some(HttpResponse::Unauthorized().json(SimpleError {
+            error: "Missing or invalid bearer token".to_owned(),
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AgentRegistration {
+    /// The agent's API, e.g. `http://worker3:2504/api/v1`
+    base_url: String,
+
+    /// The agent's own token, if it requires one
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// Adds an agent to the executor's pool
+async fn add_agent(
+    req: actix_web::HttpRequest,
+    registration: web::Json<AgentRegistration>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(refused) = check_registration(&req, &state) {
+        return refused;
+    }
+    let registration = registration.into_inner();
+    info!(base_url = %registration.base_url, "Registering agent");
+    let (response, rx) = oneshot::channel();
+    let message = ExecutorMessage::AddTarget {
+        base_url: registration.base_url,
+        token: registration.token,
+        response,
+    };
+    match ask(&state.exe_tx, message, rx).await {
+        Ok(Ok(())) => HttpResponse::Ok().finish(),
+        Ok(Err(error)) => HttpResponse::BadRequest().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => unavailable(error),
+    }
+}
+
+#[derive(Deserialize)]
+struct AgentOptions {
+    base_url: String,
+}
+
+/// Removes an agent from the executor's pool. Its running tasks finish.
+async fn remove_agent(
+    req: actix_web::HttpRequest,
+    options: web::Query<AgentOptions>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(refused) = check_registration(&req, &state) {
+        return refused;
+    }
+    let base_url = options.into_inner().base_url;
+    info!(base_url = %base_url, "Deregistering agent");
+    let (response, rx) = oneshot::channel();
+    let message = ExecutorMessage::RemoveTarget { base_url, response };
+    match ask(&state.exe_tx, message, rx).await {
+        Ok(Ok(())) => HttpResponse::Ok().finish(),
+        Ok(Err(error)) => HttpResponse::NotFound().json(SimpleError {
+            error: error.to_string(),
+        }),
+        Err(error) => unavailable(error),
+    }
+}
+
 async fn ready() -> impl Responder {
     HttpResponse::Ok()
 }
@@ -298,19 +1486,119 @@ struct Args {
     #[clap(short, long, default_value = "")]
     world: String,
 
-    /// Enable verbose logging
-    #[clap(short, long)]
-    verbose: bool,
+    #[clap(flatten)]
+    log: waterfall::telemetry::LogArgs,
 
     /// Force a full re-check
     #[clap(short, long)]
     force_recheck: bool,
+
+    /// Reload the world whenever the world file changes
+    #[cfg(feature = "watch")]
+    #[clap(long)]
+    watch: bool,
 }
 
 #[derive(Clone)]
 struct AppState {
-    storage_tx: mpsc::UnboundedSender<StorageMessage>,
-    runner_tx: mpsc::UnboundedSender<RunnerMessage>,
+    storage_tx: mpsc::Sender<StorageMessage>,
+    runner: RunnerHandle,
+    exe_tx: mpsc::Sender<ExecutorMessage>,
+    registration_token: Option<String>,
+}
+
+/// Starts a runner for the world definition in `world`
+async fn start_world(
+    world: &str,
+    exe_tx: mpsc::Sender<ExecutorMessage>,
+    storage_tx: mpsc::Sender<StorageMessage>,
+    notify_tx: mpsc::Sender<Notification>,
+    shard: Option<Shard>,
+    args: &Args,
+) -> RunnerHandle {
+    let world_def: WorldDefinition = waterfall::config_file::load(world)
+        .unwrap_or_else(|e| panic!("Unable to load world definition from {}: {}", world, e));
+
+    let tasks = world_def.taskset().unwrap();
+    for warning in world_def.warnings() {
+        warn!("{}", warning);
+    }
+    let (runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let mut runner = Runner::new(
+        tasks,
+        world_def.variables,
+        runner_rx,
+        exe_tx,
+        storage_tx,
+        world_def.output_options,
+        // A shard's state is loaded when the shard is set
+        args.force_recheck || shard.is_some(),
+    )
+    .await
+    .unwrap();
+    if let Some(breaker) = world_def.circuit_breaker {
+        runner.set_circuit_breaker(breaker);
+    }
+    if let Some(limit) = world_def.max_running {
+        runner.set_max_running(limit);
+    }
+    if let Some(config) = world_def.backfill {
+        runner.set_backfill(config);
+    }
+    if let Some(days) = world_def.action_history_days {
+        runner.set_action_history(chrono::Duration::try_days(days).unwrap());
+    }
+    runner.set_resource_aliases(world_def.resource_aliases);
+    runner.set_notifications(notify_tx);
+    if let Some(shard) = shard {
+        runner
+            .set_shard(shard, args.force_recheck)
+            .await
+            .expect("Unable to claim shard");
+    }
+    let runner = runner.into_handle(runner_tx, true);
+    #[cfg(feature = "watch")]
+    if args.watch {
+        waterfall::watch::watch_world(world, runner.sender())
+            .unwrap_or_else(|e| panic!("Unable to watch {}: {}", world, e));
+    }
+    runner
+}
+
+/// The API of a single world
+fn api(scope: actix_web::Scope) -> actix_web::Scope {
+    scope
+        .route("/state", web::get().to(get_state))
+        .route("/events", web::get().to(get_events))
+        .route("/world", web::put().to(put_world))
+        .route("/executor", web::get().to(get_executor_status))
+        .route("/storage", web::get().to(get_storage_health))
+        .route("/queues", web::get().to(get_queue_depths))
+        .route("/executor/agents", web::post().to(add_agent))
+        .route("/executor/agents", web::delete().to(remove_agent))
+        .route("/details", web::post().to(get_detailed_timeline))
+        .route(
+            "/tasks/{name:.+}/overview",
+            web::get().to(get_task_overview),
+        )
+        .route("/tasks/{name:.+}/attempts", web::get().to(get_attempts))
+        .route("/tasks/{name:.+}/artifact", web::get().to(get_artifact))
+        .route("/tasks/{name:.+}/output", web::get().to(get_stored_output))
+        .route("/tasks/{name:.+}/results", web::get().to(get_results))
+        .route("/tasks/{name:.+}/replay", web::post().to(replay_attempt))
+        .route("/tasks/{name:.+}/annotations", web::post().to(annotate))
+        .route("/tasks/{name:.+}/pause", web::post().to(pause_task))
+        .route("/tasks/{name:.+}/resume", web::post().to(resume_task))
+        .route("/groups/{group:.+}/pause", web::post().to(pause_group))
+        .route("/groups/{group:.+}/resume", web::post().to(resume_group))
+        .route("/resources/force_up", web::post().to(force_up))
+        .route("/resources/force_down", web::post().to(force_down))
+        .route("/resources/{resource}/gantt", web::post().to(get_gantt))
+        .route("/actions/retry", web::post().to(retry_actions))
+        .route("/actions/{id}", web::delete().to(kill_action))
+        .route("/actions/{id}/retry", web::post().to(retry_action))
+        .route("/actions/{id}/skip", web::post().to(skip_action))
+        .route("/actions/{id}/logs", web::get().to(get_action_logs))
 }
 
 #[actix_web::main]
@@ -318,50 +1606,91 @@ async fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
     // Parse the config
-    let world_json = std::fs::read_to_string(&args.world)
-        .expect(&format!("Unable to open {} for reading", args.config));
-    let world_def: WorldDefinition =
-        serde_json::from_str(&world_json).expect("Unable to parse world definition");
-
-    // Parse the config
-    let config_json = std::fs::read_to_string(&args.config)
-        .expect(&format!("Unable to open {} for reading", args.config));
-    let config: Config =
-        serde_json::from_str(&config_json).expect("Unable to parse config definition");
+    let config: Config = waterfall::config_file::load(&args.config)
+        .unwrap_or_else(|e| panic!("Unable to load config from {}: {}", args.config, e));
 
     // Start the workers
     let (exe_tx, exe_handle) = config.executor.start();
     let (storage_tx, storage_handle) = config.storage.start();
-    let (runner_tx, runner_rx) = mpsc::unbounded_channel();
+    let pools: HashMap<String, _> = config
+        .executor_pools
+        .iter()
+        .map(|(name, pool)| (name.clone(), pool.start()))
+        .collect();
+    let (notify_tx, notify_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    #[cfg(feature = "notifications")]
+    let sinks = config.notifications.iter().map(|n| n.sink()).collect();
+    #[cfg(not(feature = "notifications"))]
+    let sinks = Vec::new();
+    let notify_handle = waterfall::notifications::start(sinks, notify_rx);
 
-    let data = web::Data::new(AppState {
-        storage_tx: storage_tx.clone(),
-        runner_tx: runner_tx.clone(),
-    });
-
-    let tasks = world_def.taskset().unwrap();
-    let mut runner = Runner::new(
-        tasks,
-        world_def.variables,
-        runner_rx,
+    let runner = start_world(
+        &args.world,
         exe_tx.clone(),
         storage_tx.clone(),
-        world_def.output_options,
-        args.force_recheck,
+        notify_tx.clone(),
+        config.shard.clone(),
+        &args,
     )
-    .await
-    .unwrap();
-
-    let runner_handle = tokio::spawn(async move {
-        runner.run(true).await;
+    .await;
+    let registration_token = config
+        .registration_token
+        .clone()
+        .or_else(|| std::env::var("WFD_REGISTRATION_TOKEN").ok())
+        .filter(|token| !token.is_empty());
+    let data = web::Data::new(AppState {
+        storage_tx: storage_tx.clone(),
+        runner: runner.clone(),
+        exe_tx: exe_tx.clone(),
+        registration_token: registration_token.clone(),
     });
 
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let mut namespaces = Vec::new();
+    for (name, ns) in &config.namespaces {
+        if RESERVED_NAMESPACES.contains(&name.as_str()) || name.contains('/') {
+            panic!("{} can't be used as a namespace name", name);
+        }
+        let exe_tx = match &ns.executor {
+            Some(pool) => pools
+                .get(pool)
+                .unwrap_or_else(|| panic!("Namespace {} uses unknown executor pool {}", name, pool))
+                .0
+                .clone(),
+            None => exe_tx.clone(),
+        };
+        let (ns_storage_tx, ns_storage_handle) =
+            config.storage.namespaced(name, ns.prefix.as_ref()).start();
+        let ns_runner = start_world(
+            &ns.world,
+            exe_tx.clone(),
+            ns_storage_tx.clone(),
+            notify_tx.clone(),
+            None,
+            &args,
+        )
+        .await;
+        info!("Hosting namespace {} from {}", name, ns.world);
+        let state = AppState {
+            storage_tx: ns_storage_tx,
+            runner: ns_runner.clone(),
+            exe_tx,
+            registration_token: registration_token.clone(),
+        };
+        namespaces.push((name.clone(), state, ns_runner, ns_storage_handle));
+    }
+    let scopes: Vec<(String, AppState)> = namespaces
+        .iter()
+        .map(|(name, state, _, _)| (name.clone(), state.clone()))
+        .collect();
+
+    let _telemetry = waterfall::telemetry::init(&args.log, "wfd")
+        .unwrap_or_else(|e| panic!("Unable to set up logging: {}", e));
     let res = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_header()
             .allow_any_method()
             .allow_any_origin()
+            .expose_headers(["Next-Cursor"])
             .send_wildcard();
 
         let json_config = web::JsonConfig::default()
@@ -396,31 +1725,51 @@ async fn main() -> std::io::Result<()> {
                     .into()
             });
 
-        App::new()
+        let mut app = App::new()
             .wrap(cors)
             .app_data(data.clone())
             .wrap(Logger::new(
                 r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#,
             ))
             .app_data(json_config)
-            .route("/ready", web::get().to(ready))
-            .service(
-                web::scope("/api/v1")
-                    .route("/state", web::get().to(get_state))
-                    .route("/details", web::post().to(get_detailed_timeline)),
-            )
+            .route("/ready", web::get().to(ready));
+
+        // Namespaces go first, as the main scope would otherwise claim
+        // their paths
+        for (name, state) in &scopes {
+            app = app
+                .service(api(web::scope(&format!("/api/v1/{}", name))
+                    .app_data(web::Data::new(state.clone()))));
+        }
+        app.service(api(web::scope("/api/v1")))
     })
     .bind(config.server.listen_spec())?
     .run()
     .await;
 
-    // Shutdown the runner
-    runner_tx.send(RunnerMessage::Stop {}).unwrap();
-    runner_handle.await.unwrap();
-    exe_tx.send(ExecutorMessage::Stop {}).unwrap();
+    // Shutdown the runners
+    runner.shutdown().await.unwrap();
+    for (_, state, ns_runner, ns_storage_handle) in namespaces {
+        ns_runner.shutdown().await.unwrap();
+        state
+            .storage_tx
+            .send(StorageMessage::Stop {})
+            .await
+            .unwrap();
+        ns_storage_handle.await.unwrap();
+    }
+    exe_tx.send(ExecutorMessage::Stop {}).await.unwrap();
     exe_handle.await.unwrap();
-    storage_tx.send(StorageMessage::Stop {}).unwrap();
+    for (pool_tx, pool_handle) in pools.into_values() {
+        pool_tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        pool_handle.await.unwrap();
+    }
+    storage_tx.send(StorageMessage::Stop {}).await.unwrap();
     storage_handle.await.unwrap();
 
+    // Delivery finishes once the runners are gone
+    drop(notify_tx);
+    notify_handle.await.unwrap();
+
     res
 }
@@ -1,5 +1,8 @@
-pub use serde::Deserialize;
+pub use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use sysinfo::System;
 use tokio::sync::mpsc;
 use waterfall::prelude::*;
@@ -7,9 +10,9 @@ use waterfall::prelude::*;
 fn default_resources() -> TaskResources {
     let mut system = System::new_all();
     system.refresh_all();
-    let cores = (system.cpus().len() as i64) - 2;
+    let cores = (system.cpus().len() as f64) - 2.0;
     let free_memory = (system.total_memory() - system.used_memory()) as f64;
-    let memory_mb = ((free_memory * 0.8) as i64) / 1024;
+    let memory_mb = (((free_memory * 0.8) as i64) / 1024) as f64;
 
     let mut resources = TaskResources::new();
     resources.insert("cores".to_owned(), cores);
@@ -35,6 +38,148 @@ pub struct GlobalConfigSpec {
 
     #[serde(default = "default_resources")]
     pub resources: TaskResources,
+
+    /// Base environment of the tasks run by this agent
+    #[serde(default)]
+    pub environment: local_executor::EnvironmentConfig,
+
+    /// If set, task output is written to files on this agent
+    #[serde(default)]
+    pub output_sink: Option<OutputSink>,
+
+    /// If set, tasks' processes are held to their declared resources
+    #[serde(default)]
+    pub enforcement: Option<local_executor::EnforcementConfig>,
+
+    /// Where `${secret:NAME}` references in tasks are looked up, on this
+    /// agent
+    #[serde(default)]
+    pub secrets: Option<SecretsConfig>,
+
+    /// Devices tasks can be given exclusive use of, by kind
+    #[serde(default)]
+    pub devices: HashMap<String, DeviceSpec>,
+
+    /// Shared secret required as a bearer token by the API, so arbitrary
+    /// hosts can't submit commands. Falls back to `WFW_AUTH_TOKEN`.
+    #[serde(default)]
+    pub auth_token: Option<String>,
+
+    /// If set, each task runs in its own scratch directory under this one
+    #[serde(default)]
+    pub workdir: Option<WorkdirSpec>,
+
+    /// If set, the agent adds itself to a wfd's pool when it starts, and
+    /// removes itself when it stops
+    #[serde(default)]
+    pub register: Option<RegistrationSpec>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RegistrationSpec {
+    /// The wfd API, e.g. `http://wfd:2503/api/v1`
+    pub url: String,
+
+    /// The wfd's `registration_token`
+    pub token: String,
+
+    /// This agent's API, as the wfd reaches it
+    pub advertise_url: String,
+}
+
+/// When a task's scratch directory is removed
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Cleanup {
+    Always,
+    /// Failed tasks leave theirs behind to be looked into
+    #[default]
+    OnSuccess,
+    Never,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WorkdirSpec {
+    /// Scratch directories are created here, named by task id
+    pub directory: PathBuf,
+
+    #[serde(default)]
+    pub cleanup: Cleanup,
+}
+
+/// Whether a task id is a plain name, safe to use as a path component
+pub fn valid_task_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl WorkdirSpec {
+    /// Creates the scratch directory of a task
+    pub async fn create(&self, id: &str) -> std::io::Result<PathBuf> {
+        if !valid_task_id(id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid task id {:?}", id),
+            ));
+        }
+        let path = self.directory.join(id);
+        tokio::fs::create_dir_all(&path).await?;
+        let path = tokio::fs::canonicalize(&path).await?;
+        if !self.contains(&path).await {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is outside the workdir root", path.display()),
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Whether a path lies strictly under the workdir root, once links are
+    /// resolved
+    async fn contains(&self, path: &Path) -> bool {
+        let (Ok(root), Ok(path)) = (
+            tokio::fs::canonicalize(&self.directory).await,
+            tokio::fs::canonicalize(path).await,
+        ) else {
+            return false;
+        };
+        path != root && path.starts_with(&root)
+    }
+
+    /// Removes a task's scratch directory, if the cleanup policy says to
+    pub async fn finish(&self, path: &Path, succeeded: bool) {
+        let remove = match self.cleanup {
+            Cleanup::Always => true,
+            Cleanup::OnSuccess => succeeded,
+            Cleanup::Never => false,
+        };
+        if !remove {
+            return;
+        }
+        if !self.contains(path).await {
+            tracing::warn!(path = %path.display(), "Not removing workdir outside the workdir root");
+            return;
+        }
+        if let Err(e) = tokio::fs::remove_dir_all(path).await {
+            tracing::warn!(path = %path.display(), "Unable to remove workdir: {}", e);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSpec {
+    /// Ids of the devices, as the task's tooling expects them
+    pub ids: Vec<String>,
+
+    /// Environment variable listing the devices assigned to a task,
+    /// e.g. CUDA_VISIBLE_DEVICES
+    pub env: String,
 }
 
 impl Default for GlobalConfigSpec {
@@ -43,6 +188,14 @@ impl Default for GlobalConfigSpec {
             ip: String::from("127.0.0.1"),
             port: default_port(),
             resources: default_resources(),
+            environment: local_executor::EnvironmentConfig::default(),
+            output_sink: None,
+            enforcement: None,
+            secrets: None,
+            devices: HashMap::new(),
+            auth_token: None,
+            workdir: None,
+            register: None,
         }
     }
 }
@@ -52,8 +205,36 @@ pub struct GlobalConfig {
     pub ip: String,
     pub port: u32,
     pub resources: TaskResources,
-    pub storage: mpsc::UnboundedSender<StorageMessage>,
-    pub executor: mpsc::UnboundedSender<ExecutorMessage>,
+    pub devices: HashMap<String, DeviceSpec>,
+    pub auth_token: Option<String>,
+    pub workdir: Option<WorkdirSpec>,
+    pub register: Option<RegistrationSpec>,
+    pub storage: mpsc::Sender<StorageMessage>,
+    pub executor: mpsc::Sender<ExecutorMessage>,
+
+    /// The running tasks, by id
+    pub running: Arc<Mutex<HashMap<String, RunningTask>>>,
+}
+
+/// A task the agent is running
+#[derive(Serialize, Clone, Debug)]
+pub struct RunningTask {
+    /// The command as defined, before variables are filled in, so values
+    /// passed through variables or the environment aren't exposed
+    pub command: String,
+    pub started: DateTime<Utc>,
+
+    /// Resources held by the task
+    pub resources: TaskResources,
+
+    /// Devices assigned to the task, by kind
+    pub devices: HashMap<String, Vec<String>>,
+
+    #[serde(skip)]
+    pub kill: CancellationToken,
+
+    #[serde(skip)]
+    pub output: OutputTap,
 }
 
 impl GlobalConfig {
@@ -63,23 +244,36 @@ impl GlobalConfig {
 
         let workers = spec.resources.get("cores").unwrap_or(cores);
 
-        let (executor, exe_rx) = mpsc::unbounded_channel();
-        local_executor::start(*workers as usize, exe_rx);
+        let (executor, exe_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let config = local_executor::LocalExecutorConfig {
+            environment: spec.environment.clone(),
+            output_sink: spec.output_sink.clone(),
+            enforcement: spec.enforcement.clone(),
+            secrets: spec.secrets.clone(),
+            // The agent holds resources itself, before tasks get here
+            ..Default::default()
+        };
+        local_executor::start_with_config(*workers as usize, config, exe_rx);
 
         // Tracker
-        let (storage, trx) = mpsc::unbounded_channel();
+        let (storage, trx) = mpsc::channel(CHANNEL_CAPACITY);
         waterfall::storage::noop::start(trx);
 
         GlobalConfig {
             ip: spec.ip.clone(),
             port: spec.port,
             resources: spec.resources.clone(),
+            devices: spec.devices.clone(),
+            auth_token: spec
+                .auth_token
+                .clone()
+                .or_else(|| std::env::var("WFW_AUTH_TOKEN").ok())
+                .filter(|token| !token.is_empty()),
+            workdir: spec.workdir.clone(),
+            register: spec.register.clone(),
             storage,
             executor,
+            running: Arc::new(Mutex::new(HashMap::new())),
         }
     }
-
-    pub fn listen_spec(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
-    }
 }
@@ -3,11 +3,13 @@ mod config;
 use actix_cors::Cors;
 use actix_web::{error, middleware::Logger, web, App, HttpResponse, HttpServer, Responder};
 use clap::Parser;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use tokio::sync::oneshot;
 
 use config::*;
-use waterfall::executors::agent_executor::TaskSubmission;
+use waterfall::executors::agent_executor::{task_id, tokens_match, TaskSubmission};
 use waterfall::prelude::*;
 
 #[derive(Serialize)]
@@ -19,49 +21,408 @@ async fn get_resources(data: web::Data<GlobalConfig>) -> impl Responder {
     HttpResponse::Ok().json(data.resources.clone())
 }
 
+async fn get_devices(data: web::Data<GlobalConfig>) -> impl Responder {
+    let devices: HashMap<String, Vec<String>> = data
+        .devices
+        .iter()
+        .map(|(kind, spec)| (kind.clone(), spec.ids.clone()))
+        .collect();
+    HttpResponse::Ok().json(devices)
+}
+
+/// A task's entry in the running tasks, removed once its request is done
+/// with, even if the client goes away first
+struct Registered {
+    running: Arc<Mutex<HashMap<String, RunningTask>>>,
+    id: String,
+}
+
+impl Registered {
+    fn new(data: &GlobalConfig, id: &str, task: RunningTask) -> Option<Self> {
+        let mut running = data.running.lock().unwrap();
+        if running.contains_key(id) {
+            return None;
+        }
+        running.insert(id.to_owned(), task);
+        Some(Registered {
+            running: data.running.clone(),
+            id: id.to_owned(),
+        })
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.running.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// How often a running task's `/run` response is checked for a client that
+/// went away
+const HEARTBEAT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// The attempt of a task whose executor went away before it finished
+fn lost_attempt() -> TaskAttempt {
+    TaskAttempt {
+        succeeded: false,
+        infra_failure: true,
+        executor: vec!["Task was lost before it finished".to_owned()],
+        ..TaskAttempt::new()
+    }
+}
+
 async fn submit_task(
+    req: actix_web::HttpRequest,
     details: web::Json<TaskSubmission>,
     data: web::Data<GlobalConfig>,
 ) -> impl Responder {
     let (response, rx) = oneshot::channel();
 
-    let submission = details.into_inner();
+    // Continue the dispatching action's trace
+    let headers: HashMap<String, String> = req
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+        .collect();
+    let span = tracing::info_span!("run");
+    waterfall::telemetry::set_remote_parent(&span, &headers);
 
-    // Need to keep this unused, otherwise the LE will kill it immediately
-    let (_kill_tx, kill) = oneshot::channel();
+    let mut submission = details.into_inner();
+
+    // Export the assigned devices to the task
+    for (kind, ids) in &submission.devices {
+        let spec = match data.devices.get(kind) {
+            Some(spec) if ids.iter().all(|id| spec.ids.contains(id)) => spec,
+            _ => {
+                return HttpResponse::Ok().json(TaskAttempt {
+                    succeeded: false,
+                    executor: vec![format!(
+                        "Agent refused task: unknown {} devices {:?}",
+                        kind, ids
+                    )],
+                    ..TaskAttempt::new()
+                })
+            }
+        };
+        if let Some(details) = submission.details.as_object_mut() {
+            let env = details
+                .entry("environment")
+                .or_insert_with(|| serde_json::json!({}));
+            if let Some(env) = env.as_object_mut() {
+                env.insert(spec.env.clone(), serde_json::json!(ids.join(",")));
+            }
+        }
+    }
+
+    // Refuse tasks this agent can't run, such as those switching users
+    // when the agent isn't privileged
+    let (validate, validate_rx) = oneshot::channel();
+    data.executor
+        .send(ExecutorMessage::ValidateTask {
+            details: submission.details.clone(),
+            response: validate,
+        })
+        .await
+        .unwrap();
+    if let Err(e) = validate_rx.await.unwrap() {
+        return HttpResponse::Ok().json(TaskAttempt {
+            succeeded: false,
+            executor: vec![format!("Agent refused task: {}", e)],
+            ..TaskAttempt::new()
+        });
+    }
+
+    let id = submission.id.take().unwrap_or_else(task_id);
+    // Ids name workdirs and appear in URLs, so they must be plain names
+    if !valid_task_id(&id) {
+        return HttpResponse::BadRequest().json(SimpleError {
+            error: format!(
+                "Invalid task id {:?}: ids are letters, digits, '_', and '-'",
+                id
+            ),
+        });
+    }
+    let kill = CancellationToken::new();
+    let output = OutputTap::new();
+    let task = RunningTask {
+        command: command_template(&submission.details),
+        started: Utc::now(),
+        resources: submission
+            .details
+            .get("resources")
+            .and_then(|r| serde_json::from_value(r.clone()).ok())
+            .unwrap_or_default(),
+        devices: submission.devices.clone(),
+        kill: kill.clone(),
+        output: output.clone(),
+    };
+    let registered = match Registered::new(&data, &id, task) {
+        Some(registered) => registered,
+        None => {
+            return HttpResponse::Conflict().json(SimpleError {
+                error: format!("A task with id {} is already running", id),
+            })
+        }
+    };
+
+    // Give the task a scratch directory of its own, which it runs in unless
+    // it says otherwise
+    let workdir = match &data.workdir {
+        Some(spec) => match spec.create(&id).await {
+            Ok(path) => Some((spec.clone(), path)),
+            Err(e) => {
+                return HttpResponse::Ok().json(TaskAttempt {
+                    succeeded: false,
+                    infra_failure: true,
+                    executor: vec![format!("Unable to create workdir: {}", e)],
+                    ..TaskAttempt::new()
+                })
+            }
+        },
+        None => None,
+    };
+    if let Some((_, path)) = &workdir {
+        submission
+            .varmap
+            .insert("WORKDIR".to_owned(), path.to_string_lossy().to_string());
+        if let Some(details) = submission.details.as_object_mut() {
+            details
+                .entry("cwd")
+                .or_insert_with(|| serde_json::json!("${WORKDIR}"));
+        }
+    }
+
+    let varmap = submission.varmap();
     data.executor
         .send(ExecutorMessage::ExecuteTask {
             details: submission.details,
             output_options: submission.output_options,
-            varmap: submission.varmap,
+            varmap,
             response,
-            kill,
+            kill: kill.clone(),
+            span,
+            output: Some(output),
         })
+        .await
         .unwrap();
 
-    HttpResponse::Ok().json(rx.await.unwrap())
+    // Cleaning up doesn't depend on the client, which may go away first
+    let (done, finished) = oneshot::channel();
+    actix_web::rt::spawn(async move {
+        let attempt = rx.await.unwrap_or_else(|_| lost_attempt());
+        if let Some((spec, path)) = workdir {
+            spec.finish(&path, attempt.succeeded).await;
+        }
+        drop(registered);
+        done.send(attempt).unwrap_or(());
+    });
+
+    // The id is sent as soon as the task starts, so the caller can kill
+    // it, and the attempt follows once it finishes. Until then, whitespace
+    // is sent now and then, which JSON ignores, so a client that went away
+    // is noticed and the response dropped, which kills the task.
+    let abandoned = kill.drop_guard();
+    let attempt = futures::stream::unfold(Some((finished, abandoned)), |state| async move {
+        let (mut finished, abandoned) = state?;
+        tokio::select! {
+            attempt = &mut finished => {
+                let attempt = attempt.unwrap_or_else(|_| lost_attempt());
+                abandoned.disarm();
+                let body = serde_json::to_vec(&attempt)
+                    .map(web::Bytes::from)
+                    .map_err(error::ErrorInternalServerError);
+                Some((body, None))
+            }
+            _ = tokio::time::sleep(HEARTBEAT) => {
+                Some((Ok(web::Bytes::from_static(b" ")), Some((finished, abandoned))))
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .insert_header(("Task-Id", id))
+        .content_type("application/json")
+        .streaming(attempt)
+}
+
+/// Kills a running task. Its `/run` request responds with the killed
+/// attempt.
+async fn kill_task(path: web::Path<String>, data: web::Data<GlobalConfig>) -> impl Responder {
+    let id = path.into_inner();
+    match data.running.lock().unwrap().get(&id) {
+        Some(task) => {
+            tracing::info!(id, "Killing task");
+            task.kill.cancel();
+            HttpResponse::Ok().finish()
+        }
+        None => HttpResponse::NotFound().json(SimpleError {
+            error: format!("No task with id {} is running", id),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+struct LogOptions {
+    /// Keep streaming output until the task finishes
+    #[serde(default)]
+    follow: bool,
 }
 
-/*
-async fn stop_task(
-    path: web::Path<(RunID, TaskID)>,
+/// The recent output of a running task as JSON lines, one per chunk, e.g.
+/// `{"stream":"stdout","data":"..."}`
+async fn get_logs(
+    path: web::Path<String>,
+    options: web::Query<LogOptions>,
     data: web::Data<GlobalConfig>,
 ) -> impl Responder {
-    let (run_id, task_id) = path.into_inner();
-    let (response, rx) = oneshot::channel();
+    let id = path.into_inner();
+    let output = match data.running.lock().unwrap().get(&id) {
+        Some(task) => task.output.clone(),
+        None => {
+            return HttpResponse::NotFound().json(SimpleError {
+                error: format!("No task with id {} is running", id),
+            })
+        }
+    };
+    let lines = futures::StreamExt::map(output.json_lines(options.follow), |line| {
+        Ok::<_, error::Error>(web::Bytes::from(line))
+    });
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(lines)
+}
 
-    data.executor
-        .send(ExecutorMessage::StopTask {
-            run_id,
-            task_id,
-            response,
+/// A task's command as defined, without its variables filled in
+fn command_template(details: &serde_json::Value) -> String {
+    match details
+        .get("command")
+        .map(|c| serde_json::from_value(c.clone()))
+    {
+        Some(Ok(Cmd::Simple(command))) => command,
+        Some(Ok(Cmd::Split(args))) => args.join(" "),
+        Some(Ok(Cmd::Shell { shell })) => shell,
+        _ => String::new(),
+    }
+}
+
+#[derive(Serialize)]
+struct RunningEntry {
+    id: String,
+    #[serde(flatten)]
+    task: RunningTask,
+}
+
+#[derive(Serialize)]
+struct Running {
+    tasks: Vec<RunningEntry>,
+
+    /// Resources held by all the running tasks, against `capacity`
+    in_use: TaskResources,
+    capacity: TaskResources,
+}
+
+/// What the agent is running, oldest first
+async fn get_running(data: web::Data<GlobalConfig>) -> impl Responder {
+    let mut tasks: Vec<RunningEntry> = data
+        .running
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, task)| RunningEntry {
+            id: id.clone(),
+            task: task.clone(),
         })
-        .unwrap();
+        .collect();
+    tasks.sort_unstable_by(|a, b| (a.task.started, &a.id).cmp(&(b.task.started, &b.id)));
+    let mut in_use = TaskResources::new();
+    for entry in &tasks {
+        in_use.add(&entry.task.resources);
+    }
+    HttpResponse::Ok().json(Running {
+        tasks,
+        in_use,
+        capacity: data.resources.clone(),
+    })
+}
 
-    rx.await.unwrap();
-    HttpResponse::Ok()
+/// Refuses requests without the agent's bearer token, if it has one
+async fn require_token(
+    req: actix_web::dev::ServiceRequest,
+    next: actix_web::middleware::Next<impl actix_web::body::MessageBody + 'static>,
+) -> Result<actix_web::dev::ServiceResponse<impl actix_web::body::MessageBody>, error::Error> {
+    let expected = req
+        .app_data::<web::Data<GlobalConfig>>()
+        .and_then(|data| data.auth_token.clone());
+    if let Some(expected) = expected {
+        let given = req
+            .headers()
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if !tokens_match(given.as_bytes(), expected.as_bytes()) {
+            tracing::warn!(peer = ?req.peer_addr(), path = req.path(), "Refused unauthenticated request");
+            let payload = SimpleError {
+                error: "Missing or invalid bearer token".to_owned(),
+            };
+            return Err(error::InternalError::from_response(
+                "unauthorized",
+                HttpResponse::Unauthorized().json(payload),
+            )
+            .into());
+        }
+    }
+    next.call(req).await
+}
+
+/// How long to wait between attempts to register with the wfd
+const REGISTER_RETRY_SECONDS: u64 = 10;
+
+/// Adds the agent to the wfd's pool, retrying until the wfd answers
+async fn register(spec: RegistrationSpec, token: Option<String>) {
+    let client = reqwest::Client::new();
+    let body = serde_json::json!({ "base_url": spec.advertise_url, "token": token });
+    loop {
+        let request = client
+            .post(format!("{}/executor/agents", spec.url))
+            .bearer_auth(&spec.token)
+            .json(&body);
+        match request.send().await {
+            Ok(result) if result.status().is_success() => {
+                tracing::info!(url = %spec.url, "Registered with wfd");
+                return;
+            }
+            // Refusals won't change by asking again
+            Ok(result) if result.status().is_client_error() => {
+                let status = result.status();
+                tracing::error!(
+                    url = %spec.url,
+                    status = %status,
+                    "wfd refused registration: {}",
+                    result.text().await.unwrap_or_default()
+                );
+                return;
+            }
+            Ok(result) => {
+                tracing::warn!(url = %spec.url, status = %result.status(), "Unable to register")
+            }
+            Err(e) => tracing::warn!(url = %spec.url, "Unable to register: {}", e),
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(REGISTER_RETRY_SECONDS)).await;
+    }
+}
+
+/// Removes the agent from the wfd's pool
+async fn deregister(spec: &RegistrationSpec) {
+    let request = reqwest::Client::new()
+        .delete(format!("{}/executor/agents", spec.url))
+        .query(&[("base_url", &spec.advertise_url)])
+        .bearer_auth(&spec.token);
+    match request.send().await {
+        Ok(result) if result.status().is_success() => {}
+        Ok(result) => tracing::warn!(status = %result.status(), "Unable to deregister"),
+        Err(e) => tracing::warn!("Unable to deregister: {}", e),
+    }
 }
-*/
 
 async fn ready() -> impl Responder {
     HttpResponse::Ok()
@@ -71,9 +432,8 @@ fn init(config_file: &str) -> GlobalConfig {
     let spec: GlobalConfigSpec = if config_file.is_empty() {
         GlobalConfigSpec::default()
     } else {
-        let json = std::fs::read_to_string(config_file)
-            .unwrap_or_else(|_| panic!("Unable to open {} for reading", config_file));
-        serde_json::from_str(&json).expect("Error parsing config json")
+        waterfall::config_file::load(config_file)
+            .unwrap_or_else(|e| panic!("Unable to load config from {}: {}", config_file, e))
     };
 
     GlobalConfig::new(&spec)
@@ -86,9 +446,8 @@ struct Args {
     #[clap(short, long, default_value = "")]
     config: String,
 
-    /// Enable verbose logging
-    #[clap(short, long)]
-    verbose: bool,
+    #[clap(flatten)]
+    log: waterfall::telemetry::LogArgs,
 
     /// Configuration File
     #[clap(short, long)]
@@ -120,7 +479,13 @@ async fn main() -> std::io::Result<()> {
 
     let listen_spec = format!("{}:{}", host, port);
 
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let _telemetry = waterfall::telemetry::init(&args.log, "wfw")
+        .unwrap_or_else(|e| panic!("Unable to set up logging: {}", e));
+    if config.auth_token.is_none() {
+        tracing::warn!(
+            "No auth_token is set, so any host that can reach the agent can run commands on it"
+        );
+    }
     let res = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_header()
@@ -170,16 +535,34 @@ async fn main() -> std::io::Result<()> {
             .route("/ready", web::get().to(ready))
             .service(
                 web::scope("/api/v1")
+                    .wrap(actix_web::middleware::from_fn(require_token))
                     .route("/resources", web::get().to(get_resources))
-                    .route("/run", web::post().to(submit_task)),
+                    .route("/devices", web::get().to(get_devices))
+                    .route("/run", web::post().to(submit_task))
+                    .route("/run/{id}", web::delete().to(kill_task))
+                    .route("/run/{id}/logs", web::get().to(get_logs))
+                    .route("/running", web::get().to(get_running)),
             )
     })
     .bind(listen_spec)?
-    .run()
-    .await;
+    .run();
+
+    let registration = config
+        .register
+        .clone()
+        .map(|spec| tokio::spawn(register(spec, config.auth_token.clone())));
+    let res = res.await;
+    if let Some(registration) = registration {
+        registration.abort();
+        deregister(config.register.as_ref().unwrap()).await;
+    }
 
-    config.executor.send(ExecutorMessage::Stop {}).unwrap();
-    config.storage.send(StorageMessage::Stop {}).unwrap();
+    config
+        .executor
+        .send(ExecutorMessage::Stop {})
+        .await
+        .unwrap();
+    config.storage.send(StorageMessage::Stop {}).await.unwrap();
 
     res
 }
@@ -9,6 +9,69 @@ pub fn default_dow_set() -> HashSet<Weekday> {
 // TODO
 //   - Make sure include and exclude are disjoint
 
+/// Picks out days of each month by their position, rather than listing
+/// them
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum CalendarRule {
+    /// The `n`th `weekday` of the month, e.g. the third Friday. Negative
+    /// `n` counts from the end, so -1 is the last.
+    NthWeekday { n: i32, weekday: Weekday },
+
+    /// The `n`th business day of the month: a day on the mask that isn't
+    /// excluded, or one that's included. Negative `n` counts from the end,
+    /// so -1 is the last business day.
+    BusinessDay(i32),
+
+    /// The `n`th calendar day of the month, whatever the mask. Negative `n`
+    /// counts from the end, so -1 is the last day of the month.
+    MonthDay(i32),
+}
+
+fn month_days(date: NaiveDate) -> impl Iterator<Item = NaiveDate> {
+    let first = date.with_day(1).unwrap();
+    first
+        .iter_days()
+        .take_while(move |d| d.month() == first.month())
+}
+
+/// Whether `date` is the `n`th of the days matching `pred` in its month,
+/// counting from the end for negative `n`
+fn is_nth(date: NaiveDate, n: i32, pred: impl Fn(NaiveDate) -> bool) -> bool {
+    let days: Vec<NaiveDate> = month_days(date).filter(|d| pred(*d)).collect();
+    let index = if n > 0 {
+        n as usize - 1
+    } else {
+        match days.len().checked_sub(n.unsigned_abs() as usize) {
+            Some(index) => index,
+            None => return false,
+        }
+    };
+    days.get(index) == Some(&date)
+}
+
+/// The most days searched for the next day a calendar has, beyond which it
+/// is taken to have none
+pub const MAX_GAP_DAYS: usize = 3660;
+
+/// Steps from `date` by `step` days to the first day `pred` holds for.
+/// Returns the day the search stopped at if there's none within
+/// [`MAX_GAP_DAYS`], or before the first or last representable date.
+fn step_to(
+    mut date: NaiveDate,
+    step: i64,
+    pred: impl Fn(NaiveDate) -> bool,
+) -> std::result::Result<NaiveDate, NaiveDate> {
+    let step = Duration::try_days(step).unwrap_or_default();
+    for _ in 0..MAX_GAP_DAYS {
+        date = date.checked_add_signed(step).ok_or(date)?;
+        if pred(date) {
+            return Ok(date);
+        }
+    }
+    Err(date)
+}
+
 /// Maintains a list of days that are considered active
 #[derive(Clone, Serialize, Deserialize, Default, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
@@ -24,6 +87,11 @@ pub struct Calendar {
     /// Dates to explicitly include
     #[serde(default)]
     pub include: HashSet<NaiveDate>,
+
+    /// If set, only days matching one of the rules are active, rather than
+    /// every day on the mask. `exclude` and `include` still apply.
+    #[serde(default)]
+    pub rules: Vec<CalendarRule>,
 }
 
 impl Calendar {
@@ -39,9 +107,55 @@ impl Calendar {
             false
         } else if self.include.contains(&date) {
             true
-        } else {
+        } else if self.rules.is_empty() {
             self.mask.contains(&date.weekday())
+        } else {
+            self.rules.iter().any(|rule| self.matches(*rule, date))
+        }
+    }
+
+    fn is_business_day(&self, date: NaiveDate) -> bool {
+        if self.exclude.contains(&date) {
+            false
+        } else {
+            self.include.contains(&date) || self.mask.contains(&date.weekday())
+        }
+    }
+
+    fn matches(&self, rule: CalendarRule, date: NaiveDate) -> bool {
+        match rule {
+            CalendarRule::NthWeekday { n, weekday } => is_nth(date, n, |d| d.weekday() == weekday),
+            CalendarRule::BusinessDay(n) => is_nth(date, n, |d| self.is_business_day(d)),
+            CalendarRule::MonthDay(n) => is_nth(date, n, |_| true),
+        }
+    }
+
+    /// Rejects rules that could never match, and calendars with no days
+    pub fn validate(&self) -> Result<()> {
+        for rule in &self.rules {
+            let valid = match *rule {
+                CalendarRule::NthWeekday { n, .. } => n != 0 && n.abs() <= 5,
+                CalendarRule::BusinessDay(n) | CalendarRule::MonthDay(n) => n != 0 && n.abs() <= 31,
+            };
+            if !valid {
+                return Err(Error::Validation(format!(
+                    "Calendar rule {:?} never matches",
+                    rule
+                )));
+            }
+        }
+        // Only business day rules depend on the mask; the others match
+        // some day of every month
+        let has_days = !self.mask.is_empty()
+            || self.include.iter().any(|date| !self.exclude.contains(date))
+            || self
+                .rules
+                .iter()
+                .any(|rule| !matches!(rule, CalendarRule::BusinessDay(_)));
+        if !has_days {
+            return Err(Error::Validation("Calendar includes no days".to_owned()));
         }
+        Ok(())
     }
 
     pub fn next(&self, date: NaiveDate) -> NaiveDate {
@@ -52,17 +166,33 @@ impl Calendar {
         self.offset(date, -1)
     }
 
-    pub fn offset(&self, mut date: NaiveDate, mut offset: i64) -> NaiveDate {
-        let incr = if offset < 0 { 1 } else { -1 };
-        while offset != 0 {
-            date = date + Duration::try_days(-1 * incr).unwrap();
-            while !self.includes(date) {
-                date = date + Duration::try_days(-1 * incr).unwrap();
+    /// The included day `offset` included days from `date`. Past the last
+    /// or first representable date, or a gap of more than [`MAX_GAP_DAYS`]
+    /// between included days, the day the search stopped at is returned.
+    pub fn offset(&self, mut date: NaiveDate, offset: i64) -> NaiveDate {
+        for _ in 0..offset.unsigned_abs() {
+            match step_to(date, offset.signum(), |d| self.includes(d)) {
+                Ok(next) => date = next,
+                Err(stopped) => return stopped,
             }
-            offset += incr;
         }
         date
     }
+
+    /// The business day `offset` business days from `date`, skipping days
+    /// off the mask and excluded ones, but ignoring `rules`. Fails if the
+    /// calendar runs out of business days.
+    pub fn business_day_offset(&self, mut date: NaiveDate, offset: i64) -> Result<NaiveDate> {
+        for _ in 0..offset.unsigned_abs() {
+            date = step_to(date, offset.signum(), |d| self.is_business_day(d)).map_err(|_| {
+                Error::Validation(format!(
+                    "Calendar has no business day within {} days of {}",
+                    MAX_GAP_DAYS, date
+                ))
+            })?;
+        }
+        Ok(date)
+    }
 }
 
 #[cfg(test)]
@@ -77,4 +207,84 @@ mod tests {
             NaiveDate::from_ymd_opt(2022, 1, 3).unwrap()
         );
     }
+
+    #[test]
+    fn check_rules() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        // Monthly expiries, on the third Friday
+        let cal: Calendar = serde_json::from_str(
+            r#"{ "rules": [ { "nth_weekday": { "n": 3, "weekday": "Fri" } } ] }"#,
+        )
+        .unwrap();
+        cal.validate().unwrap();
+        assert!(cal.includes(date(2022, 1, 21)));
+        assert!(!cal.includes(date(2022, 1, 14)));
+        assert!(!cal.includes(date(2022, 1, 24)));
+        assert_eq!(cal.next(date(2022, 1, 21)), date(2022, 2, 18));
+        assert_eq!(cal.prev(date(2022, 1, 21)), date(2021, 12, 17));
+
+        // Month end settlement, on the last business day, moved back by a
+        // holiday
+        let cal: Calendar = serde_json::from_str(
+            r#"{ "rules": [ { "business_day": -1 } ], "exclude": [ "2022-03-31" ] }"#,
+        )
+        .unwrap();
+        assert!(cal.includes(date(2022, 1, 31)));
+        // The 30th of April 2022 is a Saturday
+        assert!(cal.includes(date(2022, 4, 29)));
+        assert!(cal.includes(date(2022, 3, 30)));
+        assert!(!cal.includes(date(2022, 3, 31)));
+        assert_eq!(cal.next(date(2022, 1, 31)), date(2022, 2, 28));
+
+        let cal = Calendar {
+            rules: vec![CalendarRule::BusinessDay(1), CalendarRule::BusinessDay(-1)],
+            ..Calendar::new()
+        };
+        assert!(cal.includes(date(2022, 1, 3)));
+        assert!(cal.includes(date(2022, 1, 31)));
+        assert!(!cal.includes(date(2022, 1, 4)));
+
+        let cal = Calendar {
+            rules: vec![CalendarRule::NthWeekday {
+                n: 6,
+                weekday: Weekday::Mon,
+            }],
+            ..Calendar::new()
+        };
+        assert!(cal.validate().is_err());
+    }
+
+    #[test]
+    fn check_exhausted_calendar() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        // Only days that have passed, as for a backfill
+        let cal = Calendar {
+            mask: HashSet::new(),
+            include: HashSet::from([date(2020, 1, 6), date(2020, 1, 8)]),
+            ..Calendar::new()
+        };
+        cal.validate().unwrap();
+        assert_eq!(
+            cal.business_day_offset(date(2020, 1, 6), 1).unwrap(),
+            date(2020, 1, 8)
+        );
+        assert!(cal.business_day_offset(date(2020, 1, 8), 1).is_err());
+        assert!(cal.business_day_offset(date(2020, 1, 6), -1).is_err());
+
+        // Searches give up rather than running to the end of time
+        assert_eq!(cal.next(date(2020, 1, 6)), date(2020, 1, 8));
+        let stopped = cal.next(date(2020, 1, 8));
+        assert!(stopped > date(2020, 1, 8) && stopped < date(2031, 1, 1));
+
+        // Calendars with no days at all are still rejected
+        let cal = Calendar {
+            mask: HashSet::new(),
+            exclude: HashSet::from([date(2020, 1, 6)]),
+            include: HashSet::from([date(2020, 1, 6)]),
+            rules: vec![CalendarRule::BusinessDay(1)],
+        };
+        assert!(cal.validate().is_err());
+    }
 }
@@ -0,0 +1,338 @@
+//! A global brake on retries. When failures spike, typically because
+//! something everything depends on went down, retrying every failed action
+//! on its own timer just hammers whatever is recovering. Once tripped, the
+//! breaker holds the retries of the affected tasks, periodically lets a
+//! single canary through, and once one succeeds releases the rest with
+//! doubling concurrency.
+
+use super::*;
+use crate::runner::ActionId;
+use std::collections::VecDeque;
+
+fn default_cooldown_seconds() -> i64 {
+    60
+}
+
+fn default_initial_retries() -> usize {
+    1
+}
+
+/// The longest the breaker waits between canaries, a day
+const MAX_COOLDOWN_SECONDS: i64 = 86400;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// The breaker trips when more than this many actions fail within a
+    /// minute
+    pub max_failures_per_minute: usize,
+
+    /// How long to wait before each canary retry
+    #[serde(default = "default_cooldown_seconds")]
+    pub cooldown_seconds: i64,
+
+    /// How many held retries may run at once after a canary succeeds. Each
+    /// successful retry doubles it.
+    #[serde(default = "default_initial_retries")]
+    pub initial_retries: usize,
+}
+
+impl CircuitBreakerConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_failures_per_minute == 0 {
+            return Err(Error::Validation(
+                "Circuit breaker must allow at least one failure a minute".to_owned(),
+            ));
+        }
+        if !(1..=MAX_COOLDOWN_SECONDS).contains(&self.cooldown_seconds) {
+            return Err(Error::Validation(format!(
+                "Circuit breaker cooldown_seconds of {} must be between 1 and {}",
+                self.cooldown_seconds, MAX_COOLDOWN_SECONDS
+            )));
+        }
+        if self.initial_retries == 0 {
+            return Err(Error::Validation(
+                "Circuit breaker must release at least one retry at a time".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum BreakerState {
+    Closed,
+    /// Holding all retries until the cooldown ends
+    Open {
+        until: DateTime<Utc>,
+    },
+    /// A single canary retry is running
+    Probing {
+        canary: ActionId,
+    },
+    /// Releasing held retries, at most `allowed` at a time
+    Ramping {
+        allowed: usize,
+        in_flight: HashSet<ActionId>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: BreakerState,
+
+    /// Recent failures, as (time, task)
+    failures: VecDeque<(DateTime<Utc>, usize)>,
+
+    /// Tasks whose retries are held while the breaker isn't closed
+    affected: HashSet<usize>,
+
+    /// Actions waiting to be retried, oldest first
+    held: VecDeque<ActionId>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            state: BreakerState::Closed,
+            failures: VecDeque::new(),
+            affected: HashSet::new(),
+            held: VecDeque::new(),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == BreakerState::Closed
+    }
+
+    fn trip(&mut self, now: DateTime<Utc>) {
+        let until = Duration::try_seconds(self.config.cooldown_seconds)
+            .and_then(|cooldown| now.checked_add_signed(cooldown))
+            .unwrap_or(MAX_TIME);
+        self.state = BreakerState::Open { until };
+    }
+
+    /// Records a failed action. Returns true if the action should be
+    /// retried as usual, or false if the breaker is holding its retry.
+    pub fn on_failure(&mut self, now: DateTime<Utc>, task: usize, action_id: ActionId) -> bool {
+        let window_start = now - Duration::try_minutes(1).unwrap();
+        while self
+            .failures
+            .front()
+            .is_some_and(|(t, _)| *t < window_start)
+        {
+            self.failures.pop_front();
+        }
+        self.failures.push_back((now, task));
+
+        match &mut self.state {
+            BreakerState::Closed => {
+                if self.failures.len() <= self.config.max_failures_per_minute {
+                    return true;
+                }
+                self.affected = self.failures.iter().map(|(_, task)| *task).collect();
+                error!(
+                    "Circuit breaker tripped: {} failures in the last minute across {} tasks. Holding retries.",
+                    self.failures.len(),
+                    self.affected.len()
+                );
+                self.trip(now);
+            }
+            BreakerState::Open { .. } => {}
+            BreakerState::Probing { canary } => {
+                if *canary == action_id {
+                    warn!("Circuit breaker canary failed, holding retries");
+                    self.trip(now);
+                }
+            }
+            BreakerState::Ramping { in_flight, .. } => {
+                if in_flight.remove(&action_id) {
+                    warn!("Circuit breaker retry failed, holding retries");
+                    // Whatever was released but hasn't finished will come
+                    // back through here if it fails too
+                    self.trip(now);
+                } else if !self.affected.contains(&task) {
+                    return true;
+                }
+            }
+        }
+
+        // An outage spreading to new tasks holds their retries too
+        self.affected.insert(task);
+        self.held.push_back(action_id);
+        false
+    }
+
+    /// Follows tasks being renumbered as retired ones are dropped.
+    /// `renumbered` has the new index of each task, or `None` if dropped.
+    pub fn renumber(&mut self, renumbered: &[Option<usize>]) {
+        self.failures = self
+            .failures
+            .iter()
+            .filter_map(|(time, task)| renumbered[*task].map(|task| (*time, task)))
+            .collect();
+        self.affected = self
+            .affected
+            .iter()
+            .filter_map(|task| renumbered[*task])
+            .collect();
+    }
+
+    /// Records a retry coming due. Returns true if it should go ahead, or
+    /// false if the breaker is holding it.
+    pub fn on_retry_due(&mut self, task: usize, action_id: ActionId) -> bool {
+        match &self.state {
+            BreakerState::Closed => return true,
+            BreakerState::Ramping { .. } if !self.affected.contains(&task) => return true,
+            _ => {}
+        }
+        self.affected.insert(task);
+        if !self.held.contains(&action_id) {
+            self.held.push_back(action_id);
+        }
+        false
+    }
+
+    /// Records a succeeded action
+    pub fn on_success(&mut self, action_id: ActionId) {
+        match &mut self.state {
+            BreakerState::Probing { canary } if *canary == action_id => {
+                info!("Circuit breaker canary succeeded, releasing held retries");
+                self.state = BreakerState::Ramping {
+                    allowed: self.config.initial_retries.max(1),
+                    in_flight: HashSet::new(),
+                };
+            }
+            BreakerState::Ramping { allowed, in_flight } if in_flight.contains(&action_id) => {
+                in_flight.remove(&action_id);
+                *allowed = allowed.saturating_mul(2);
+            }
+            _ => {}
+        }
+    }
+
+    /// The held actions to retry now
+    pub fn release(&mut self, now: DateTime<Utc>) -> Vec<ActionId> {
+        let mut released = Vec::new();
+        match &mut self.state {
+            BreakerState::Open { until } if *until <= now => match self.held.pop_front() {
+                Some(canary) => {
+                    info!("Circuit breaker retrying canary action {}", canary);
+                    self.state = BreakerState::Probing { canary };
+                    released.push(canary);
+                }
+                None => {
+                    self.state = BreakerState::Closed;
+                    self.affected.clear();
+                }
+            },
+            BreakerState::Ramping { allowed, in_flight } => {
+                while in_flight.len() < *allowed {
+                    match self.held.pop_front() {
+                        Some(action_id) => {
+                            in_flight.insert(action_id);
+                            released.push(action_id);
+                        }
+                        None => break,
+                    }
+                }
+                if in_flight.is_empty() && self.held.is_empty() {
+                    info!("Circuit breaker closed");
+                    self.state = BreakerState::Closed;
+                    self.affected.clear();
+                }
+            }
+            _ => {}
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_validate() {
+        let config = |json| serde_json::from_str::<CircuitBreakerConfig>(json).unwrap();
+        assert!(config(r#"{ "max_failures_per_minute": 5 }"#)
+            .validate()
+            .is_ok());
+        assert!(config(r#"{ "max_failures_per_minute": 0 }"#)
+            .validate()
+            .is_err());
+        assert!(
+            config(r#"{ "max_failures_per_minute": 5, "initial_retries": 0 }"#)
+                .validate()
+                .is_err()
+        );
+        let huge =
+            config(r#"{ "max_failures_per_minute": 1, "cooldown_seconds": 9223372036854775807 }"#);
+        assert!(huge.validate().is_err());
+
+        // Even unchecked, a huge cooldown doesn't panic when tripping
+        let mut breaker = CircuitBreaker::new(huge);
+        let now = Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap();
+        breaker.on_failure(now, 0, ActionId(0));
+        assert!(!breaker.on_failure(now, 0, ActionId(1)));
+        assert!(breaker.release(now).is_empty());
+    }
+
+    #[test]
+    fn check_breaker() {
+        let mut breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            max_failures_per_minute: 2,
+            cooldown_seconds: 60,
+            initial_retries: 1,
+        });
+        let start = Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap();
+        let at = |secs| start + Duration::try_seconds(secs).unwrap();
+
+        // Under the threshold, retries proceed as usual
+        assert!(breaker.on_failure(at(0), 0, ActionId(0)));
+        assert!(breaker.on_failure(at(1), 0, ActionId(1)));
+
+        // The third failure trips the breaker
+        assert!(!breaker.on_failure(at(2), 1, ActionId(2)));
+        assert!(!breaker.on_failure(at(3), 2, ActionId(3)));
+        assert!(breaker.release(at(30)).is_empty());
+
+        // A canary goes out after the cooldown, and fails
+        assert_eq!(breaker.release(at(62)), vec![ActionId(2)]);
+        assert!(breaker.release(at(63)).is_empty());
+        assert!(!breaker.on_failure(at(64), 1, ActionId(2)));
+
+        // The next canary succeeds, and the rest ramp up
+        assert_eq!(breaker.release(at(124)), vec![ActionId(3)]);
+        breaker.on_success(ActionId(3));
+        assert_eq!(breaker.release(at(125)), vec![ActionId(2)]);
+        breaker.on_success(ActionId(2));
+        assert!(breaker.release(at(126)).is_empty());
+        assert!(breaker.is_closed());
+    }
+
+    #[test]
+    fn check_retry_due() {
+        let mut breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            max_failures_per_minute: 1,
+            cooldown_seconds: 60,
+            initial_retries: 1,
+        });
+        let start = Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap();
+        let at = |secs| start + Duration::try_seconds(secs).unwrap();
+
+        // A retry scheduled before the breaker trips goes ahead while it's
+        // closed, and is held once it's open
+        assert!(breaker.on_failure(at(0), 0, ActionId(0)));
+        assert!(breaker.on_retry_due(0, ActionId(0)));
+        assert!(!breaker.on_failure(at(1), 1, ActionId(1)));
+        assert!(!breaker.on_retry_due(2, ActionId(2)));
+
+        // Held retries are released like any other
+        assert_eq!(breaker.release(at(62)), vec![ActionId(1)]);
+        breaker.on_success(ActionId(1));
+        assert_eq!(breaker.release(at(63)), vec![ActionId(2)]);
+    }
+}
@@ -0,0 +1,74 @@
+//! Worlds and configs are read from files as JSON, or as YAML when the
+//! file's extension is `.yaml` or `.yml`, so large task graphs can be kept
+//! with comments.
+
+use super::*;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+
+/// Whether `path` names a YAML file
+pub fn is_yaml(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("yaml" | "yml")
+    )
+}
+
+/// Parses `contents`, read from `path`, in the format its extension names
+pub fn parse<T: DeserializeOwned>(path: &Path, contents: &str) -> Result<T> {
+    if !is_yaml(path) {
+        return Ok(serde_json::from_str(contents)?);
+    }
+
+    #[cfg(feature = "yaml")]
+    return serde_yaml_ng::from_str(contents)
+        .map_err(|e| Error::Validation(format!("{}: {}", path.display(), e)));
+
+    #[cfg(not(feature = "yaml"))]
+    Err(Error::Validation(format!(
+        "{} is YAML, but waterfall was built without the yaml feature",
+        path.display()
+    )))
+}
+
+/// Reads and parses `path`
+pub fn load<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)?;
+    parse(path, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn check_yaml_world() {
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let json: WorldDefinition = load(examples.join("world.json")).unwrap();
+        let yaml: WorldDefinition = load(examples.join("world.yaml")).unwrap();
+        // Calendar masks are sets, so they're compared as they are
+        assert_eq!(json.calendars, yaml.calendars);
+        assert_eq!(
+            serde_json::to_value(&json.tasks).unwrap(),
+            serde_json::to_value(&yaml.tasks).unwrap()
+        );
+        assert_eq!(json.variables, yaml.variables);
+        yaml.taskset().unwrap();
+
+        // Unknown fields are caught as they are in JSON
+        assert!(parse::<Calendar>(Path::new("cal.yml"), "mask: [Mon]\nholidays: []").is_err());
+    }
+
+    #[test]
+    fn check_format_detection() {
+        assert!(is_yaml(Path::new("world.yaml")));
+        assert!(is_yaml(Path::new("/etc/waterfall/config.yml")));
+        assert!(!is_yaml(Path::new("world.json")));
+        assert!(!is_yaml(Path::new("yaml")));
+
+        let cal: Calendar = parse(Path::new("cal.json"), r#"{ "mask": [ "Sat" ] }"#).unwrap();
+        assert_eq!(cal.mask, HashSet::from([Weekday::Sat]));
+    }
+}
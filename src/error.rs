@@ -0,0 +1,113 @@
+/// Errors surfaced by the library, grouped by the subsystem that failed so
+/// embedders can match on the failure category.
+#[derive(Debug, thiserror::Error)]
+pub enum WaterfallError {
+    /// A schedule couldn't produce the requested intervals
+    #[error("Schedule error: {0}")]
+    Schedule(String),
+
+    /// A world, task set, or task definition is inconsistent
+    #[error("Validation error: {0}")]
+    Validation(String),
+
+    /// The storage backend failed
+    #[error("Storage error: {0}")]
+    Storage(String),
+
+    /// An executor rejected or failed to run a task
+    #[error("Executor error: {0}")]
+    Executor(String),
+
+    /// A message couldn't be delivered to, or answered by, another component
+    #[error("Channel error: {0}")]
+    Channel(String),
+
+    /// A remote waterfall deployment couldn't be queried
+    #[error("Remote error: {0}")]
+    Remote(String),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// The library's error, by its shorter name
+pub type Error = WaterfallError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for Error {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        Error::Channel("The receiver has shut down".to_owned())
+    }
+}
+
+#[cfg(feature = "redis-storage")]
+impl From<redis::RedisError> for Error {
+    fn from(e: redis::RedisError) -> Self {
+        Error::Storage(e.to_string())
+    }
+}
+
+#[cfg(feature = "sqlite-storage")]
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Storage(e.to_string())
+    }
+}
+
+#[cfg(feature = "s3-storage")]
+impl From<object_store::Error> for Error {
+    fn from(e: object_store::Error) -> Self {
+        Error::Storage(e.to_string())
+    }
+}
+
+#[cfg(any(feature = "agent", feature = "federation", feature = "notifications"))]
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Executor(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Error::Executor(e.to_string())
+    }
+}
+
+impl From<tokio::sync::oneshot::error::RecvError> for Error {
+    fn from(e: tokio::sync::oneshot::error::RecvError) -> Self {
+        Error::Channel(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::WorldDefinition;
+
+    #[test]
+    fn check_validation_category() {
+        let json = r#"{
+            "calendars": {},
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "missing",
+                    "times": [ "09:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-02-01T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json).unwrap();
+        match world_def.taskset() {
+            Err(Error::Validation(msg)) => assert!(msg.contains("missing")),
+            other => panic!("Expected a validation error, got {:?}", other.err()),
+        }
+    }
+}
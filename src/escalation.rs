@@ -0,0 +1,69 @@
+use super::*;
+
+/// What happens to an interval once it has failed too many times in a row
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EscalationAction {
+    /// Keep retrying as before
+    #[default]
+    Retry,
+
+    /// Stop retrying, leaving the interval failed
+    Stop,
+
+    /// Stop retrying and mark the interval skipped
+    Skip,
+
+    /// Keep retrying, but run this in place of the task's `up`
+    Fallback(TaskDetails),
+}
+
+/// Escalation rules for a task, applied to each interval separately
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EscalationPolicy {
+    /// Escalate once an interval has failed this many consecutive times
+    pub after_failures: usize,
+
+    /// Run once when escalating, e.g. to page someone. Gets the same
+    /// variables as the task's `up`.
+    #[serde(default)]
+    pub page: Option<TaskDetails>,
+
+    #[serde(default)]
+    pub then: EscalationAction,
+}
+
+impl EscalationPolicy {
+    /// The commands the policy may run, for validation against an executor
+    pub fn commands(&self) -> Vec<&TaskDetails> {
+        let mut cmds: Vec<&TaskDetails> = self.page.iter().collect();
+        if let EscalationAction::Fallback(cmd) = &self.then {
+            cmds.push(cmd);
+        }
+        cmds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_parse() {
+        let policy: EscalationPolicy = serde_json::from_str(
+            r#"{
+                "after_failures": 3,
+                "page": { "command": "/usr/bin/page-oncall ${yyyymmdd}" },
+                "then": { "fallback": { "command": "/bin/load-yesterday" } }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(policy.commands().len(), 2);
+
+        let policy: EscalationPolicy =
+            serde_json::from_str(r#"{ "after_failures": 5, "then": "skip" }"#).unwrap();
+        assert_eq!(policy.then, EscalationAction::Skip);
+        assert!(policy.commands().is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+//! A live feed of what the runner is doing, for clients that would
+//! otherwise poll `/details`: action state transitions, resources coming up
+//! or going down, and the alerts posted as notifications.
+//!
+//! The runner marks the actions it changes, and after each message it
+//! handles publishes those whose state differs from what was last
+//! published, along with any change to its resources. Nothing is tracked
+//! while nobody is subscribed.
+
+use super::*;
+use crate::notifications::Notification;
+use crate::runner::{Action, ActionId, ActionState};
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::sync::broadcast;
+
+/// How many events a slow subscriber can fall behind before it misses some
+pub const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RunnerEvent {
+    /// An action was added, or moved to a new state
+    ActionState {
+        action_id: ActionId,
+        task_name: String,
+        interval: Interval,
+        state: ActionState,
+    },
+    /// Intervals of a resource became available
+    ResourceUp {
+        resource: Resource,
+        intervals: IntervalSet,
+    },
+    /// Intervals of a resource are no longer available
+    ResourceDown {
+        resource: Resource,
+        intervals: IntervalSet,
+    },
+    /// A notification was posted
+    Alert { notification: Notification },
+}
+
+impl RunnerEvent {
+    /// The name of the event, as it's serialized
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RunnerEvent::ActionState { .. } => "action_state",
+            RunnerEvent::ResourceUp { .. } => "resource_up",
+            RunnerEvent::ResourceDown { .. } => "resource_down",
+            RunnerEvent::Alert { .. } => "alert",
+        }
+    }
+}
+
+/// Publishes [`RunnerEvent`]s to subscribers, remembering what was last
+/// published to tell what changed since
+#[derive(Debug)]
+pub struct EventStream {
+    sender: broadcast::Sender<RunnerEvent>,
+    states: BTreeMap<ActionId, ActionState>,
+    /// Actions changed since the last publish
+    changed: BTreeSet<ActionId>,
+    current: ResourceInterval,
+}
+
+impl Default for EventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn states(actions: &BTreeMap<ActionId, Action>) -> BTreeMap<ActionId, ActionState> {
+    actions
+        .iter()
+        .map(|(action_id, action)| (*action_id, action.state))
+        .collect()
+}
+
+impl EventStream {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        EventStream {
+            sender,
+            states: BTreeMap::new(),
+            changed: BTreeSet::new(),
+            current: ResourceInterval::new(),
+        }
+    }
+
+    fn is_subscribed(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    /// A new subscriber, which receives changes from `actions` and
+    /// `current` onwards
+    pub fn subscribe(
+        &mut self,
+        actions: &BTreeMap<ActionId, Action>,
+        current: &ResourceInterval,
+    ) -> broadcast::Receiver<RunnerEvent> {
+        if !self.is_subscribed() {
+            // Nothing was published while nobody listened
+            self.states = states(actions);
+            self.changed.clear();
+            self.current = current.clone();
+        }
+        self.sender.subscribe()
+    }
+
+    /// Publishes an alert
+    pub fn alert(&self, notification: &Notification) {
+        if self.is_subscribed() {
+            self.sender
+                .send(RunnerEvent::Alert {
+                    notification: notification.clone(),
+                })
+                .unwrap_or(0);
+        }
+    }
+
+    /// Marks an action as added, changed, or removed, to be looked at by
+    /// the next publish
+    pub fn changed(&mut self, action_id: ActionId) {
+        if self.is_subscribed() {
+            self.changed.insert(action_id);
+        }
+    }
+
+    /// Publishes the changes to the actions marked as changed, and to
+    /// `current`, since they were last published. `task_name` names the
+    /// task of an action.
+    pub fn publish<'a>(
+        &mut self,
+        actions: &BTreeMap<ActionId, Action>,
+        task_name: impl Fn(&Action) -> &'a str,
+        current: &ResourceInterval,
+    ) {
+        if !self.is_subscribed() {
+            return;
+        }
+
+        for action_id in std::mem::take(&mut self.changed) {
+            let Some(action) = actions.get(&action_id) else {
+                self.states.remove(&action_id);
+                continue;
+            };
+            if self.states.insert(action_id, action.state) == Some(action.state) {
+                continue;
+            }
+            self.send(RunnerEvent::ActionState {
+                action_id,
+                task_name: task_name(action).to_owned(),
+                interval: action.interval,
+                state: action.state,
+            });
+        }
+
+        if *current == self.current {
+            return;
+        }
+        let mut resources: Vec<&Resource> = current.keys().chain(self.current.keys()).collect();
+        resources.sort_unstable();
+        resources.dedup();
+        let none = IntervalSet::new();
+        for resource in resources {
+            let now = current.get(resource).unwrap_or(&none);
+            let before = self.current.get(resource).unwrap_or(&none);
+            let up = now.difference(before);
+            if !up.is_empty() {
+                self.send(RunnerEvent::ResourceUp {
+                    resource: resource.clone(),
+                    intervals: up,
+                });
+            }
+            let down = before.difference(now);
+            if !down.is_empty() {
+                self.send(RunnerEvent::ResourceDown {
+                    resource: resource.clone(),
+                    intervals: down,
+                });
+            }
+        }
+        self.current = current.clone();
+    }
+
+    fn send(&self, event: RunnerEvent) {
+        self.sender.send(event).unwrap_or(0);
+    }
+}
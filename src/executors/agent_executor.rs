@@ -3,11 +3,12 @@
 
 use super::*;
 use futures::stream::futures_unordered::FuturesUnordered;
-use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn, Instrument};
 
-use futures::StreamExt;
+use futures::{FutureExt, StreamExt};
 
 fn default_as_true() -> bool {
     true
@@ -23,8 +24,116 @@ pub struct AgentTarget {
     #[serde(default)]
     pub current_resources: TaskResources,
 
+    /// Device ids advertised by the agent, by kind (e.g. gpu)
+    #[serde(default)]
+    pub devices: Devices,
+
+    /// Device ids not currently assigned to a task
+    #[serde(default)]
+    pub free_devices: Devices,
+
+    /// Device ids assigned to running tasks
+    #[serde(skip)]
+    pub allocated_devices: Devices,
+
+    /// Resources held by running tasks
+    #[serde(skip)]
+    pub allocated: TaskResources,
+
     #[serde(default)]
     pub enabled: bool,
+
+    /// Shared secret the agent requires as a bearer token
+    #[serde(default, skip_serializing)]
+    pub token: Option<String>,
+
+    /// When the agent last answered a heartbeat
+    #[serde(default)]
+    pub last_seen: Option<DateTime<Utc>>,
+
+    /// Removed from the pool. Targets are addressed by index, so they're
+    /// kept but no longer dispatched to.
+    #[serde(skip)]
+    pub removed: bool,
+}
+
+/// How long an agent has to answer a heartbeat or a request for what it
+/// has, so one that hangs doesn't hold up the others
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Authenticates a request to an agent with its token, if it has one
+fn with_token(request: reqwest::RequestBuilder, token: Option<&str>) -> reqwest::RequestBuilder {
+    match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+/// Compares tokens in time independent of where they differ, for checking
+/// the bearer and registration tokens agents and the server are given
+pub fn tokens_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Device ids by kind
+pub type Devices = HashMap<String, Vec<String>>;
+
+fn has_devices(free: &Devices, requested: &HashMap<String, usize>) -> bool {
+    requested
+        .iter()
+        .all(|(kind, count)| *count == 0 || free.get(kind).map_or(0, |ids| ids.len()) >= *count)
+}
+
+/// Takes the requested number of devices of each kind out of the free pool.
+/// Returns None, leaving the pool as it was, if there aren't enough.
+fn allocate_devices(free: &mut Devices, requested: &HashMap<String, usize>) -> Option<Devices> {
+    if !has_devices(free, requested) {
+        return None;
+    }
+    Some(
+        requested
+            .iter()
+            .filter(|(_, count)| **count > 0)
+            .map(|(kind, count)| {
+                let ids = free.get_mut(kind).unwrap();
+                (kind.clone(), ids.drain(..*count).collect())
+            })
+            .collect(),
+    )
+}
+
+/// Adds devices to a pool, each at most once
+fn release_devices(pool: &mut Devices, devices: &Devices) {
+    for (kind, ids) in devices {
+        let pool = pool.entry(kind.clone()).or_default();
+        for id in ids {
+            if !pool.contains(id) {
+                pool.push(id.clone());
+            }
+        }
+        pool.sort();
+    }
+}
+
+/// Takes devices out of a pool
+fn remove_devices(pool: &mut Devices, devices: &Devices) {
+    for (kind, ids) in devices {
+        if let Some(pool) = pool.get_mut(kind) {
+            pool.retain(|id| !ids.contains(id));
+        }
+    }
+}
+
+/// The devices of `advertised` that aren't in `allocated`
+fn unallocated(advertised: &Devices, allocated: &Devices) -> Devices {
+    let mut free = advertised.clone();
+    remove_devices(&mut free, allocated);
+    free
 }
 
 impl AgentTarget {
@@ -33,20 +142,71 @@ impl AgentTarget {
             base_url,
             resources: resources.clone(),
             current_resources: resources,
+            devices: Devices::new(),
+            free_devices: Devices::new(),
+            allocated_devices: Devices::new(),
+            allocated: TaskResources::new(),
             enabled: true,
+            token: None,
+            last_seen: None,
+            removed: false,
         }
     }
 
+    /// Agents that predate device support don't serve `/devices`, and are
+    /// treated as having none
+    async fn refresh_devices(&mut self, client: &reqwest::Client) {
+        let devices_url = format!("{}/devices", self.base_url);
+        let request =
+            with_token(client.get(devices_url), self.token.as_deref()).timeout(PROBE_TIMEOUT);
+        self.devices = match request.send().await {
+            Ok(result) if result.status() == reqwest::StatusCode::OK => {
+                result.json().await.unwrap_or_default()
+            }
+            _ => Devices::new(),
+        };
+        // Devices held by running tasks stay theirs until released
+        self.free_devices = unallocated(&self.devices, &self.allocated_devices);
+    }
+
+    /// Takes devices for a task out of the free pool
+    fn allocate_devices(&mut self, requested: &HashMap<String, usize>) -> Option<Devices> {
+        let devices = allocate_devices(&mut self.free_devices, requested)?;
+        release_devices(&mut self.allocated_devices, &devices);
+        Some(devices)
+    }
+
+    /// Returns a finished task's devices to the free pool, unless the agent
+    /// no longer has them
+    fn release_devices(&mut self, devices: &Devices) {
+        remove_devices(&mut self.allocated_devices, devices);
+        let mut still_advertised = devices.clone();
+        for (kind, ids) in &mut still_advertised {
+            let advertised = self.devices.get(kind);
+            ids.retain(|id| advertised.is_some_and(|a| a.contains(id)));
+        }
+        release_devices(&mut self.free_devices, &still_advertised);
+    }
+
     async fn refresh_resources(&mut self, client: &reqwest::Client) {
         let resource_url = format!("{}/resources", self.base_url);
-        let disabled = match client.get(resource_url).send().await {
+        let request =
+            with_token(client.get(resource_url), self.token.as_deref()).timeout(PROBE_TIMEOUT);
+        let disabled = match request.send().await {
             Ok(result) => {
-                if result.status() == reqwest::StatusCode::OK {
-                    self.resources = result.json().await.unwrap();
-                    self.current_resources = self.resources.clone();
-                    false
-                } else {
-                    true
+                if result.status() == reqwest::StatusCode::UNAUTHORIZED {
+                    warn!("{} refused our token", self.base_url);
+                }
+                match result.status() {
+                    reqwest::StatusCode::OK => match result.json().await {
+                        Ok(resources) => {
+                            self.resources = resources;
+                            self.current_resources = self.unallocated();
+                            false
+                        }
+                        Err(_) => true,
+                    },
+                    _ => true,
                 }
             }
             Err(_) => true,
@@ -59,10 +219,147 @@ impl AgentTarget {
 
     async fn ping(&mut self, client: &reqwest::Client) -> Result<()> {
         let resource_url = format!("{}/ready", self.base_url);
-        let result = client.get(resource_url).send().await?;
+        let result = client
+            .get(resource_url)
+            .timeout(PROBE_TIMEOUT)
+            .send()
+            .await?;
         self.enabled = result.status() == reqwest::StatusCode::OK;
         Ok(())
     }
+
+    /// Checks in on the agent. Enabled agents are pinged, and disabled ones
+    /// have their resources refreshed to see if they're back. Returns true
+    /// if the agent was enabled again.
+    async fn heartbeat(&mut self, client: &reqwest::Client) -> bool {
+        if self.removed {
+            return false;
+        }
+        if self.enabled {
+            if let Err(e) = self.ping(client).await {
+                debug!("Unable to ping {}: {}", self.base_url, e);
+                self.enabled = false;
+            }
+            if self.enabled {
+                self.last_seen = Some(Utc::now());
+            } else {
+                warn!("Disabling {}: missed a heartbeat", self.base_url);
+            }
+            return false;
+        }
+        self.refresh_resources(client).await;
+        if !self.enabled {
+            return false;
+        }
+        self.refresh_devices(client).await;
+        self.last_seen = Some(Utc::now());
+        info!("{} is now enabled.", self.base_url);
+        true
+    }
+
+    /// The capacity not held by running tasks, which keep what they hold
+    /// while the agent is disabled and enabled again
+    fn unallocated(&self) -> TaskResources {
+        let mut free = self.resources.clone();
+        for (k, held) in self.allocated.iter() {
+            *free.entry(k.clone()).or_default() -= held;
+        }
+        free
+    }
+
+    /// Holds an agent's resources and devices for a task
+    fn allocate(
+        &mut self,
+        resources: &TaskResources,
+        devices: &HashMap<String, usize>,
+    ) -> Option<Devices> {
+        if !self.current_resources.can_satisfy(resources) {
+            return None;
+        }
+        let devices = self.allocate_devices(devices)?;
+        self.current_resources.sub(resources).ok()?;
+        self.allocated.add(resources);
+        Some(devices)
+    }
+
+    /// Returns what a finished task held
+    fn release(&mut self, resources: &TaskResources, devices: &Devices) {
+        for (k, held) in resources.iter() {
+            *self.allocated.entry(k.clone()).or_default() -= held;
+        }
+        self.current_resources = self.unallocated();
+        self.release_devices(devices);
+    }
+
+    fn status(&self) -> AgentStatus {
+        AgentStatus {
+            base_url: self.base_url.clone(),
+            enabled: self.enabled,
+            resources: self.resources.clone(),
+            current_resources: self.current_resources.clone(),
+            last_seen: self.last_seen,
+        }
+    }
+
+    /// The largest fraction of any of the agent's resources in use
+    fn load(&self) -> f64 {
+        self.resources
+            .iter()
+            .filter(|(_, total)| **total > 0.0)
+            .map(|(k, total)| {
+                let free = self.current_resources.get(k).copied().unwrap_or(0.0);
+                1.0 - free / total
+            })
+            .fold(0.0, f64::max)
+    }
+
+    fn can_run(&self, task: &AgentTaskDetail) -> bool {
+        self.enabled
+            && !self.removed
+            && self.current_resources.can_satisfy(&task.resources)
+            && has_devices(&self.free_devices, &task.devices)
+    }
+}
+
+/// How a task is assigned to one of the agents with capacity for it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Selection {
+    /// The first agent listed
+    #[default]
+    First,
+    /// The agent with the smallest fraction of its resources in use
+    LeastLoaded,
+    /// Each agent in turn
+    RoundRobin,
+    Random,
+}
+
+fn default_heartbeat_seconds() -> u64 {
+    30
+}
+
+/// Options for the agent executor beyond its targets
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AgentExecutorConfig {
+    #[serde(default)]
+    pub selection: Selection,
+
+    /// How often every agent is checked in on, disabling those that don't
+    /// answer and enabling those that answer again. 0 only checks on
+    /// agents when a task is waiting for capacity.
+    #[serde(default = "default_heartbeat_seconds")]
+    pub heartbeat_seconds: u64,
+}
+
+impl Default for AgentExecutorConfig {
+    fn default() -> Self {
+        AgentExecutorConfig {
+            selection: Selection::default(),
+            heartbeat_seconds: default_heartbeat_seconds(),
+        }
+    }
 }
 
 /// Contains specifics on how to run a local task
@@ -81,23 +378,40 @@ struct AgentTaskDetail {
 
     /// resources required by the task
     resources: TaskResources,
+
+    /// Number of devices of each kind the task needs exclusive use of
+    #[serde(default)]
+    devices: HashMap<String, usize>,
+
+    /// User and group to run the command as on the agent
+    #[serde(default)]
+    run_as: Option<RunAs>,
 }
 
 fn extract_details(details: &TaskDetails) -> Result<AgentTaskDetail, serde_json::Error> {
     serde_json::from_value::<AgentTaskDetail>(details.clone())
 }
 
-fn validate_task(details: &TaskDetails, max_capacities: &[TaskResources]) -> Result<()> {
+fn validate_task(
+    details: &TaskDetails,
+    max_capacities: &[TaskResources],
+    max_devices: &[Devices],
+) -> Result<()> {
     let parsed = extract_details(details)?;
     if max_capacities.is_empty()
-        || max_capacities.iter().all(|x| x.values().all(|x| *x == 0))
+        || max_capacities.iter().all(|x| x.values().all(|x| *x == 0.0))
         || max_capacities
             .iter()
-            .any(|x| x.can_satisfy(&parsed.resources))
+            .zip(max_devices)
+            .any(|(caps, devices)| {
+                caps.can_satisfy(&parsed.resources) && has_devices(devices, &parsed.devices)
+            })
     {
         Ok(())
     } else {
-        Err(anyhow!("No Agent target satisfies the required resources"))
+        Err(Error::Executor(
+            "No Agent target satisfies the required resources".to_owned(),
+        ))
     }
 }
 
@@ -107,180 +421,1058 @@ pub struct TaskSubmission {
     pub details: TaskDetails,
     pub varmap: VarMap,
     pub output_options: TaskOutputOptions,
+
+    /// Device ids assigned to the task, by kind
+    #[serde(default)]
+    pub devices: Devices,
+
+    /// Identifies the task on the agent, to kill it by. The agent picks one
+    /// if it isn't given.
+    #[serde(default)]
+    pub id: Option<String>,
+
+    /// The schedule of the task's interval, so the agent counts business
+    /// day and period offsets like `${end-1bd}` as the runner would
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+}
+
+impl TaskSubmission {
+    /// The variables to run the task with, counting offsets on its schedule
+    pub fn varmap(&self) -> VarMap {
+        match &self.schedule {
+            Some(schedule) => self.varmap.clone().with_schedule(schedule.clone()),
+            None => self.varmap.clone(),
+        }
+    }
 }
 
+/// A new id for a task submitted to an agent
+pub fn task_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// How many times the output of a submitted task is asked for before the
+/// agent knows of it
+const FOLLOW_ATTEMPTS: usize = 20;
+
+/// Copies the output of a task running on an agent into `tap` until the
+/// task finishes
+async fn follow_output(
+    client: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+    id: String,
+    tap: OutputTap,
+) {
+    let logs_url = format!("{}/run/{}/logs?follow=true", base_url, id);
+    let mut response = None;
+    for _ in 0..FOLLOW_ATTEMPTS {
+        let request = with_token(client.get(&logs_url), token.as_deref());
+        match request.send().await {
+            Ok(result) if result.status() == reqwest::StatusCode::OK => {
+                response = Some(result);
+                break;
+            }
+            _ => tokio::time::sleep(tokio::time::Duration::from_millis(100)).await,
+        }
+    }
+    let Some(mut response) = response else {
+        warn!(id, "Unable to follow output of task on agent");
+        return;
+    };
+    let mut pending = Vec::new();
+    while let Ok(Some(bytes)) = response.chunk().await {
+        pending.extend_from_slice(&bytes);
+        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            if let Ok(chunk) = serde_json::from_slice(&line) {
+                tap.push_chunk(chunk);
+            }
+        }
+    }
+}
+
+/// Why a task submitted to an agent has no attempt
+#[derive(Debug)]
+enum SubmitError {
+    /// The agent never started the task, so it can run elsewhere
+    Refused(String),
+    /// The task may have started, so running it elsewhere could run it
+    /// twice
+    Lost(String),
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubmitError::Refused(e) | SubmitError::Lost(e) => f.write_str(e),
+        }
+    }
+}
+
+/// Whether a response shows the agent turned the task away before
+/// accepting it
+fn is_refusal(status: reqwest::StatusCode) -> bool {
+    matches!(
+        status,
+        reqwest::StatusCode::SERVICE_UNAVAILABLE
+            | reqwest::StatusCode::UNAUTHORIZED
+            | reqwest::StatusCode::FORBIDDEN
+    )
+}
+
+/// Runs a task on an agent. Cancelling `kill` kills the task on the agent,
+/// which then responds with the killed attempt. The output tap is left
+/// open, since the task may fail over to another agent.
+#[allow(clippy::too_many_arguments)]
 async fn submit_task(
     base_url: String,
+    token: Option<String>,
     details: TaskDetails,
     output_options: TaskOutputOptions,
     client: reqwest::Client,
     varmap: VarMap,
-) -> Result<TaskAttempt> {
+    devices: Devices,
+    kill: CancellationToken,
+    output: Option<OutputTap>,
+) -> std::result::Result<TaskAttempt, SubmitError> {
     let submit_url = format!("{}/run", base_url);
+    let id = task_id();
     let submission = TaskSubmission {
         details,
+        schedule: varmap.schedule().cloned(),
         varmap,
         output_options,
+        devices,
+        id: Some(id.clone()),
     };
-    match client.post(submit_url).json(&submission).send().await {
+    // Continues the action's trace on the agent
+    let mut request = with_token(client.post(submit_url), token.as_deref()).json(&submission);
+    for (name, value) in telemetry::trace_headers(&tracing::Span::current()) {
+        request = request.header(name, value);
+    }
+    // The agent answers once the task starts, and sends the attempt once it
+    // finishes
+    let run = async { read_attempt(&base_url, request.send().await).await };
+    tokio::pin!(run);
+    let follower = output.clone().map(|tap| {
+        tokio::spawn(follow_output(
+            client.clone(),
+            base_url.clone(),
+            token.clone(),
+            id.clone(),
+            tap,
+        ))
+    });
+    let result = tokio::select! {
+        result = &mut run => result,
+        _ = kill.cancelled() => {
+            info!(id, "Killing task on agent");
+            let kill_url = format!("{}/run/{}", base_url, id);
+            let request = with_token(client.delete(kill_url), token.as_deref())
+                .timeout(PROBE_TIMEOUT);
+            match request.send().await {
+                Ok(result) if result.status() == reqwest::StatusCode::OK => {}
+                Ok(result) => warn!(id, status = %result.status(), "Agent didn't kill task"),
+                Err(e) => warn!(id, "Unable to kill task on agent: {:?}", e),
+            }
+            run.await
+        }
+    };
+
+    // The last of the output may still be on its way
+    if let Some(follower) = follower {
+        let grace = tokio::time::Duration::from_secs(1);
+        if tokio::time::timeout(grace, follower).await.is_err() {
+            debug!(id, "Gave up waiting for the rest of the output");
+        }
+    }
+    let mut attempt = result?;
+    attempt
+        .executor
+        .push(format!("Executed on agent at {}", base_url));
+    Ok(attempt)
+}
+
+/// The attempt an agent answered a submission with
+async fn read_attempt(
+    base_url: &str,
+    result: reqwest::Result<reqwest::Response>,
+) -> std::result::Result<TaskAttempt, SubmitError> {
+    match result {
+        Ok(result) if result.status() == reqwest::StatusCode::OK => {
+            result.json().await.map_err(|e| {
+                SubmitError::Lost(format!(
+                    "Unable to read the attempt from agent at {}: {}",
+                    base_url, e
+                ))
+            })
+        }
         Ok(result) => {
-            if result.status() == reqwest::StatusCode::OK {
-                let mut attempt: TaskAttempt = result.json().await.unwrap();
-                attempt
-                    .executor
-                    .push(format!("Executed on agent at {}", base_url));
-                Ok(attempt)
+            let status = result.status();
+            let message = format!(
+                "Agent at {} answered {}: {:?}",
+                base_url,
+                status,
+                result.text().await.unwrap_or_default()
+            );
+            if is_refusal(status) {
+                Err(SubmitError::Refused(message))
             } else {
-                Err(anyhow!(
-                    "Unable to dispatch to agent at {}: {:?}",
-                    base_url,
-                    result.text().await.unwrap()
-                ))
+                Err(SubmitError::Lost(message))
             }
         }
-        Err(e) => Err(anyhow!(
-            "Unable to dispatch to agent at {}: {:?}",
-            base_url,
-            e
-        )),
+        // Only a connection that was never made shows the task didn't start
+        Err(e) if e.is_connect() => Err(SubmitError::Refused(format!(
+            "Unable to reach agent at {}: {:?}",
+            base_url, e
+        ))),
+        Err(e) => Err(SubmitError::Lost(format!(
+            "Lost the task on agent at {}: {:?}",
+            base_url, e
+        ))),
     }
 }
 
-// async fn select_target() -> Option<usize> {}
+/// Picks the agent to run a task on, if any but those in `exclude` can.
+/// `cursor` is where the next round-robin search starts.
+fn select_target(
+    selection: Selection,
+    targets: &[AgentTarget],
+    task: &AgentTaskDetail,
+    exclude: &[usize],
+    cursor: &mut usize,
+) -> Option<usize> {
+    let mut eligible = targets
+        .iter()
+        .enumerate()
+        .filter(|(tid, target)| !exclude.contains(tid) && target.can_run(task));
+    match selection {
+        Selection::First => eligible.next().map(|(tid, _)| tid),
+        Selection::LeastLoaded => eligible
+            .min_by(|(_, a), (_, b)| a.load().total_cmp(&b.load()))
+            .map(|(tid, _)| tid),
+        Selection::RoundRobin => {
+            let eligible: Vec<usize> = eligible.map(|(tid, _)| tid).collect();
+            let tid = eligible
+                .iter()
+                .find(|tid| **tid >= *cursor)
+                .or(eligible.first())
+                .copied()?;
+            *cursor = tid + 1;
+            Some(tid)
+        }
+        Selection::Random => {
+            let eligible: Vec<usize> = eligible.map(|(tid, _)| tid).collect();
+            (!eligible.is_empty()).then(|| eligible[rand::random::<usize>() % eligible.len()])
+        }
+    }
+}
 
 struct RunningTask {
     resources: TaskResources,
     target_id: usize,
 }
 
-/// The mpsc channel can be sized to fit max parallelism
-async fn start_agent_executor(
-    mut targets: Vec<AgentTarget>,
-    mut exe_msgs: mpsc::UnboundedReceiver<ExecutorMessage>,
+/// A task waiting to be dispatched, along with the agents that failed to
+/// take it
+struct PendingTask {
+    details: TaskDetails,
+    varmap: VarMap,
+    output_options: TaskOutputOptions,
+    response: oneshot::Sender<TaskAttempt>,
+    kill: CancellationToken,
+    span: tracing::Span,
+    output: Option<OutputTap>,
+    tried: Vec<usize>,
+    errors: Vec<String>,
+}
+
+impl PendingTask {
+    fn respond(self, attempt: TaskAttempt) {
+        self.response.send(attempt).unwrap_or(());
+    }
+}
+
+/// Returns a finished dispatch's resources and devices to its agent. A
+/// dispatch that panicked can't say what it held, so it is only logged.
+fn release(
+    targets: &mut [AgentTarget],
+    result: std::result::Result<(usize, TaskResources, Devices), tokio::task::JoinError>,
 ) {
-    let client = reqwest::Client::new();
+    match result {
+        Ok((tid, resources, devices)) => targets[tid].release(&resources, &devices),
+        Err(e) => error!("A dispatch to an agent failed: {}", e),
+    }
+}
+
+/// Runs tasks on remote agents, picking one with the capacity for each
+/// task and failing over to another if it can't be reached
+pub struct AgentExecutor {
+    targets: Vec<AgentTarget>,
+    config: AgentExecutorConfig,
+    client: reqwest::Client,
+
+    /// Where the next round-robin search starts
+    cursor: usize,
+
+    /// The capacity and devices of each agent when it was last enabled
+    max_caps: Vec<TaskResources>,
+    max_devices: Vec<Devices>,
+
+    /// Checks tasks can run on this host, other than switching users
+    local: mpsc::Sender<ExecutorMessage>,
+
+    /// Dispatches, which release their resources when they finish
+    running: FuturesUnordered<tokio::task::JoinHandle<(usize, TaskResources, Devices)>>,
+
+    /// Tasks an agent couldn't be reached to run, along with the agent
+    failover_tx: mpsc::UnboundedSender<(usize, PendingTask)>,
+    failovers: mpsc::UnboundedReceiver<(usize, PendingTask)>,
+
+    /// Tasks that failed over while no other agent had capacity for them
+    waiting: VecDeque<PendingTask>,
+
+    heartbeat: tokio::time::Interval,
+}
+
+impl AgentExecutor {
+    /// Asks each agent what it has, disabling those that don't answer
+    pub async fn new(mut targets: Vec<AgentTarget>, config: AgentExecutorConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .connect_timeout(PROBE_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        futures::future::join_all(targets.iter_mut().map(|target| async {
+            target.refresh_resources(&client).await;
+            target.refresh_devices(&client).await;
+        }))
+        .await;
+        let max_caps = targets.iter().map(|x| x.resources.clone()).collect();
+        let max_devices = targets.iter().map(|x| x.devices.clone()).collect();
+
+        let (local, le_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        local_executor::start(1, le_rx);
+
+        let (failover_tx, failovers) = mpsc::unbounded_channel();
 
-    for target in &mut targets {
-        target.refresh_resources(&client).await;
+        let mut heartbeat = tokio::time::interval(tokio::time::Duration::from_secs(
+            config.heartbeat_seconds.max(1),
+        ));
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        AgentExecutor {
+            targets,
+            config,
+            client,
+            cursor: 0,
+            max_caps,
+            max_devices,
+            local,
+            running: FuturesUnordered::new(),
+            failover_tx,
+            failovers,
+            waiting: VecDeque::new(),
+            heartbeat,
+        }
     }
-    let mut max_caps: Vec<TaskResources> = targets.iter().map(|x| x.resources.clone()).collect();
 
-    // Set up the local executor
-    let (le_tx, le_rx) = mpsc::unbounded_channel();
-    local_executor::start(1, le_rx);
+    /// Releases the resources of finished dispatches
+    fn reap(&mut self) {
+        while let Some(Some(result)) = self.running.next().now_or_never() {
+            release(&mut self.targets, result);
+        }
+    }
 
-    // Tasks waiting to release resources
-    let mut running = FuturesUnordered::new();
+    /// Checks in on the agents, or only the disabled ones, all at once
+    async fn heartbeat(&mut self, disabled_only: bool) {
+        let client = &self.client;
+        let enabled = futures::future::join_all(
+            self.targets
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, target)| !(disabled_only && target.enabled))
+                .map(|(tid, target)| async move { (tid, target.heartbeat(client).await) }),
+        )
+        .await;
+        for (tid, enabled) in enabled {
+            if enabled {
+                self.max_caps[tid] = self.targets[tid].resources.clone();
+                self.max_devices[tid] = self.targets[tid].devices.clone();
+            }
+        }
+    }
 
-    while let Some(msg) = exe_msgs.recv().await {
-        use ExecutorMessage::*;
-        match msg {
-            ValidateTask { details, response } => {
-                let ltx = le_tx.clone();
-                let caps = max_caps.clone();
-                tokio::spawn(async move {
-                    let result = validate_task(&details, &caps);
-                    if result.is_err() {
-                        response.send(result).unwrap_or(());
-                    } else {
-                        ltx.send(ValidateTask { details, response }).unwrap_or(());
-                    }
+    /// Dispatches a task to an agent with capacity for it, or fails it once
+    /// no agent it hasn't tried could ever run it. The task is handed back
+    /// if every agent that could run it is busy.
+    fn dispatch(&mut self, mut pending: PendingTask) -> Option<PendingTask> {
+        let task = match extract_details(&pending.details) {
+            Ok(task) => task,
+            Err(e) => {
+                pending.respond(TaskAttempt {
+                    succeeded: false,
+                    executor: vec![format!("Invalid task details: {}", e)],
+                    ..TaskAttempt::new()
                 });
+                return None;
             }
-            ExecuteTask {
-                details,
-                varmap,
-                output_options,
-                response,
-                kill: _,
-            } => {
-                let task = extract_details(&details).unwrap();
-                let resources = task.resources.clone();
-
-                loop {
-                    match targets.iter_mut().enumerate().find(|(_, x)| {
-                        x.enabled && x.current_resources.can_satisfy(&task.resources)
-                    }) {
-                        // There is a remote agent with capacity
-                        Some((tid, target)) => {
-                            info!("Dispatching job to {}", target.base_url);
-                            target.current_resources.sub(&resources).unwrap();
-                            let base_url = target.base_url.clone();
-                            let submit_client = client.clone();
-                            running.push(tokio::spawn(async move {
-                                let res = submit_task(
-                                    base_url,
-                                    details,
-                                    output_options,
-                                    submit_client,
-                                    varmap,
-                                )
-                                .await;
-                                let mut rc = false;
-                                if let Ok(attempt) = res {
-                                    response.send(attempt).unwrap();
-                                    rc = true;
-                                }
-                                (tid, resources, rc)
-                            }));
-                            break;
-                        }
-                        // No agent has capacity
-                        None => {
-                            // Give the outstanding tasks a chance to complete or agents
-                            // recover
-                            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
-
-                            // Refresh any disabled targets
-                            for (tid, target) in targets.iter_mut().enumerate() {
-                                if target.enabled {
-                                    continue;
-                                }
-                                target.refresh_resources(&client).await;
-                                if target.enabled {
-                                    max_caps[tid] = target.resources.clone();
-                                    info!("{} is now enabled.", target.base_url);
-                                }
-                            }
-
-                            // Wait for the next item
-                            if !running.is_empty() {
-                                let result: Result<
-                                    (usize, TaskResources, bool),
-                                    tokio::task::JoinError,
-                                > = running.next().await.unwrap();
-
-                                let (tid, resources, submit_ok) = result.unwrap();
-                                if !submit_ok {
-                                    warn!(
-                                        "Disabling agent at {} due to incomplete submission.",
-                                        targets[tid].base_url
-                                    );
-                                    targets[tid].enabled = false;
-                                }
-                                targets[tid].current_resources.add(&resources);
-                            }
-                        }
+        };
+        let resources = task.resources.clone();
+
+        // Give up on a task that failed over, once no agent it hasn't
+        // tried could ever run it
+        if !pending.tried.is_empty()
+            && (0..self.targets.len()).all(|tid| {
+                pending.tried.contains(&tid)
+                    || self.targets[tid].removed
+                    || !self.max_caps[tid].can_satisfy(&resources)
+                    || !has_devices(&self.max_devices[tid], &task.devices)
+            })
+        {
+            warn!(parent: &pending.span, "No agent left to fail over to");
+            let attempt = TaskAttempt {
+                succeeded: false,
+                infra_failure: true,
+                executor: std::mem::take(&mut pending.errors),
+                ..TaskAttempt::new()
+            };
+            pending.respond(attempt);
+            return None;
+        }
+
+        let Some(tid) = select_target(
+            self.config.selection,
+            &self.targets,
+            &task,
+            &pending.tried,
+            &mut self.cursor,
+        ) else {
+            return Some(pending);
+        };
+
+        let target = &mut self.targets[tid];
+        let span = info_span!(parent: &pending.span, "dispatch", agent = %target.base_url);
+        span.in_scope(|| info!("Dispatching job"));
+        let devices = target.allocate(&resources, &task.devices).unwrap();
+        let base_url = target.base_url.clone();
+        let token = target.token.clone();
+        let client = self.client.clone();
+        let failover_tx = self.failover_tx.clone();
+        self.running.push(tokio::spawn(
+            async move {
+                let res = submit_task(
+                    base_url,
+                    token,
+                    pending.details.clone(),
+                    pending.output_options,
+                    client,
+                    pending.varmap.clone(),
+                    devices.clone(),
+                    pending.kill.clone(),
+                    pending.output.clone(),
+                )
+                .await;
+                match res {
+                    Ok(attempt) => pending.respond(attempt),
+                    // A killed task isn't worth running elsewhere
+                    Err(e) if pending.kill.is_cancelled() => pending.respond(TaskAttempt {
+                        succeeded: false,
+                        killed: true,
+                        infra_failure: true,
+                        executor: vec![e.to_string()],
+                        ..TaskAttempt::new()
+                    }),
+                    Err(SubmitError::Refused(e)) => {
+                        warn!("{}, failing over", e);
+                        pending.tried.push(tid);
+                        pending.errors.push(e);
+                        failover_tx.send((tid, pending)).unwrap_or(());
+                    }
+                    Err(SubmitError::Lost(e)) => {
+                        warn!("{}", e);
+                        let mut executor = std::mem::take(&mut pending.errors);
+                        executor.push(e);
+                        pending.respond(TaskAttempt {
+                            succeeded: false,
+                            infra_failure: true,
+                            executor,
+                            ..TaskAttempt::new()
+                        });
                     }
                 }
+                (tid, resources, devices)
             }
-            /*
-            msg @ StopTask { .. } => {
-                le_tx.send(msg).unwrap_or(());
+            .instrument(span),
+        ));
+        None
+    }
+
+    /// Dispatches the tasks waiting to fail over that now can be
+    fn dispatch_waiting(&mut self) {
+        for _ in 0..self.waiting.len() {
+            let pending = self.waiting.pop_front().unwrap();
+            if let Some(pending) = self.dispatch(pending) {
+                self.waiting.push_back(pending);
             }
-            */
-            Stop {} => {
-                break;
+        }
+    }
+
+    /// Gives running tasks a chance to finish, and disabled agents a chance
+    /// to come back
+    async fn wait_for_capacity(&mut self) {
+        tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+        self.heartbeat(true).await;
+        if let Some(result) = self.running.next().await {
+            release(&mut self.targets, result);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Executor for AgentExecutor {
+    async fn validate(&mut self, details: &TaskDetails) -> Result<()> {
+        validate_task(details, &self.max_caps, &self.max_devices)?;
+
+        // Switching users is checked by the agent running the task, not by
+        // this host
+        let mut details = details.clone();
+        if let Some(obj) = details.as_object_mut() {
+            obj.remove("run_as");
+        }
+        let (response, rx) = oneshot::channel();
+        self.local
+            .send(ExecutorMessage::ValidateTask { details, response })
+            .await
+            .map_err(|_| Error::Channel("The local executor has stopped".to_owned()))?;
+        rx.await?
+    }
+
+    async fn execute(&mut self, task: TaskExecution) -> AttemptFuture {
+        let (response, attempt) = oneshot::channel();
+        let mut pending = Some(PendingTask {
+            details: task.details,
+            varmap: task.varmap,
+            output_options: task.output_options,
+            response,
+            kill: task.kill,
+            span: task.span,
+            output: task.output,
+            tried: Vec::new(),
+            errors: Vec::new(),
+        });
+        while let Some(task) = pending.take() {
+            // Tasks failing over were here first
+            self.dispatch_waiting();
+            pending = self.dispatch(task);
+            if pending.is_some() {
+                self.wait_for_capacity().await;
+            }
+        }
+        async move {
+            attempt.await.unwrap_or_else(|_| TaskAttempt {
+                succeeded: false,
+                executor: vec!["Task was lost before it finished".to_owned()],
+                ..TaskAttempt::new()
+            })
+        }
+        .boxed()
+    }
+
+    async fn status(&mut self) -> ExecutorStatus {
+        self.reap();
+        ExecutorStatus {
+            running: self.running.len(),
+            agents: self
+                .targets
+                .iter()
+                .filter(|t| !t.removed)
+                .map(|t| t.status())
+                .collect(),
+        }
+    }
+
+    async fn add_target(&mut self, base_url: &str, token: Option<String>) -> Result<()> {
+        let tid = match self.targets.iter().position(|t| t.base_url == base_url) {
+            Some(tid) if !self.targets[tid].removed => {
+                return Err(Error::Validation(format!(
+                    "{} is already a target",
+                    base_url
+                )));
+            }
+            Some(tid) => tid,
+            None => {
+                self.targets
+                    .push(AgentTarget::new(base_url.to_owned(), TaskResources::new()));
+                self.max_caps.push(TaskResources::new());
+                self.max_devices.push(Devices::new());
+                self.targets.len() - 1
+            }
+        };
+        let target = &mut self.targets[tid];
+        target.removed = false;
+        target.enabled = false;
+        target.token = token;
+        // An agent that isn't up yet is enabled by a later heartbeat
+        if target.heartbeat(&self.client).await {
+            self.max_caps[tid] = target.resources.clone();
+            self.max_devices[tid] = target.devices.clone();
+        }
+        info!("Added agent at {}", target.base_url);
+        Ok(())
+    }
+
+    async fn remove_target(&mut self, base_url: &str) -> Result<()> {
+        let Some(tid) = self
+            .targets
+            .iter()
+            .position(|t| t.base_url == base_url && !t.removed)
+        else {
+            return Err(Error::Validation(format!("{} isn't a target", base_url)));
+        };
+        self.targets[tid].removed = true;
+        self.targets[tid].enabled = false;
+        self.max_caps[tid] = TaskResources::new();
+        self.max_devices[tid] = Devices::new();
+        info!("Removed agent at {}", base_url);
+        Ok(())
+    }
+
+    async fn background(&mut self) {
+        let heartbeats = self.config.heartbeat_seconds > 0;
+        tokio::select! {
+            _ = self.heartbeat.tick(), if heartbeats => {
+                self.heartbeat(false).await;
+                self.dispatch_waiting();
+            }
+            Some((tid, pending)) = self.failovers.recv() => {
+                let target = &mut self.targets[tid];
+                if target.enabled && !target.removed {
+                    warn!(
+                        "Disabling agent at {} due to incomplete submission.",
+                        target.base_url
+                    );
+                    target.enabled = false;
+                }
+                self.waiting.push_back(pending);
+                self.dispatch_waiting();
+            }
+            Some(result) = self.running.next(), if !self.running.is_empty() => {
+                release(&mut self.targets, result);
+                self.dispatch_waiting();
+            }
+            // Agents may come back without a heartbeat to notice
+            _ = tokio::time::sleep(tokio::time::Duration::from_millis(5)),
+                if !self.waiting.is_empty() =>
+            {
+                self.heartbeat(true).await;
+                self.dispatch_waiting();
             }
         }
     }
 }
 
+/// The mpsc channel can be sized to fit max parallelism
+async fn start_agent_executor(
+    targets: Vec<AgentTarget>,
+    config: AgentExecutorConfig,
+    exe_msgs: mpsc::Receiver<ExecutorMessage>,
+) {
+    serve_executor(AgentExecutor::new(targets, config).await, exe_msgs).await
+}
+
 pub fn start(
     targets: Vec<AgentTarget>,
-    msgs: mpsc::UnboundedReceiver<ExecutorMessage>,
+    msgs: mpsc::Receiver<ExecutorMessage>,
+) -> tokio::task::JoinHandle<()> {
+    start_with_config(targets, AgentExecutorConfig::default(), msgs)
+}
+
+pub fn start_with_config(
+    targets: Vec<AgentTarget>,
+    config: AgentExecutorConfig,
+    msgs: mpsc::Receiver<ExecutorMessage>,
 ) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        start_agent_executor(targets, msgs).await;
+        start_agent_executor(targets, config, msgs).await;
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_tokens_match() {
+        assert!(tokens_match(b"secret", b"secret"));
+        assert!(!tokens_match(b"secreT", b"secret"));
+        assert!(!tokens_match(b"secret", b"secret2"));
+        assert!(!tokens_match(b"", b"secret"));
+        assert!(tokens_match(b"", b""));
+    }
+
+    #[test]
+    fn check_device_allocation() {
+        let mut free = Devices::from([(
+            "gpu".to_owned(),
+            vec!["0".to_owned(), "1".to_owned(), "2".to_owned()],
+        )]);
+        let two = HashMap::from([("gpu".to_owned(), 2)]);
+
+        let first = allocate_devices(&mut free, &two).unwrap();
+        assert_eq!(first["gpu"], vec!["0", "1"]);
+
+        // Only one left, and a failed allocation takes nothing
+        assert!(allocate_devices(&mut free, &two).is_none());
+        assert_eq!(free["gpu"], vec!["2"]);
+
+        // Kinds the agent doesn't have can't be allocated
+        assert!(!has_devices(
+            &free,
+            &HashMap::from([("fpga".to_owned(), 1)])
+        ));
+        assert!(has_devices(&free, &HashMap::new()));
+
+        // Releasing is idempotent, so a device is never free twice
+        release_devices(&mut free, &first);
+        release_devices(&mut free, &first);
+        assert_eq!(free["gpu"], vec!["0", "1", "2"]);
+    }
+
+    #[test]
+    fn check_device_refresh() {
+        let gpus = |ids: &[&str]| {
+            Devices::from([(
+                "gpu".to_owned(),
+                ids.iter().map(|id| id.to_string()).collect(),
+            )])
+        };
+        let mut target = AgentTarget::new("http://worker1".to_owned(), TaskResources::new());
+        target.devices = gpus(&["0", "1"]);
+        target.free_devices = gpus(&["0", "1"]);
+        let held = target
+            .allocate_devices(&HashMap::from([("gpu".to_owned(), 1)]))
+            .unwrap();
+        assert_eq!(held, gpus(&["0"]));
+
+        // The agent advertising all of its devices again doesn't free the
+        // one a task holds
+        target.free_devices = unallocated(&target.devices, &target.allocated_devices);
+        assert_eq!(target.free_devices, gpus(&["1"]));
+
+        target.release_devices(&held);
+        target.release_devices(&held);
+        assert_eq!(target.free_devices, gpus(&["0", "1"]));
+        assert_eq!(target.allocated_devices, gpus(&[]));
+
+        // Devices the agent stopped advertising aren't handed out again
+        let held = target
+            .allocate_devices(&HashMap::from([("gpu".to_owned(), 2)]))
+            .unwrap();
+        target.devices = gpus(&["1"]);
+        target.release_devices(&held);
+        assert_eq!(target.free_devices, gpus(&["1"]));
+    }
+
+    #[test]
+    fn check_capacity_accounting() {
+        let cores = |n| TaskResources::from(HashMap::from([("cores".to_owned(), n)]));
+        let mut target = AgentTarget::new("http://worker1".to_owned(), cores(4.0));
+        let held = target.allocate(&cores(3.0), &HashMap::new()).unwrap();
+        assert!(target.allocate(&cores(2.0), &HashMap::new()).is_none());
+
+        // Disabled and enabled again while the task runs, as a heartbeat
+        // refreshing its resources does
+        target.enabled = false;
+        target.resources = cores(4.0);
+        target.current_resources = target.unallocated();
+        assert_eq!(target.current_resources, cores(1.0));
+
+        target.release(&cores(3.0), &held);
+        assert_eq!(target.current_resources, cores(4.0));
+    }
+
+    #[test]
+    fn check_selection() {
+        let cores = |n| TaskResources::from(HashMap::from([("cores".to_owned(), n)]));
+        let mut targets: Vec<AgentTarget> = (0..3)
+            .map(|i| AgentTarget::new(format!("http://worker{}", i), cores(4.0)))
+            .collect();
+        targets[0].current_resources = cores(1.0);
+        targets[1].current_resources = cores(3.0);
+        targets[2].enabled = false;
+        let task = extract_details(&serde_json::json!({
+            "command": "/bin/true",
+            "resources": { "cores": 1 }
+        }))
+        .unwrap();
+
+        let mut cursor = 0;
+        let mut select = |selection| select_target(selection, &targets, &task, &[], &mut cursor);
+        assert_eq!(select(Selection::First), Some(0));
+        assert_eq!(select(Selection::LeastLoaded), Some(1));
+
+        // Disabled agents are skipped, and the search wraps around
+        let picks: Vec<Option<usize>> = (0..3).map(|_| select(Selection::RoundRobin)).collect();
+        assert_eq!(picks, vec![Some(0), Some(1), Some(0)]);
+        assert!(matches!(select(Selection::Random), Some(0 | 1)));
+
+        // Agents a task failed over from aren't picked again
+        assert_eq!(
+            select_target(Selection::First, &targets, &task, &[0], &mut 0),
+            Some(1)
+        );
+
+        // Nothing fits a task bigger than any agent's free capacity
+        let task = extract_details(&serde_json::json!({
+            "command": "/bin/true",
+            "resources": { "cores": 4 }
+        }))
+        .unwrap();
+        assert_eq!(
+            select_target(Selection::LeastLoaded, &targets, &task, &[], &mut 0),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn check_status() {
+        let target: AgentTarget =
+            serde_json::from_str(r#"{"base_url": "http://127.0.0.1:1/api/v1"}"#).unwrap();
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let config = AgentExecutorConfig {
+            heartbeat_seconds: 1,
+            ..AgentExecutorConfig::default()
+        };
+        let handle = start_with_config(vec![target], config, rx);
+
+        // Nothing answers there, so the agent stays disabled
+        tokio::time::sleep(tokio::time::Duration::from_millis(1500)).await;
+        let (response, status) = oneshot::channel();
+        tx.send(ExecutorMessage::GetStatus { response })
+            .await
+            .unwrap();
+        let status = status.await.unwrap();
+        assert_eq!(status.running, 0);
+        assert_eq!(status.agents.len(), 1);
+        assert!(!status.agents[0].enabled);
+        assert_eq!(status.agents[0].last_seen, None);
+
+        // Agents can join and leave the pool
+        let (response, added) = oneshot::channel();
+        tx.send(ExecutorMessage::AddTarget {
+            base_url: "http://127.0.0.1:2/api/v1".to_owned(),
+            token: None,
+            response,
+        })
+        .await
+        .unwrap();
+        added.await.unwrap().unwrap();
+        let (response, removed) = oneshot::channel();
+        tx.send(ExecutorMessage::RemoveTarget {
+            base_url: "http://127.0.0.1:1/api/v1".to_owned(),
+            response,
+        })
+        .await
+        .unwrap();
+        removed.await.unwrap().unwrap();
+        let (response, removed) = oneshot::channel();
+        tx.send(ExecutorMessage::RemoveTarget {
+            base_url: "http://127.0.0.1:1/api/v1".to_owned(),
+            response,
+        })
+        .await
+        .unwrap();
+        assert!(removed.await.unwrap().is_err());
+
+        let (response, status) = oneshot::channel();
+        tx.send(ExecutorMessage::GetStatus { response })
+            .await
+            .unwrap();
+        let agents: Vec<String> = status
+            .await
+            .unwrap()
+            .agents
+            .into_iter()
+            .map(|a| a.base_url)
+            .collect();
+        assert_eq!(agents, vec!["http://127.0.0.1:2/api/v1"]);
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        handle.await.unwrap();
+    }
+
+    /// An agent at the returned URL that answers every request with
+    /// `response`, or hangs up once it's read the request if it's empty
+    async fn canned_agent(response: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 65536];
+                if socket.read(&mut buf).await.is_err() {
+                    continue;
+                }
+                socket.write_all(response.as_bytes()).await.unwrap_or(());
+            }
+        });
+        format!("http://{}/api/v1", addr)
+    }
+
+    #[tokio::test]
+    async fn check_submit_errors() {
+        let submit = |base_url| {
+            submit_task(
+                base_url,
+                None,
+                serde_json::json!({ "command": "/bin/true", "resources": {} }),
+                TaskOutputOptions::default(),
+                reqwest::Client::new(),
+                VarMap::new(),
+                Devices::new(),
+                CancellationToken::new(),
+                None,
+            )
+        };
+
+        // Nothing listens there, so the task never started
+        let result = submit("http://127.0.0.1:1/api/v1".to_owned()).await;
+        assert!(matches!(result, Err(SubmitError::Refused(_))));
+
+        let unavailable = "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n";
+        let result = submit(canned_agent(unavailable).await).await;
+        assert!(matches!(result, Err(SubmitError::Refused(_))));
+
+        // Once the agent has the task, it may be running it, so it isn't
+        // sent anywhere else
+        let result = submit(canned_agent("").await).await;
+        assert!(matches!(result, Err(SubmitError::Lost(_))));
+        let conflict = "HTTP/1.1 409 Conflict\r\ncontent-length: 0\r\n\r\n";
+        let result = submit(canned_agent(conflict).await).await;
+        assert!(matches!(result, Err(SubmitError::Lost(_))));
+        let garbled = "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n{oops";
+        let result = submit(canned_agent(garbled).await).await;
+        assert!(matches!(result, Err(SubmitError::Lost(_))));
+    }
+
+    #[tokio::test]
+    async fn check_kill_after_start() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Answers a submission as soon as it arrives, and only finishes it
+        // once it's asked to kill it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let killed = std::sync::Arc::new(tokio::sync::Notify::new());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let killed = killed.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 65536];
+                    let Ok(read) = socket.read(&mut buf).await else {
+                        return;
+                    };
+                    if buf[..read].starts_with(b"DELETE") {
+                        killed.notify_one();
+                        let ok = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n";
+                        socket.write_all(ok.as_bytes()).await.unwrap_or(());
+                        return;
+                    }
+                    let head =
+                        "HTTP/1.1 200 OK\r\ntask-id: 1\r\ntransfer-encoding: chunked\r\n\r\n";
+                    socket.write_all(head.as_bytes()).await.unwrap_or(());
+                    killed.notified().await;
+                    let attempt = serde_json::to_string(&TaskAttempt {
+                        killed: true,
+                        ..TaskAttempt::new()
+                    })
+                    .unwrap();
+                    let body = format!("{:x}\r\n{}\r\n0\r\n\r\n", attempt.len(), attempt);
+                    socket.write_all(body.as_bytes()).await.unwrap_or(());
+                });
+            }
+        });
+
+        let kill = CancellationToken::new();
+        let submission = tokio::spawn(submit_task(
+            format!("http://{}/api/v1", addr),
+            None,
+            serde_json::json!({ "command": "/bin/true", "resources": {} }),
+            TaskOutputOptions::default(),
+            reqwest::Client::new(),
+            VarMap::new(),
+            Devices::new(),
+            kill.clone(),
+            None,
+        ));
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        kill.cancel();
+        let attempt = submission.await.unwrap().unwrap();
+        assert!(attempt.killed);
+    }
+
+    #[test]
+    fn check_submission_schedule() {
+        // Business days exclude a holiday, which plain weekdays don't know
+        let tz = chrono_tz::America::New_York;
+        let calendar = Calendar {
+            exclude: HashSet::from([NaiveDate::from_ymd_opt(2021, 12, 31).unwrap()]),
+            ..Calendar::new()
+        };
+        let schedule = Schedule::new(
+            calendar,
+            vec![NaiveTime::from_hms_opt(17, 0, 0).unwrap()],
+            tz,
+        );
+        let monday = tz.with_ymd_and_hms(2022, 1, 3, 17, 0, 0).unwrap();
+        let varmap = VarMap::from_schedule(&schedule.interval(monday, 0), tz, &schedule);
+        let submission = TaskSubmission {
+            details: serde_json::json!({}),
+            schedule: varmap.schedule().cloned(),
+            varmap: varmap.clone(),
+            output_options: TaskOutputOptions::default(),
+            devices: Devices::new(),
+            id: None,
+        };
+
+        // The agent counts offsets as the runner would
+        let json = serde_json::to_string(&submission).unwrap();
+        let received: TaskSubmission = serde_json::from_str(&json).unwrap();
+        let references = "${end-1bd:%Y-%m-%d} ${end-1p}";
+        assert_eq!(
+            received.varmap().apply_to(references),
+            "2021-12-30 2021-12-30T17:00:00-05:00"
+        );
+        assert_eq!(
+            received.varmap().apply_to(references),
+            varmap.apply_to(references)
+        );
+    }
+
+    #[test]
+    fn check_target_token() {
+        let target: AgentTarget = serde_json::from_str(
+            r#"{"base_url": "http://worker1:2504/api/v1", "token": "sekrit"}"#,
+        )
+        .unwrap();
+        assert_eq!(target.token.as_deref(), Some("sekrit"));
+
+        // Targets are reported elsewhere, so the token is never written out
+        let json = serde_json::to_string(&target).unwrap();
+        assert!(!json.contains("sekrit"));
+
+        let request = with_token(reqwest::Client::new().get(&target.base_url), None)
+            .build()
+            .unwrap();
+        assert!(request.headers().get("authorization").is_none());
+        let request = with_token(
+            reqwest::Client::new().get(&target.base_url),
+            target.token.as_deref(),
+        )
+        .build()
+        .unwrap();
+        assert_eq!(request.headers()["authorization"], "Bearer sekrit");
+    }
+}
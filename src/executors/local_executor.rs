@@ -3,16 +3,194 @@ use futures::stream::futures_unordered::FuturesUnordered;
 use psutil;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::process::Stdio;
 use tokio::process::Command;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc;
 use tokio::time::{sleep, Duration};
 
-use futures::StreamExt;
-use tokio::io::AsyncReadExt;
+use futures::{FutureExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
 type Environment = HashMap<String, Option<String>>;
 
+/// Variables passed through from the executor's environment by default
+const DEFAULT_INHERITED: [&str; 12] = [
+    "LANG",
+    "HOSTNAME",
+    "LOGNAME",
+    "USER",
+    "PATH",
+    "HOME",
+    "XDG_CONFIG_HOME",
+    "ALL_PROXY",
+    "FTP_PROXY",
+    "HTTPS_PROXY",
+    "HTTP_PROXY",
+    "NO_PROXY",
+];
+
+/// Which of the executor's environment variables tasks inherit
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InheritEnv {
+    All,
+    None,
+    Only(Vec<String>),
+}
+
+impl Default for InheritEnv {
+    fn default() -> Self {
+        InheritEnv::Only(DEFAULT_INHERITED.iter().map(|x| x.to_string()).collect())
+    }
+}
+
+/// The base environment of every task run by the executor. Variables set
+/// in a task's details take precedence.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EnvironmentConfig {
+    #[serde(default)]
+    pub inherit: InheritEnv,
+
+    /// Variables never inherited, even with `inherit` set to `all`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// A dotenv file of `KEY=value` lines, read as each task starts, so
+    /// edits apply without a restart. Overrides inherited variables.
+    #[serde(default)]
+    pub env_file: Option<PathBuf>,
+
+    /// Static variables set for every task, overriding inherited ones and
+    /// those in `env_file`
+    #[serde(default)]
+    pub set: HashMap<String, String>,
+}
+
+/// Options for the local executor beyond its parallelism
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LocalExecutorConfig {
+    #[serde(default)]
+    pub environment: EnvironmentConfig,
+
+    /// If set, output is written to files rather than kept in the attempt
+    #[serde(default)]
+    pub output_sink: Option<OutputSink>,
+
+    /// The machine's capacity (cores, memory_mb, ...). If set, tasks only
+    /// start once the `resources` in their details are free.
+    #[serde(default)]
+    pub resources: Option<TaskResources>,
+
+    /// If set, tasks' processes are held to their declared resources
+    #[serde(default)]
+    pub enforcement: Option<EnforcementConfig>,
+
+    /// Where `${secret:NAME}` references in tasks are looked up
+    #[serde(default)]
+    pub secrets: Option<SecretsConfig>,
+}
+
+/// Limits placed on tasks' processes, beyond accounting for them
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EnforcementConfig {
+    /// A cgroup v2 directory, with the cpu and memory controllers enabled
+    /// for its children, that the executor can create cgroups in. Each
+    /// task runs in its own, weighted by its `cores` and limited to its
+    /// `memory_mb`.
+    #[serde(default)]
+    pub cgroup: Option<PathBuf>,
+
+    /// Niceness of tasks that don't set their own
+    #[serde(default)]
+    pub nice: Option<i32>,
+}
+
+impl EnvironmentConfig {
+    async fn environment(&self) -> Result<Environment> {
+        let mut env: Environment = match &self.inherit {
+            InheritEnv::All => std::env::vars().map(|(k, v)| (k, Some(v))).collect(),
+            InheritEnv::None => Environment::new(),
+            InheritEnv::Only(vars) => vars
+                .iter()
+                .map(|envvar| (envvar.clone(), std::env::var(envvar).ok()))
+                .collect(),
+        };
+        for envvar in &self.exclude {
+            env.remove(envvar);
+        }
+        if let Some(path) = &self.env_file {
+            let contents = tokio::fs::read_to_string(path).await.map_err(|e| {
+                Error::Executor(format!("Unable to read {}: {}", path.display(), e))
+            })?;
+            let vars = parse_env_file(&contents)
+                .map_err(|e| Error::Executor(format!("{}: {}", path.display(), e)))?;
+            env.extend(vars.into_iter().map(|(k, v)| (k, Some(v))));
+        }
+        env.extend(self.set.iter().map(|(k, v)| (k.clone(), Some(v.clone()))));
+        Ok(env)
+    }
+}
+
+/// Parses dotenv `KEY=value` lines. Blank lines and `#` comments are
+/// skipped, and a leading `export` is allowed. Values in single quotes are
+/// taken as is; values in double quotes can contain `\n`, `\"`, and `\\`.
+fn parse_env_file(contents: &str) -> std::result::Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected KEY=value", lineno + 1))?;
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("line {}: bad variable name {:?}", lineno + 1, key));
+        }
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            quoted
+                .strip_suffix('\'')
+                .ok_or_else(|| format!("line {}: unterminated quote", lineno + 1))?
+                .to_owned()
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let quoted = quoted
+                .strip_suffix('"')
+                .ok_or_else(|| format!("line {}: unterminated quote", lineno + 1))?;
+            let mut unescaped = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match (c, chars.clone().next()) {
+                    ('\\', Some('n')) => {
+                        unescaped.push('\n');
+                        chars.next();
+                    }
+                    ('\\', Some(escaped @ ('"' | '\\'))) => {
+                        unescaped.push(escaped);
+                        chars.next();
+                    }
+                    _ => unescaped.push(c),
+                }
+            }
+            unescaped
+        } else {
+            // Unquoted values end at a comment
+            match value.split_once(" #") {
+                Some((value, _)) => value.trim_end().to_owned(),
+                None => value.to_owned(),
+            }
+        };
+        vars.push((key.to_owned(), value));
+    }
+    Ok(vars)
+}
+
 /// Contains specifics on how to run a local task
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct LocalTaskDetail {
@@ -26,17 +204,236 @@ struct LocalTaskDetail {
     /// Timeout in seconds
     #[serde(default)]
     timeout: u64,
+
+    /// User and group to run the command as
+    #[serde(default)]
+    run_as: Option<RunAs>,
+
+    /// Working directory of the command, which may contain variables.
+    /// Defaults to the executor's working directory.
+    #[serde(default)]
+    cwd: Option<String>,
+
+    /// File mode creation mask, as an octal string (e.g. "027").
+    /// Defaults to the executor's umask.
+    #[serde(default, deserialize_with = "deserialize_umask")]
+    umask: Option<u32>,
+
+    /// Paths or globs of files to collect once the command exits, which
+    /// may contain variables. Relative paths are relative to `cwd`.
+    #[serde(default)]
+    artifacts: Vec<String>,
+
+    /// Resources held while the task runs, if the executor has a capacity,
+    /// and enforced if it has a cgroup
+    #[serde(default)]
+    resources: TaskResources,
+
+    /// Scheduling niceness, from -20 (favoured) to 19. Only root can go
+    /// below 0.
+    #[serde(default)]
+    nice: Option<i32>,
+}
+
+fn deserialize_umask<'de, D>(deserializer: D) -> std::result::Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(s) => match u32::from_str_radix(&s, 8) {
+            Ok(mask) if mask <= 0o777 => Ok(Some(mask)),
+            _ => Err(serde::de::Error::custom(format!(
+                "umask must be an octal string between 000 and 777, got {}",
+                s
+            ))),
+        },
+    }
 }
 
 fn extract_details(details: &TaskDetails) -> Result<LocalTaskDetail, serde_json::Error> {
     serde_json::from_value::<LocalTaskDetail>(details.clone())
 }
 
-fn validate_task(details: &TaskDetails) -> Result<()> {
-    if let Err(err) = extract_details(details) {
-        Err(anyhow!("{}", err))
-    } else {
-        Ok(())
+fn validate_task(details: &TaskDetails, capacity: Option<&TaskResources>) -> Result<()> {
+    match extract_details(details) {
+        Err(err) => Err(Error::Executor(err.to_string())),
+        Ok(parsed) => {
+            if let Some(run_as) = &parsed.run_as {
+                resolve_run_as(run_as)?;
+            }
+            if let Some(nice) = parsed.nice {
+                check_nice(nice)?;
+            }
+            if let Some(capacity) = capacity {
+                if !capacity.can_satisfy(&parsed.resources) {
+                    return Err(Error::Executor(
+                        "Task requires more resources than the executor has".to_owned(),
+                    ));
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Resolves the uid and gid to run as, ensuring this process is allowed to
+/// switch to them
+fn resolve_run_as(run_as: &RunAs) -> Result<(u32, u32)> {
+    let user = users::get_user_by_name(&run_as.user)
+        .ok_or_else(|| Error::Validation(format!("run_as user {} does not exist", run_as.user)))?;
+    let gid = match &run_as.group {
+        Some(name) => users::get_group_by_name(name)
+            .ok_or_else(|| Error::Validation(format!("run_as group {} does not exist", name)))?
+            .gid(),
+        None => user.primary_group_id(),
+    };
+
+    let euid = users::get_effective_uid();
+    if euid != 0 && (euid != user.uid() || users::get_effective_gid() != gid) {
+        return Err(Error::Validation(format!(
+            "Task is set to run as {}, but the executor isn't running as root",
+            run_as.user
+        )));
+    }
+    Ok((user.uid(), gid))
+}
+
+fn check_nice(nice: i32) -> Result<()> {
+    if !(-20..=19).contains(&nice) {
+        return Err(Error::Validation(format!(
+            "nice must be between -20 and 19, got {}",
+            nice
+        )));
+    }
+    if nice < 0 && users::get_effective_uid() != 0 {
+        return Err(Error::Validation(
+            "Task has a negative nice, but the executor isn't running as root".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// The cgroup v2 settings enforcing a task's resources
+fn cgroup_limits(resources: &TaskResources) -> Vec<(&'static str, String)> {
+    let mut limits = Vec::new();
+    // A single core gets the default weight of 100
+    if let Some(cores) = resources.get("cores") {
+        let weight = (cores * 100.0).round().clamp(1.0, 10000.0) as u64;
+        limits.push(("cpu.weight", weight.to_string()));
+    }
+    if let Some(memory_mb) = resources.get("memory_mb") {
+        let bytes = (memory_mb * 1024.0 * 1024.0).round() as u64;
+        limits.push(("memory.max", bytes.to_string()));
+        limits.push(("memory.swap.max", "0".to_owned()));
+        // Kill the whole task rather than whichever process tipped it over
+        limits.push(("memory.oom.group", "1".to_owned()));
+    }
+    limits
+}
+
+/// Tells apart the cgroups and result files of tasks run by the same
+/// executor
+static TASK_SEQUENCE: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+fn next_task_sequence() -> usize {
+    TASK_SEQUENCE.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Names the file a task can write a JSON result to
+const RESULT_ENV_VAR: &str = "WATERFALL_RESULT";
+
+/// Starts a JSON result in a task's stdout, which runs to a line of ```
+const RESULT_FENCE: &str = "```waterfall-result";
+
+/// The last complete fenced result block in the output
+fn fenced_result(output: &str) -> Option<String> {
+    let mut block: Option<Vec<&str>> = None;
+    let mut result = None;
+    for line in output.lines() {
+        match &mut block {
+            None if line.trim() == RESULT_FENCE => block = Some(Vec::new()),
+            None => {}
+            Some(lines) if line.trim() == "```" => {
+                result = Some(lines.join("\n"));
+                block = None;
+            }
+            Some(lines) => lines.push(line),
+        }
+    }
+    result
+}
+
+/// The result a task reported, from its result file, or failing that a
+/// fenced block in its stdout
+async fn read_result(path: &std::path::Path, stdout: &str) -> Result<Option<serde_json::Value>> {
+    let payload = match tokio::fs::read_to_string(path).await {
+        Ok(payload) if !payload.trim().is_empty() => Some(payload),
+        Ok(_) => fenced_result(stdout),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => fenced_result(stdout),
+        Err(e) => return Err(e.into()),
+    };
+    match payload {
+        Some(payload) => Ok(Some(serde_json::from_str(&payload)?)),
+        None => Ok(None),
+    }
+}
+
+/// A cgroup made for a single task, removed once the task exits
+struct TaskCgroup {
+    path: PathBuf,
+}
+
+impl TaskCgroup {
+    async fn create(root: &std::path::Path, resources: &TaskResources) -> Result<Self> {
+        let path = root.join(format!(
+            "task_{}_{}",
+            std::process::id(),
+            next_task_sequence()
+        ));
+        tokio::fs::create_dir(&path).await?;
+        let cgroup = TaskCgroup { path };
+        for (file, value) in cgroup_limits(resources) {
+            let result = tokio::fs::write(cgroup.path.join(file), &value).await;
+            // Swap may not be accounted on this machine
+            if let Err(e) = result {
+                if file != "memory.swap.max" {
+                    cgroup.remove().await;
+                    return Err(Error::Executor(format!(
+                        "Unable to set {} to {}: {}",
+                        file, value, e
+                    )));
+                }
+            }
+        }
+        Ok(cgroup)
+    }
+
+    /// Whether the kernel killed anything in the cgroup for running out of
+    /// memory
+    async fn oom_killed(&self) -> bool {
+        let events = tokio::fs::read_to_string(self.path.join("memory.events"))
+            .await
+            .unwrap_or_default();
+        events.lines().any(|line| match line.split_once(' ') {
+            Some(("oom_kill", count)) => count.trim() != "0",
+            _ => false,
+        })
+    }
+
+    /// Kills anything the task left running, then removes the cgroup
+    async fn remove(&self) {
+        tokio::fs::write(self.path.join("cgroup.kill"), "1")
+            .await
+            .unwrap_or(());
+        // The cgroup can't be removed until its processes are reaped
+        for _ in 0..50 {
+            if tokio::fs::remove_dir(&self.path).await.is_ok() {
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        warn!("Unable to remove cgroup {}", self.path.display());
     }
 }
 
@@ -57,7 +454,8 @@ async fn gather_child_stats(pid: psutil::Pid) -> Result<ChildStats> {
     };
     let mut periods: f32 = 0.0;
 
-    let mut proc = psutil::process::Process::new(pid)?;
+    let mut proc =
+        psutil::process::Process::new(pid).map_err(|e| Error::Executor(e.to_string()))?;
 
     while let (Ok(pct), Ok(mem)) = (proc.cpu_percent(), proc.memory_info()) {
         // update CPU
@@ -83,21 +481,162 @@ async fn gather_child_stats(pid: psutil::Pid) -> Result<ChildStats> {
     Ok(stats)
 }
 
+/// Reads a stream to the end, keeping a bounded excerpt and copying all of
+/// it to `file` and `tap`, with secrets masked
+async fn capture<R: AsyncRead + Unpin>(
+    mut reader: R,
+    mut truncator: Truncator,
+    mut file: Option<tokio::fs::File>,
+    tap: Option<(OutputTap, OutputStream)>,
+    mut redactor: Redactor,
+) -> Result<Truncator> {
+    let mut buf = vec![0u8; 8192];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        let data = if n == 0 {
+            redactor.finish()
+        } else {
+            redactor.push(&buf[..n])
+        };
+        truncator.push(&data);
+        if let Some(file) = &mut file {
+            file.write_all(&data).await?;
+        }
+        if let Some((tap, stream)) = &tap {
+            if !data.is_empty() {
+                tap.push(*stream, &data);
+            }
+        }
+        if n == 0 {
+            break;
+        }
+    }
+    if let Some(file) = &mut file {
+        file.flush().await?;
+    }
+    Ok(truncator)
+}
+
+/// Looks up the secrets referred to by a task's details or environment,
+/// adding them to its variables. The returned redactor masks their values.
+async fn add_secrets(
+    secrets: Option<&SecretsConfig>,
+    task: &TaskDetails,
+    env: &Environment,
+    varmap: &mut VarMap,
+) -> Result<Redactor> {
+    let details = task.to_string();
+    let mut names = secrets::secret_references(&details);
+    let templated = if task.get("templates") == Some(&serde_json::Value::Bool(true)) {
+        secrets::template_secret_references(&details)
+    } else {
+        Vec::new()
+    };
+    let env_refs = env
+        .values()
+        .flatten()
+        .flat_map(|value| secrets::secret_references(value));
+    for name in templated.into_iter().chain(env_refs) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    if names.is_empty() {
+        return Ok(Redactor::default());
+    }
+    let Some(secrets) = secrets else {
+        return Err(Error::Executor(format!(
+            "Task refers to secret {}, but the executor has no secrets configured",
+            names[0]
+        )));
+    };
+    let values = secrets.lookup(&names).await?;
+    for (name, value) in names.iter().zip(&values) {
+        varmap.insert(secrets::secret_var(name), value.clone());
+    }
+    Ok(Redactor::new(values))
+}
+
+/// The most bytes of artifacts collected from an attempt, all told, as
+/// they're held in memory until storage has them
+const MAX_ARTIFACT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Reads every file matching the patterns, named relative to `base`, until
+/// they'd come to more than `limit` bytes. Files that would go past it are
+/// left out, and returned by name.
+async fn collect_artifacts(
+    patterns: &[String],
+    base: &std::path::Path,
+    varmap: &VarMap,
+    limit: u64,
+) -> Result<(Vec<Artifact>, Vec<String>)> {
+    use tokio::io::AsyncReadExt;
+
+    let mut artifacts = Vec::new();
+    let mut skipped = Vec::new();
+    let mut collected = 0;
+    for pattern in patterns {
+        let pattern = base.join(varmap.apply_to(pattern));
+        let paths = glob::glob(&pattern.to_string_lossy())
+            .map_err(|e| Error::Executor(format!("Bad artifact pattern: {}", e)))?;
+        for path in paths.filter_map(|p| p.ok()) {
+            if !path.is_file() {
+                continue;
+            }
+            let name = path
+                .strip_prefix(base)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .to_string();
+            let size = tokio::fs::metadata(&path).await?.len();
+            if collected + size > limit {
+                skipped.push(name);
+                continue;
+            }
+            // Anything written since is left off, rather than going over
+            let mut data = Vec::new();
+            tokio::fs::File::open(&path)
+                .await?
+                .take(limit - collected)
+                .read_to_end(&mut data)
+                .await?;
+            collected += data.len() as u64;
+            artifacts.push(Artifact {
+                name,
+                size: data.len() as u64,
+                data,
+            });
+        }
+    }
+    Ok((artifacts, skipped))
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn run_task(
     task: TaskDetails,
-    mut stop_rx: oneshot::Receiver<()>,
+    kill: CancellationToken,
     output_options: TaskOutputOptions,
     varmap: VarMap,
     mut env: Environment,
+    sink: Option<OutputSink>,
+    enforcement: Option<EnforcementConfig>,
+    output: Option<OutputTap>,
+    redactor: Redactor,
 ) -> Result<TaskAttempt> {
-    let mut details = extract_details(&task).unwrap();
+    let task = varmap.render(&task)?;
+    let mut details = extract_details(&task)?;
     let mut attempt = TaskAttempt::new();
     let cmd = details.command.generate(&varmap);
+    attempt.command = cmd.iter().map(|arg| redactor.redact(arg)).collect();
     details.command = Cmd::Split(cmd.clone());
-    let (program, args) = cmd.split_first().unwrap();
-    attempt.executor.push(format!("{:?}\n", details));
+    let (program, args) = cmd
+        .split_first()
+        .ok_or_else(|| Error::Executor("The command is empty".to_owned()))?;
+    attempt
+        .executor
+        .push(redactor.redact(&format!("{:?}\n", details)));
 
-    debug!("Running command {:?}", cmd);
+    debug!("Running command {:?}", attempt.command);
 
     let mut command = Command::new(program);
     command.stdout(Stdio::piped());
@@ -107,56 +646,156 @@ async fn run_task(
     // Build out environment. This takes the initial environment, and will
     // upsert it with the task details.
     env.extend(details.environment);
-    let cmd_env: HashMap<String, String> = env
+    let mut cmd_env: HashMap<String, String> = env
         .iter()
         .filter(|(_, v)| v.is_some())
         .map(|(k, v)| (k.clone(), varmap.apply_to(&v.clone().unwrap())))
         .collect();
 
+    // The task creates the result file itself, if it has anything to say
+    let result_path = std::env::temp_dir().join(format!(
+        "waterfall_result_{}_{}.json",
+        std::process::id(),
+        next_task_sequence()
+    ));
+    cmd_env.insert(
+        RESULT_ENV_VAR.to_owned(),
+        result_path.to_string_lossy().to_string(),
+    );
+
     command.env_clear();
     command.envs(cmd_env);
 
+    let cwd = match &details.cwd {
+        Some(cwd) => std::path::PathBuf::from(varmap.apply_to(cwd)),
+        None => std::env::current_dir()?,
+    };
+    command.current_dir(&cwd);
+
+    if let Some(mask) = details.umask {
+        // SAFETY: umask is async-signal-safe and touches no memory
+        unsafe {
+            command.pre_exec(move || {
+                libc::umask(mask as libc::mode_t);
+                Ok(())
+            });
+        }
+    }
+
+    let enforcement = enforcement.unwrap_or_default();
+    if let Some(nice) = details.nice.or(enforcement.nice) {
+        check_nice(nice)?;
+        // SAFETY: setpriority is async-signal-safe and touches no memory
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // The child moves itself into the cgroup before running the command,
+    // so anything it starts is held to the limits too
+    let cgroup = match &enforcement.cgroup {
+        Some(root) => Some(TaskCgroup::create(root, &details.resources).await?),
+        None => None,
+    };
+    if let Some(cgroup) = &cgroup {
+        let procs = std::ffi::CString::new(
+            cgroup
+                .path
+                .join("cgroup.procs")
+                .to_string_lossy()
+                .as_bytes(),
+        )
+        .map_err(|e| Error::Executor(e.to_string()))?;
+        // SAFETY: open, write, and close are async-signal-safe, and only
+        // touch memory allocated before the fork
+        unsafe {
+            command.pre_exec(move || {
+                let fd = libc::open(procs.as_ptr(), libc::O_WRONLY);
+                if fd == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+                libc::close(fd);
+                if written != 1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    if let Some(run_as) = &details.run_as {
+        let (uid, gid) = resolve_run_as(run_as)?;
+        command.gid(gid);
+        command.uid(uid);
+    }
+
     attempt.start_time = Utc::now();
-    let mut child = command.spawn()?;
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            if let Some(cgroup) = &cgroup {
+                cgroup.remove().await;
+            }
+            return Err(e.into());
+        }
+    };
 
     // Start getting performance stats
-    let pid = child.id().unwrap();
+    // A child that already exited has no pid, and no stats to gather
+    let pid = child.id().unwrap_or(0);
     let perf_monitor = tokio::spawn(async move { gather_child_stats(pid).await });
 
-    // Read from stdout constantly to prevent pipe blocking
-    let mut stdout_handle = child.stdout.take().unwrap();
-    let stdout_reader: tokio::task::JoinHandle<Result<Vec<u8>>> = tokio::spawn(async move {
-        let mut data = Vec::new();
-        stdout_handle.read_to_end(&mut data).await?;
-        Ok(data)
-    });
-
-    // Read from stderr constantly to prevent pipe blocking
-    let mut stderr_handle = child.stderr.take().unwrap();
-    let stderr_reader: tokio::task::JoinHandle<Result<Vec<u8>>> = tokio::spawn(async move {
-        let mut data = Vec::new();
-        stderr_handle.read_to_end(&mut data).await?;
-        Ok(data)
-    });
-
-    // Generate a timeout message, if needed
-    let (timeout_tx, mut timeout_rx) = oneshot::channel();
-    if details.timeout > 0 {
-        let timeout = details.timeout;
-        tokio::spawn(async move {
-            sleep(Duration::from_millis(1000 * timeout)).await;
-            timeout_tx.send(()).unwrap_or(());
-        });
-    }
+    // With a sink, the full output is streamed to files and the attempt
+    // only keeps an excerpt
+    let truncator = if output_options.truncate || sink.is_some() {
+        Truncator::new(output_options.head_bytes, output_options.tail_bytes)
+    } else {
+        Truncator::unbounded()
+    };
+    let (stdout_file, stderr_file, sink_files) = match &sink {
+        Some(sink) => {
+            let prefix = format!("{}_{}", attempt.start_time.format("%Y%m%dT%H%M%S%.6f"), pid);
+            let (stdout_file, output_file) = sink.create(&format!("{}.stdout", prefix)).await?;
+            let (stderr_file, error_file) = sink.create(&format!("{}.stderr", prefix)).await?;
+            (
+                Some(stdout_file),
+                Some(stderr_file),
+                Some((output_file, error_file)),
+            )
+        }
+        None => (None, None, None),
+    };
+
+    // Read from stdout and stderr constantly to prevent pipe blocking
+    let stdout_reader = tokio::spawn(capture(
+        child.stdout.take().unwrap(),
+        truncator.clone(),
+        stdout_file,
+        output.clone().map(|tap| (tap, OutputStream::Stdout)),
+        redactor.clone(),
+    ));
+    let stderr_reader = tokio::spawn(capture(
+        child.stderr.take().unwrap(),
+        truncator,
+        stderr_file,
+        output.map(|tap| (tap, OutputStream::Stderr)),
+        redactor,
+    ));
 
     tokio::select! {
         _ = child.wait() => {},
-        _ = (&mut stop_rx) => {
+        _ = kill.cancelled() => {
             attempt.killed = true;
             child.kill().await.unwrap_or(());
             attempt.executor.push("Task was killed by request".to_owned());
         }
-        _ = (&mut timeout_rx) => {
+        _ = sleep(Duration::from_secs(details.timeout)), if details.timeout > 0 => {
             child.kill().await.unwrap_or(());
             attempt.killed = true;
             attempt.executor.push("Task exceeded the timeout interval and was killed".to_owned());
@@ -164,27 +803,63 @@ async fn run_task(
     }
 
     // Get any output
-    let mut stdout = String::from_utf8_lossy(&stdout_reader.await??).to_string();
-    let mut stderr = String::from_utf8_lossy(&stderr_reader.await??).to_string();
+    let (stdout, stdout_dropped) = stdout_reader.await??.finish();
+    let (stderr, stderr_dropped) = stderr_reader.await??.finish();
 
-    let output = child.wait_with_output().await.unwrap();
+    let output = child.wait_with_output().await?;
     attempt.exit_code = output.status.code().unwrap_or(-1i32);
     attempt.succeeded = output.status.success();
+
+    // A bad result is reported, but doesn't fail a task that otherwise ran
+    match read_result(&result_path, &stdout).await {
+        Ok(result) => attempt.result = result,
+        Err(e) => attempt
+            .executor
+            .push(format!("Unable to read the task's result: {}", e)),
+    }
+    tokio::fs::remove_file(&result_path).await.unwrap_or(());
+    if let Some(cgroup) = cgroup {
+        if cgroup.oom_killed().await {
+            attempt.succeeded = false;
+            attempt.memory_exceeded = true;
+            attempt.executor.push(format!(
+                "Task exceeded its memory_mb of {} and was killed",
+                details.resources.get("memory_mb").copied().unwrap_or(0.0)
+            ));
+        }
+        cgroup.remove().await;
+    }
     if !(attempt.succeeded && output_options.discard_successful) {
-        if output_options.truncate {
-            stdout = head_tail(
-                &stdout,
-                output_options.head_bytes,
-                output_options.tail_bytes,
-            );
-            stderr = head_tail(
-                &stdout,
-                output_options.head_bytes,
-                output_options.tail_bytes,
-            );
+        if let (Some(sink), Some((output_file, error_file))) = (&sink, sink_files) {
+            sink.rotate(&[output_file.clone(), error_file.clone()])
+                .await?;
+            attempt.output_file = Some(output_file);
+            attempt.error_file = Some(error_file);
         }
         attempt.output = stdout;
         attempt.error = stderr;
+        attempt.output_truncated_bytes = stdout_dropped;
+        attempt.error_truncated_bytes = stderr_dropped;
+    } else if let Some((output_file, error_file)) = sink_files {
+        tokio::fs::remove_file(output_file).await.unwrap_or(());
+        tokio::fs::remove_file(error_file).await.unwrap_or(());
+    }
+
+    // Artifacts are kept even when the task fails, since they're often
+    // what's needed to see why
+    match collect_artifacts(&details.artifacts, &cwd, &varmap, MAX_ARTIFACT_BYTES).await {
+        Ok((artifacts, skipped)) => {
+            attempt.artifacts = artifacts;
+            for name in skipped {
+                attempt.executor.push(format!(
+                    "Artifact {} wasn't collected, as the attempt's artifacts would come to over {} bytes",
+                    name, MAX_ARTIFACT_BYTES
+                ));
+            }
+        }
+        Err(e) => attempt
+            .executor
+            .push(format!("Unable to collect artifacts: {}", e)),
     }
 
     // Set stats
@@ -199,80 +874,804 @@ async fn run_task(
     Ok(attempt)
 }
 
-/// The mpsc channel can be sized to fit max parallelism
-pub async fn start_local_executor(
+/// Runs tasks as child processes of this one
+pub struct LocalExecutor {
     max_parallel: usize,
-    mut exe_msgs: mpsc::UnboundedReceiver<ExecutorMessage>,
-) {
-    let mut running = FuturesUnordered::new();
-
-    /*
-    Inherited environment vars
-    */
-
-    let default_vars = [
-        "LANG",
-        "HOSTNAME",
-        "LOGNAME",
-        "USER",
-        "PATH",
-        "HOME",
-        "XDG_CONFIG_HOME",
-        "ALL_PROXY",
-        "FTP_PROXY",
-        "HTTPS_PROXY",
-        "HTTP_PROXY",
-        "NO_PROXY",
-    ];
-    let inherited_env: Environment = default_vars
-        .iter()
-        .map(|envvar| (envvar.to_string(), std::env::var(envvar).ok()))
-        .collect();
+    config: LocalExecutorConfig,
 
-    while let Some(msg) = exe_msgs.recv().await {
-        use ExecutorMessage::{ExecuteTask, Stop, ValidateTask};
-        match msg {
-            ValidateTask { details, response } => {
-                tokio::spawn(async move {
-                    let result = validate_task(&details);
-                    response.send(result).unwrap_or(());
-                });
-            }
-            ExecuteTask {
-                details,
-                varmap,
-                output_options,
-                response,
-                kill,
-            } => {
-                if running.len() == max_parallel {
-                    running.next().await;
-                }
-                let env = inherited_env.clone();
-                running.push(tokio::spawn(async move {
-                    let attempt = match run_task(details, kill, output_options, varmap, env).await {
-                        Ok(attempt) => attempt,
-                        Err(e) => TaskAttempt {
-                            succeeded: false,
-                            executor: vec![format!("Failed to launch command: {:?}", e)],
-                            ..TaskAttempt::new()
-                        },
+    /// Running tasks, which release their resources when they finish
+    running: FuturesUnordered<BoxFuture<'static, TaskResources>>,
+
+    /// Resources not held by running tasks
+    available: TaskResources,
+}
+
+impl LocalExecutor {
+    pub fn new(max_parallel: usize, config: LocalExecutorConfig) -> Self {
+        LocalExecutor {
+            max_parallel,
+            available: config.resources.clone().unwrap_or_default(),
+            config,
+            running: FuturesUnordered::new(),
+        }
+    }
+
+    /// Drops finished tasks, releasing their resources. They're otherwise
+    /// only dropped once waited on.
+    fn reap(&mut self) {
+        while let Some(Some(released)) = self.running.next().now_or_never() {
+            self.available.add(&released);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Executor for LocalExecutor {
+    async fn validate(&mut self, details: &TaskDetails) -> Result<()> {
+        validate_task(details, self.config.resources.as_ref())
+    }
+
+    async fn execute(&mut self, task: TaskExecution) -> AttemptFuture {
+        // Without a capacity, resources are only limited by max_parallel
+        let resources = match &self.config.resources {
+            Some(capacity) => {
+                let resources = extract_details(&task.details)
+                    .map(|parsed| parsed.resources)
+                    .unwrap_or_default();
+                if !capacity.can_satisfy(&resources) {
+                    let attempt = TaskAttempt {
+                        succeeded: false,
+                        executor: vec![
+                            "Task requires more resources than the executor has".to_owned()
+                        ],
+                        ..TaskAttempt::new()
                     };
-                    response.send(attempt).unwrap();
-                }));
+                    return futures::future::ready(attempt).boxed();
+                }
+                resources
             }
-            Stop {} => {
-                break;
+            None => TaskResources::new(),
+        };
+        while self.running.len() == self.max_parallel || !self.available.can_satisfy(&resources) {
+            match self.running.next().await {
+                Some(released) => self.available.add(&released),
+                // Nothing is running, so all of it is free
+                None => self.available = self.config.resources.clone().unwrap_or_default(),
             }
         }
+        if let Err(e) = self.available.sub(&resources) {
+            let attempt = TaskAttempt {
+                succeeded: false,
+                executor: vec![format!("Unable to reserve resources: {}", e)],
+                ..TaskAttempt::new()
+            };
+            return futures::future::ready(attempt).boxed();
+        }
+
+        let TaskExecution {
+            details,
+            mut varmap,
+            output_options,
+            kill,
+            span,
+            output,
+        } = task;
+        let environment = self.config.environment.clone();
+        let sink = self.config.output_sink.clone();
+        let enforcement = self.config.enforcement.clone();
+        let secrets = self.config.secrets.clone();
+        let (response, attempt) = oneshot::channel();
+        let handle = tokio::spawn(
+            async move {
+                let result = async {
+                    let env = environment.environment().await?;
+                    // Secret stores can be slow, and the task can be killed
+                    // while they're read
+                    let redactor = tokio::select! {
+                        redactor = add_secrets(secrets.as_ref(), &details, &env, &mut varmap) => {
+                            redactor?
+                        }
+                        _ = kill.cancelled() => {
+                            return Ok(TaskAttempt {
+                                killed: true,
+                                executor: vec![
+                                    "Task was killed while its secrets were looked up".to_owned(),
+                                ],
+                                ..TaskAttempt::new()
+                            });
+                        }
+                    };
+                    run_task(
+                        details,
+                        kill,
+                        output_options,
+                        varmap,
+                        env,
+                        sink,
+                        enforcement,
+                        output,
+                        redactor,
+                    )
+                    .await
+                }
+                .await;
+                let attempt = match result {
+                    Ok(attempt) => attempt,
+                    Err(e) => TaskAttempt {
+                        succeeded: false,
+                        executor: vec![format!("Failed to launch command: {:?}", e)],
+                        ..TaskAttempt::new()
+                    },
+                };
+                response.send(attempt).unwrap_or(());
+            }
+            .instrument(info_span!(parent: &span, "execute")),
+        );
+        self.running.push(release_when_done(handle, resources));
+        async move {
+            attempt.await.unwrap_or_else(|_| TaskAttempt {
+                succeeded: false,
+                executor: vec!["Task was lost before it finished".to_owned()],
+                ..TaskAttempt::new()
+            })
+        }
+        .boxed()
+    }
+
+    async fn status(&mut self) -> ExecutorStatus {
+        self.reap();
+        ExecutorStatus {
+            running: self.running.len(),
+            ..ExecutorStatus::default()
+        }
     }
 }
 
+/// Gives back a task's resources once it's done, even if it panicked. Its
+/// attempt is then reported as lost.
+fn release_when_done(
+    handle: tokio::task::JoinHandle<()>,
+    resources: TaskResources,
+) -> BoxFuture<'static, TaskResources> {
+    async move {
+        if let Err(e) = handle.await {
+            error!("Task failed to finish: {}", e);
+        }
+        resources
+    }
+    .boxed()
+}
+
+/// The mpsc channel can be sized to fit max parallelism
+pub async fn start_local_executor(
+    max_parallel: usize,
+    config: LocalExecutorConfig,
+    exe_msgs: mpsc::Receiver<ExecutorMessage>,
+) {
+    serve_executor(LocalExecutor::new(max_parallel, config), exe_msgs).await
+}
+
 pub fn start(
     max_parallel: usize,
-    msgs: mpsc::UnboundedReceiver<ExecutorMessage>,
+    msgs: mpsc::Receiver<ExecutorMessage>,
+) -> tokio::task::JoinHandle<()> {
+    start_with_config(max_parallel, LocalExecutorConfig::default(), msgs)
+}
+
+pub fn start_with_config(
+    max_parallel: usize,
+    config: LocalExecutorConfig,
+    msgs: mpsc::Receiver<ExecutorMessage>,
 ) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        start_local_executor(max_parallel, msgs).await;
+        start_local_executor(max_parallel, config, msgs).await;
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_panicked_task_releases_resources() {
+        let resources: TaskResources = serde_json::from_str(r#"{ "cores": 2 }"#).unwrap();
+        let handle = tokio::spawn(async { panic!("task blew up") });
+        assert_eq!(
+            release_when_done(handle, resources.clone()).await,
+            resources
+        );
+    }
+
+    #[test]
+    fn check_run_as_validation() {
+        let details = serde_json::json!({
+            "command": "/bin/true",
+            "run_as": { "user": "no_such_user_waterfall" }
+        });
+        match validate_task(&details, None) {
+            Err(Error::Validation(msg)) => assert!(msg.contains("no_such_user_waterfall")),
+            other => panic!("Expected a validation error, got {:?}", other),
+        }
+
+        // Running as ourselves never requires privileges
+        let me = users::get_user_by_uid(users::get_effective_uid()).unwrap();
+        let group = users::get_group_by_gid(users::get_effective_gid()).unwrap();
+        let details = serde_json::json!({
+            "command": "/bin/true",
+            "run_as": {
+                "user": me.name().to_str().unwrap(),
+                "group": group.name().to_str().unwrap()
+            }
+        });
+        assert!(validate_task(&details, None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn check_environment_config() {
+        std::env::set_var("WATERFALL_ENV_TEST", "inherited");
+
+        let config: EnvironmentConfig = serde_json::from_str(
+            r#"{ "inherit": { "only": [ "WATERFALL_ENV_TEST" ] }, "set": { "JAVA_HOME": "/opt/java" } }"#,
+        )
+        .unwrap();
+        let env = config.environment().await.unwrap();
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["WATERFALL_ENV_TEST"].as_deref(), Some("inherited"));
+        assert_eq!(env["JAVA_HOME"].as_deref(), Some("/opt/java"));
+
+        let config: EnvironmentConfig = serde_json::from_str(r#"{ "inherit": "none" }"#).unwrap();
+        assert!(config.environment().await.unwrap().is_empty());
+
+        let config: EnvironmentConfig = serde_json::from_str(r#"{ "inherit": "all" }"#).unwrap();
+        assert!(config
+            .environment()
+            .await
+            .unwrap()
+            .contains_key("WATERFALL_ENV_TEST"));
+
+        let config: EnvironmentConfig =
+            serde_json::from_str(r#"{ "inherit": "all", "exclude": [ "WATERFALL_ENV_TEST" ] }"#)
+                .unwrap();
+        assert!(!config
+            .environment()
+            .await
+            .unwrap()
+            .contains_key("WATERFALL_ENV_TEST"));
+
+        // The env file overrides inherited variables, and set overrides both
+        let env_file = std::env::temp_dir().join("waterfall_env_file_test.env");
+        std::fs::write(
+            &env_file,
+            "WATERFALL_ENV_TEST=from_file\nDB_HOST=db1\nJAVA_HOME=/usr/lib/jvm\n",
+        )
+        .unwrap();
+        let config = EnvironmentConfig {
+            inherit: InheritEnv::Only(vec!["WATERFALL_ENV_TEST".to_owned()]),
+            env_file: Some(env_file.clone()),
+            set: HashMap::from([("JAVA_HOME".to_owned(), "/opt/java".to_owned())]),
+            ..EnvironmentConfig::default()
+        };
+        let env = config.environment().await.unwrap();
+        assert_eq!(env["WATERFALL_ENV_TEST"].as_deref(), Some("from_file"));
+        assert_eq!(env["DB_HOST"].as_deref(), Some("db1"));
+        assert_eq!(env["JAVA_HOME"].as_deref(), Some("/opt/java"));
+
+        std::fs::remove_file(&env_file).unwrap();
+        assert!(config.environment().await.is_err());
+
+        assert_eq!(
+            serde_json::from_str::<EnvironmentConfig>("{}").unwrap(),
+            EnvironmentConfig::default()
+        );
+    }
+
+    #[test]
+    fn check_env_file_parsing() {
+        let vars = parse_env_file(
+            r#"
+# Site settings
+export JAVA_HOME=/opt/java
+DB_URL = postgres://db1/prices # primary
+GREETING="hello \"world\"\nbye"
+RAW='no $expansion \n here'
+EMPTY=
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("JAVA_HOME".to_owned(), "/opt/java".to_owned()),
+                ("DB_URL".to_owned(), "postgres://db1/prices".to_owned()),
+                ("GREETING".to_owned(), "hello \"world\"\nbye".to_owned()),
+                ("RAW".to_owned(), "no $expansion \\n here".to_owned()),
+                ("EMPTY".to_owned(), "".to_owned()),
+            ]
+        );
+
+        assert!(parse_env_file("JAVA_HOME").unwrap_err().contains("line 1"));
+        assert!(parse_env_file("\nBAD-NAME=1")
+            .unwrap_err()
+            .contains("line 2"));
+        assert!(parse_env_file("A=\"open").is_err());
+    }
+
+    #[test]
+    fn check_umask_parsing() {
+        let parse = |umask: &str| {
+            extract_details(&serde_json::json!({ "command": "/bin/true", "umask": umask }))
+                .map(|d| d.umask)
+        };
+        assert_eq!(parse("027").unwrap(), Some(0o027));
+        assert_eq!(parse("0022").unwrap(), Some(0o022));
+        assert!(parse("999").is_err());
+        assert!(parse("1777").is_err());
+    }
+
+    #[tokio::test]
+    async fn check_cwd_and_umask() {
+        let dir = std::env::temp_dir().join("waterfall_cwd_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let details = serde_json::json!({
+            "command": { "shell": "pwd; umask" },
+            "cwd": "${dir}",
+            "umask": "027"
+        });
+        let varmap = VarMap::from(HashMap::from([(
+            "dir".to_owned(),
+            dir.to_str().unwrap().to_owned(),
+        )]));
+        let output_options = TaskOutputOptions {
+            discard_successful: false,
+            ..TaskOutputOptions::default()
+        };
+        let attempt = run_task(
+            details,
+            CancellationToken::new(),
+            output_options,
+            varmap,
+            Environment::new(),
+            None,
+            None,
+            None,
+            Redactor::default(),
+        )
+        .await
+        .unwrap();
+        assert!(attempt.succeeded);
+        let lines: Vec<&str> = attempt.output.lines().collect();
+        assert_eq!(lines, vec![dir.to_str().unwrap(), "0027"]);
+    }
+
+    #[tokio::test]
+    async fn check_artifacts() {
+        let dir = std::env::temp_dir().join("waterfall_artifacts_test");
+        tokio::fs::remove_dir_all(&dir).await.unwrap_or(());
+        std::fs::create_dir_all(&dir).unwrap();
+        let details = serde_json::json!({
+            "command": { "shell": "mkdir out; echo a > out/a.csv; echo bb > out/b.csv; echo c > out/c.log; false" },
+            "cwd": dir.to_str().unwrap(),
+            "artifacts": [ "out/*.${ext}" ]
+        });
+        let varmap = VarMap::from(HashMap::from([("ext".to_owned(), "csv".to_owned())]));
+        let attempt = run_task(
+            details,
+            CancellationToken::new(),
+            TaskOutputOptions::default(),
+            varmap,
+            Environment::new(),
+            None,
+            None,
+            None,
+            Redactor::default(),
+        )
+        .await
+        .unwrap();
+
+        // Failed tasks still have their artifacts collected
+        assert!(!attempt.succeeded);
+        let artifacts: Vec<(&str, u64, &[u8])> = attempt
+            .artifacts
+            .iter()
+            .map(|a| (a.name.as_str(), a.size, a.data.as_slice()))
+            .collect();
+        assert_eq!(
+            artifacts,
+            vec![
+                ("out/a.csv", 2, &b"a\n"[..]),
+                ("out/b.csv", 3, &b"bb\n"[..])
+            ]
+        );
+
+        // Files that would go over the limit are left out
+        let (artifacts, skipped) =
+            collect_artifacts(&["out/*".to_owned()], &dir, &VarMap::new(), 4)
+                .await
+                .unwrap();
+        let names: Vec<&str> = artifacts.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["out/a.csv", "out/c.log"]);
+        assert_eq!(skipped, vec!["out/b.csv".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn check_nice() {
+        let output_options = TaskOutputOptions {
+            discard_successful: false,
+            ..TaskOutputOptions::default()
+        };
+        let enforcement = EnforcementConfig {
+            nice: Some(3),
+            ..EnforcementConfig::default()
+        };
+        let run = |details| {
+            run_task(
+                details,
+                CancellationToken::new(),
+                output_options,
+                VarMap::new(),
+                Environment::new(),
+                None,
+                Some(enforcement.clone()),
+                None,
+                Redactor::default(),
+            )
+        };
+
+        let attempt = run(serde_json::json!({ "command": "nice" })).await.unwrap();
+        assert_eq!(attempt.output.trim(), "3");
+
+        // Tasks can set their own
+        let attempt = run(serde_json::json!({ "command": "nice", "nice": 7 }))
+            .await
+            .unwrap();
+        assert_eq!(attempt.output.trim(), "7");
+
+        assert!(
+            validate_task(&serde_json::json!({ "command": "nice", "nice": 20 }), None).is_err()
+        );
+    }
+
+    #[test]
+    fn check_cgroup_limits() {
+        let resources = TaskResources::from(HashMap::from([
+            ("cores".to_owned(), 2.5),
+            ("memory_mb".to_owned(), 512.0),
+            ("gpus".to_owned(), 1.0),
+        ]));
+        let mut limits = cgroup_limits(&resources);
+        limits.sort();
+        assert_eq!(
+            limits,
+            vec![
+                ("cpu.weight", "250".to_owned()),
+                ("memory.max", "536870912".to_owned()),
+                ("memory.oom.group", "1".to_owned()),
+                ("memory.swap.max", "0".to_owned()),
+            ]
+        );
+        assert!(cgroup_limits(&TaskResources::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn check_resources() {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let config = LocalExecutorConfig {
+            resources: Some(TaskResources::from(HashMap::from([(
+                "cores".to_owned(),
+                2.0,
+            )]))),
+            ..LocalExecutorConfig::default()
+        };
+        start_with_config(10, config, rx);
+
+        let submit = |cores: u32| {
+            let (response, attempt) = oneshot::channel();
+            tx.try_send(ExecutorMessage::ExecuteTask {
+                details: serde_json::json!({
+                    "command": "sleep 1",
+                    "resources": { "cores": cores }
+                }),
+                varmap: VarMap::new(),
+                output_options: TaskOutputOptions::default(),
+                response,
+                kill: CancellationToken::new(),
+                span: tracing::Span::current(),
+                output: None,
+            })
+            .unwrap();
+            attempt
+        };
+
+        // Too big to ever run
+        let (response, validation) = oneshot::channel();
+        tx.send(ExecutorMessage::ValidateTask {
+            details: serde_json::json!({ "command": "true", "resources": { "cores": 4 } }),
+            response,
+        })
+        .await
+        .unwrap();
+        assert!(validation.await.unwrap().is_err());
+        assert!(!submit(4).await.unwrap().succeeded);
+
+        // Only one of these fits at a time, despite the free workers
+        let start = std::time::Instant::now();
+        let (first, second) = (submit(2), submit(2));
+        assert!(first.await.unwrap().succeeded);
+        assert!(second.await.unwrap().succeeded);
+        assert!(start.elapsed() >= Duration::from_secs(2));
+
+        // These fit side by side
+        let start = std::time::Instant::now();
+        let (first, second) = (submit(1), submit(1));
+        assert!(first.await.unwrap().succeeded);
+        assert!(second.await.unwrap().succeeded);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_result() {
+        let run = |command: &str| {
+            run_task(
+                serde_json::json!({ "command": { "shell": command } }),
+                CancellationToken::new(),
+                TaskOutputOptions::default(),
+                VarMap::new(),
+                Environment::new(),
+                None,
+                None,
+                None,
+                Redactor::default(),
+            )
+        };
+
+        let attempt = run(r#"echo '{ "rows": 42 }' > "$WATERFALL_RESULT""#)
+            .await
+            .unwrap();
+        assert_eq!(attempt.result, Some(serde_json::json!({ "rows": 42 })));
+
+        // The last complete block in stdout is used without a result file
+        let attempt = run(
+            "printf '```waterfall-result\\n{ \"rows\": 1 }\\n```\\nlog\\n```waterfall-result\\n{ \"rows\": 2,\\n  \"sha\": \"ab\" }\\n```\\n'",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            attempt.result,
+            Some(serde_json::json!({ "rows": 2, "sha": "ab" }))
+        );
+
+        let attempt = run("echo done").await.unwrap();
+        assert_eq!(attempt.result, None);
+
+        let attempt = run(r#"echo 'rows: 42' > "$WATERFALL_RESULT""#)
+            .await
+            .unwrap();
+        assert!(attempt.succeeded);
+        assert_eq!(attempt.result, None);
+        assert!(attempt.executor.iter().any(|e| e.contains("result")));
+    }
+
+    #[tokio::test]
+    async fn check_secrets() {
+        std::env::set_var("WATERFALL_SECRET_API_KEY", "s3cr3t-value");
+        let config = SecretsConfig::Env {
+            prefix: "WATERFALL_SECRET_".to_owned(),
+        };
+        let details = serde_json::json!({
+            "command": { "shell": "echo key=${secret:API_KEY}; echo \"env=$KEY\" >&2" },
+            "environment": { "KEY": "${secret:API_KEY}" },
+        });
+        let mut varmap = VarMap::new();
+        let env = extract_details(&details).unwrap().environment;
+        let redactor = add_secrets(Some(&config), &details, &env, &mut varmap)
+            .await
+            .unwrap();
+        let tap = OutputTap::new();
+        let attempt = run_task(
+            details.clone(),
+            CancellationToken::new(),
+            TaskOutputOptions {
+                discard_successful: false,
+                ..TaskOutputOptions::default()
+            },
+            varmap,
+            Environment::new(),
+            None,
+            None,
+            Some(tap.clone()),
+            redactor,
+        )
+        .await
+        .unwrap();
+
+        // The task got the value, but nothing kept shows it
+        assert!(attempt.succeeded);
+        assert_eq!(attempt.output, "key=********\n");
+        assert_eq!(attempt.error, "env=********\n");
+        let kept = format!("{:?} {:?}", attempt.command, attempt.executor);
+        assert!(!kept.contains("s3cr3t-value"));
+        let (followed, _) = tap.follow();
+        assert!(!followed.is_empty());
+        assert!(followed.iter().all(|chunk| !chunk.data.contains("s3cr3t")));
+
+        // Without a provider, or without the secret, the task can't start
+        let mut varmap = VarMap::new();
+        assert!(add_secrets(None, &details, &env, &mut varmap)
+            .await
+            .is_err());
+        std::env::remove_var("WATERFALL_SECRET_API_KEY");
+        assert!(add_secrets(Some(&config), &details, &env, &mut varmap)
+            .await
+            .is_err());
+    }
+
+    #[cfg(feature = "templates")]
+    #[tokio::test]
+    async fn check_template_secrets() {
+        std::env::set_var("WATERFALL_TEMPLATE_SECRET_TOKEN", "t0k3n-value");
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let config = LocalExecutorConfig {
+            secrets: Some(SecretsConfig::Env {
+                prefix: "WATERFALL_TEMPLATE_SECRET_".to_owned(),
+            }),
+            ..LocalExecutorConfig::default()
+        };
+        start_with_config(1, config, rx);
+
+        // The secret is only referenced by the template, yet is looked up
+        let (response, attempt) = oneshot::channel();
+        tx.send(ExecutorMessage::ExecuteTask {
+            details: serde_json::json!({
+                "templates": true,
+                "command": { "shell": "test '{{ secret.TOKEN }}' = t0k3n-value && echo {{ secret.TOKEN }}" },
+            }),
+            varmap: VarMap::new(),
+            output_options: TaskOutputOptions {
+                discard_successful: false,
+                ..TaskOutputOptions::default()
+            },
+            response,
+            kill: CancellationToken::new(),
+            span: tracing::Span::current(),
+            output: None,
+        })
+        .await
+        .unwrap();
+        let attempt = attempt.await.unwrap();
+        assert!(attempt.succeeded, "{:?}", attempt);
+        assert_eq!(attempt.output, "********\n");
+        assert!(!format!("{:?}", attempt.command).contains("t0k3n-value"));
+
+        std::env::remove_var("WATERFALL_TEMPLATE_SECRET_TOKEN");
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_kill_during_secret_lookup() {
+        // Reading a FIFO blocks until something writes to it
+        let dir = std::env::temp_dir().join("waterfall_secret_fifo_test");
+        tokio::fs::remove_dir_all(&dir).await.unwrap_or(());
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let fifo = dir.join("slow");
+        assert!(std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap()
+            .success());
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let config = LocalExecutorConfig {
+            secrets: Some(SecretsConfig::File { dir: dir.clone() }),
+            ..LocalExecutorConfig::default()
+        };
+        start_with_config(1, config, rx);
+        let kill = CancellationToken::new();
+        let (response, attempt) = oneshot::channel();
+        tx.send(ExecutorMessage::ExecuteTask {
+            details: serde_json::json!({ "command": "echo ${secret:slow}" }),
+            varmap: VarMap::new(),
+            output_options: TaskOutputOptions::default(),
+            response,
+            kill: kill.clone(),
+            span: tracing::Span::current(),
+            output: None,
+        })
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        kill.cancel();
+        let attempt = tokio::time::timeout(Duration::from_secs(5), attempt)
+            .await
+            .expect("The kill waited on the secret")
+            .unwrap();
+        assert!(attempt.killed && !attempt.succeeded);
+
+        // Lets the abandoned read finish
+        tokio::task::spawn_blocking(move || std::fs::write(fifo, "value"))
+            .await
+            .unwrap()
+            .unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap_or(());
+    }
+
+    #[tokio::test]
+    async fn check_output_sink() {
+        let directory = std::env::temp_dir().join("waterfall_local_sink_test");
+        tokio::fs::remove_dir_all(&directory).await.unwrap_or(());
+        let sink = OutputSink {
+            directory,
+            max_bytes: 1 << 20,
+        };
+        let output_options = TaskOutputOptions {
+            discard_successful: false,
+            truncate: false,
+            head_bytes: 4,
+            tail_bytes: 4,
+        };
+        let tap = OutputTap::new();
+        let attempt = run_task(
+            serde_json::json!({ "command": { "shell": "echo 0123456789abcdef; echo oops >&2" } }),
+            CancellationToken::new(),
+            output_options,
+            VarMap::new(),
+            Environment::new(),
+            Some(sink),
+            None,
+            Some(tap.clone()),
+            Redactor::default(),
+        )
+        .await
+        .unwrap();
+
+        // Followers see all of it, not just the excerpt
+        let (followed, _) = tap.follow();
+        let stdout: String = followed
+            .iter()
+            .filter(|c| c.stream == OutputStream::Stdout)
+            .map(|c| c.data.as_str())
+            .collect();
+        assert_eq!(stdout, "0123456789abcdef\n");
+
+        let output_file = attempt.output_file.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output_file).unwrap(),
+            "0123456789abcdef\n"
+        );
+        assert_eq!(attempt.output, "0123\n... 9 bytes truncated ...\ndef\n");
+        assert_eq!(attempt.output_truncated_bytes, 9);
+        let error_file = attempt.error_file.unwrap();
+        assert_eq!(std::fs::read_to_string(error_file).unwrap(), "oops\n");
+    }
+
+    #[tokio::test]
+    async fn check_large_output() {
+        let output_options = TaskOutputOptions {
+            discard_successful: false,
+            truncate: true,
+            head_bytes: 1024,
+            tail_bytes: 1024,
+        };
+        let attempt = run_task(
+            serde_json::json!({ "command": { "shell": "yes waterfall | head -n 1000000; echo oops >&2" } }),
+            CancellationToken::new(),
+            output_options,
+            VarMap::new(),
+            Environment::new(),
+            None,
+            None,
+            None,
+            Redactor::default(),
+        )
+        .await
+        .unwrap();
+        assert!(attempt.succeeded);
+        assert_eq!(attempt.output_truncated_bytes, 10_000_000 - 2048);
+        assert!(attempt.output.starts_with("waterfall\n"));
+        assert!(attempt.output.ends_with("waterfall\n"));
+        assert_eq!(attempt.error, "oops\n");
+        assert_eq!(attempt.error_truncated_bytes, 0);
+    }
+}
@@ -1,6 +1,19 @@
 use super::*;
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+#[cfg(feature = "agent")]
 pub mod agent_executor;
+#[cfg(feature = "local-exec")]
 pub mod local_executor;
+pub mod output_sink;
+pub mod output_tap;
+pub mod secrets;
+pub mod truncator;
+
+pub use output_sink::OutputSink;
+pub use output_tap::{OutputChunk, OutputStream, OutputTap};
+pub use secrets::{Redactor, SecretsConfig};
+pub use truncator::Truncator;
 
 /// Messages for interacting with an Executor
 #[derive(Debug)]
@@ -21,11 +34,207 @@ pub enum ExecutorMessage {
         varmap: VarMap,
         output_options: TaskOutputOptions,
         response: oneshot::Sender<TaskAttempt>,
-        kill: oneshot::Receiver<()>,
+        /// Cancelling the token kills the task
+        kill: CancellationToken,
+        /// The caller's span, so the executor's logs carry its fields
+        span: tracing::Span,
+        /// If set, output is pushed here as it's produced, and the tap is
+        /// closed once the task finishes
+        output: Option<OutputTap>,
+    },
+    /// Start dispatching to another agent. Fails for executors that don't
+    /// dispatch to agents, or if the agent is already a target.
+    AddTarget {
+        base_url: String,
+        /// Shared secret the agent requires as a bearer token
+        token: Option<String>,
+        response: oneshot::Sender<Result<()>>,
+    },
+    /// Stop dispatching to an agent. Tasks already running there finish.
+    RemoveTarget {
+        base_url: String,
+        response: oneshot::Sender<Result<()>>,
+    },
+    /// Report the executor's view of what it's running, and where
+    GetStatus {
+        response: oneshot::Sender<ExecutorStatus>,
     },
     Stop {},
 }
 
+/// A remote agent, as its executor last saw it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AgentStatus {
+    pub base_url: String,
+    pub enabled: bool,
+
+    /// The agent's capacity
+    pub resources: TaskResources,
+
+    /// The capacity not held by running tasks
+    pub current_resources: TaskResources,
+
+    /// When the agent last answered a heartbeat
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ExecutorStatus {
+    /// Tasks currently running
+    pub running: usize,
+
+    /// The agents tasks are dispatched to, for executors that have them
+    #[serde(default)]
+    pub agents: Vec<AgentStatus>,
+}
+
+/// A task handed to an [`Executor`], as sent in
+/// `ExecutorMessage::ExecuteTask`
+#[derive(Debug)]
+pub struct TaskExecution {
+    pub details: TaskDetails,
+    pub varmap: VarMap,
+    pub output_options: TaskOutputOptions,
+    /// Cancelling the token kills the task
+    pub kill: CancellationToken,
+    /// The caller's span, so the executor's logs carry its fields
+    pub span: tracing::Span,
+    /// If set, output is pushed here as it's produced. It's closed for the
+    /// executor once the task finishes.
+    pub output: Option<OutputTap>,
+}
+
+/// Resolves to the attempt of a started task once it finishes
+pub type AttemptFuture = BoxFuture<'static, TaskAttempt>;
+
+/// Something that runs tasks.
+///
+/// Implementations don't need to deal with channels: [`serve_executor`] adapts
+/// any `Executor` to the `ExecutorMessage` actor model used by the runner and
+/// daemons, so a custom executor can be plugged in by implementing this
+/// trait and handing it to [`start_executor`]. Work of the executor's own,
+/// like the agent executor's heartbeats, goes in [`Executor::background`].
+#[async_trait]
+pub trait Executor: Send {
+    /// Checks the details describe a task this executor can run
+    async fn validate(&mut self, details: &TaskDetails) -> Result<()>;
+
+    /// Starts a task, first waiting for the capacity to run it if need be.
+    /// The returned future is driven outside the executor, so tasks run
+    /// side by side. Tasks that can't be started resolve to a failed
+    /// attempt.
+    async fn execute(&mut self, task: TaskExecution) -> AttemptFuture;
+
+    async fn status(&mut self) -> ExecutorStatus {
+        ExecutorStatus::default()
+    }
+
+    /// Start dispatching to another agent
+    async fn add_target(&mut self, _base_url: &str, _token: Option<String>) -> Result<()> {
+        Err(Error::Executor(
+            "This executor doesn't dispatch to agents".to_owned(),
+        ))
+    }
+
+    /// Stop dispatching to an agent
+    async fn remove_target(&mut self, _base_url: &str) -> Result<()> {
+        Err(Error::Executor(
+            "This executor doesn't dispatch to agents".to_owned(),
+        ))
+    }
+
+    /// Waits for work of the executor's own and does it, such as checking
+    /// in on agents. It's run whenever no message is waiting, and dropped
+    /// as soon as one arrives, so it must be cancel safe. Never resolves by
+    /// default.
+    async fn background(&mut self) {
+        futures::future::pending::<()>().await
+    }
+
+    /// Called once before the executor stops
+    async fn stop(&mut self) {}
+}
+
+/// Services `ExecutorMessage`s with the given executor until a `Stop` is
+/// received or the channel closes
+pub async fn serve_executor<E: Executor>(
+    mut executor: E,
+    mut msgs: mpsc::Receiver<ExecutorMessage>,
+) {
+    loop {
+        let msg = tokio::select! {
+            msg = msgs.recv() => msg,
+            () = executor.background() => continue,
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        use ExecutorMessage::*;
+        match msg {
+            ValidateTask { details, response } => {
+                let res = executor.validate(&details).await;
+                response.send(res).unwrap_or(());
+            }
+            ExecuteTask {
+                details,
+                varmap,
+                output_options,
+                response,
+                kill,
+                span,
+                output,
+            } => {
+                let tap = output.clone();
+                let running = executor
+                    .execute(TaskExecution {
+                        details,
+                        varmap,
+                        output_options,
+                        kill,
+                        span,
+                        output,
+                    })
+                    .await;
+                tokio::spawn(async move {
+                    let attempt = running.await;
+                    if let Some(tap) = tap {
+                        tap.close();
+                    }
+                    response.send(attempt).unwrap_or(());
+                });
+            }
+            AddTarget {
+                base_url,
+                token,
+                response,
+            } => {
+                let res = executor.add_target(&base_url, token).await;
+                response.send(res).unwrap_or(());
+            }
+            RemoveTarget { base_url, response } => {
+                let res = executor.remove_target(&base_url).await;
+                response.send(res).unwrap_or(());
+            }
+            GetStatus { response } => {
+                let status = executor.status().await;
+                response.send(status).unwrap_or(());
+            }
+            Stop {} => {
+                executor.stop().await;
+                break;
+            }
+        }
+    }
+}
+
+/// Spawns a task servicing `msgs` with the given executor
+pub fn start_executor<E: Executor + 'static>(
+    executor: E,
+    msgs: mpsc::Receiver<ExecutorMessage>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(serve_executor(executor, msgs))
+}
+
 fn default_bytes() -> usize {
     20480
 }
@@ -35,6 +244,13 @@ fn default_bytes() -> usize {
 pub enum Cmd {
     Simple(String),
     Split(Vec<String>),
+
+    /// Run through `sh -c`, so pipes, redirects, and globs work.
+    /// Interpolated variables are single-quoted, so they shouldn't be
+    /// quoted in the command itself.
+    Shell {
+        shell: String,
+    },
 }
 
 impl Cmd {
@@ -42,12 +258,31 @@ impl Cmd {
         let cmd = match self {
             Cmd::Simple(s) => s.split_whitespace().map(|x| x.to_string()).collect(),
             Cmd::Split(v) => v.clone(),
+            Cmd::Shell { shell } => {
+                return vec![
+                    "/bin/sh".to_owned(),
+                    "-c".to_owned(),
+                    varmap.apply_quoted(shell),
+                ]
+            }
         };
 
         cmd.into_iter().map(|x| varmap.apply_to(&x)).collect()
     }
 }
 
+/// The identity a task runs as. Switching identities requires the
+/// executor to be running as root.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RunAs {
+    pub user: String,
+
+    /// Defaults to the user's primary group
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
 /// Options in how to handle task output. Some tasks can be quite
 /// verbose, and the output may not be needed.
 #[derive(Clone, Serialize, Deserialize, Copy, Debug, PartialEq, Hash, Eq)]
@@ -59,16 +294,15 @@ pub struct TaskOutputOptions {
     pub discard_successful: bool,
 
     /// If true, and output is not discarded, truncate the output of
-    /// each task to a maximum of the first / last `preserve` kb of
-    /// data
+    /// each task to the first `head_bytes` and last `tail_bytes` bytes
     #[serde(default)]
     pub truncate: bool,
 
-    /// Number of KB of output to preserve at the beginning of the ouptut
+    /// Number of bytes of output to preserve at the beginning of the output
     #[serde(default = "default_bytes")]
     pub head_bytes: usize,
 
-    /// Number of KB of output to preserve at the end of the outut
+    /// Number of bytes of output to preserve at the end of the output
     #[serde(default = "default_bytes")]
     pub tail_bytes: usize,
 }
@@ -84,11 +318,51 @@ impl Default for TaskOutputOptions {
     }
 }
 
+/// A file a task produced, collected from its working directory after it
+/// ran so it outlives the host it ran on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Artifact {
+    /// The file's path, relative to the task's working directory
+    pub name: String,
+
+    /// Size in bytes
+    pub size: u64,
+
+    /// The contents, until they're handed off to storage
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub data: Vec<u8>,
+}
+
+/// Why an attempt was run
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttemptKind {
+    /// Part of the interval's action, bringing it up
+    #[default]
+    Up,
+
+    /// A recheck of an interval that was already up
+    Check,
+
+    /// The down command, cleaning up after the interval
+    Down,
+}
+
+impl AttemptKind {
+    pub fn is_up(&self) -> bool {
+        *self == AttemptKind::Up
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TaskAttempt {
     #[serde(default)]
     pub task_name: String,
 
+    /// Only up attempts count as runs of the task
+    #[serde(default, skip_serializing_if = "AttemptKind::is_up")]
+    pub kind: AttemptKind,
+
     #[serde(default = "chrono::Utc::now")]
     pub scheduled_time: DateTime<Utc>,
 
@@ -107,18 +381,59 @@ pub struct TaskAttempt {
     #[serde(default)]
     pub infra_failure: bool,
 
+    /// The task was killed for using more memory than it declared
+    #[serde(default)]
+    pub memory_exceeded: bool,
+
     #[serde(default)]
     pub output: String,
 
     #[serde(default)]
     pub error: String,
 
+    /// Bytes of stdout dropped from `output` by truncation
+    #[serde(default)]
+    pub output_truncated_bytes: usize,
+
+    /// Bytes of stderr dropped from `error` by truncation
+    #[serde(default)]
+    pub error_truncated_bytes: usize,
+
+    /// Where the full stdout was written, if the executor has an output
+    /// sink. `output` then only holds an excerpt.
+    #[serde(default)]
+    pub output_file: Option<String>,
+
+    /// Where the full stderr was written, if the executor has an output sink
+    #[serde(default)]
+    pub error_file: Option<String>,
+
+    /// Files collected from the task's declared artifact paths
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+
     #[serde(default)]
     pub executor: Vec<String>,
 
+    /// The task details submitted to the executor, before expansion
+    #[serde(default)]
+    pub details: Option<TaskDetails>,
+
+    /// The variables the details were expanded with
+    #[serde(default)]
+    pub varmap: VarMap,
+
+    /// The command as executed, after expansion
+    #[serde(default)]
+    pub command: Vec<String>,
+
     #[serde(default)]
     pub exit_code: i32,
 
+    /// JSON the task reported back, such as row counts or checksums
+    #[serde(default)]
+    pub result: Option<serde_json::Value>,
+
     /// as a percentage
     #[serde(default)]
     pub max_cpu: f32,
@@ -140,16 +455,27 @@ impl Default for TaskAttempt {
     fn default() -> Self {
         TaskAttempt {
             task_name: String::new(),
+            kind: AttemptKind::Up,
             scheduled_time: Utc::now(),
             start_time: Utc::now(),
             stop_time: Utc::now(),
             succeeded: false,
             killed: false,
             infra_failure: false,
+            memory_exceeded: false,
             output: "".to_owned(),
             error: "".to_owned(),
+            output_truncated_bytes: 0,
+            error_truncated_bytes: 0,
+            output_file: None,
+            error_file: None,
+            artifacts: Vec::new(),
             executor: Vec::new(),
+            details: None,
+            varmap: VarMap::new(),
+            command: Vec::new(),
             exit_code: 0i32,
+            result: None,
             max_cpu: 0.0,
             avg_cpu: 0.0,
             max_rss: 0,
@@ -163,25 +489,15 @@ impl TaskAttempt {
     pub fn new() -> Self {
         TaskAttempt::default()
     }
-}
 
-/// Keeps the first / last bytes of a str
-#[must_use]
-pub fn head_tail(data: &str, head: usize, tail: usize) -> String {
-    if data.len() < head + tail {
-        data.to_owned()
-    } else {
-        let n_chars = data.chars().count();
-        let charsize = (data.len() as f64 / n_chars as f64).ceil() as usize;
-        let head_chars = head / charsize;
-        let tail_chars = tail / charsize;
-        let mut tail: String = data.chars().rev().take(tail_chars).collect();
-        tail = tail.chars().rev().collect();
-        format!(
-            "{}\n...\n{}",
-            data.chars().take(head_chars).collect::<String>(),
-            tail
-        )
+    /// What went wrong, for reporting: the attempt's stderr, or the
+    /// executor's own errors if it never produced any
+    pub fn failure(&self) -> String {
+        if self.error.trim().is_empty() {
+            self.executor.join("\n")
+        } else {
+            self.error.trim_end().to_owned()
+        }
     }
 }
 
@@ -190,9 +506,162 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_head_tail() {
-        let sample = "This is a very long string".to_owned();
-        assert_eq!(head_tail(&sample, 5, 5), "This \n...\ntring".to_owned());
-        assert_eq!(head_tail(&sample, 50, 50), sample);
+    fn check_shell_cmd() {
+        let cmd: Cmd = serde_json::from_str(r#"{ "shell": "cat ${file} | wc -l > out" }"#).unwrap();
+        let varmap = VarMap::from(HashMap::from([(
+            "file".to_owned(),
+            "it's here; rm -rf /".to_owned(),
+        )]));
+        assert_eq!(
+            cmd.generate(&varmap),
+            vec![
+                "/bin/sh",
+                "-c",
+                r#"cat 'it'\''s here; rm -rf /' | wc -l > out"#
+            ]
+        );
+
+        // Other forms are unaffected
+        let cmd: Cmd = serde_json::from_str(r#""cat ${file}""#).unwrap();
+        assert_eq!(cmd.generate(&varmap), vec!["cat", "it's here; rm -rf /"]);
+    }
+
+    /// Echoes the details back as output, without running anything
+    struct EchoExecutor {
+        executed: usize,
+    }
+
+    #[async_trait]
+    impl Executor for EchoExecutor {
+        async fn validate(&mut self, details: &TaskDetails) -> Result<()> {
+            if details.is_string() {
+                Ok(())
+            } else {
+                Err(Error::Validation("Details must be a string".to_owned()))
+            }
+        }
+
+        async fn execute(&mut self, task: TaskExecution) -> AttemptFuture {
+            self.executed += 1;
+            if let Some(output) = &task.output {
+                output.push(OutputStream::Stdout, b"echoing\n");
+            }
+            let output = task.varmap.apply_to(task.details.as_str().unwrap_or(""));
+            Box::pin(async move {
+                TaskAttempt {
+                    succeeded: true,
+                    output,
+                    ..TaskAttempt::new()
+                }
+            })
+        }
+
+        async fn status(&mut self) -> ExecutorStatus {
+            ExecutorStatus {
+                running: self.executed,
+                ..ExecutorStatus::default()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn check_custom_executor() {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = start_executor(EchoExecutor { executed: 0 }, rx);
+
+        let (response, validation) = oneshot::channel();
+        tx.send(ExecutorMessage::ValidateTask {
+            details: serde_json::json!(42),
+            response,
+        })
+        .await
+        .unwrap();
+        assert!(validation.await.unwrap().is_err());
+
+        let tap = OutputTap::new();
+        let (response, attempt) = oneshot::channel();
+        tx.send(ExecutorMessage::ExecuteTask {
+            details: serde_json::json!("hello ${name}"),
+            varmap: VarMap::from(HashMap::from([("name".to_owned(), "world".to_owned())])),
+            output_options: TaskOutputOptions::default(),
+            response,
+            kill: CancellationToken::new(),
+            span: tracing::Span::current(),
+            output: Some(tap.clone()),
+        })
+        .await
+        .unwrap();
+        let attempt = attempt.await.unwrap();
+        assert!(attempt.succeeded);
+        assert_eq!(attempt.output, "hello world");
+
+        // The tap is closed for the executor
+        let (chunks, follow) = tap.follow();
+        assert_eq!(chunks.len(), 1);
+        assert!(follow.is_none());
+
+        let (response, status) = oneshot::channel();
+        tx.send(ExecutorMessage::GetStatus { response })
+            .await
+            .unwrap();
+        assert_eq!(status.await.unwrap().running, 1);
+
+        // Agent management isn't supported unless implemented
+        let (response, added) = oneshot::channel();
+        tx.send(ExecutorMessage::AddTarget {
+            base_url: "http://worker1:2504/api/v1".to_owned(),
+            token: None,
+            response,
+        })
+        .await
+        .unwrap();
+        assert!(added.await.unwrap().is_err());
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        handle.await.unwrap();
+    }
+    /// Counts how often its background work runs
+    struct TickingExecutor {
+        ticks: usize,
+    }
+
+    #[async_trait]
+    impl Executor for TickingExecutor {
+        async fn validate(&mut self, _details: &TaskDetails) -> Result<()> {
+            Ok(())
+        }
+
+        async fn execute(&mut self, _task: TaskExecution) -> AttemptFuture {
+            Box::pin(futures::future::ready(TaskAttempt::new()))
+        }
+
+        async fn status(&mut self) -> ExecutorStatus {
+            ExecutorStatus {
+                running: self.ticks,
+                ..ExecutorStatus::default()
+            }
+        }
+
+        async fn background(&mut self) {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            self.ticks += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn check_background_work() {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = start_executor(TickingExecutor { ticks: 0 }, rx);
+
+        // Background work carries on between messages
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let (response, status) = oneshot::channel();
+        tx.send(ExecutorMessage::GetStatus { response })
+            .await
+            .unwrap();
+        assert!(status.await.unwrap().running >= 3);
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        handle.await.unwrap();
     }
 }
@@ -0,0 +1,105 @@
+//! Writes task output to files, so attempts only need to carry a
+//! reference and an excerpt rather than the full output.
+
+use super::*;
+use std::path::PathBuf;
+
+fn default_max_bytes() -> u64 {
+    1 << 30
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OutputSink {
+    /// Directory the output files are written to
+    pub directory: PathBuf,
+
+    /// Once the files in the directory exceed this many bytes, the oldest
+    /// are removed
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+}
+
+impl OutputSink {
+    /// Writes the output, returning the path of the new file
+    pub async fn write(&self, name: &str, data: &[u8]) -> Result<String> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let path = self.directory.join(name);
+        tokio::fs::write(&path, data).await?;
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    /// Creates a file to stream output into, returning it and its path
+    pub async fn create(&self, name: &str) -> Result<(tokio::fs::File, String)> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let path = self.directory.join(name);
+        let file = tokio::fs::File::create(&path).await?;
+        Ok((file, path.to_string_lossy().to_string()))
+    }
+
+    /// Removes the oldest files until the directory fits in `max_bytes`.
+    /// Files in `keep` are never removed.
+    pub async fn rotate(&self, keep: &[String]) -> Result<()> {
+        let mut files = Vec::new();
+        let mut total = 0;
+        let mut entries = tokio::fs::read_dir(&self.directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let meta = entry.metadata().await?;
+            if !meta.is_file() {
+                continue;
+            }
+            total += meta.len();
+            files.push((meta.modified()?, entry.path(), meta.len()));
+        }
+        files.sort();
+
+        for (_, path, size) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            if keep.contains(&path.to_string_lossy().to_string()) {
+                continue;
+            }
+            match tokio::fs::remove_file(&path).await {
+                // Another attempt may have rotated it out already
+                Ok(()) => total -= size,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => total -= size,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_rotation() {
+        let directory = std::env::temp_dir().join("waterfall_sink_test");
+        tokio::fs::remove_dir_all(&directory).await.unwrap_or(());
+        let sink = OutputSink {
+            directory: directory.clone(),
+            max_bytes: 10,
+        };
+
+        let mut paths = Vec::new();
+        for i in 0..3 {
+            let path = sink
+                .write(&format!("{}.stdout", i), b"12345")
+                .await
+                .unwrap();
+            sink.rotate(std::slice::from_ref(&path)).await.unwrap();
+            paths.push(path);
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // The oldest file was rotated out to stay under 10 bytes
+        let exists: Vec<bool> = paths
+            .iter()
+            .map(|p| std::path::Path::new(p).exists())
+            .collect();
+        assert_eq!(exists, vec![false, true, true]);
+    }
+}
@@ -0,0 +1,155 @@
+//! The output of a running task, for anyone following along before it
+//! finishes. Executors push output into a tap as it's produced; followers
+//! get the most recent output, then the rest as it arrives.
+
+use super::*;
+use futures::Stream;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// How much of the most recent output a new follower is sent first
+pub const TAP_BACKLOG_BYTES: usize = 65536;
+
+/// How many chunks a slow follower can fall behind before it misses some
+const TAP_CAPACITY: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A piece of output, as it was read. Invalid UTF-8, including characters
+/// split between chunks, is replaced.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OutputChunk {
+    pub stream: OutputStream,
+    pub data: String,
+}
+
+#[derive(Debug)]
+struct TapState {
+    backlog: VecDeque<OutputChunk>,
+    backlog_bytes: usize,
+
+    /// Gone once the task finishes, ending every follow
+    sender: Option<broadcast::Sender<OutputChunk>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputTap(Arc<Mutex<TapState>>);
+
+impl Default for OutputTap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutputTap {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(TAP_CAPACITY);
+        OutputTap(Arc::new(Mutex::new(TapState {
+            backlog: VecDeque::new(),
+            backlog_bytes: 0,
+            sender: Some(sender),
+        })))
+    }
+
+    pub fn push(&self, stream: OutputStream, data: &[u8]) {
+        self.push_chunk(OutputChunk {
+            stream,
+            data: String::from_utf8_lossy(data).into_owned(),
+        });
+    }
+
+    pub fn push_chunk(&self, chunk: OutputChunk) {
+        let mut state = self.0.lock().unwrap();
+        if let Some(sender) = &state.sender {
+            sender.send(chunk.clone()).unwrap_or(0);
+        }
+        state.backlog_bytes += chunk.data.len();
+        state.backlog.push_back(chunk);
+        while state.backlog_bytes > TAP_BACKLOG_BYTES && state.backlog.len() > 1 {
+            let dropped = state.backlog.pop_front().unwrap();
+            state.backlog_bytes -= dropped.data.len();
+        }
+    }
+
+    /// Marks the task finished
+    pub fn close(&self) {
+        self.0.lock().unwrap().sender = None;
+    }
+
+    /// The recent output, and the output to come, unless the task has
+    /// finished
+    pub fn follow(&self) -> (Vec<OutputChunk>, Option<broadcast::Receiver<OutputChunk>>) {
+        let state = self.0.lock().unwrap();
+        (
+            state.backlog.iter().cloned().collect(),
+            state.sender.as_ref().map(|s| s.subscribe()),
+        )
+    }
+
+    /// The output as JSON lines, one per chunk. With `follow`, the stream
+    /// ends when the task finishes, otherwise once the recent output is
+    /// sent.
+    pub fn json_lines(&self, follow: bool) -> impl Stream<Item = String> {
+        use broadcast::error::RecvError;
+
+        let (backlog, receiver) = self.follow();
+        let receiver = receiver.filter(|_| follow);
+        let backlog = futures::stream::iter(backlog);
+        let live = futures::stream::unfold(receiver, |receiver| async move {
+            let mut receiver = receiver?;
+            loop {
+                match receiver.recv().await {
+                    Ok(chunk) => return Some((chunk, Some(receiver))),
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(missed, "Output follower fell behind");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        futures::StreamExt::map(futures::StreamExt::chain(backlog, live), |chunk| {
+            format!("{}\n", serde_json::to_string(&chunk).unwrap())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn check_output_tap() {
+        let tap = OutputTap::new();
+        tap.push(OutputStream::Stdout, b"starting\n");
+
+        // Followers get what they missed, then the rest as it comes
+        let following = tokio::spawn(tap.json_lines(true).collect::<Vec<String>>());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        tap.push(OutputStream::Stderr, b"oops\n");
+        tap.close();
+        let lines = following.await.unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "{\"stream\":\"stdout\",\"data\":\"starting\\n\"}\n",
+                "{\"stream\":\"stderr\",\"data\":\"oops\\n\"}\n",
+            ]
+        );
+
+        // Only recent output is kept
+        let big = vec![b'x'; TAP_BACKLOG_BYTES / 2 + 1];
+        tap.push(OutputStream::Stdout, &big);
+        tap.push(OutputStream::Stdout, &big);
+        let (backlog, receiver) = tap.follow();
+        assert_eq!(backlog.len(), 1);
+        assert!(receiver.is_none());
+        assert_eq!(tap.json_lines(false).count().await, 1);
+    }
+}
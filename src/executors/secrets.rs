@@ -0,0 +1,392 @@
+//! Secrets referenced by tasks as `${secret:NAME}`, or as `secret.NAME` in
+//! templates. They're looked up by
+//! the executor as each task starts, so they never pass through the runner
+//! or storage, and are masked wherever the task's output or command is
+//! kept.
+
+use super::*;
+use std::path::PathBuf;
+
+/// What secret values are replaced with
+pub const REDACTED: &str = "********";
+
+const SECRET_PREFIX: &str = "${secret:";
+
+/// Where an executor looks up secrets
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields, tag = "provider")]
+pub enum SecretsConfig {
+    /// The executor's environment variable `{prefix}NAME`. The prefix
+    /// can't be empty, so tasks can't read the executor's other variables.
+    Env {
+        #[serde(deserialize_with = "deserialize_prefix")]
+        prefix: String,
+    },
+
+    /// The contents of the file `NAME` in `dir`, less a trailing newline,
+    /// as with mounted Docker or Kubernetes secrets
+    File { dir: PathBuf },
+
+    /// A key of the HashiCorp Vault KV v2 secret at `path` under `mount`
+    #[cfg(feature = "vault")]
+    Vault {
+        url: String,
+
+        #[serde(default = "default_vault_mount")]
+        mount: String,
+
+        path: String,
+
+        /// The environment variable holding the Vault token
+        #[serde(default = "default_vault_token_env")]
+        token_env: String,
+    },
+}
+
+/// How long a Vault lookup can take before the task fails
+#[cfg(feature = "vault")]
+const VAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+fn deserialize_prefix<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let prefix = String::deserialize(deserializer)?;
+    if prefix.is_empty() {
+        return Err(serde::de::Error::custom(
+            "prefix can't be empty, or tasks could read any of the executor's variables",
+        ));
+    }
+    Ok(prefix)
+}
+
+#[cfg(feature = "vault")]
+fn default_vault_mount() -> String {
+    "secret".to_owned()
+}
+
+#[cfg(feature = "vault")]
+fn default_vault_token_env() -> String {
+    "VAULT_TOKEN".to_owned()
+}
+
+/// The variable a secret is added to a task's VarMap as
+pub fn secret_var(name: &str) -> String {
+    format!("secret:{}", name)
+}
+
+/// The names of the secrets referenced in `s`, without duplicates
+pub fn secret_references(s: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut rest = s;
+    while let Some(open) = rest.find(SECRET_PREFIX) {
+        rest = &rest[open + SECRET_PREFIX.len()..];
+        let Some(close) = rest.find('}') else {
+            break;
+        };
+        let name = &rest[..close];
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_owned());
+        }
+        rest = &rest[close + 1..];
+    }
+    names
+}
+
+/// The names of the secrets referenced as `secret.NAME` or
+/// `secret["NAME"]` in the `{{ }}` and `{% %}` tags of a template, without
+/// duplicates. Escaped quotes, as in a task's JSON, are allowed.
+pub fn template_secret_references(s: &str) -> Vec<String> {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut names: Vec<String> = Vec::new();
+    let mut rest = s;
+    while let Some(open) = rest.find("{{").into_iter().chain(rest.find("{%")).min() {
+        rest = &rest[open + 2..];
+        let close = ["}}", "%}"]
+            .iter()
+            .filter_map(|end| rest.find(end))
+            .min()
+            .unwrap_or(rest.len());
+        let tag = &rest[..close];
+        for (at, _) in tag.match_indices("secret") {
+            if tag[..at].ends_with(|c: char| is_ident(c) || c == '.') {
+                continue;
+            }
+            let after = &tag[at + "secret".len()..];
+            let name: String = if let Some(field) = after.strip_prefix('.') {
+                field.chars().take_while(|c| is_ident(*c)).collect()
+            } else if let Some(index) = after.strip_prefix('[') {
+                index
+                    .trim_start_matches(['\\', '"', '\''])
+                    .chars()
+                    .take_while(|c| is_ident(*c) || *c == '-')
+                    .collect()
+            } else {
+                continue;
+            };
+            if !name.is_empty() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        rest = &rest[close..];
+    }
+    names
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(Error::Validation(format!(
+            "Bad secret name {:?}: use letters, digits, _, and -",
+            name
+        )));
+    }
+    Ok(())
+}
+
+impl SecretsConfig {
+    /// Looks up the named secrets, returning their values in the same order
+    pub async fn lookup(&self, names: &[String]) -> Result<Vec<String>> {
+        for name in names {
+            check_name(name)?;
+        }
+        match self {
+            SecretsConfig::Env { prefix } => names
+                .iter()
+                .map(|name| {
+                    let var = format!("{}{}", prefix, name);
+                    std::env::var(&var).map_err(|_| {
+                        Error::Executor(format!("Secret {} isn't set, as {}", name, var))
+                    })
+                })
+                .collect(),
+            SecretsConfig::File { dir } => {
+                let mut values = Vec::new();
+                for name in names {
+                    let path = dir.join(name);
+                    let value = tokio::fs::read_to_string(&path).await.map_err(|e| {
+                        Error::Executor(format!(
+                            "Unable to read secret {} from {}: {}",
+                            name,
+                            path.display(),
+                            e
+                        ))
+                    })?;
+                    let value = value.strip_suffix('\n').unwrap_or(&value);
+                    values.push(value.strip_suffix('\r').unwrap_or(value).to_owned());
+                }
+                Ok(values)
+            }
+            #[cfg(feature = "vault")]
+            SecretsConfig::Vault {
+                url,
+                mount,
+                path,
+                token_env,
+            } => {
+                if names.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let token = std::env::var(token_env).map_err(|_| {
+                    Error::Executor(format!("Vault token isn't set, as {}", token_env))
+                })?;
+                let response = reqwest::Client::builder()
+                    .timeout(VAULT_TIMEOUT)
+                    .build()
+                    .map_err(|e| Error::Executor(format!("Unable to reach Vault: {}", e)))?
+                    .get(format!(
+                        "{}/v1/{}/data/{}",
+                        url.trim_end_matches('/'),
+                        mount,
+                        path
+                    ))
+                    .header("X-Vault-Token", token)
+                    .send()
+                    .await
+                    .map_err(|e| Error::Executor(format!("Unable to reach Vault: {}", e)))?;
+                let status = response.status();
+                if !status.is_success() {
+                    return Err(Error::Executor(format!(
+                        "Vault returned {} for {}/{}",
+                        status, mount, path
+                    )));
+                }
+                let body: serde_json::Value = response
+                    .json()
+                    .await
+                    .map_err(|e| Error::Executor(format!("Bad response from Vault: {}", e)))?;
+                names
+                    .iter()
+                    .map(|name| match &body["data"]["data"][name] {
+                        serde_json::Value::String(value) => Ok(value.clone()),
+                        serde_json::Value::Null => Err(Error::Executor(format!(
+                            "Secret {} isn't in {}/{}",
+                            name, mount, path
+                        ))),
+                        value => Ok(value.to_string()),
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Masks secret values, in whole strings or in streams of output
+#[derive(Clone, Debug, Default)]
+pub struct Redactor {
+    /// Longest first, so a secret containing another is masked whole
+    secrets: Vec<Vec<u8>>,
+
+    /// The end of the stream, held back in case it starts a secret
+    pending: Vec<u8>,
+}
+
+impl Redactor {
+    pub fn new(secrets: impl IntoIterator<Item = String>) -> Self {
+        let mut secrets: Vec<Vec<u8>> = secrets
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .map(String::into_bytes)
+            .collect();
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        secrets.dedup();
+        Redactor {
+            secrets,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Masks the secrets starting before `limit`, returning the masked data
+    /// and where it stopped
+    fn mask(&self, data: &[u8], limit: usize) -> (Vec<u8>, usize) {
+        let mut masked = Vec::with_capacity(data.len());
+        let mut i = 0;
+        while i < limit {
+            match self.secrets.iter().find(|s| data[i..].starts_with(s)) {
+                Some(secret) => {
+                    masked.extend_from_slice(REDACTED.as_bytes());
+                    i += secret.len();
+                }
+                None => {
+                    masked.push(data[i]);
+                    i += 1;
+                }
+            }
+        }
+        (masked, i)
+    }
+
+    /// Masks every secret in `s`
+    pub fn redact(&self, s: &str) -> String {
+        if self.secrets.is_empty() {
+            return s.to_owned();
+        }
+        String::from_utf8_lossy(&self.mask(s.as_bytes(), s.len()).0).to_string()
+    }
+
+    /// Adds the next part of a stream, returning what can be passed on.
+    /// Enough of the end to hold all but the last byte of a secret is kept
+    /// until the next push or `finish`.
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        let Some(longest) = self.secrets.first() else {
+            return data.to_vec();
+        };
+        self.pending.extend_from_slice(data);
+        let limit = self.pending.len().saturating_sub(longest.len() - 1);
+        let (masked, end) = self.mask(&self.pending, limit);
+        self.pending.drain(..end);
+        masked
+    }
+
+    /// The rest of the stream
+    pub fn finish(&mut self) -> Vec<u8> {
+        let pending = std::mem::take(&mut self.pending);
+        self.mask(&pending, pending.len()).0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_references() {
+        assert_eq!(
+            secret_references("curl -u ${secret:USER}:${secret:PASS} ${end} ${secret:USER}"),
+            vec!["USER".to_owned(), "PASS".to_owned()]
+        );
+        assert!(secret_references("${secret:UNCLOSED").is_empty());
+
+        // An env provider has to have a prefix
+        for config in [
+            serde_json::json!({ "provider": "env" }),
+            serde_json::json!({ "provider": "env", "prefix": "" }),
+        ] {
+            assert!(serde_json::from_value::<SecretsConfig>(config).is_err());
+        }
+
+        let details = serde_json::json!({
+            "command": "curl -u {{ secret.USER }}:{{ secret[\"PASS-WORD\"] }} {{ secret.USER }}",
+            "note": "secret.IGNORED {% if not_secret.X %}{% endif %}",
+        });
+        assert_eq!(
+            template_secret_references(&details.to_string()),
+            vec!["USER".to_owned(), "PASS-WORD".to_owned()]
+        );
+    }
+
+    #[tokio::test]
+    async fn check_file_lookup() {
+        let dir = std::env::temp_dir().join(format!("waterfall_secrets_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join("db_pass"), "hunter2\n")
+            .await
+            .unwrap();
+
+        let config: SecretsConfig = serde_json::from_value(serde_json::json!({
+            "provider": "file",
+            "dir": dir,
+        }))
+        .unwrap();
+        assert_eq!(
+            config.lookup(&["db_pass".to_owned()]).await.unwrap(),
+            vec!["hunter2".to_owned()]
+        );
+        assert!(config.lookup(&["missing".to_owned()]).await.is_err());
+        assert!(matches!(
+            config.lookup(&["../db_pass".to_owned()]).await,
+            Err(Error::Validation(_))
+        ));
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn check_redaction() {
+        let redactor = Redactor::new(["hunter2".to_owned(), "hunter".to_owned(), String::new()]);
+        assert_eq!(
+            redactor.redact("pass=hunter2 user=hunter"),
+            "pass=******** user=********"
+        );
+
+        // Secrets split between reads are still caught
+        let mut stream = redactor.clone();
+        let mut output = Vec::new();
+        for part in ["the pass is hun", "ter", "2, ok"] {
+            output.extend(stream.push(part.as_bytes()));
+        }
+        output.extend(stream.finish());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "the pass is ********, ok"
+        );
+
+        // Nothing is held back without secrets
+        let mut stream = Redactor::default();
+        assert_eq!(stream.push(b"hunter2"), b"hunter2");
+        assert!(stream.finish().is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+//! Bounded capture of task output. Output is fed in as it's read, and only
+//! the first and last bytes are held, so a chatty task can't exhaust the
+//! executor's memory.
+
+use std::collections::VecDeque;
+
+/// Keeps the first `head` and last `tail` bytes of a stream
+#[derive(Clone, Debug)]
+pub struct Truncator {
+    head: usize,
+    tail: usize,
+    head_buf: Vec<u8>,
+    tail_buf: VecDeque<u8>,
+    total: usize,
+}
+
+impl Truncator {
+    pub fn new(head: usize, tail: usize) -> Self {
+        Truncator {
+            head,
+            tail,
+            head_buf: Vec::new(),
+            tail_buf: VecDeque::new(),
+            total: 0,
+        }
+    }
+
+    /// Keeps everything
+    pub fn unbounded() -> Self {
+        Truncator::new(usize::MAX, 0)
+    }
+
+    pub fn push(&mut self, mut data: &[u8]) {
+        self.total += data.len();
+        if self.head_buf.len() < self.head {
+            let take = data.len().min(self.head - self.head_buf.len());
+            self.head_buf.extend_from_slice(&data[..take]);
+            data = &data[take..];
+        }
+        if self.tail == 0 {
+            return;
+        }
+        if data.len() >= self.tail {
+            self.tail_buf.clear();
+            data = &data[data.len() - self.tail..];
+        } else {
+            let overflow = (self.tail_buf.len() + data.len()).saturating_sub(self.tail);
+            self.tail_buf.drain(..overflow);
+        }
+        self.tail_buf.extend(data);
+    }
+
+    /// Total bytes pushed
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Bytes pushed that weren't kept
+    pub fn dropped(&self) -> usize {
+        self.total - self.head_buf.len() - self.tail_buf.len()
+    }
+
+    /// The kept output, along with how many bytes were dropped. Characters
+    /// split by the cut are dropped whole rather than mangled.
+    pub fn finish(self) -> (String, usize) {
+        let tail: Vec<u8> = self.tail_buf.into();
+        if self.total == self.head_buf.len() + tail.len() {
+            let mut data = self.head_buf;
+            data.extend(tail);
+            return (String::from_utf8_lossy(&data).to_string(), 0);
+        }
+
+        let head = &self.head_buf[..char_end(&self.head_buf)];
+        let tail = &tail[char_start(&tail)..];
+        let dropped = self.total - head.len() - tail.len();
+        (
+            format!(
+                "{}\n... {} bytes truncated ...\n{}",
+                String::from_utf8_lossy(head),
+                dropped,
+                String::from_utf8_lossy(tail)
+            ),
+            dropped,
+        )
+    }
+}
+
+fn is_continuation(byte: u8) -> bool {
+    byte & 0b1100_0000 == 0b1000_0000
+}
+
+/// The length of `data` without a trailing partial character
+fn char_end(data: &[u8]) -> usize {
+    // Find where the last character starts
+    let start = match data.iter().rev().take(4).position(|b| !is_continuation(*b)) {
+        Some(pos) => data.len() - 1 - pos,
+        None => return data.len(),
+    };
+    let width = match data[start] {
+        b if b >= 0b1111_0000 => 4,
+        b if b >= 0b1110_0000 => 3,
+        b if b >= 0b1100_0000 => 2,
+        _ => 1,
+    };
+    if start + width > data.len() {
+        start
+    } else {
+        data.len()
+    }
+}
+
+/// Where the first whole character of `data` starts
+fn char_start(data: &[u8]) -> usize {
+    data.iter()
+        .take(3)
+        .take_while(|b| is_continuation(**b))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn truncate(data: &str, head: usize, tail: usize) -> (String, usize) {
+        let mut truncator = Truncator::new(head, tail);
+        truncator.push(data.as_bytes());
+        truncator.finish()
+    }
+
+    #[test]
+    fn check_truncation() {
+        let sample = "This is a very long string";
+        assert_eq!(
+            truncate(sample, 5, 5),
+            ("This \n... 16 bytes truncated ...\ntring".to_owned(), 16)
+        );
+        assert_eq!(truncate(sample, 50, 50), (sample.to_owned(), 0));
+        assert_eq!(truncate(sample, 20, 6), (sample.to_owned(), 0));
+        assert_eq!(
+            truncate(sample, 0, 3),
+            ("\n... 23 bytes truncated ...\ning".to_owned(), 23)
+        );
+    }
+
+    #[test]
+    fn check_multibyte() {
+        // Each é is two bytes, so both cuts land mid-character
+        let sample = "ééééé";
+        assert_eq!(
+            truncate(sample, 3, 3),
+            ("é\n... 6 bytes truncated ...\né".to_owned(), 6)
+        );
+    }
+
+    #[test]
+    fn check_streaming() {
+        let line = "0123456789abcdef\n";
+        let mut truncator = Truncator::new(1024, 1024);
+        for _ in 0..100_000 {
+            truncator.push(line.as_bytes());
+        }
+        assert_eq!(truncator.total(), line.len() * 100_000);
+        assert_eq!(truncator.dropped(), line.len() * 100_000 - 2048);
+
+        // Small pushes and one large one end up the same
+        let mut whole = Truncator::new(1024, 1024);
+        whole.push(line.repeat(100_000).as_bytes());
+
+        let (output, dropped) = truncator.finish();
+        assert_eq!((output.clone(), dropped), whole.finish());
+        assert!(output.starts_with("0123456789abcdef\n"));
+        assert!(output.ends_with("0123456789abcdef\n"));
+        assert!(output.len() < 2100);
+    }
+}
@@ -0,0 +1,175 @@
+//! Requirements on resources produced by other waterfall deployments.
+//!
+//! The runner periodically pulls the state of every remote its tasks refer
+//! to, and exposes the remote resources to requirements under keys built by
+//! [`remote_key`], so they're evaluated like any local resource.
+
+use super::*;
+use crate::runner::RunnerState;
+
+/// How often the state of each remote is refreshed
+pub const REMOTE_REFRESH_SECONDS: i64 = 30;
+
+/// How long a remote has to answer before it's taken to be unreachable
+#[cfg(feature = "federation")]
+const REMOTE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Shared by every fetch, so connections to remotes are reused
+#[cfg(feature = "federation")]
+static CLIENT: std::sync::LazyLock<reqwest::Client> = std::sync::LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(REMOTE_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+});
+
+/// What a remote requirement does while its remote can't be reached
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteFailurePolicy {
+    /// Not satisfied until the remote is reachable again
+    #[default]
+    Wait,
+
+    /// Evaluated against the last state fetched from the remote
+    UseCached,
+
+    /// Satisfied
+    AssumeSatisfied,
+}
+
+/// The key a remote resource is made available to requirements under
+pub fn remote_key(remote: &str, resource: &str) -> Resource {
+    format!("{}#{}", remote.trim_end_matches('/'), resource)
+}
+
+/// The key marking a remote as unreachable
+pub fn unreachable_key(remote: &str) -> Resource {
+    format!("{}#!unreachable", remote.trim_end_matches('/'))
+}
+
+/// The cached state of a remote
+#[derive(Clone, Debug, Default)]
+pub struct RemoteState {
+    /// The last state successfully fetched
+    pub state: Option<ResourceInterval>,
+
+    /// Whether the most recent fetch succeeded
+    pub reachable: bool,
+}
+
+impl RemoteState {
+    /// Records the result of a fetch. Failed fetches keep the cached state.
+    pub fn update(&mut self, fetched: Option<ResourceInterval>) {
+        self.reachable = fetched.is_some();
+        if fetched.is_some() {
+            self.state = fetched;
+        }
+    }
+}
+
+/// Flattens the cached remote states into resources for requirements to
+/// be evaluated against
+pub fn available(remotes: &HashMap<String, RemoteState>) -> ResourceInterval {
+    let mut res = ResourceInterval::new();
+    for (remote, cache) in remotes {
+        if !cache.reachable {
+            res.insert(&unreachable_key(remote), &IntervalSet::new());
+        }
+        if let Some(state) = &cache.state {
+            for (resource, is) in state.iter() {
+                res.insert(&remote_key(remote, resource), is);
+            }
+        }
+    }
+    res
+}
+
+/// Fetches the current resource state of a remote waterfall deployment
+#[cfg(feature = "federation")]
+pub async fn fetch_state(remote: &str) -> Result<ResourceInterval> {
+    let url = format!("{}/api/v1/state", remote.trim_end_matches('/'));
+    let state: RunnerState = CLIENT
+        .get(&url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| Error::Remote(format!("Unable to query {}: {}", url, e)))?
+        .json()
+        .await
+        .map_err(|e| Error::Remote(format!("Unable to parse state from {}: {}", url, e)))?;
+    Ok(state.current)
+}
+
+#[cfg(not(feature = "federation"))]
+pub async fn fetch_state(remote: &str) -> Result<ResourceInterval> {
+    Err(Error::Remote(format!(
+        "Unable to query {}, built without the federation feature",
+        remote
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_failure_policies() {
+        let schedule = Schedule::new(Calendar::new(), vec![NaiveTime::MIN], Tz::UTC);
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 4, 0, 0, 0).unwrap(),
+        );
+        let requirement = |on_failure: &str| -> Requirement {
+            serde_json::from_str(&format!(
+                r#"{{ "remote": "https://other/", "resource": "prices", "offset": 0, "on_failure": "{}" }}"#,
+                on_failure
+            ))
+            .unwrap()
+        };
+
+        let mut cache = RemoteState::default();
+        cache.update(Some(ResourceInterval::from(HashMap::from([(
+            "prices".to_owned(),
+            IntervalSet::from(interval),
+        )]))));
+        let mut remotes = HashMap::from([("https://other".to_owned(), cache)]);
+
+        let satisfied = |remotes: &HashMap<String, RemoteState>, on_failure: &str| {
+            requirement(on_failure).is_satisfied(interval, &schedule, &available(remotes))
+        };
+        for policy in ["wait", "use_cached", "assume_satisfied"] {
+            assert!(satisfied(&remotes, policy));
+        }
+
+        // The remote goes away
+        remotes.get_mut("https://other").unwrap().update(None);
+        assert!(!satisfied(&remotes, "wait"));
+        assert!(satisfied(&remotes, "use_cached"));
+        assert!(satisfied(&remotes, "assume_satisfied"));
+
+        // Never reached
+        let remotes = HashMap::from([("https://other".to_owned(), RemoteState::default())]);
+        assert!(!satisfied(&remotes, "use_cached"));
+        assert!(satisfied(&remotes, "assume_satisfied"));
+    }
+
+    #[cfg(feature = "federation")]
+    #[tokio::test]
+    async fn check_unresponsive_remote() {
+        // Accepts connections, but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                held.push(socket);
+            }
+        });
+        let res =
+            tokio::time::timeout(REMOTE_TIMEOUT * 2, fetch_state(&format!("http://{}", addr)))
+                .await
+                .expect("The fetch outlasted its timeout");
+        assert!(matches!(res, Err(Error::Remote(_))));
+    }
+}
@@ -0,0 +1,152 @@
+//! Task templates, expanded into one task for every combination of their
+//! parameters' values, e.g. one load per region and symbol.
+
+use super::*;
+use std::collections::BTreeMap;
+
+/// A task repeated over parameters. `${param}` in the template's name, and
+/// in any text of its task, is replaced by each of the parameter's values.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TaskTemplate {
+    /// The values of each parameter. A task is made for every combination.
+    pub parameters: BTreeMap<String, Vec<String>>,
+
+    pub task: TaskDefinition,
+}
+
+fn substitute(value: &serde_json::Value, params: &[(&String, &String)]) -> serde_json::Value {
+    use serde_json::Value;
+    let text = |s: &str| {
+        params.iter().fold(s.to_owned(), |acc, (name, value)| {
+            acc.replace(&format!("${{{}}}", name), value)
+        })
+    };
+    match value {
+        Value::String(s) => Value::String(text(s)),
+        Value::Array(values) => {
+            Value::Array(values.iter().map(|v| substitute(v, params)).collect())
+        }
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (text(k), substitute(v, params)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+impl TaskTemplate {
+    /// The named tasks the template expands to, in parameter order
+    pub fn expand(&self, name: &str) -> Result<Vec<(String, TaskDefinition)>> {
+        if self.parameters.is_empty() {
+            return Err(Error::Validation(format!(
+                "Task template {} has no parameters",
+                name
+            )));
+        }
+        for (param, values) in &self.parameters {
+            if values.is_empty() {
+                return Err(Error::Validation(format!(
+                    "Task template {} has no values for parameter {}",
+                    name, param
+                )));
+            }
+            // Otherwise the tasks wouldn't have distinct names
+            if !name.contains(&format!("${{{}}}", param)) {
+                return Err(Error::Validation(format!(
+                    "Task template {} doesn't use parameter {} in its name",
+                    name, param
+                )));
+            }
+        }
+
+        let mut combinations: Vec<Vec<(&String, &String)>> = vec![Vec::new()];
+        for (param, values) in &self.parameters {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.push((param, value));
+                        combination
+                    })
+                })
+                .collect();
+        }
+
+        let task = serde_json::to_value(&self.task)?;
+        combinations
+            .into_iter()
+            .map(|params| {
+                let task_name = substitute(&serde_json::Value::from(name), &params);
+                let task_name = task_name.as_str().unwrap_or(name).to_owned();
+                let def = serde_json::from_value(substitute(&task, &params)).map_err(|e| {
+                    Error::Validation(format!("Task {} from template {}: {}", task_name, name, e))
+                })?;
+                Ok((task_name, def))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_expand() {
+        let template: TaskTemplate = serde_json::from_value(serde_json::json!({
+            "parameters": {
+                "region": [ "us", "eu" ],
+                "symbol": [ "AAPL", "MSFT" ]
+            },
+            "task": {
+                "up": { "command": "load.sh ${region} ${symbol} ${yyyymmdd}" },
+                "provides": [ "prices_${region}_${symbol}" ],
+                "requires": [ { "resource": "calendar_${region}", "offset": 0 } ],
+                "calendar_name": "std",
+                "times": [ "17:00:00" ],
+                "timezone": "America/New_York",
+                "valid_from": "2022-01-03T00:00:00"
+            }
+        }))
+        .unwrap();
+
+        let tasks = template.expand("load_${region}_${symbol}").unwrap();
+        let names: Vec<&str> = tasks.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "load_us_AAPL",
+                "load_us_MSFT",
+                "load_eu_AAPL",
+                "load_eu_MSFT"
+            ]
+        );
+
+        let (_, def) = &tasks[2];
+        assert_eq!(
+            def.up,
+            serde_json::json!({ "command": "load.sh eu AAPL ${yyyymmdd}" })
+        );
+        assert_eq!(def.provides, HashSet::from(["prices_eu_AAPL".to_owned()]));
+        assert_eq!(
+            def.requires[0].resources(),
+            HashSet::from(["calendar_eu".to_owned()])
+        );
+
+        // Every parameter has to be in the name
+        assert!(matches!(
+            template.expand("load_${region}"),
+            Err(Error::Validation(_))
+        ));
+
+        let mut empty = template.clone();
+        empty.parameters.insert("venue".to_owned(), Vec::new());
+        assert!(matches!(
+            empty.expand("load_${region}_${symbol}_${venue}"),
+            Err(Error::Validation(_))
+        ));
+    }
+}
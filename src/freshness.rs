@@ -0,0 +1,111 @@
+//! Requirements that a resource interval was produced recently, not merely
+//! that it exists, so stale upstream data blocks dependents.
+//!
+//! The runner records when each interval of each resource was produced,
+//! seeded from stored attempts when it starts, and exposes the intervals
+//! still fresh enough for each requirement under keys built by
+//! [`fresh_key`].
+
+use super::*;
+
+/// How many of a task's most recent attempts are read back from storage to
+/// learn when its intervals were produced
+pub const FRESHNESS_ATTEMPTS: usize = 100;
+
+/// The key the intervals of `resource` produced within the last
+/// `max_age_minutes` are made available to requirements under
+pub fn fresh_key(resource: &str, max_age_minutes: u64) -> Resource {
+    format!("{}#!fresh_{}m", resource, max_age_minutes)
+}
+
+/// When each interval of each resource was last produced
+#[derive(Clone, Debug, Default)]
+pub struct Production(HashMap<Resource, HashMap<Interval, DateTime<Utc>>>);
+
+impl Production {
+    pub fn new() -> Self {
+        Production::default()
+    }
+
+    /// Records `interval` of `resource` as produced `at`, unless it's
+    /// known to have been produced since
+    pub fn record(&mut self, resource: &Resource, interval: Interval, at: DateTime<Utc>) {
+        let produced = self
+            .0
+            .entry(resource.clone())
+            .or_default()
+            .entry(interval)
+            .or_insert(at);
+        *produced = (*produced).max(at);
+    }
+
+    /// The intervals of `resource` produced at or after `since`
+    pub fn since(&self, resource: &Resource, since: DateTime<Utc>) -> IntervalSet {
+        let mut is = IntervalSet::new();
+        for (interval, at) in self.0.get(resource).into_iter().flatten() {
+            if *at >= since {
+                is.insert(*interval);
+            }
+        }
+        is
+    }
+
+    /// The fresh intervals for each of `requirements`, a resource and its
+    /// maximum age in minutes, keyed by [`fresh_key`]
+    pub fn available(
+        &self,
+        requirements: &HashSet<(Resource, u64)>,
+        now: DateTime<Utc>,
+    ) -> ResourceInterval {
+        let mut res = ResourceInterval::new();
+        for (resource, max_age_minutes) in requirements {
+            let since = now - Duration::minutes(*max_age_minutes as i64);
+            res.insert(
+                &fresh_key(resource, *max_age_minutes),
+                &self.since(resource, since),
+            );
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_freshness() {
+        let schedule = Schedule::new(Calendar::new(), vec![NaiveTime::MIN], Tz::UTC);
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 4, 0, 0, 0).unwrap(),
+        );
+        let req: Requirement =
+            serde_json::from_str(r#"{ "resource": "prices", "offset": 0, "max_age_minutes": 60 }"#)
+                .unwrap();
+        assert_eq!(req.resources(), HashSet::from(["prices".to_owned()]));
+        let requirements = req.freshness();
+        assert_eq!(requirements, HashSet::from([("prices".to_owned(), 60)]));
+
+        // Present isn't enough, it has to have been produced recently
+        let now = Utc.with_ymd_and_hms(2022, 1, 4, 12, 0, 0).unwrap();
+        let mut produced = Production::new();
+        produced.record(&"prices".to_owned(), interval, now - Duration::hours(3));
+        let current = ResourceInterval::from(HashMap::from([(
+            "prices".to_owned(),
+            IntervalSet::from(interval),
+        )]));
+        let available = |now| current.union(&produced.available(&requirements, now));
+        assert!(!req.is_satisfied(interval, &schedule, &available(now)));
+
+        // Older records don't replace newer ones
+        produced.record(&"prices".to_owned(), interval, now - Duration::minutes(10));
+        produced.record(&"prices".to_owned(), interval, now - Duration::hours(5));
+        let available = |now| current.union(&produced.available(&requirements, now));
+        assert!(req.is_satisfied(interval, &schedule, &available(now)));
+        assert!(!req.is_satisfied(interval, &schedule, &available(now + Duration::hours(1))));
+
+        // Fresh isn't enough either, once it's been taken down
+        assert!(!req.is_satisfied(interval, &schedule, &produced.available(&requirements, now)));
+    }
+}
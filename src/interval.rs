@@ -10,7 +10,7 @@ use std::ops::{Add, BitAnd, BitOr, Sub};
     in charge of
 */
 
-#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Ord, PartialOrd)]
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Ord, PartialOrd)]
 pub struct Interval {
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
@@ -28,7 +28,7 @@ impl Interval {
     }
 
     pub fn is_empty(&self) -> bool {
-        return self.start == self.end;
+        self.start == self.end
     }
 
     pub fn len(&self) -> Duration {
@@ -36,23 +36,23 @@ impl Interval {
     }
 
     pub fn contains<T: TimeZone>(&self, dt: DateTime<T>) -> bool {
-        return self.start < dt && dt <= self.end;
+        self.start < dt && dt <= self.end
     }
 
     /// True if `other` is a subset of this interval
     pub fn has_subset(&self, other: Interval) -> bool {
-        return self.start <= other.start && other.end <= self.end;
+        self.start <= other.start && other.end <= self.end
     }
 
     /// True if `other` overlaps or is immediately adjascent to self
     pub fn is_contiguous(&self, other: Interval) -> bool {
-        return (self.start <= other.start && other.start <= self.end)
-            || (other.start <= self.start && self.start <= other.end);
+        (self.start <= other.start && other.start <= self.end)
+            || (other.start <= self.start && self.start <= other.end)
     }
 
     /// True if self intersection other is an empty set
     pub fn is_disjoint(&self, other: Interval) -> bool {
-        return self.end <= other.start || other.end <= self.start;
+        self.end <= other.start || other.end <= self.start
     }
 
     pub fn intersection(&self, other: Interval) -> Interval {
@@ -86,15 +86,15 @@ mod tests {
 
     macro_rules! dt {
         ( $x:literal ) => {
-            Utc.with_ymd_and_hms(2022, 1, 1,$x, 0, 0).unwrap()
+            Utc.with_ymd_and_hms(2022, 1, 1, $x, 0, 0).unwrap()
         };
     }
 
     macro_rules! intv {
         ( $x:literal, $y:literal ) => {
             Interval::new(
-                Utc.with_ymd_and_hms(2022, 1, 1,$x, 0, 0).unwrap(),
-                Utc.with_ymd_and_hms(2022, 1, 1,$y, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, 1, $x, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, 1, $y, 0, 0).unwrap(),
             )
         };
     }
@@ -1,8 +1,12 @@
 use super::*;
 use std::ops::{Add, BitAnd, BitOr, Deref, DerefMut, Not, Sub};
 
-/// A coalescing set of intervals
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd)]
+/// A coalescing set of intervals, kept sorted with no two intervals
+/// overlapping or adjacent, so lookups can binary search and set operations
+/// can merge in a single pass. Anything changed through `DerefMut` must
+/// keep it that way, or `coalesce` afterwards.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq, PartialOrd)]
+#[serde(from = "Vec<Interval>")]
 pub struct IntervalSet(Vec<Interval>);
 
 impl IntervalSet {
@@ -10,50 +14,70 @@ impl IntervalSet {
         IntervalSet(Vec::new())
     }
 
-    pub fn start(&self) -> Option<DateTime<Utc>> {
-        if let Some(interval) = self.first() {
-            Some(interval.start)
-        } else {
-            None
+    /// Builds a set from intervals sorted by start, joining neighbours that
+    /// overlap or touch
+    fn from_sorted(intervals: impl IntoIterator<Item = Interval>) -> Self {
+        let mut acc: Vec<Interval> = Vec::new();
+        for intv in intervals.into_iter().filter(|x| !x.is_empty()) {
+            match acc.last_mut() {
+                Some(lst) if lst.is_contiguous(intv) => lst.end = lst.end.max(intv.end),
+                _ => acc.push(intv),
+            }
         }
+        IntervalSet(acc)
+    }
+
+    pub fn start(&self) -> Option<DateTime<Utc>> {
+        self.first().map(|interval| interval.start)
     }
 
     pub fn end(&self) -> Option<DateTime<Utc>> {
-        if let Some(intv) = self.last() {
-            Some(intv.end)
-        } else {
-            None
-        }
+        self.last().map(|intv| intv.end)
     }
 
     /// Returns true if interval is a subset
     pub fn has_subset(&self, interval: Interval) -> bool {
-        self.0.iter().any(|x| x.has_subset(interval))
+        // Only the last interval starting at or before it can hold it
+        let i = self.0.partition_point(|x| x.start <= interval.start);
+        i > 0 && self.0[i - 1].has_subset(interval)
     }
 
     pub fn contains<T: TimeZone>(&self, dt: DateTime<T>) -> bool {
-        self.0.iter().any(|x| x.contains(dt.with_timezone(&Utc)))
+        let dt = dt.with_timezone(&Utc);
+        let i = self.0.partition_point(|x| x.end < dt);
+        i < self.0.len() && self.0[i].contains(dt)
     }
 
-    // Naive O(n^2) implementation
     pub fn is_disjoint(&self, other: &IntervalSet) -> bool {
-        self.0
-            .iter()
-            .all(|x| other.iter().all(|y| x.is_disjoint(*y)))
+        let (mut i, mut j) = (0, 0);
+        while i < self.0.len() && j < other.0.len() {
+            let (x, y) = (self.0[i], other.0[j]);
+            if !x.is_disjoint(y) {
+                return false;
+            }
+            if x.end <= y.end {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        true
     }
 
     pub fn intersection(&self, other: &IntervalSet) -> Self {
-        let mut res = IntervalSet(self.0.iter().fold(Vec::<Interval>::new(), |mut acc, x| {
-            let new_intervals: Vec<Interval> = other
-                .iter()
-                .map(|y| x.intersection(*y))
-                .filter(|x| !x.is_empty())
-                .collect();
-            acc.extend(new_intervals);
-            acc
-        }));
-        res.coalesce();
-        res
+        let mut acc = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < self.0.len() && j < other.0.len() {
+            let (x, y) = (self.0[i], other.0[j]);
+            acc.push(x.intersection(y));
+            // Whichever ends first can't overlap anything further along
+            if x.end <= y.end {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        IntervalSet::from_sorted(acc)
     }
 
     pub fn complement(&self) -> Self {
@@ -82,43 +106,45 @@ impl IntervalSet {
     }
 
     pub fn insert(&mut self, interval: Interval) {
-        let should_coalesce = self.0.iter().any(|intv| intv.is_contiguous(interval));
-        self.0.push(interval);
-        if should_coalesce {
-            self.coalesce();
+        if interval.is_empty() {
+            return;
+        }
+        // The intervals it overlaps or touches are all together
+        let lo = self.0.partition_point(|x| x.end < interval.start);
+        let hi = self.0.partition_point(|x| x.start <= interval.end);
+        if lo == hi {
+            self.0.insert(lo, interval);
+        } else {
+            let merged = Interval {
+                start: interval.start.min(self.0[lo].start),
+                end: interval.end.max(self.0[hi - 1].end),
+            };
+            self.0.splice(lo..hi, [merged]);
         }
     }
 
     pub fn merge(&mut self, other: &IntervalSet) {
-        self.0.extend(other.0.iter().cloned());
-        self.coalesce();
+        *self = self.union(other);
     }
 
     pub fn coalesce(&mut self) {
         self.0.sort_unstable();
-        self.0 = self
-            .0
-            .iter()
-            .filter(|x| !x.is_empty())
-            .fold(Vec::new(), |mut acc, int| {
-                if let Some(lst) = acc.last_mut() {
-                    if !lst.is_contiguous(*int) {
-                        acc.push(*int)
-                    } else {
-                        lst.end = int.end
-                    }
-                } else {
-                    acc.push(*int);
-                }
-
-                acc
-            });
+        *self = IntervalSet::from_sorted(std::mem::take(&mut self.0));
     }
 
     pub fn union(&self, other: &IntervalSet) -> Self {
-        let mut is = IntervalSet(self.0.iter().chain(other.0.iter()).copied().collect());
-        is.coalesce();
-        is
+        let mut acc = Vec::with_capacity(self.0.len() + other.0.len());
+        let (mut i, mut j) = (0, 0);
+        while i < self.0.len() || j < other.0.len() {
+            if j == other.0.len() || (i < self.0.len() && self.0[i] <= other.0[j]) {
+                acc.push(self.0[i]);
+                i += 1;
+            } else {
+                acc.push(other.0[j]);
+                j += 1;
+            }
+        }
+        IntervalSet::from_sorted(acc)
     }
 
     /// Subtract all intervals in `other` from self
@@ -231,6 +257,63 @@ mod tests {
         // TODO need more tests here
     }
 
+    /// A random set of whole hours, as likely to touch as to overlap
+    fn random_set(rng: &mut impl rand::Rng) -> IntervalSet {
+        let hour =
+            |h: u32| Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap() + Duration::hours(h as i64);
+        let intervals: Vec<Interval> = (0..rng.gen_range(0..8))
+            .map(|_| {
+                let start = rng.gen_range(0..40);
+                Interval::new(hour(start), hour(start + rng.gen_range(0..6)))
+            })
+            .collect();
+        IntervalSet::from(intervals)
+    }
+
+    #[test]
+    fn test_intervalset_matches_naive() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(4600);
+        for _ in 0..500 {
+            let (a, b) = (random_set(&mut rng), random_set(&mut rng));
+
+            let disjoint = a.iter().all(|x| b.iter().all(|y| x.is_disjoint(*y)));
+            assert_eq!(a.is_disjoint(&b), disjoint, "{:?} {:?}", a, b);
+
+            let pieces: Vec<Interval> = a
+                .iter()
+                .flat_map(|x| b.iter().map(move |y| x.intersection(*y)))
+                .collect();
+            assert_eq!(a.intersection(&b), IntervalSet::from(pieces));
+
+            let all: Vec<Interval> = a.iter().chain(b.iter()).copied().collect();
+            assert_eq!(a.union(&b), IntervalSet::from(all));
+            let mut inserted = a.clone();
+            for intv in b.iter() {
+                inserted.insert(*intv);
+            }
+            assert_eq!(inserted, a.union(&b));
+
+            for intv in b.iter() {
+                assert_eq!(a.has_subset(*intv), a.iter().any(|x| x.has_subset(*intv)));
+                for dt in [intv.start, intv.end] {
+                    assert_eq!(a.contains(dt), a.iter().any(|x| x.contains(dt)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_intervalset_deserialize_coalesces() {
+        let is: IntervalSet = serde_json::from_value(serde_json::json!([
+            { "start": "2022-01-01T05:00:00Z", "end": "2022-01-01T06:00:00Z" },
+            { "start": "2022-01-01T01:00:00Z", "end": "2022-01-01T03:00:00Z" },
+            { "start": "2022-01-01T03:00:00Z", "end": "2022-01-01T04:00:00Z" }
+        ]))
+        .unwrap();
+        assert_eq!(is, IntervalSet(vec![interval!(1, 4), interval!(5, 6)]));
+    }
+
     #[test]
     fn test_intervalset_complement() {
         // Complement's complement is the same
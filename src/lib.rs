@@ -2,22 +2,37 @@
 #![allow(dead_code)]
 // #![feature(slice_group_by)]
 
-use anyhow::{anyhow, Result};
 use chrono::prelude::*;
 use chrono::{Duration, TimeZone};
 use chrono_tz::Tz;
-use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
 
+pub use crate::error::{Error, Result};
+
+use crate::backfill::*;
 use crate::calendar::*;
+use crate::circuit_breaker::*;
+use crate::escalation::*;
+use crate::event_stream::*;
 use crate::executors::*;
+use crate::federation::*;
+use crate::foreach::*;
+use crate::freshness::*;
 use crate::interval::*;
 use crate::interval_set::*;
+use crate::replay::*;
 use crate::requirement::*;
 use crate::resource_interval::*;
+use crate::retry::*;
 use crate::schedule::*;
+use crate::shard::*;
+use crate::simulate::*;
+use crate::sql::*;
 use crate::storage::*;
 use crate::task::*;
 use crate::task_set::*;
@@ -27,20 +42,43 @@ use crate::world::*;
 const MAX_TIME: DateTime<Utc> = chrono::DateTime::<Utc>::MAX_UTC;
 const MIN_TIME: DateTime<Utc> = chrono::DateTime::<Utc>::MIN_UTC;
 
+/// How many messages the runner, storage, executor, and notification
+/// channels hold before their senders wait. See "Backpressure" in the README.
+pub const CHANNEL_CAPACITY: usize = 1024;
+
 pub type Resource = String;
 pub type TaskDetails = serde_json::Value;
 
+pub mod backfill;
 pub mod calendar;
+pub mod circuit_breaker;
+pub mod config_file;
+pub mod error;
+pub mod escalation;
+pub mod event_stream;
 pub mod executors;
+pub mod federation;
+pub mod foreach;
+pub mod freshness;
 pub mod interval;
 pub mod interval_set;
+pub mod notifications;
 pub mod prelude;
+pub mod replay;
 pub mod requirement;
 pub mod resource_interval;
+pub mod retry;
 pub mod runner;
 pub mod schedule;
+pub mod shard;
+pub mod simulate;
+pub mod sql;
 pub mod storage;
 pub mod task;
 pub mod task_set;
+pub mod telemetry;
+pub mod upstream;
 pub mod varmap;
+#[cfg(feature = "watch")]
+pub mod watch;
 pub mod world;
@@ -0,0 +1,401 @@
+//! Emails notifications over SMTP, either one email per event, or as a
+//! digest batching events up every few minutes
+
+use super::*;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// TLS from the start, usually on port 465
+    Tls,
+    /// Upgraded to TLS after connecting, usually on port 587
+    #[default]
+    StartTls,
+    /// Plain text, for a relay on localhost
+    None,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    /// Hostname of the SMTP server
+    pub server: String,
+
+    /// Defaults to the usual port for `security`
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    #[serde(default)]
+    pub security: SmtpSecurity,
+
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub password: Option<String>,
+
+    pub from: String,
+
+    /// Sent every kind of event
+    #[serde(default)]
+    pub to: Vec<String>,
+
+    /// Sent these kinds of events as well, by kind, e.g. `gave_up`
+    #[serde(default)]
+    pub recipients: HashMap<String, Vec<String>>,
+
+    /// Batches events up, sending each recipient one email with everything
+    /// from the last this many minutes
+    #[serde(default)]
+    pub digest_minutes: Option<u64>,
+}
+
+impl EmailConfig {
+    /// Everyone who should hear about an event
+    fn recipients(&self, notification: &Notification) -> Vec<&str> {
+        let mut recipients: Vec<&str> = self
+            .to
+            .iter()
+            .chain(
+                self.recipients
+                    .get(notification.kind())
+                    .into_iter()
+                    .flatten(),
+            )
+            .map(|r| r.as_str())
+            .collect();
+        recipients.sort_unstable();
+        recipients.dedup();
+        recipients
+    }
+}
+
+/// The summary, followed by the failed attempt's stderr if there is one
+fn describe(notification: &Notification) -> String {
+    match notification {
+        Notification::ActionErrored {
+            error: Some(error), ..
+        }
+        | Notification::GaveUp {
+            error: Some(error), ..
+        }
+        | Notification::RecheckFailed {
+            error: Some(error), ..
+        } if !error.is_empty() => format!("{}\n\n{}", notification.summary(), error),
+        _ => notification.summary(),
+    }
+}
+
+pub struct EmailSink<T = AsyncSmtpTransport<Tokio1Executor>> {
+    config: EmailConfig,
+    transport: T,
+    /// Events waiting for the next digest, by recipient
+    pending: HashMap<String, Vec<Notification>>,
+    /// When the pending events are due to be sent
+    digest_due: Option<DateTime<Utc>>,
+}
+
+impl EmailSink {
+    pub fn new(config: EmailConfig) -> Result<Self> {
+        let mut builder = match config.security {
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.server),
+            SmtpSecurity::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.server)
+            }
+            SmtpSecurity::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                &config.server,
+            )),
+        }
+        .map_err(|e| Error::Validation(format!("Bad SMTP server {}: {}", config.server, e)))?;
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        let transport = builder.build();
+        EmailSink::with_transport(config, transport)
+    }
+}
+
+impl<T> EmailSink<T>
+where
+    T: AsyncTransport + Send + Sync,
+    T::Error: std::fmt::Display,
+{
+    /// Sends through an arbitrary transport
+    pub fn with_transport(config: EmailConfig, transport: T) -> Result<Self> {
+        for address in config
+            .to
+            .iter()
+            .chain(config.recipients.values().flatten())
+            .chain([&config.from])
+        {
+            mailbox(address)?;
+        }
+        Ok(EmailSink {
+            config,
+            transport,
+            pending: HashMap::new(),
+            digest_due: None,
+        })
+    }
+
+    async fn send(&self, to: &[&str], subject: &str, body: String) -> Result<()> {
+        let mut message = Message::builder()
+            .from(mailbox(&self.config.from)?)
+            .subject(subject);
+        for address in to {
+            message = message.to(mailbox(address)?);
+        }
+        let message = message
+            .body(body)
+            .map_err(|e| Error::Validation(e.to_string()))?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| Error::Remote(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Sends each recipient one email of the pending events they get.
+    /// Recipients that can't be sent to keep their events for the next
+    /// digest.
+    async fn send_digest(&mut self) -> Result<()> {
+        self.digest_due = None;
+        let mut errors = Vec::new();
+        for (recipient, notifications) in std::mem::take(&mut self.pending) {
+            let subject = format!("[waterfall] {} events", notifications.len());
+            let body: Vec<String> = notifications.iter().map(describe).collect();
+            if let Err(e) = self
+                .send(&[&recipient], &subject, body.join("\n\n---\n\n"))
+                .await
+            {
+                errors.push(format!("{}: {}", recipient, e));
+                self.pending.insert(recipient, notifications);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            if let Some(minutes) = self.config.digest_minutes {
+                self.digest_due = Some(Utc::now() + Duration::minutes(minutes as i64));
+            }
+            Err(Error::Remote(errors.join(", ")))
+        }
+    }
+}
+
+fn mailbox(address: &str) -> Result<Mailbox> {
+    address
+        .parse()
+        .map_err(|e| Error::Validation(format!("Bad email address {}: {}", address, e)))
+}
+
+#[async_trait]
+impl<T> Sink for EmailSink<T>
+where
+    T: AsyncTransport + Send + Sync,
+    T::Error: std::fmt::Display,
+{
+    async fn notify(&mut self, notification: &Notification) -> Result<()> {
+        let recipients = self.config.recipients(notification);
+        if recipients.is_empty() {
+            return Ok(());
+        }
+        match self.config.digest_minutes {
+            Some(minutes) => {
+                if self.digest_due.is_none() {
+                    self.digest_due = Some(Utc::now() + Duration::minutes(minutes as i64));
+                }
+                for recipient in recipients {
+                    self.pending
+                        .entry(recipient.to_owned())
+                        .or_default()
+                        .push(notification.clone());
+                }
+                Ok(())
+            }
+            None => {
+                let subject = format!("[waterfall] {}", notification.summary());
+                self.send(&recipients, &subject, describe(notification))
+                    .await
+            }
+        }
+    }
+
+    async fn tick(&mut self, now: DateTime<Utc>) -> Result<()> {
+        match self.digest_due {
+            Some(due) if due <= now => self.send_digest().await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            Ok(())
+        } else {
+            self.send_digest().await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::transport::stub::AsyncStubTransport;
+
+    fn config(digest_minutes: Option<u64>) -> EmailConfig {
+        serde_json::from_value(serde_json::json!({
+            "server": "localhost",
+            "from": "waterfall@example.com",
+            "to": [ "ops@example.com" ],
+            "recipients": { "gave_up": [ "oncall@example.com" ] },
+            "digest_minutes": digest_minutes
+        }))
+        .unwrap()
+    }
+
+    fn gave_up(task_name: &str) -> Notification {
+        Notification::GaveUp {
+            task_name: task_name.to_owned(),
+            interval: Interval::new(
+                Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+            ),
+            failures: 3,
+            error: Some("vendor file missing".to_owned()),
+        }
+    }
+
+    fn sla_breached(task_name: &str) -> Notification {
+        Notification::SlaBreached {
+            task_name: task_name.to_owned(),
+            interval: Interval::new(
+                Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+            ),
+            deadline: Utc.with_ymd_and_hms(2022, 1, 2, 1, 0, 0).unwrap(),
+            state: ActionState::Queued,
+        }
+    }
+
+    /// Recipients of each email sent
+    async fn sent(sink: &EmailSink<AsyncStubTransport>) -> Vec<Vec<String>> {
+        sink.transport
+            .messages()
+            .await
+            .iter()
+            .map(|(envelope, _)| envelope.to().iter().map(|a| a.to_string()).collect())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn check_email() {
+        let mut sink =
+            EmailSink::with_transport(config(None), AsyncStubTransport::new_ok()).unwrap();
+        sink.notify(&gave_up("task_a")).await.unwrap();
+        sink.notify(&sla_breached("task_a")).await.unwrap();
+        assert_eq!(
+            sent(&sink).await,
+            vec![
+                vec!["oncall@example.com", "ops@example.com"],
+                vec!["ops@example.com"]
+            ]
+        );
+        let (_, body) = &sink.transport.messages().await[0];
+        assert!(body.contains("vendor file missing"));
+
+        // Bad addresses are caught up front
+        let mut bad = config(None);
+        bad.to.push("not an address".to_owned());
+        assert!(EmailSink::with_transport(bad, AsyncStubTransport::new_ok()).is_err());
+    }
+
+    #[tokio::test]
+    async fn check_digest() {
+        let mut sink =
+            EmailSink::with_transport(config(Some(15)), AsyncStubTransport::new_ok()).unwrap();
+        sink.notify(&gave_up("task_a")).await.unwrap();
+        sink.notify(&sla_breached("task_b")).await.unwrap();
+        sink.notify(&gave_up("task_c")).await.unwrap();
+
+        // Nothing goes out until the digest is due
+        sink.tick(Utc::now()).await.unwrap();
+        assert!(sent(&sink).await.is_empty());
+
+        sink.tick(Utc::now() + Duration::minutes(15)).await.unwrap();
+        let mut recipients = sent(&sink).await;
+        recipients.sort();
+        assert_eq!(
+            recipients,
+            vec![vec!["oncall@example.com"], vec!["ops@example.com"]]
+        );
+        let messages = sink.transport.messages().await;
+        let ops = messages
+            .iter()
+            .find(|(envelope, _)| envelope.to()[0].to_string() == "ops@example.com")
+            .unwrap();
+        assert!(ops.1.contains("3 events"));
+
+        // Whatever is left goes out as the sink closes
+        sink.notify(&sla_breached("task_d")).await.unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(sent(&sink).await.len(), 3);
+    }
+
+    /// Refuses mail for one address, and otherwise passes it on to a stub
+    struct Refusing {
+        stub: AsyncStubTransport,
+        refused: std::sync::Mutex<Option<String>>,
+    }
+
+    #[async_trait]
+    impl AsyncTransport for Refusing {
+        type Ok = ();
+        type Error = String;
+
+        async fn send_raw(
+            &self,
+            envelope: &lettre::address::Envelope,
+            email: &[u8],
+        ) -> std::result::Result<(), String> {
+            let refused = self.refused.lock().unwrap().clone();
+            if envelope.to().iter().any(|a| Some(a.to_string()) == refused) {
+                return Err("mailbox unavailable".to_owned());
+            }
+            self.stub
+                .send_raw(envelope, email)
+                .await
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn check_failed_digest() {
+        let transport = Refusing {
+            stub: AsyncStubTransport::new_ok(),
+            refused: std::sync::Mutex::new(Some("oncall@example.com".to_owned())),
+        };
+        let mut sink = EmailSink::with_transport(config(Some(15)), transport).unwrap();
+        sink.notify(&gave_up("task_a")).await.unwrap();
+        sink.notify(&gave_up("task_b")).await.unwrap();
+
+        // Everyone else still gets the digest, and the refused recipient's
+        // events wait for the next one
+        assert!(sink.tick(Utc::now() + Duration::minutes(15)).await.is_err());
+        assert_eq!(sink.transport.stub.messages().await.len(), 1);
+        assert!(sink.digest_due.is_some());
+
+        *sink.transport.refused.lock().unwrap() = None;
+        sink.flush().await.unwrap();
+        let messages = sink.transport.stub.messages().await;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].0.to()[0].to_string(), "oncall@example.com");
+        assert!(messages[1].1.contains("2 events"));
+        assert!(sink.pending.is_empty());
+    }
+}
@@ -0,0 +1,382 @@
+//! Lifecycle events the runner posts as they happen, so failures reach
+//! people without anyone polling `/state`. The runner only sends to a
+//! channel; [`serve`] fans each event out to the configured sinks.
+
+use super::*;
+use crate::runner::ActionState;
+use async_trait::async_trait;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Notification {
+    /// An action failed, and will be retried unless something says
+    /// otherwise
+    ActionErrored {
+        task_name: String,
+        interval: Interval,
+        /// Consecutive failures of the interval so far
+        failures: usize,
+        /// Stderr of the failed attempt
+        #[serde(default)]
+        error: Option<String>,
+    },
+    /// An action ran out of retries, and is left failed until someone
+    /// retries it
+    GaveUp {
+        task_name: String,
+        interval: Interval,
+        failures: usize,
+        /// Stderr of the last attempt
+        #[serde(default)]
+        error: Option<String>,
+    },
+    /// An interval's resources were still down `alert_delay_seconds`
+    /// after its scheduled end
+    SlaBreached {
+        task_name: String,
+        interval: Interval,
+        deadline: DateTime<Utc>,
+        state: ActionState,
+    },
+    /// Resources were forced down over an interval
+    ForcedDown {
+        resources: HashSet<Resource>,
+        interval: Interval,
+    },
+    /// A completed interval failed its recheck, so its resources were
+    /// marked down to be produced again
+    RecheckFailed {
+        task_name: String,
+        interval: Interval,
+        /// Stderr of the check
+        #[serde(default)]
+        error: Option<String>,
+    },
+}
+
+impl Notification {
+    /// The name of the event, as it's serialized
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Notification::ActionErrored { .. } => "action_errored",
+            Notification::GaveUp { .. } => "gave_up",
+            Notification::SlaBreached { .. } => "sla_breached",
+            Notification::ForcedDown { .. } => "forced_down",
+            Notification::RecheckFailed { .. } => "recheck_failed",
+        }
+    }
+
+    /// A one-line description, for sinks that deliver text
+    pub fn summary(&self) -> String {
+        match self {
+            Notification::ActionErrored {
+                task_name,
+                interval,
+                failures,
+                ..
+            } => format!(
+                "{} failed for {} ({} in a row)",
+                task_name, interval, failures
+            ),
+            Notification::GaveUp {
+                task_name,
+                interval,
+                failures,
+                ..
+            } => format!(
+                "{} gave up on {} after {} failures",
+                task_name, interval, failures
+            ),
+            Notification::SlaBreached {
+                task_name,
+                interval,
+                deadline,
+                state,
+            } => format!(
+                "{} is still {:?} for {}, past its deadline of {}",
+                task_name, state, interval, deadline
+            ),
+            Notification::ForcedDown {
+                resources,
+                interval,
+            } => {
+                let mut resources: Vec<&str> = resources.iter().map(|r| r.as_str()).collect();
+                resources.sort_unstable();
+                format!("{} forced down over {}", resources.join(", "), interval)
+            }
+            Notification::RecheckFailed {
+                task_name,
+                interval,
+                ..
+            } => format!(
+                "{} failed its recheck for {}, producing it again",
+                task_name, interval
+            ),
+        }
+    }
+
+    /// Variables for sinks that format notifications from templates: the
+    /// usual interval variables (in UTC), plus `event`, `summary`, and
+    /// whichever of `task_name`, `interval`, `failures`, `error`,
+    /// `deadline`, `state`, and `resources` the event has
+    pub fn varmap(&self) -> VarMap {
+        let (interval, mut vars) = match self {
+            Notification::ActionErrored {
+                task_name,
+                interval,
+                failures,
+                error,
+            }
+            | Notification::GaveUp {
+                task_name,
+                interval,
+                failures,
+                error,
+            } => (
+                interval,
+                vec![
+                    ("task_name", task_name.clone()),
+                    ("failures", failures.to_string()),
+                    ("error", error.clone().unwrap_or_default()),
+                ],
+            ),
+            Notification::SlaBreached {
+                task_name,
+                interval,
+                deadline,
+                state,
+            } => (
+                interval,
+                vec![
+                    ("task_name", task_name.clone()),
+                    ("deadline", deadline.to_rfc3339()),
+                    ("state", format!("{:?}", state)),
+                ],
+            ),
+            Notification::ForcedDown {
+                resources,
+                interval,
+            } => {
+                let mut resources: Vec<&str> = resources.iter().map(|r| r.as_str()).collect();
+                resources.sort_unstable();
+                (interval, vec![("resources", resources.join(", "))])
+            }
+            Notification::RecheckFailed {
+                task_name,
+                interval,
+                error,
+            } => (
+                interval,
+                vec![
+                    ("task_name", task_name.clone()),
+                    ("error", error.clone().unwrap_or_default()),
+                ],
+            ),
+        };
+        vars.push(("event", self.kind().to_owned()));
+        vars.push(("summary", self.summary()));
+        vars.push(("interval", interval.to_string()));
+        let mut varmap = VarMap::from_interval(interval, chrono_tz::UTC);
+        varmap.extend(vars.into_iter().map(|(k, v)| (k.to_owned(), v)));
+        varmap
+    }
+}
+
+/// How often sinks are ticked, so batching sinks can send what's due
+const TICK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Somewhere notifications are delivered
+#[async_trait]
+pub trait Sink: Send {
+    async fn notify(&mut self, notification: &Notification) -> Result<()>;
+
+    /// Called every few seconds, for sinks that batch notifications up
+    async fn tick(&mut self, _now: DateTime<Utc>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Delivers anything held back, as the channel closes
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// How long a sink posting over HTTP has to get an answer
+#[cfg(feature = "notifications")]
+const POST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Shared by the sinks posting over HTTP, so none of them can hang on an
+/// endpoint that never answers
+#[cfg(feature = "notifications")]
+static CLIENT: std::sync::LazyLock<reqwest::Client> = std::sync::LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(POST_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+});
+
+/// Delivers notifications to one sink until its channel closes
+async fn serve_sink(mut sink: Box<dyn Sink>, mut notifications: mpsc::Receiver<Notification>) {
+    let mut ticker = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        tokio::select! {
+            notification = notifications.recv() => {
+                let Some(notification) = notification else {
+                    break;
+                };
+                if let Err(e) = sink.notify(&notification).await {
+                    warn!(
+                        "Unable to deliver {} notification: {}",
+                        notification.kind(),
+                        e
+                    );
+                }
+            }
+            _ = ticker.tick() => {
+                if let Err(e) = sink.tick(Utc::now()).await {
+                    warn!("Unable to deliver notifications: {}", e);
+                }
+            }
+        }
+    }
+    if let Err(e) = sink.flush().await {
+        warn!("Unable to deliver notifications: {}", e);
+    }
+}
+
+/// Delivers each notification to every sink until the channel closes.
+/// Each sink runs on its own, so one that fails or is slow doesn't hold up
+/// the others; a sink too far behind has notifications dropped.
+pub async fn serve(sinks: Vec<Box<dyn Sink>>, mut notifications: mpsc::Receiver<Notification>) {
+    let mut senders = Vec::new();
+    let mut handles = Vec::new();
+    for sink in sinks {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        senders.push(tx);
+        handles.push(tokio::spawn(serve_sink(sink, rx)));
+    }
+    while let Some(notification) = notifications.recv().await {
+        for tx in &senders {
+            if tx.try_send(notification.clone()).is_err() {
+                warn!(
+                    "Dropping {} notification for a sink that's behind",
+                    notification.kind()
+                );
+            }
+        }
+    }
+    drop(senders);
+    futures::future::join_all(handles).await;
+}
+
+pub fn start(
+    sinks: Vec<Box<dyn Sink>>,
+    notifications: mpsc::Receiver<Notification>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(serve(sinks, notifications))
+}
+
+#[cfg(feature = "email")]
+pub mod email;
+#[cfg(feature = "notifications")]
+pub mod slack;
+#[cfg(feature = "notifications")]
+pub mod webhook;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Collect(mpsc::Sender<Notification>);
+
+    #[async_trait]
+    impl Sink for Collect {
+        async fn notify(&mut self, notification: &Notification) -> Result<()> {
+            self.0.send(notification.clone()).await.unwrap();
+            Ok(())
+        }
+    }
+
+    struct Broken;
+
+    #[async_trait]
+    impl Sink for Broken {
+        async fn notify(&mut self, _notification: &Notification) -> Result<()> {
+            Err(Error::Remote("unreachable".to_owned()))
+        }
+    }
+
+    struct Stalled;
+
+    #[async_trait]
+    impl Sink for Stalled {
+        async fn notify(&mut self, _notification: &Notification) -> Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn check_stalled_sink() {
+        let (collected_tx, mut collected) = mpsc::channel(CHANNEL_CAPACITY);
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = start(vec![Box::new(Stalled), Box::new(Collect(collected_tx))], rx);
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        for failures in 1..=3 {
+            tx.send(Notification::ActionErrored {
+                task_name: "task_a".to_owned(),
+                interval,
+                failures,
+                error: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        // A sink that never answers doesn't hold up the rest
+        for _ in 1..=3 {
+            tokio::time::timeout(std::time::Duration::from_secs(5), collected.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn check_serve() {
+        let (collected_tx, mut collected) = mpsc::channel(CHANNEL_CAPACITY);
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = start(vec![Box::new(Broken), Box::new(Collect(collected_tx))], rx);
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        let notification = Notification::GaveUp {
+            task_name: "task_a".to_owned(),
+            interval,
+            failures: 3,
+            error: Some("vendor file missing".to_owned()),
+        };
+        tx.send(notification.clone()).await.unwrap();
+        drop(tx);
+        handle.await.unwrap();
+
+        // A broken sink doesn't stop delivery to the rest
+        assert_eq!(collected.recv().await.unwrap(), notification);
+
+        let json = serde_json::to_value(&notification).unwrap();
+        assert_eq!(json["event"], "gave_up");
+        assert_eq!(json["task_name"], "task_a");
+        assert_eq!(notification.kind(), "gave_up");
+
+        let varmap = notification.varmap();
+        assert_eq!(varmap["task_name"], "task_a");
+        assert_eq!(varmap["error"], "vendor file missing");
+        assert_eq!(varmap["yyyy"], "2022");
+    }
+}
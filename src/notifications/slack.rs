@@ -0,0 +1,165 @@
+//! Posts notifications into a Slack channel through an incoming webhook
+
+use super::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SlackConfig {
+    /// The incoming webhook URL Slack generated for the channel
+    pub webhook_url: String,
+
+    /// Posts somewhere other than the webhook's own channel, for webhooks
+    /// that allow it
+    #[serde(default)]
+    pub channel: Option<String>,
+
+    /// Only these kinds of events are posted, e.g. `gave_up`. All of them
+    /// if empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+
+    /// Message text by event kind, with `${var}`s filled in from
+    /// [`Notification::varmap`]. Kinds without one get a default message.
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+}
+
+/// The message posted when an event kind has no template: its summary,
+/// followed by the failed attempt's stderr when there is one
+fn default_template(notification: &Notification) -> &'static str {
+    match notification {
+        Notification::ActionErrored {
+            error: Some(error), ..
+        }
+        | Notification::GaveUp {
+            error: Some(error), ..
+        }
+        | Notification::RecheckFailed {
+            error: Some(error), ..
+        } if !error.is_empty() => "${summary}\n```${error}```",
+        _ => "${summary}",
+    }
+}
+
+pub struct SlackSink {
+    config: SlackConfig,
+}
+
+impl SlackSink {
+    pub fn new(config: SlackConfig) -> Self {
+        SlackSink { config }
+    }
+
+    /// The text posted for a notification
+    pub fn message(&self, notification: &Notification) -> String {
+        let template = self
+            .config
+            .templates
+            .get(notification.kind())
+            .map(|t| t.as_str())
+            .unwrap_or_else(|| default_template(notification));
+        notification.varmap().apply_to(template)
+    }
+}
+
+#[async_trait]
+impl Sink for SlackSink {
+    async fn notify(&mut self, notification: &Notification) -> Result<()> {
+        if !self.config.events.is_empty()
+            && !self.config.events.iter().any(|e| e == notification.kind())
+        {
+            return Ok(());
+        }
+        let mut payload = serde_json::json!({ "text": self.message(notification) });
+        if let Some(channel) = &self.config.channel {
+            payload["channel"] = channel.clone().into();
+        }
+        CLIENT
+            .post(&self.config.webhook_url)
+            .json(&payload)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| Error::Remote(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn check_slack() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let webhook_url = format!("http://{}/services/T0/B0/x", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // The body is the last thing sent
+            while !String::from_utf8_lossy(&request).ends_with("}") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        let errored = Notification::ActionErrored {
+            task_name: "load_prices".to_owned(),
+            interval,
+            failures: 2,
+            error: Some("vendor file missing".to_owned()),
+        };
+        let mut sink = SlackSink::new(SlackConfig {
+            webhook_url,
+            channel: Some("#data-ops".to_owned()),
+            events: vec!["action_errored".to_owned(), "gave_up".to_owned()],
+            templates: HashMap::from([(
+                "gave_up".to_owned(),
+                "${task_name} gave up on ${yyyy}: ${error}".to_owned(),
+            )]),
+        });
+
+        // Untemplated events get their summary and error
+        assert_eq!(
+            sink.message(&errored),
+            format!(
+                "load_prices failed for {} (2 in a row)\n```vendor file missing```",
+                interval
+            )
+        );
+        assert_eq!(
+            sink.message(&Notification::GaveUp {
+                task_name: "load_prices".to_owned(),
+                interval,
+                failures: 5,
+                error: Some("vendor file missing".to_owned()),
+            }),
+            "load_prices gave up on 2022: vendor file missing"
+        );
+
+        // Filtered out events are never sent
+        sink.notify(&Notification::ForcedDown {
+            resources: HashSet::from(["prices".to_owned()]),
+            interval,
+        })
+        .await
+        .unwrap();
+        sink.notify(&errored).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /services/T0/B0/x"));
+        assert!(request.contains(r##""channel":"#data-ops""##));
+        assert!(request.contains("vendor file missing"));
+    }
+}
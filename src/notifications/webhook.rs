@@ -0,0 +1,101 @@
+//! Posts each notification as JSON to a URL
+
+use super::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+
+    /// Only these kinds of events are posted, e.g. `gave_up`. All of them
+    /// if empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+pub struct WebhookSink {
+    config: WebhookConfig,
+}
+
+impl WebhookSink {
+    pub fn new(config: WebhookConfig) -> Self {
+        WebhookSink { config }
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    async fn notify(&mut self, notification: &Notification) -> Result<()> {
+        if !self.config.events.is_empty()
+            && !self.config.events.iter().any(|e| e == notification.kind())
+        {
+            return Ok(());
+        }
+        CLIENT
+            .post(&self.config.url)
+            .json(notification)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| Error::Remote(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn check_webhook() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // The body is the last thing sent
+            while !String::from_utf8_lossy(&request).contains("}") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        let mut sink = WebhookSink::new(WebhookConfig {
+            url,
+            events: vec!["gave_up".to_owned()],
+        });
+
+        // Filtered out events are never sent
+        sink.notify(&Notification::ActionErrored {
+            task_name: "task_a".to_owned(),
+            interval,
+            failures: 1,
+            error: None,
+        })
+        .await
+        .unwrap();
+        sink.notify(&Notification::GaveUp {
+            task_name: "task_a".to_owned(),
+            interval,
+            failures: 3,
+            error: None,
+        })
+        .await
+        .unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook"));
+        assert!(request.contains(r#""event":"gave_up""#));
+    }
+}
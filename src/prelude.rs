@@ -1,10 +1,24 @@
 pub use chrono::prelude::*;
 pub use chrono_tz::*;
 
+pub use crate::backfill::BackfillConfig;
 pub use crate::calendar::Calendar;
+pub use crate::circuit_breaker::CircuitBreakerConfig;
+pub use crate::error::{Error, WaterfallError};
+pub use crate::event_stream::RunnerEvent;
 pub use crate::executors::*;
 pub use crate::interval::Interval;
-pub use crate::runner::{ActionState, Runner, RunnerMessage};
+pub use crate::notifications::Notification;
+pub use crate::retry::RetryPolicy;
+pub use crate::runner::{
+    ActionId, ActionState, DetailsCursor, DetailsOptions, DetailsPage, ForcedInterval, QueueDepth,
+    QueueDepths, Runner, RunnerHandle, RunnerMessage, TaskOverview,
+};
+pub use crate::shard::Shard;
+pub use crate::simulate::{plan, simulate, PlanStatus, PlannedAction, SimulatedAction};
 pub use crate::storage::*;
 pub use crate::task::{TaskDefinition, TaskResources};
+pub use crate::upstream::UpstreamNode;
 pub use crate::world::WorldDefinition;
+pub use crate::CHANNEL_CAPACITY;
+pub use tokio_util::sync::CancellationToken;
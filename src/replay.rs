@@ -0,0 +1,141 @@
+use super::*;
+
+/// Re-executes the most recent failed attempt of a task's interval exactly
+/// as it ran, using the details and variables captured with the attempt
+/// rather than re-deriving them from the current world. The new attempt is
+/// stored alongside the old ones.
+///
+/// Replaying doesn't change any resource state.
+#[instrument(skip_all, fields(task_name = %task_name, interval = %interval))]
+pub async fn replay(
+    task_name: &str,
+    interval: Interval,
+    executor: &mpsc::Sender<ExecutorMessage>,
+    storage: &mpsc::Sender<StorageMessage>,
+    output_options: TaskOutputOptions,
+) -> Result<TaskAttempt> {
+    let (response, rx) = oneshot::channel();
+    storage
+        .send(StorageMessage::GetAttempts {
+            task_name: task_name.to_owned(),
+            interval,
+            response,
+        })
+        .await
+        .map_err(|e| Error::Channel(e.to_string()))?;
+    let previous = rx
+        .await?
+        .into_iter()
+        .rev()
+        .find(|a| !a.succeeded && a.details.is_some())
+        .ok_or_else(|| {
+            Error::Validation(format!(
+                "No replayable failed attempt of {} for {}",
+                task_name, interval
+            ))
+        })?;
+
+    let details = previous.details.unwrap();
+    let (response, rx) = oneshot::channel();
+    executor
+        .send(ExecutorMessage::ExecuteTask {
+            details: details.clone(),
+            varmap: previous.varmap.clone(),
+            output_options,
+            response,
+            kill: CancellationToken::new(),
+            span: tracing::Span::current(),
+            output: None,
+        })
+        .await
+        .map_err(|e| Error::Channel(e.to_string()))?;
+    let mut attempt = rx.await?;
+    attempt.task_name = task_name.to_owned();
+    attempt.scheduled_time = interval.end;
+    attempt.details = Some(details);
+    attempt.varmap = previous.varmap;
+    attempt
+        .executor
+        .push("Replayed from a previous attempt".to_owned());
+
+    storage
+        .send(StorageMessage::StoreAttempt {
+            task_name: task_name.to_owned(),
+            interval,
+            attempt: attempt.clone(),
+            span: tracing::Span::current(),
+        })
+        .await
+        .map_err(|e| Error::Channel(e.to_string()))?;
+    Ok(attempt)
+}
+
+#[cfg(all(test, feature = "local-exec"))]
+mod tests {
+    use super::*;
+    use crate::executors::local_executor;
+
+    #[tokio::test]
+    async fn check_replay() {
+        let (exe_tx, exe_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(1, exe_rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        let output_options = TaskOutputOptions {
+            discard_successful: false,
+            ..TaskOutputOptions::default()
+        };
+
+        // Nothing to replay yet
+        assert!(
+            replay("task", interval, &exe_tx, &storage_tx, output_options)
+                .await
+                .is_err()
+        );
+
+        // The variable has since changed, but the replay uses what ran
+        let varmap = VarMap::from(HashMap::from([("msg".to_owned(), "before".to_owned())]));
+        storage_tx
+            .send(StorageMessage::StoreAttempt {
+                task_name: "task".to_owned(),
+                interval,
+                attempt: TaskAttempt {
+                    succeeded: false,
+                    details: Some(serde_json::json!({ "command": "/bin/echo ${msg}" })),
+                    varmap,
+                    ..TaskAttempt::new()
+                },
+                span: tracing::Span::none(),
+            })
+            .await
+            .unwrap();
+
+        let attempt = replay("task", interval, &exe_tx, &storage_tx, output_options)
+            .await
+            .unwrap();
+        assert!(attempt.succeeded);
+        assert_eq!(attempt.output, "before\n");
+        assert_eq!(attempt.command, vec!["/bin/echo", "before"]);
+
+        let (response, rx) = oneshot::channel();
+        storage_tx
+            .send(StorageMessage::GetAttempts {
+                task_name: "task".to_owned(),
+                interval,
+                response,
+            })
+            .await
+            .unwrap();
+        assert_eq!(rx.await.unwrap().len(), 2);
+
+        exe_tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+}
@@ -28,6 +28,12 @@ pub enum AggregateRequirement {
     All(Vec<Box<Requirement>>),
     Any(Vec<Box<Requirement>>),
     None(Vec<Box<Requirement>>),
+    /// Satisfied when at least `n` of `reqs` are, e.g. 3 of 5 regional
+    /// feeds having delivered
+    AtLeast {
+        n: usize,
+        reqs: Vec<Box<Requirement>>,
+    },
 }
 
 impl Satisfiable for AggregateRequirement {
@@ -45,6 +51,12 @@ impl Satisfiable for AggregateRequirement {
                 acc.extend(req.resources());
                 acc
             }),
+            AggregateRequirement::AtLeast { reqs, .. } => {
+                reqs.iter().fold(HashSet::new(), |mut acc, req| {
+                    acc.extend(req.resources());
+                    acc
+                })
+            }
         }
     }
 
@@ -64,6 +76,12 @@ impl Satisfiable for AggregateRequirement {
             AggregateRequirement::None(reqs) => !reqs
                 .iter()
                 .any(|x| x.is_satisfied(interval, schedule, available)),
+            AggregateRequirement::AtLeast { n, reqs } => {
+                reqs.iter()
+                    .filter(|x| x.is_satisfied(interval, schedule, available))
+                    .count()
+                    >= *n
+            }
         }
     }
 
@@ -83,22 +101,83 @@ impl Satisfiable for AggregateRequirement {
             AggregateRequirement::None(reqs) => !reqs
                 .iter()
                 .any(|x| x.can_be_satisfied(interval, schedule, available)),
+            AggregateRequirement::AtLeast { n, reqs } => {
+                reqs.iter()
+                    .filter(|x| x.can_be_satisfied(interval, schedule, available))
+                    .count()
+                    >= *n
+            }
         }
     }
 }
 
+/// Which scheduled intervals of a resource are required, relative to the
+/// interval being evaluated: one, e.g. `-1` for the one before, or an
+/// inclusive range, e.g. `[-5, 0]` for the last six
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Offsets {
+    One(i32),
+    Range(i32, i32),
+}
+
+impl Offsets {
+    /// The scheduled intervals required when evaluating the interval
+    /// ending at `end`
+    pub fn intervals(&self, schedule: &Schedule, end: DateTime<Utc>) -> Vec<Interval> {
+        let (first, last) = match *self {
+            Offsets::One(offset) => (offset, offset),
+            Offsets::Range(first, last) => (first, last),
+        };
+        (first..=last)
+            .map(|offset| schedule.interval(end, offset))
+            .collect()
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "snake_case", untagged)]
 pub enum SingleRequirement {
-    Offset { resource: String, offset: i32 },
-    File { path: String },
+    /// A resource of another waterfall deployment, read from its API. Must
+    /// come before `Offset`, which would otherwise match it.
+    Remote {
+        remote: String,
+        resource: String,
+        offset: i32,
+        #[serde(default)]
+        on_failure: RemoteFailurePolicy,
+    },
+    /// Satisfied when a query against `database`, a connection URL, returns
+    /// a row that isn't false, zero, or NULL. The interval's variables are
+    /// filled into the query as quoted literals.
+    Sql {
+        database: String,
+        query: String,
+    },
+    /// Like `Offset`, but the interval must also have been produced within
+    /// the last `max_age_minutes`. Must come before `Offset`.
+    Fresh {
+        resource: String,
+        offset: Offsets,
+        max_age_minutes: u64,
+    },
+    Offset {
+        resource: String,
+        offset: Offsets,
+    },
+    File {
+        path: String,
+    },
 }
 
 impl Satisfiable for SingleRequirement {
     fn resources(&self) -> HashSet<Resource> {
         match self {
-            SingleRequirement::Offset { resource, .. } => HashSet::from([resource.to_owned()]),
-            SingleRequirement::File { path: _ } => HashSet::new(),
+            SingleRequirement::Offset { resource, .. }
+            | SingleRequirement::Fresh { resource, .. } => HashSet::from([resource.to_owned()]),
+            SingleRequirement::Remote { .. }
+            | SingleRequirement::Sql { .. }
+            | SingleRequirement::File { path: _ } => HashSet::new(),
         }
     }
 
@@ -110,13 +189,52 @@ impl Satisfiable for SingleRequirement {
     ) -> bool {
         match self {
             //SingleRequirement::ResourceInterval { .. } => true,
-            SingleRequirement::Offset { resource, offset } => {
+            SingleRequirement::Offset { resource, offset } => match available.get(resource) {
+                Some(is) => offset
+                    .intervals(schedule, interval.end)
+                    .into_iter()
+                    .all(|intv| is.has_subset(intv)),
+                None => false,
+            },
+            SingleRequirement::Remote {
+                remote,
+                resource,
+                offset,
+                on_failure,
+            } => {
+                if available.contains_key(&unreachable_key(remote)) {
+                    match on_failure {
+                        RemoteFailurePolicy::Wait => return false,
+                        RemoteFailurePolicy::AssumeSatisfied => return true,
+                        RemoteFailurePolicy::UseCached => {}
+                    }
+                }
                 let intv = schedule.interval(interval.end, *offset);
-                match available.get(resource) {
+                match available.get(&remote_key(remote, resource)) {
                     Some(is) => is.has_subset(intv),
                     None => false,
                 }
             }
+            SingleRequirement::Fresh {
+                resource,
+                offset,
+                max_age_minutes,
+            } => {
+                let intervals = offset.intervals(schedule, interval.end);
+                [resource.clone(), fresh_key(resource, *max_age_minutes)]
+                    .iter()
+                    .all(|key| {
+                        available
+                            .get(key)
+                            .is_some_and(|is| intervals.iter().all(|intv| is.has_subset(*intv)))
+                    })
+            }
+            SingleRequirement::Sql { database, query } => {
+                match available.get(&sql_key(database, query)) {
+                    Some(is) => is.has_subset(interval),
+                    None => false,
+                }
+            }
             SingleRequirement::File { path } => Path::new(path).exists(),
         }
     }
@@ -128,14 +246,20 @@ impl Satisfiable for SingleRequirement {
         available: &HashMap<Resource, IntervalSet>,
     ) -> bool {
         match self {
-            SingleRequirement::Offset { resource, offset } => {
-                let intv = schedule.interval(interval.end, *offset);
-                match available.get(resource) {
-                    Some(is) => is.has_subset(intv),
-                    None => false,
-                }
-            }
-            SingleRequirement::File { .. } => true,
+            // Stale intervals can be produced again
+            SingleRequirement::Offset { resource, offset }
+            | SingleRequirement::Fresh {
+                resource, offset, ..
+            } => match available.get(resource) {
+                Some(is) => offset
+                    .intervals(schedule, interval.end)
+                    .into_iter()
+                    .all(|intv| is.has_subset(intv)),
+                None => false,
+            },
+            SingleRequirement::Remote { .. }
+            | SingleRequirement::Sql { .. }
+            | SingleRequirement::File { .. } => true,
         }
     }
 }
@@ -147,6 +271,44 @@ pub enum Requirement {
     Group(AggregateRequirement),
 }
 
+impl Requirement {
+    /// The resource intervals this requirement refers to when evaluated
+    /// for `interval`. Requirements that aren't based on resources,
+    /// like files, don't contribute anything.
+    pub fn required_intervals(
+        &self,
+        interval: Interval,
+        schedule: &Schedule,
+    ) -> Vec<(Resource, Interval)> {
+        match self {
+            Requirement::One(
+                SingleRequirement::Offset { resource, offset }
+                | SingleRequirement::Fresh {
+                    resource, offset, ..
+                },
+            ) => offset
+                .intervals(schedule, interval.end)
+                .into_iter()
+                .map(|intv| (resource.clone(), intv))
+                .collect(),
+            Requirement::One(
+                SingleRequirement::Remote { .. }
+                | SingleRequirement::Sql { .. }
+                | SingleRequirement::File { .. },
+            ) => Vec::new(),
+            Requirement::Group(
+                AggregateRequirement::All(reqs)
+                | AggregateRequirement::Any(reqs)
+                | AggregateRequirement::None(reqs)
+                | AggregateRequirement::AtLeast { reqs, .. },
+            ) => reqs
+                .iter()
+                .flat_map(|req| req.required_intervals(interval, schedule))
+                .collect(),
+        }
+    }
+}
+
 impl Satisfiable for Requirement {
     fn is_satisfied(
         &self,
@@ -180,6 +342,122 @@ impl Satisfiable for Requirement {
     }
 }
 
+impl Requirement {
+    /// Replaces aliased resources, keyed by alias, with their canonical
+    /// names
+    pub fn rename(&mut self, aliases: &HashMap<Resource, Resource>) {
+        match self {
+            Requirement::One(
+                SingleRequirement::Offset { resource, .. }
+                | SingleRequirement::Fresh { resource, .. },
+            ) => {
+                if let Some(canonical) = aliases.get(resource) {
+                    *resource = canonical.clone();
+                }
+            }
+            Requirement::One(_) => {}
+            Requirement::Group(
+                AggregateRequirement::All(reqs)
+                | AggregateRequirement::Any(reqs)
+                | AggregateRequirement::None(reqs)
+                | AggregateRequirement::AtLeast { reqs, .. },
+            ) => {
+                for req in reqs {
+                    req.rename(aliases);
+                }
+            }
+        }
+    }
+
+    /// The remote deployments this requirement queries
+    pub fn remotes(&self) -> HashSet<String> {
+        match self {
+            Requirement::One(SingleRequirement::Remote { remote, .. }) => {
+                HashSet::from([remote.trim_end_matches('/').to_owned()])
+            }
+            Requirement::One(_) => HashSet::new(),
+            Requirement::Group(
+                AggregateRequirement::All(reqs)
+                | AggregateRequirement::Any(reqs)
+                | AggregateRequirement::None(reqs)
+                | AggregateRequirement::AtLeast { reqs, .. },
+            ) => reqs.iter().flat_map(|req| req.remotes()).collect(),
+        }
+    }
+
+    /// Checks quorums can be met by their requirements, and offset ranges
+    /// run forwards
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Requirement::One(
+                SingleRequirement::Offset {
+                    resource,
+                    offset: Offsets::Range(first, last),
+                }
+                | SingleRequirement::Fresh {
+                    resource,
+                    offset: Offsets::Range(first, last),
+                    ..
+                },
+            ) if first > last => Err(Error::Validation(format!(
+                "The offsets of {} run from {} back to {}",
+                resource, first, last
+            ))),
+            Requirement::One(_) => Ok(()),
+            Requirement::Group(AggregateRequirement::AtLeast { n, reqs })
+                if *n == 0 || *n > reqs.len() =>
+            {
+                Err(Error::Validation(format!(
+                    "at_least needs between 1 and {} requirements, not {}",
+                    reqs.len(),
+                    n
+                )))
+            }
+            Requirement::Group(
+                AggregateRequirement::All(reqs)
+                | AggregateRequirement::Any(reqs)
+                | AggregateRequirement::None(reqs)
+                | AggregateRequirement::AtLeast { reqs, .. },
+            ) => reqs.iter().try_for_each(|req| req.validate()),
+        }
+    }
+
+    /// The resources and maximum ages, in minutes, of this requirement's
+    /// freshness requirements
+    pub fn freshness(&self) -> HashSet<(Resource, u64)> {
+        match self {
+            Requirement::One(SingleRequirement::Fresh {
+                resource,
+                max_age_minutes,
+                ..
+            }) => HashSet::from([(resource.clone(), *max_age_minutes)]),
+            Requirement::One(_) => HashSet::new(),
+            Requirement::Group(
+                AggregateRequirement::All(reqs)
+                | AggregateRequirement::Any(reqs)
+                | AggregateRequirement::None(reqs)
+                | AggregateRequirement::AtLeast { reqs, .. },
+            ) => reqs.iter().flat_map(|req| req.freshness()).collect(),
+        }
+    }
+
+    /// The databases and queries of this requirement's SQL requirements
+    pub fn queries(&self) -> HashSet<(String, String)> {
+        match self {
+            Requirement::One(SingleRequirement::Sql { database, query }) => {
+                HashSet::from([(database.clone(), query.clone())])
+            }
+            Requirement::One(_) => HashSet::new(),
+            Requirement::Group(
+                AggregateRequirement::All(reqs)
+                | AggregateRequirement::Any(reqs)
+                | AggregateRequirement::None(reqs)
+                | AggregateRequirement::AtLeast { reqs, .. },
+            ) => reqs.iter().flat_map(|req| req.queries()).collect(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -208,5 +486,128 @@ mod tests {
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn check_at_least() {
+        let json = r#"{
+        "at_least": {
+            "n": 2,
+            "reqs": [
+                { "resource": "feed_us", "offset": 0 },
+                { "resource": "feed_eu", "offset": 0 },
+                { "resource": "feed_ap", "offset": 0 }
+            ]
+        }
+        }"#;
+        let req: Requirement = serde_json::from_str(json).unwrap();
+        assert!(req.validate().is_ok());
+        assert_eq!(req.resources().len(), 3);
+
+        let schedule = Schedule::new(Calendar::new(), vec![NaiveTime::MIN], Tz::UTC);
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 4, 0, 0, 0).unwrap(),
+        );
+        let mut available = HashMap::from([("feed_us".to_owned(), IntervalSet::from(interval))]);
+        assert!(!req.is_satisfied(interval, &schedule, &available));
+        available.insert("feed_ap".to_owned(), IntervalSet::from(interval));
+        assert!(req.is_satisfied(interval, &schedule, &available));
+
+        // Quorums that can't be met, or are always met, are rejected
+        for n in [0, 4] {
+            let req: Requirement =
+                serde_json::from_str(&json.replace(r#""n": 2"#, &format!(r#""n": {}"#, n)))
+                    .unwrap();
+            assert!(req.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn check_offset_range() {
+        let req: Requirement =
+            serde_json::from_str(r#"{ "resource": "prices", "offset": [-5, 0] }"#).unwrap();
+        assert!(req.validate().is_ok());
+
+        // The last six scheduled intervals, which skip the weekend
+        let schedule = Schedule::new(Calendar::new(), vec![NaiveTime::MIN], Tz::UTC);
+        let days = |from, to| {
+            Interval::new(
+                Utc.with_ymd_and_hms(2022, 1, from, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, to, 0, 0, 0).unwrap(),
+            )
+        };
+        let interval = days(10, 11);
+        let window = vec![
+            days(3, 4),
+            days(4, 5),
+            days(5, 6),
+            days(6, 7),
+            days(7, 10),
+            days(10, 11),
+        ];
+        assert_eq!(
+            req.required_intervals(interval, &schedule)
+                .into_iter()
+                .map(|(_, intv)| intv)
+                .collect::<Vec<Interval>>(),
+            window
+        );
+
+        // Every interval in the window has to be up
+        let mut is = IntervalSet::new();
+        for intv in window.iter().filter(|intv| **intv != days(5, 6)) {
+            is.insert(*intv);
+        }
+        let mut available = HashMap::from([("prices".to_owned(), is)]);
+        assert!(!req.is_satisfied(interval, &schedule, &available));
+        available.get_mut("prices").unwrap().insert(days(5, 6));
+        assert!(req.is_satisfied(interval, &schedule, &available));
+
+        let backwards: Requirement =
+            serde_json::from_str(r#"{ "resource": "prices", "offset": [0, -5] }"#).unwrap();
+        assert!(backwards.validate().is_err());
+    }
+
+    #[test]
+    fn check_fresh_range() {
+        let req: Requirement = serde_json::from_str(
+            r#"{ "resource": "prices", "offset": [-1, 0], "max_age_minutes": 60 }"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            req,
+            Requirement::One(SingleRequirement::Fresh {
+                offset: Offsets::Range(-1, 0),
+                max_age_minutes: 60,
+                ..
+            })
+        ));
+        assert_eq!(req.freshness(), HashSet::from([("prices".to_owned(), 60)]));
+
+        // Both intervals have to be up and fresh
+        let schedule = Schedule::new(Calendar::new(), vec![NaiveTime::MIN], Tz::UTC);
+        let days = |from, to| {
+            Interval::new(
+                Utc.with_ymd_and_hms(2022, 1, from, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, to, 0, 0, 0).unwrap(),
+            )
+        };
+        let interval = days(5, 6);
+        let both = IntervalSet::from(days(4, 6));
+        let mut available = HashMap::from([
+            ("prices".to_owned(), both.clone()),
+            (fresh_key("prices", 60), IntervalSet::from(days(5, 6))),
+        ]);
+        assert!(!req.is_satisfied(interval, &schedule, &available));
+        assert!(req.can_be_satisfied(interval, &schedule, &available));
+        available.insert(fresh_key("prices", 60), both);
+        assert!(req.is_satisfied(interval, &schedule, &available));
+
+        let backwards: Requirement = serde_json::from_str(
+            r#"{ "resource": "prices", "offset": [0, -1], "max_age_minutes": 60 }"#,
+        )
+        .unwrap();
+        assert!(backwards.validate().is_err());
+    }
+
     // TODO Add tests for satisfies
 }
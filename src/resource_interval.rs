@@ -5,7 +5,7 @@ use std::ops::{Add, Deref, DerefMut, Sub};
 /// represent where a resource is available, or where it's required
 /// Resources are independent, so overlaps between the
 /// interval sets are possible.
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ResourceInterval(HashMap<Resource, IntervalSet>);
 
 impl ResourceInterval {
@@ -14,23 +14,28 @@ impl ResourceInterval {
     }
 
     pub fn insert(&mut self, resource: &Resource, intervals: &IntervalSet) {
-        self.0
-            .entry(resource.clone())
-            .or_insert(IntervalSet::new())
-            .merge(intervals);
+        self.0.entry(resource.clone()).or_default().merge(intervals);
     }
 
     pub fn union(&self, other: &ResourceInterval) -> Self {
         let res: HashMap<Resource, IntervalSet> =
             other.0.iter().fold(self.0.clone(), |mut acc, (res, is)| {
-                acc.entry(res.clone())
-                    .or_insert(IntervalSet::new())
-                    .merge(is);
+                acc.entry(res.clone()).or_default().merge(is);
                 acc
             });
         ResourceInterval(res)
     }
 
+    /// Moves the intervals of aliased resources, keyed by alias, to their
+    /// canonical names
+    pub fn rename(&mut self, aliases: &HashMap<Resource, Resource>) {
+        for (alias, canonical) in aliases {
+            if let Some(is) = self.0.remove(alias) {
+                self.insert(canonical, &is);
+            }
+        }
+    }
+
     pub fn difference(&self, other: &ResourceInterval) -> Self {
         let res: HashMap<Resource, IntervalSet> = self
             .0
@@ -78,8 +83,8 @@ mod tests {
     macro_rules! intv {
         ( $x:literal, $y:literal ) => {
             Interval::new(
-                Utc.with_ymd_and_hms(2022, 1, 1,$x, 0, 0).unwrap(),
-                Utc.with_ymd_and_hms(2022, 1, 1,$y, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, 1, $x, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, 1, $y, 0, 0).unwrap(),
             )
         };
     }
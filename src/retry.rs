@@ -0,0 +1,173 @@
+//! When, and how many times, a failed action is retried
+
+use super::*;
+use rand::Rng;
+
+fn default_initial_delay_seconds() -> u64 {
+    30
+}
+
+fn default_backoff() -> f64 {
+    1.0
+}
+
+fn default_max_delay_seconds() -> u64 {
+    3600
+}
+
+/// The longest delay between retries, a year
+const MAX_DELAY_SECONDS: u64 = 366 * 86400;
+
+/// Retry rules for a task, applied to each interval separately. The
+/// defaults retry every 30 seconds, forever.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Give up once an interval has failed this many times in a row,
+    /// leaving it errored
+    #[serde(default)]
+    pub max_attempts: Option<usize>,
+
+    /// Delay before the first retry
+    #[serde(default = "default_initial_delay_seconds")]
+    pub initial_delay_seconds: u64,
+
+    /// Each further retry waits this many times longer than the last
+    #[serde(default = "default_backoff")]
+    pub backoff: f64,
+
+    /// Upper bound on the delay between retries
+    #[serde(default = "default_max_delay_seconds")]
+    pub max_delay_seconds: u64,
+
+    /// Randomly lengthens or shortens each delay by up to this fraction,
+    /// so intervals that failed together don't retry together
+    #[serde(default)]
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: None,
+            initial_delay_seconds: default_initial_delay_seconds(),
+            backoff: default_backoff(),
+            max_delay_seconds: default_max_delay_seconds(),
+            jitter: 0.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<()> {
+        for (field, seconds) in [
+            ("initial_delay_seconds", self.initial_delay_seconds),
+            ("max_delay_seconds", self.max_delay_seconds),
+        ] {
+            if seconds > MAX_DELAY_SECONDS {
+                return Err(Error::Validation(format!(
+                    "Retry {} of {} must be at most {}",
+                    field, seconds, MAX_DELAY_SECONDS
+                )));
+            }
+        }
+        if !(self.backoff.is_finite() && self.backoff >= 1.0) {
+            return Err(Error::Validation(format!(
+                "Retry backoff of {} must be at least 1",
+                self.backoff
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(Error::Validation(format!(
+                "Retry jitter of {} must be between 0 and 1",
+                self.jitter
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether an interval that has failed `failures` times in a row is
+    /// retried again
+    pub fn should_retry(&self, failures: usize) -> bool {
+        self.max_attempts.is_none_or(|max| failures < max)
+    }
+
+    /// The delay before retrying an interval that has failed `failures`
+    /// times in a row
+    pub fn delay(&self, failures: usize) -> Duration {
+        let offset = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-1.0..=1.0)
+        } else {
+            0.0
+        };
+        self.jittered_delay(failures, offset)
+    }
+
+    /// The delay with the jitter applied at `offset`, in [-1, 1]
+    fn jittered_delay(&self, failures: usize, offset: f64) -> Duration {
+        let exponent = failures.saturating_sub(1).min(i32::MAX as usize) as i32;
+        let max = self.max_delay_seconds.min(MAX_DELAY_SECONDS) as f64;
+        let base = (self.initial_delay_seconds as f64 * self.backoff.powi(exponent)).min(max);
+        let seconds = (base * (1.0 + self.jitter.clamp(0.0, 1.0) * offset)).clamp(0.0, max);
+        Duration::milliseconds((seconds * 1000.0) as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_default() {
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry(1_000));
+        assert_eq!(policy.delay(1), Duration::seconds(30));
+        assert_eq!(policy.delay(10), Duration::seconds(30));
+    }
+
+    #[test]
+    fn check_validate() {
+        let policy = |json| serde_json::from_str::<RetryPolicy>(json).unwrap();
+        assert!(RetryPolicy::default().validate().is_ok());
+        assert!(policy(r#"{ "backoff": 0.5 }"#).validate().is_err());
+        assert!(policy(r#"{ "jitter": 1.5 }"#).validate().is_err());
+        let huge = policy(r#"{ "max_delay_seconds": 18446744073709551615, "backoff": 10.0 }"#);
+        assert!(huge.validate().is_err());
+
+        // Even unchecked, a huge delay is capped
+        assert_eq!(
+            huge.jittered_delay(100, 0.0),
+            Duration::seconds(MAX_DELAY_SECONDS as i64)
+        );
+    }
+
+    #[test]
+    fn check_backoff() {
+        let policy: RetryPolicy = serde_json::from_str(
+            r#"{
+                "max_attempts": 5,
+                "initial_delay_seconds": 10,
+                "backoff": 2.0,
+                "max_delay_seconds": 60,
+                "jitter": 0.5
+            }"#,
+        )
+        .unwrap();
+        assert!(policy.should_retry(4));
+        assert!(!policy.should_retry(5));
+
+        let delays: Vec<i64> = (1..=5)
+            .map(|n| policy.jittered_delay(n, 0.0).num_seconds())
+            .collect();
+        assert_eq!(delays, vec![10, 20, 40, 60, 60]);
+
+        // Jitter stretches or shrinks the delay, but never past the cap
+        assert_eq!(policy.jittered_delay(2, -1.0), Duration::seconds(10));
+        assert_eq!(policy.jittered_delay(2, 1.0), Duration::seconds(30));
+        assert_eq!(policy.jittered_delay(4, 1.0), Duration::seconds(60));
+        for _ in 0..100 {
+            let delay = policy.delay(2);
+            assert!(delay >= Duration::seconds(10) && delay <= Duration::seconds(30));
+        }
+    }
+}
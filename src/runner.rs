@@ -1,8 +1,12 @@
 use super::*;
 use futures::stream::futures_unordered::FuturesUnordered;
 use futures::StreamExt;
+use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::notifications::Notification;
+use crate::upstream::{self, UpstreamNode};
 
 /*
     Runner is responsible for taking a TaskSet and a varmap and
@@ -13,12 +17,29 @@ use std::collections::VecDeque;
         - A Stop message is sent
         - current = TaskSet::coverage (the theoretical)
 */
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, PartialOrd)]
 pub enum ActionState {
     Queued,
     Running,
     Errored,
     Completed,
+    /// Given up on, and won't be retried
+    Skipped,
+    /// Out of retries. Stays failed until someone retries it.
+    Failed,
+}
+
+/// Identifies an action. Ids are handed out in order and never reused, so
+/// one always refers to the same action, even after older actions are
+/// compacted away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ActionId(pub(crate) u64);
+
+impl std::fmt::Display for ActionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize)]
@@ -26,48 +47,358 @@ pub struct Action {
     task: usize,
     pub interval: Interval,
     pub state: ActionState,
-    // kill: Option<oneshot::Receiver<()>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RunnerState {
-    coverage: ResourceInterval,
-    current: ResourceInterval,
+    pub coverage: ResourceInterval,
+    pub current: ResourceInterval,
+
+    /// Intervals of resources whose actions ran out of retries
+    #[serde(default)]
+    pub failed: ResourceInterval,
+}
+
+/// How many messages are waiting on a channel, out of how many it holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueDepth {
+    pub depth: usize,
+    pub capacity: usize,
+}
+
+impl QueueDepth {
+    pub fn of<T>(tx: &mpsc::Sender<T>) -> Self {
+        QueueDepth {
+            depth: tx.max_capacity() - tx.capacity(),
+            capacity: tx.max_capacity(),
+        }
+    }
+}
+
+/// How backed up the channels the runner works through are
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueDepths {
+    pub runner: QueueDepth,
+    pub storage: QueueDepth,
+    pub executor: QueueDepth,
+
+    /// Dispatch is held back until storage catches up
+    pub lagging: bool,
+}
+
+/// A task interval forced up or down, aligned to the task's schedule
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForcedInterval {
+    pub task_name: String,
+    pub interval: Interval,
+}
+
+/// An interval of a task that hasn't completed yet, along with the
+/// requirements that are currently holding it back
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingInterval {
+    /// Identifies the action for retrying or killing it
+    pub action_id: ActionId,
+    pub interval: Interval,
+    pub state: ActionState,
+    pub unmet_requirements: Vec<Requirement>,
+}
+
+/// Summary of a single task, used to back a task drill-down page
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskOverview {
+    pub name: String,
+    pub group: Option<String>,
+    pub provides: HashSet<Resource>,
+    pub requires: Vec<Requirement>,
+    pub timezone: Tz,
+    pub valid_over: IntervalSet,
+
+    /// The next scheduled intervals, starting with the one in progress
+    pub upcoming: Vec<Interval>,
+
+    /// The oldest intervals that are queued, running, or errored
+    pub pending: Vec<PendingInterval>,
+
+    /// Paused tasks don't start new actions
+    pub paused: bool,
 }
 
 // Eventually we want to coerce the data into this format for timelines-chart
 // Resource (group) -> Task (label) -> data [ { "timeRange": [date,date], "val": state } ]
 pub type ResourceStateDetails = HashMap<Resource, HashMap<String, Vec<Action>>>;
 
+/// Which actions [`RunnerMessage::GetResourceStateDetails`] returns, and
+/// how they're summarized
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DetailsOptions {
+    /// Only the actions of tasks providing these resources
+    #[serde(default)]
+    pub resources: Option<HashSet<Resource>>,
+
+    /// Only the actions of tasks in these groups, or groups nested under
+    /// them
+    #[serde(default)]
+    pub groups: Option<HashSet<String>>,
+
+    /// Coalesces adjacent actions in the same state if there are more
+    /// than this many
+    #[serde(default)]
+    pub max_intervals: Option<usize>,
+
+    /// Merges each task's actions into buckets this many seconds long,
+    /// aligned to the epoch. A bucket takes the state of its least healthy
+    /// action.
+    #[serde(default)]
+    pub bucket_seconds: Option<i64>,
+
+    /// The most actions in a page
+    #[serde(default)]
+    pub limit: Option<usize>,
+
+    /// Where the previous page left off
+    #[serde(default)]
+    pub cursor: Option<DetailsCursor>,
+}
+
+/// The last action of a page of details. Pages are ordered by task name,
+/// then interval start, so a cursor stays valid as actions are added.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DetailsCursor {
+    pub task_name: String,
+    pub start: DateTime<Utc>,
+}
+
+/// Cursors are written as `task_name@start`, e.g. for query strings
+impl std::fmt::Display for DetailsCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.task_name, self.start.to_rfc3339())
+    }
+}
+
+impl std::str::FromStr for DetailsCursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (task_name, start) = s
+            .rsplit_once('@')
+            .ok_or_else(|| Error::Validation(format!("Malformed cursor {}", s)))?;
+        let start = DateTime::parse_from_rfc3339(start)
+            .map_err(|e| Error::Validation(format!("Malformed cursor {}: {}", s, e)))?;
+        Ok(DetailsCursor {
+            task_name: task_name.to_owned(),
+            start: start.with_timezone(&Utc),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct DetailsPage {
+    pub details: ResourceStateDetails,
+
+    /// The group of each task in the page that has one
+    pub groups: HashMap<String, String>,
+
+    /// Where the next page starts, if there are more actions
+    pub next_cursor: Option<DetailsCursor>,
+}
+
 #[derive(Debug)]
 pub enum RunnerMessage {
     Tick,
     PollMessages,
     ActionCompleted {
-        action_id: usize,
+        action_id: ActionId,
+        succeeded: bool,
+        /// Stderr of the attempt that failed, if one ran
+        error: Option<String>,
+        /// The command failed because it couldn't be run, not because of
+        /// anything it did
+        infra_failure: bool,
+    },
+    /// A task's down command finished for an interval whose resources were
+    /// taken down. `action_id` is the action to run again afterwards, if
+    /// there is one.
+    DownCompleted {
+        action_id: Option<ActionId>,
         succeeded: bool,
     },
+    /// A completed action's periodic recheck finished
+    RecheckCompleted {
+        action_id: ActionId,
+        passed: bool,
+        /// Stderr of the check, if it failed
+        error: Option<String>,
+    },
+    /// Requeues an errored, failed, or skipped action immediately
     RetryAction {
-        action_id: usize,
+        action_id: ActionId,
+        response: oneshot::Sender<Result<()>>,
+    },
+    /// Requeues every action in the state, which must be errored, failed,
+    /// or skipped, of the tasks in `group` if given. Responds with the ids
+    /// of the requeued actions.
+    RetryActions {
+        state: ActionState,
+        group: Option<String>,
+        response: oneshot::Sender<Result<Vec<ActionId>>>,
+    },
+    /// A scheduled retry of an errored action is due. Ignored if the action
+    /// has moved on since, e.g. by being skipped.
+    RetryDue {
+        action_id: ActionId,
+    },
+    /// Gives up on an action that isn't running or completed. It stays
+    /// skipped, leaving a visible gap, until it's retried.
+    SkipAction {
+        action_id: ActionId,
+        response: oneshot::Sender<Result<()>>,
     },
-    /// Marks all resources in the set available over the interval
+    /// Kills a running action. It's left errored rather than retried.
+    KillAction {
+        action_id: ActionId,
+        response: oneshot::Sender<Result<()>>,
+    },
+    /// Marks all resources in the set available over the interval.
+    /// Responds with the task intervals forced up.
     ForceUp {
         resources: HashSet<String>,
         interval: Interval,
+        response: oneshot::Sender<Result<Vec<ForcedInterval>>>,
     },
     /// Marks all resources in the set as down over _at least_ the interval.
-    /// Will cause a re-check / re-gen
+    /// Will cause a re-check / re-gen. Responds with the task intervals
+    /// forced down.
     ForceDown {
         resources: HashSet<String>,
         interval: Interval,
+        response: oneshot::Sender<Result<Vec<ForcedInterval>>>,
     },
     GetState {
         response: oneshot::Sender<RunnerState>,
     },
+    GetQueueDepths {
+        response: oneshot::Sender<QueueDepths>,
+    },
+    /// The output of a running action's up command
+    GetOutput {
+        action_id: ActionId,
+        response: oneshot::Sender<Result<OutputTap>>,
+    },
+    /// Subscribes to the runner's events from now on
+    Subscribe {
+        response: oneshot::Sender<tokio::sync::broadcast::Receiver<RunnerEvent>>,
+    },
     GetResourceStateDetails {
         interval: Interval,
-        response: oneshot::Sender<ResourceStateDetails>,
-        max_intervals: Option<usize>,
+        options: DetailsOptions,
+        response: oneshot::Sender<DetailsPage>,
+    },
+    /// Retrieve the upcoming schedule and outstanding work of a task.
+    /// Responds with None if the task doesn't exist.
+    GetTaskOverview {
+        task_name: String,
+        max_intervals: usize,
+        response: oneshot::Sender<Option<TaskOverview>>,
+    },
+    /// Retrieve the chain of task intervals feeding the resource at the
+    /// end of the interval. Responds with None if nothing provides it.
+    GetUpstream {
+        resource: Resource,
+        interval: Interval,
+        max_depth: usize,
+        response: oneshot::Sender<Option<Vec<UpstreamNode>>>,
+    },
+    /// Discard the in-memory resource state and reload it from storage,
+    /// re-deriving the state of any action that isn't running
+    ReloadState {
+        response: oneshot::Sender<Result<()>>,
+    },
+    /// Kills the running actions of a task. Killed actions are errored
+    /// and retried like any other failure.
+    CancelTask {
+        task_name: String,
+    },
+    /// Kills all running actions
+    CancelAll,
+    /// Holds a task's queued actions until it's resumed. Running actions
+    /// are left to finish.
+    PauseTask {
+        task_name: String,
+        response: oneshot::Sender<Result<()>>,
+    },
+    ResumeTask {
+        task_name: String,
+        response: oneshot::Sender<Result<()>>,
+    },
+    /// Pauses every task in a group, or a group nested under it. Responds
+    /// with the names of the tasks.
+    PauseGroup {
+        group: String,
+        response: oneshot::Sender<Result<Vec<String>>>,
+    },
+    ResumeGroup {
+        group: String,
+        response: oneshot::Sender<Result<Vec<String>>>,
+    },
+    /// Replaces the world definition. Tasks are matched by name: removed
+    /// and changed tasks have their running actions killed, and added and
+    /// changed tasks get new actions. On error, the current world is kept.
+    ReloadWorld {
+        definition: Box<WorldDefinition>,
+        response: oneshot::Sender<Result<()>>,
+    },
+    /// Re-executes the most recent failed attempt of the task's interval
+    /// ending at `end`, exactly as it ran. Resource state is unchanged.
+    ReplayAttempt {
+        task_name: String,
+        end: DateTime<Utc>,
+        response: oneshot::Sender<Result<TaskAttempt>>,
+    },
+    /// Retrieves every stored attempt of the task interval ending at or
+    /// containing `end`, oldest first
+    GetAttempts {
+        task_name: String,
+        end: DateTime<Utc>,
+        response: oneshot::Sender<Result<Vec<TaskAttempt>>>,
+    },
+    /// Retrieves the contents of the named artifact of the task interval
+    /// ending at or containing `end`, or None if there isn't one
+    GetArtifact {
+        task_name: String,
+        end: DateTime<Utc>,
+        name: String,
+        response: oneshot::Sender<Result<Option<Vec<u8>>>>,
+    },
+    /// Retrieves the output kept of the latest attempt of the task interval
+    /// ending at or containing `end`, including one still running or cut
+    /// short
+    GetStoredOutput {
+        task_name: String,
+        end: DateTime<Utc>,
+        response: oneshot::Sender<Result<Vec<OutputChunk>>>,
+    },
+    /// Pulls the state of the remote deployments tasks require
+    RefreshRemotes,
+    RemoteStatesLoaded {
+        /// None for remotes that couldn't be reached
+        states: HashMap<String, Option<ResourceInterval>>,
+    },
+    /// The recent attempts of tasks providing resources that freshness
+    /// requirements refer to, by task name
+    AttemptsLoaded {
+        attempts: HashMap<String, Vec<TaskAttempt>>,
+    },
+    /// Runs the SQL requirements of queued actions
+    CheckQueries,
+    QueriesChecked {
+        /// The keys and intervals of queries that were satisfied
+        satisfied: Vec<(Resource, Interval)>,
+    },
+    /// Pulls the state other shards have stored
+    RefreshShards,
+    ShardStatesLoaded {
+        states: Result<ShardStates>,
     },
     Stop,
 }
@@ -80,22 +411,137 @@ pub struct Runner {
 
     // States
     end_state: ResourceInterval,
-    target: ResourceInterval,
     current: ResourceInterval,
 
-    actions: Vec<Action>,
+    /// Actions by id. Ids aren't reused, so messages about an action
+    /// still find it after older actions are compacted away.
+    actions: BTreeMap<ActionId, Action>,
+    next_action: u64,
     qidx: usize,
 
+    /// How long completed actions are kept after their interval ends.
+    /// Older ones are compacted into `current`, which already records
+    /// them, and dropped.
+    action_history: Duration,
+    compacted_at: DateTime<Utc>,
+
+    /// The index of the action for each (task, interval). Every action is
+    /// added through [`Runner::add_actions`], so an interval is never run
+    /// twice.
+    registry: HashMap<(usize, Interval), ActionId>,
+
+    /*
+        Kill switches form a hierarchy: cancelling the runner's token kills
+        every task's token, which kill every action's token, which kill
+        every command the action runs.
+    */
+    cancel: CancellationToken,
+    task_cancels: Vec<CancellationToken>,
+    action_cancels: HashMap<ActionId, CancellationToken>,
+
+    /// Output of the up commands of running actions, for followers
+    outputs: HashMap<ActionId, OutputTap>,
+
+    /// Actions killed through [`RunnerMessage::KillAction`], which aren't
+    /// retried when they fail
+    killed: HashSet<ActionId>,
+
+    /// When sharded, only tasks flagged in `owned` are run, and the
+    /// resources of other shards are read from storage
+    shard: Option<Shard>,
+    owned: Vec<bool>,
+
+    /// Tasks removed or replaced by a world reload. Tasks and actions are
+    /// addressed by index, so they're kept, but no longer run, until
+    /// nothing of theirs is running and the tasks can be renumbered.
+    retired: HashSet<usize>,
+
+    /// Cached states of the remote deployments tasks require
+    remotes: HashMap<String, RemoteState>,
+
+    /// Intervals SQL requirements were satisfied for, by [`sql_key`]
+    queried: ResourceInterval,
+
+    /// When resource intervals were produced, for freshness requirements
+    produced: Production,
+
+    breaker: Option<CircuitBreaker>,
+
+    /// The most actions that may be running at once
+    max_running: Option<usize>,
+
+    backfill: Option<BackfillThrottle>,
+
+    /// Names of tasks whose actions aren't dispatched. Kept by name so a
+    /// pause outlives a world reload.
+    paused: HashSet<String>,
+
+    /// Consecutive failures of each action, for escalation
+    failures: HashMap<ActionId, usize>,
+
+    /// When errored actions are due to be retried
+    retry_at: HashMap<ActionId, DateTime<Utc>>,
+
+    /// The action ledger of the previous run, reconciled with the actions
+    /// once the runner starts
+    recovered: Vec<ActionRecord>,
+
+    /// Actions escalated to running their task's fallback
+    fallbacks: HashSet<ActionId>,
+
+    /// Old resource names, mapped to their canonical names
+    aliases: HashMap<Resource, Resource>,
+
+    /// Actions already alerted on for being late
+    alerted: HashSet<ActionId>,
+
+    /// When each task with `recheck_every_seconds` was last rechecked, and
+    /// the actions being rechecked now
+    rechecked: HashMap<usize, DateTime<Utc>>,
+    rechecking: HashSet<ActionId>,
+    notifications: Option<mpsc::Sender<Notification>>,
+
+    /// Changes published to subscribers as they happen
+    event_stream: EventStream,
+
+    /// Deadlines that passed before the runner started aren't alerted on,
+    /// so catching up after downtime doesn't raise an alert per interval
+    started: DateTime<Utc>,
+
     events: FuturesUnordered<tokio::task::JoinHandle<RunnerMessage>>,
 
+    /// How far ahead actions have been generated
     last_horizon: DateTime<Utc>,
-    messages: mpsc::UnboundedReceiver<RunnerMessage>,
-    executor: mpsc::UnboundedSender<ExecutorMessage>,
-    storage: mpsc::UnboundedSender<StorageMessage>,
+    messages: mpsc::Receiver<RunnerMessage>,
+    executor: mpsc::Sender<ExecutorMessage>,
+    storage: mpsc::Sender<StorageMessage>,
+
+    /// Snapshots storage was too backed up to take, sent again each tick
+    state_unstored: bool,
+    actions_unstored: bool,
+
+    /// Whether dispatch is held back for storage to catch up
+    lagging: bool,
+}
+
+fn post(
+    notifications: &Option<mpsc::Sender<Notification>>,
+    event_stream: &EventStream,
+    notification: Notification,
+) {
+    event_stream.alert(&notification);
+    // Notifications are dropped rather than holding up the runner
+    if let Some(notifications) = notifications {
+        if let Err(mpsc::error::TrySendError::Full(notification)) =
+            notifications.try_send(notification)
+        {
+            warn!(?notification, "Notifications are backed up, dropping one");
+        }
+    }
 }
 
 async fn validate_cmd(
-    executor: mpsc::UnboundedSender<ExecutorMessage>,
+    executor: mpsc::Sender<ExecutorMessage>,
     cmd: serde_json::Value,
 ) -> Result<()> {
     let (response, rx) = oneshot::channel();
@@ -104,136 +550,460 @@ async fn validate_cmd(
             details: cmd,
             response,
         })
-        .unwrap();
+        .await?;
     rx.await?
 }
 
+/// Validates every command of a task can run on the executor
+async fn validate_task(executor: &mpsc::Sender<ExecutorMessage>, task: &Task) -> Result<()> {
+    for (_, cmd) in task.commands() {
+        validate_cmd(executor.clone(), cmd.clone()).await?;
+    }
+    Ok(())
+}
+
+/// How many days completed actions are kept after their interval ends,
+/// unless set with [`Runner::set_action_history`]
+pub const DEFAULT_ACTION_HISTORY_DAYS: i64 = 7;
+
+/// How often completed actions past the action history are compacted
+const COMPACTION_SECONDS: i64 = 60;
+
+/// How often a running command's output is written to storage
+const OUTPUT_FLUSH_SECONDS: u64 = 5;
+
+/// Output is written sooner once this much of it has built up
+const OUTPUT_FLUSH_BYTES: usize = 65536;
+
+/// The most of an attempt's output kept in storage. The attempt itself
+/// still has the head and tail of it.
+const MAX_STORED_OUTPUT_BYTES: usize = 16 * 1024 * 1024;
+
+/// Passes on `chunk` while `stored` is under [`MAX_STORED_OUTPUT_BYTES`],
+/// cutting the chunk that reaches it short with a note
+fn cap_output(mut chunk: OutputChunk, stored: &mut usize) -> Option<OutputChunk> {
+    let room = MAX_STORED_OUTPUT_BYTES.saturating_sub(*stored);
+    if room == 0 {
+        return None;
+    }
+    if chunk.data.len() > room {
+        let mut end = room;
+        while !chunk.data.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunk.data.truncate(end);
+        chunk.data.push_str(&format!(
+            "\n[Output past {} bytes wasn't stored]\n",
+            MAX_STORED_OUTPUT_BYTES
+        ));
+        *stored = MAX_STORED_OUTPUT_BYTES;
+    } else {
+        *stored += chunk.data.len();
+    }
+    Some(chunk)
+}
+
+/// Copies a command's output into storage as it's produced, so commands
+/// that are cut short, or outlive the runner, leave something to go on
+async fn persist_output(
+    task_name: String,
+    interval: Interval,
+    tap: OutputTap,
+    storage: mpsc::Sender<StorageMessage>,
+) {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let (backlog, receiver) = tap.follow();
+    let mut stored = 0;
+    let mut pending: Vec<OutputChunk> = backlog
+        .into_iter()
+        .filter_map(|chunk| cap_output(chunk, &mut stored))
+        .collect();
+    let mut pending_bytes: usize = pending.iter().map(|c| c.data.len()).sum();
+    // The first write replaces whatever an earlier attempt left
+    let mut replace = true;
+    let mut batch = |pending: &mut Vec<OutputChunk>, pending_bytes: &mut usize| {
+        if pending.is_empty() && !replace {
+            return None;
+        }
+        let msg = StorageMessage::AppendOutput {
+            task_name: task_name.clone(),
+            interval,
+            chunks: std::mem::take(pending),
+            replace,
+        };
+        *pending_bytes = 0;
+        replace = false;
+        Some(msg)
+    };
+    // While storage is backed up, output that falls behind is dropped
+    // rather than buffered
+    let flush = |msg: Option<StorageMessage>| {
+        let storage = storage.clone();
+        async move {
+            if let Some(msg) = msg {
+                storage.send(msg).await.unwrap_or(());
+            }
+        }
+    };
+
+    if let Some(mut receiver) = receiver {
+        let mut ticker =
+            tokio::time::interval(tokio::time::Duration::from_secs(OUTPUT_FLUSH_SECONDS));
+        loop {
+            tokio::select! {
+                chunk = receiver.recv() => match chunk {
+                    Ok(chunk) => {
+                        let Some(chunk) = cap_output(chunk, &mut stored) else {
+                            continue;
+                        };
+                        pending_bytes += chunk.data.len();
+                        pending.push(chunk);
+                        if pending_bytes >= OUTPUT_FLUSH_BYTES {
+                            flush(batch(&mut pending, &mut pending_bytes)).await;
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(missed, "Output fell behind being stored");
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = ticker.tick() => flush(batch(&mut pending, &mut pending_bytes)).await,
+            }
+        }
+    }
+    flush(batch(&mut pending, &mut pending_bytes)).await;
+}
+
+#[allow(clippy::too_many_arguments)]
+#[instrument(name = "attempt", skip_all)]
 async fn run_task(
     task_name: String,
     interval: Interval,
     details: serde_json::Value,
-    executor: mpsc::UnboundedSender<ExecutorMessage>,
-    storage: mpsc::UnboundedSender<StorageMessage>,
-    kill: oneshot::Receiver<()>,
+    executor: mpsc::Sender<ExecutorMessage>,
+    storage: mpsc::Sender<StorageMessage>,
+    kill: CancellationToken,
     output_options: &TaskOutputOptions,
     varmap: &VarMap,
-) -> bool {
-    info!("Running {}/{}", task_name, interval);
+    output: Option<OutputTap>,
+    kind: AttemptKind,
+) -> TaskAttempt {
+    info!(command = %details, ?kind, "Running");
+    let persister = output.clone().map(|tap| {
+        let persister = persist_output(task_name.clone(), interval, tap.clone(), storage.clone());
+        (tap, tokio::spawn(persister))
+    });
     let (response, response_rx) = oneshot::channel();
     executor
         .send(ExecutorMessage::ExecuteTask {
-            details,
-            output_options: output_options.clone(),
+            details: details.clone(),
+            output_options: *output_options,
             varmap: varmap.clone(),
             response,
             kill,
+            span: tracing::Span::current(),
+            output,
         })
-        .unwrap();
-    let attempt = response_rx.await.unwrap();
-    let rc = attempt.succeeded;
+        .await
+        .unwrap_or(());
+    // An executor that's gone is a failure of the infrastructure, not the task
+    let mut attempt = response_rx.await.unwrap_or_else(|_| {
+        error!("Executor stopped before the task finished");
+        TaskAttempt {
+            infra_failure: true,
+            error: "The executor stopped before the task finished".to_owned(),
+            ..TaskAttempt::new()
+        }
+    });
+    // The output is all stored before the attempt is
+    if let Some((tap, persister)) = persister {
+        tap.close();
+        persister.await.unwrap_or(());
+        if attempt.succeeded && output_options.discard_successful {
+            storage
+                .send(StorageMessage::AppendOutput {
+                    task_name: task_name.clone(),
+                    interval,
+                    chunks: Vec::new(),
+                    replace: true,
+                })
+                .await
+                .unwrap_or_else(|_| storage_gone());
+        }
+    }
+    attempt.task_name = task_name.clone();
+    attempt.kind = kind;
+    attempt.scheduled_time = interval.end;
+    attempt.details = Some(details);
+    attempt.varmap = varmap.clone();
+    // The attempt only keeps a record of its artifacts, storage keeps them
+    for artifact in attempt.artifacts.iter_mut() {
+        storage
+            .send(StorageMessage::StoreArtifact {
+                task_name: task_name.clone(),
+                interval,
+                artifact: Artifact {
+                    data: std::mem::take(&mut artifact.data),
+                    ..artifact.clone()
+                },
+            })
+            .await
+            .unwrap_or_else(|_| storage_gone());
+    }
     storage
         .send(StorageMessage::StoreAttempt {
             task_name,
             interval,
             attempt: attempt.clone(),
+            span: tracing::Span::current(),
         })
-        .unwrap();
-    rc
+        .await
+        .unwrap_or_else(|_| storage_gone());
+    attempt
+}
+
+/// Logs a message that couldn't be sent to storage, as it stopped
+fn storage_gone() {
+    error!("Storage has stopped, unable to store");
+}
+
+/// Runs a task's escalation page command, logging the outcome
+#[instrument(name = "page", skip_all, fields(task_name = %task_name))]
+async fn page_task(
+    task_name: String,
+    page: TaskDetails,
+    varmap: VarMap,
+    executor: mpsc::Sender<ExecutorMessage>,
+) {
+    let (response, rx) = oneshot::channel();
+    executor
+        .send(ExecutorMessage::ExecuteTask {
+            details: page,
+            varmap,
+            output_options: TaskOutputOptions::default(),
+            response,
+            kill: CancellationToken::new(),
+            span: tracing::Span::current(),
+            output: None,
+        })
+        .await
+        .unwrap_or(());
+    match rx.await {
+        Ok(attempt) if attempt.succeeded => info!("Paged"),
+        Ok(attempt) => error!(error = %attempt.failure(), "Paging failed"),
+        Err(e) => error!(error = %e, "Paging failed"),
+    }
+}
+
+/// Re-runs a task's check command on a completed interval
+#[allow(clippy::too_many_arguments)]
+#[instrument(
+    name = "recheck",
+    skip_all,
+    fields(action_id = %action_id, task_name = %task_name, interval = %interval)
+)]
+async fn recheck_task(
+    action_id: ActionId,
+    task_name: String,
+    interval: Interval,
+    kill: CancellationToken,
+    varmap: VarMap,
+    check: TaskDetails,
+    output_options: TaskOutputOptions,
+    executor: mpsc::Sender<ExecutorMessage>,
+    storage: mpsc::Sender<StorageMessage>,
+) -> RunnerMessage {
+    let attempt = run_task(
+        task_name,
+        interval,
+        check,
+        executor,
+        storage,
+        kill,
+        &output_options,
+        &varmap,
+        None,
+        AttemptKind::Check,
+    )
+    .await;
+    RunnerMessage::RecheckCompleted {
+        action_id,
+        passed: attempt.succeeded,
+        error: (!attempt.succeeded).then(|| attempt.failure()),
+    }
+}
+
+/// Runs a task's down command to clean up after an interval whose
+/// resources were taken down
+#[allow(clippy::too_many_arguments)]
+#[instrument(name = "down", skip_all, fields(task_name = %task_name, interval = %interval))]
+async fn down_task(
+    action_id: Option<ActionId>,
+    task_name: String,
+    interval: Interval,
+    kill: CancellationToken,
+    varmap: VarMap,
+    down: TaskDetails,
+    output_options: TaskOutputOptions,
+    executor: mpsc::Sender<ExecutorMessage>,
+    storage: mpsc::Sender<StorageMessage>,
+) -> RunnerMessage {
+    let attempt = run_task(
+        task_name,
+        interval,
+        down,
+        executor,
+        storage,
+        kill,
+        &output_options,
+        &varmap,
+        None,
+        AttemptKind::Down,
+    )
+    .await;
+    if attempt.succeeded {
+        info!("Cleaned up");
+    } else {
+        error!(error = %attempt.failure(), "Down failed");
+    }
+    RunnerMessage::DownCompleted {
+        action_id,
+        succeeded: attempt.succeeded,
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
+#[instrument(
+    name = "action",
+    skip_all,
+    fields(action_id = %action_id, task_name = %task_name, interval = %interval)
+)]
 async fn up_task(
-    action_id: usize,
+    action_id: ActionId,
     task_name: String,
     interval: Interval,
-    _kill: oneshot::Receiver<()>,
+    kill: CancellationToken,
     varmap: VarMap,
     up: TaskDetails,
     check: Option<TaskDetails>,
     output_options: TaskOutputOptions,
-    executor: mpsc::UnboundedSender<ExecutorMessage>,
-    storage: mpsc::UnboundedSender<StorageMessage>,
+    executor: mpsc::Sender<ExecutorMessage>,
+    storage: mpsc::Sender<StorageMessage>,
+    output: OutputTap,
 ) -> RunnerMessage {
     if let Some(check_cmd) = check.clone() {
-        let (_subkill, subkill_rx) = oneshot::channel();
-        let succeeded = run_task(
+        let attempt = run_task(
             task_name.clone(),
             interval,
             check_cmd.clone(),
             executor.clone(),
             storage.clone(),
-            subkill_rx,
+            kill.child_token(),
             &output_options,
             &varmap,
+            None,
+            AttemptKind::Up,
         )
         .await;
 
         // If check succeeded, resources are up
-        if succeeded {
+        if attempt.succeeded {
             return RunnerMessage::ActionCompleted {
                 action_id,
                 succeeded: true,
+                error: None,
+                infra_failure: false,
             };
         }
     }
 
+    // A killed action doesn't move on to its next command
+    if kill.is_cancelled() {
+        return RunnerMessage::ActionCompleted {
+            action_id,
+            succeeded: false,
+            error: None,
+            infra_failure: false,
+        };
+    }
+
     // UP
-    let (_subkill, subkill_rx) = oneshot::channel();
-    let succeeded = run_task(
+    let attempt = run_task(
         task_name.clone(),
         interval,
         up,
         executor.clone(),
         storage.clone(),
-        subkill_rx,
+        kill.child_token(),
         &output_options,
         &varmap,
+        Some(output),
+        AttemptKind::Up,
     )
     .await;
-    if !succeeded {
+    if !attempt.succeeded || kill.is_cancelled() {
         return RunnerMessage::ActionCompleted {
             action_id,
             succeeded: false,
+            error: Some(attempt.failure()),
+            infra_failure: attempt.infra_failure,
         };
     }
 
     // recheck
     if let Some(check_cmd) = check {
-        let (_subkill, subkill_rx) = oneshot::channel();
-        let succeeded = run_task(
+        let attempt = run_task(
             task_name.clone(),
             interval,
             check_cmd.clone(),
             executor.clone(),
             storage.clone(),
-            subkill_rx,
+            kill.child_token(),
             &output_options,
             &varmap,
+            None,
+            AttemptKind::Up,
         )
         .await;
 
         // If check succeeded, resources are up
-        if succeeded {
-            return RunnerMessage::ActionCompleted {
-                action_id,
-                succeeded: true,
-            };
-        } else {
-            return RunnerMessage::ActionCompleted {
-                action_id,
-                succeeded: false,
-            };
+        RunnerMessage::ActionCompleted {
+            action_id,
+            succeeded: attempt.succeeded,
+            error: (!attempt.succeeded).then(|| attempt.failure()),
+            infra_failure: attempt.infra_failure,
         }
     } else {
-        return RunnerMessage::ActionCompleted {
+        RunnerMessage::ActionCompleted {
             action_id,
             succeeded: true,
-        };
+            error: None,
+            infra_failure: false,
+        }
     }
 }
 
+/// Actions in these states are only run again when retried
+fn is_retryable(state: ActionState) -> bool {
+    matches!(
+        state,
+        ActionState::Errored | ActionState::Failed | ActionState::Skipped
+    )
+}
+
+/// Keeps the items of the tasks that are kept through a renumbering
+fn keep_renumbered<T>(items: &mut Vec<T>, renumbered: &[Option<usize>]) {
+    let mut tid = 0;
+    items.retain(|_| {
+        tid += 1;
+        renumbered[tid - 1].is_some()
+    });
+}
+
 fn delayed_event(delay: Duration, event: RunnerMessage) -> tokio::task::JoinHandle<RunnerMessage> {
     tokio::spawn(async move {
-        tokio::time::sleep(delay.to_std().unwrap()).await;
+        tokio::time::sleep(delay.to_std().unwrap_or_default()).await;
         event
     })
 }
@@ -272,60 +1042,173 @@ fn coalesce_actions(mut actions: Vec<Action>) -> Vec<Action> {
     res
 }
 
+/// How unhealthy an action's state is, for summarizing several actions by
+/// one state
+fn severity(state: ActionState) -> usize {
+    match state {
+        ActionState::Completed => 0,
+        ActionState::Skipped => 1,
+        ActionState::Queued => 2,
+        ActionState::Running => 3,
+        ActionState::Errored => 4,
+        ActionState::Failed => 5,
+    }
+}
+
+// Merges each task's actions into buckets of `seconds`
+fn bucket_actions(actions: Vec<Action>, seconds: i64) -> Vec<Action> {
+    let mut buckets: HashMap<(usize, i64), Action> = HashMap::new();
+    for action in actions {
+        let bucket = action.interval.start.timestamp().div_euclid(seconds);
+        buckets
+            .entry((action.task, bucket))
+            .and_modify(|merged| {
+                merged.interval = Interval::new(
+                    merged.interval.start.min(action.interval.start),
+                    merged.interval.end.max(action.interval.end),
+                );
+                if severity(action.state) > severity(merged.state) {
+                    merged.state = action.state;
+                }
+            })
+            .or_insert(action);
+    }
+    buckets.into_values().collect()
+}
+
 impl Runner {
+    /// Creates a runner and drives it on its own task, returning a handle
+    /// used to interact with it. If `stay_up` is false, the runner exits
+    /// once the end state is reached.
+    pub async fn spawn(
+        tasks: TaskSet,
+        vars: VarMap,
+        executor: mpsc::Sender<ExecutorMessage>,
+        storage: mpsc::Sender<StorageMessage>,
+        output_options: TaskOutputOptions,
+        force_check: bool,
+        stay_up: bool,
+    ) -> Result<RunnerHandle> {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let runner = Runner::new(
+            tasks,
+            vars,
+            rx,
+            executor,
+            storage,
+            output_options,
+            force_check,
+        )
+        .await?;
+        Ok(runner.into_handle(tx, stay_up))
+    }
+
+    /// Drives an already-created runner on its own task. `tx` must be the
+    /// sender for the `messages` the runner was created with.
+    pub fn into_handle(mut self, tx: mpsc::Sender<RunnerMessage>, stay_up: bool) -> RunnerHandle {
+        let join = tokio::spawn(async move {
+            self.run(stay_up).await;
+        });
+        RunnerHandle {
+            tx,
+            join: Arc::new(tokio::sync::Mutex::new(Some(join))),
+        }
+    }
+
     pub async fn new(
         tasks: TaskSet,
         vars: VarMap,
-        messages: mpsc::UnboundedReceiver<RunnerMessage>,
-        executor: mpsc::UnboundedSender<ExecutorMessage>,
-        storage: mpsc::UnboundedSender<StorageMessage>,
+        messages: mpsc::Receiver<RunnerMessage>,
+        executor: mpsc::Sender<ExecutorMessage>,
+        storage: mpsc::Sender<StorageMessage>,
         output_options: TaskOutputOptions,
         force_check: bool,
     ) -> Result<Self> {
         tasks.validate()?;
 
         // Validate the task commands can run on the executor
-        for tdef in tasks.iter() {
-            validate_cmd(executor.clone(), tdef.up.clone()).await?;
-            if let Some(cmd) = &tdef.down {
-                validate_cmd(executor.clone(), cmd.clone()).await?;
-            }
-            if let Some(cmd) = &tdef.check {
-                validate_cmd(executor.clone(), cmd.clone()).await?;
-            }
+        for task in tasks.iter() {
+            validate_task(&executor, task).await?;
         }
 
         // Load last-known state
-        let current = if force_check {
+        let (current, recovered) = if force_check {
             info!("Force re-check set, starting with empty current state.");
-            ResourceInterval::new()
+            (ResourceInterval::new(), Vec::new())
         } else {
             info!("Pulling last state from storage");
             let (response, rx) = oneshot::channel();
+            storage.send(StorageMessage::LoadState { response }).await?;
+            let res = rx
+                .await
+                .map_err(|_| Error::Storage("Unable to load the stored state".to_owned()))?;
+            let (response, rx) = oneshot::channel();
             storage
-                .send(StorageMessage::LoadState { response })
-                .unwrap();
-            let res = rx.await.unwrap();
-            res
+                .send(StorageMessage::LoadActions { response })
+                .await?;
+            let actions = rx
+                .await
+                .map_err(|_| Error::Storage("Unable to load the action ledger".to_owned()))?;
+            (res, actions)
         };
-        // let target = current.clone();
-        let target = ResourceInterval::new();
-
+        // New actions are numbered after the recovered ones, which keep
+        // their ids
+        let next_action = recovered
+            .iter()
+            .filter_map(|record| record.action_id)
+            .map(|action_id| action_id.0 + 1)
+            .max()
+            .unwrap_or(0);
         let end_state = tasks.coverage();
+        let cancel = CancellationToken::new();
+        let task_cancels = tasks.iter().map(|_| cancel.child_token()).collect();
+        let owned = vec![true; tasks.len()];
         let mut runner = Runner {
             tasks,
             vars,
             output_options,
             end_state,
-            target,
             current,
-            actions: Vec::new(),
+            actions: BTreeMap::new(),
+            next_action,
             qidx: 0,
+            action_history: Duration::try_days(DEFAULT_ACTION_HISTORY_DAYS).unwrap(),
+            compacted_at: Utc::now(),
+            cancel,
+            task_cancels,
+            action_cancels: HashMap::new(),
+            outputs: HashMap::new(),
+            killed: HashSet::new(),
+            registry: HashMap::new(),
+            shard: None,
+            owned,
+            retired: HashSet::new(),
+            remotes: HashMap::new(),
+            queried: ResourceInterval::new(),
+            produced: Production::new(),
+            breaker: None,
+            max_running: None,
+            backfill: None,
+            paused: HashSet::new(),
+            failures: HashMap::new(),
+            retry_at: HashMap::new(),
+            recovered,
+            fallbacks: HashSet::new(),
+            aliases: HashMap::new(),
             events: FuturesUnordered::new(),
+            alerted: HashSet::new(),
+            rechecked: HashMap::new(),
+            rechecking: HashSet::new(),
+            notifications: None,
+            event_stream: EventStream::new(),
+            started: Utc::now(),
             last_horizon: DateTime::<Utc>::MIN_UTC,
             messages,
             executor,
             storage,
+            state_unstored: false,
+            actions_unstored: false,
+            lagging: false,
         };
 
         runner.update_target();
@@ -333,45 +1216,720 @@ impl Runner {
         Ok(runner)
     }
 
-    // Generate a new target state and generate any required actions
-    pub fn update_target(&mut self) {
-        let new_target = self
+    /// Restricts the runner to the tasks providing the shard's resources,
+    /// and claims those resources in storage. Must be called before the
+    /// runner is run. The runner's unsharded state isn't used, so it should
+    /// be created with `force_check`; `force_check` here controls whether
+    /// the shard's own stored state is loaded.
+    pub async fn set_shard(&mut self, shard: Shard, force_check: bool) -> Result<()> {
+        let owned = shard.owned_tasks(&self.tasks)?;
+        let resources: HashSet<Resource> = self
             .tasks
-            .get_state(Utc::now() + Duration::try_days(1).unwrap());
-        let new_required = new_target.difference(&self.target);
-        let mut new_actions =
-            self.tasks
+            .iter()
+            .zip(owned.iter())
+            .filter(|(_, owned)| **owned)
+            .flat_map(|(task, _)| task.provides.iter().cloned())
+            .collect();
+
+        let (response, rx) = oneshot::channel();
+        self.storage
+            .send(StorageMessage::ClaimResources {
+                shard: shard.name.clone(),
+                resources,
+                response,
+            })
+            .await
+            .map_err(|e| Error::Channel(e.to_string()))?;
+        rx.await??;
+
+        info!(
+            "Running shard {} with {} of {} tasks",
+            shard.name,
+            owned.iter().filter(|x| **x).count(),
+            owned.len()
+        );
+        let event_stream = &mut self.event_stream;
+        self.actions.retain(|action_id, a| {
+            if !owned[a.task] {
+                event_stream.changed(*action_id);
+            }
+            owned[a.task]
+        });
+        self.registry = self
+            .actions
+            .iter()
+            .map(|(action_id, a)| ((a.task, a.interval), *action_id))
+            .collect();
+        self.owned = owned;
+        self.shard = Some(shard);
+        // The ledger isn't kept per shard
+        self.recovered.clear();
+
+        let (response, rx) = oneshot::channel();
+        self.storage
+            .send(StorageMessage::LoadShardStates { response })
+            .await
+            .map_err(|e| Error::Channel(e.to_string()))?;
+        self.current = ResourceInterval::new();
+        self.merge_shard_states(rx.await??, !force_check);
+        self.rederive_action_states();
+        Ok(())
+    }
+
+    /// Sets the old names of renamed resources. The loaded state is migrated
+    /// to the canonical names, and messages may refer to either.
+    pub fn set_resource_aliases(&mut self, aliases: HashMap<Resource, Resource>) {
+        self.aliases = aliases;
+        if self
+            .aliases
+            .keys()
+            .any(|alias| self.current.contains_key(alias))
+        {
+            info!("Migrating state of renamed resources");
+            self.current.rename(&self.aliases);
+            self.rederive_action_states();
+            self.store_state();
+        }
+    }
+
+    fn canonical(&self, resource: Resource) -> Resource {
+        self.aliases.get(&resource).cloned().unwrap_or(resource)
+    }
+
+    /// Holds retries when failures across all tasks spike
+    pub fn set_circuit_breaker(&mut self, config: CircuitBreakerConfig) {
+        self.breaker = Some(CircuitBreaker::new(config));
+    }
+
+    /// Posts lifecycle events, like actions failing or missing their
+    /// deadline, to the channel
+    pub fn set_notifications(&mut self, notifications: mpsc::Sender<Notification>) {
+        self.notifications = Some(notifications);
+    }
+
+    fn notify(&self, notification: Notification) {
+        post(&self.notifications, &self.event_stream, notification);
+    }
+
+    /// Caps how many actions may be running at once. Runnable actions past
+    /// the cap stay queued until others finish.
+    pub fn set_max_running(&mut self, limit: usize) {
+        self.max_running = Some(limit);
+    }
+
+    /// Limits how many actions for intervals that ended a while ago are
+    /// launched a minute. Intervals that just ended aren't held back.
+    pub fn set_backfill(&mut self, config: BackfillConfig) {
+        match &mut self.backfill {
+            Some(throttle) => throttle.reconfigure(config),
+            None => self.backfill = Some(BackfillThrottle::new(config)),
+        }
+    }
+
+    /// Merges the states stored by shards into the current state. Each
+    /// shard is only trusted for the resources it owns.
+    fn merge_shard_states(&mut self, states: ShardStates, include_own: bool) {
+        let shard = match &self.shard {
+            Some(shard) => shard,
+            None => return,
+        };
+        for (name, mut state) in states {
+            if name == shard.name && !include_own {
+                continue;
+            }
+            state.rename(&self.aliases);
+            for (res, is) in state.iter() {
+                if (name == shard.name) == shard.owns(res) {
+                    *self.current.entry(res.clone()).or_default() = is.clone();
+                }
+            }
+        }
+    }
+
+    /// Sets the state of every action that isn't running from the
+    /// current state
+    fn rederive_action_states(&mut self) {
+        for (action_id, action) in self.actions.iter_mut() {
+            if action.state == ActionState::Running {
+                continue;
+            }
+            self.event_stream.changed(*action_id);
+            let task = &self.tasks[action.task];
+            let is_up = task.provides.iter().all(|res| {
+                self.current
+                    .get(res)
+                    .map(|is| is.has_subset(action.interval))
+                    .unwrap_or(false)
+            });
+            if is_up {
+                action.state = ActionState::Completed;
+            } else if action.state == ActionState::Completed {
+                action.state = ActionState::Queued;
+            }
+        }
+    }
+
+    fn refresh_shards(&mut self) {
+        let storage = self.storage.clone();
+        self.events.push(tokio::spawn(async move {
+            let (response, rx) = oneshot::channel();
+            storage
+                .send(StorageMessage::LoadShardStates { response })
+                .await
+                .unwrap_or(());
+            RunnerMessage::ShardStatesLoaded {
+                states: rx.await.map_err(Error::from).and_then(|res| res),
+            }
+        }));
+    }
+
+    fn refresh_remotes(&mut self) {
+        let remotes: HashSet<String> = self
+            .tasks
+            .iter()
+            .flat_map(|task| task.requires.iter().flat_map(|req| req.remotes()))
+            .collect();
+        if remotes.is_empty() {
+            return;
+        }
+        self.events.push(tokio::spawn(async move {
+            // A slow remote doesn't hold up the others
+            let states = futures::future::join_all(remotes.into_iter().map(|remote| async move {
+                let state = match fetch_state(&remote).await {
+                    Ok(state) => Some(state),
+                    Err(e) => {
+                        warn!("{}", e);
+                        None
+                    }
+                };
+                (remote, state)
+            }))
+            .await
+            .into_iter()
+            .collect();
+            RunnerMessage::RemoteStatesLoaded { states }
+        }));
+    }
+
+    /// The resources requirements are evaluated against: the current
+    /// state, plus the cached state of any remotes, the results of SQL
+    /// queries, and the intervals fresh enough for freshness requirements
+    fn available(&self) -> Cow<'_, ResourceInterval> {
+        let freshness: HashSet<(Resource, u64)> = self
+            .tasks
+            .iter()
+            .flat_map(|task| task.requires.iter().flat_map(|req| req.freshness()))
+            .collect();
+        if self.remotes.is_empty() && self.queried.is_empty() && freshness.is_empty() {
+            Cow::Borrowed(&self.current)
+        } else {
+            Cow::Owned(
+                self.current
+                    .union(&federation::available(&self.remotes))
+                    .union(&self.queried)
+                    .union(&self.produced.available(&freshness, Utc::now())),
+            )
+        }
+    }
+
+    /// Reads back when the resources freshness requirements refer to were
+    /// produced, from their providers' stored attempts
+    fn load_production(&mut self) {
+        let resources: HashSet<Resource> = self
+            .tasks
+            .iter()
+            .flat_map(|task| task.requires.iter().flat_map(|req| req.freshness()))
+            .map(|(resource, _)| resource)
+            .collect();
+        let task_names: HashSet<String> = self
+            .tasks
+            .iter()
+            .filter(|task| task.provides.iter().any(|res| resources.contains(res)))
+            .map(|task| task.name.clone())
+            .collect();
+        if task_names.is_empty() {
+            return;
+        }
+        let storage = self.storage.clone();
+        self.events.push(tokio::spawn(async move {
+            let mut attempts = HashMap::new();
+            for task_name in task_names {
+                let (response, rx) = oneshot::channel();
+                storage
+                    .send(StorageMessage::GetRecentAttempts {
+                        task_name: task_name.clone(),
+                        max_attempts: FRESHNESS_ATTEMPTS,
+                        response,
+                    })
+                    .await
+                    .unwrap_or(());
+                attempts.insert(task_name, rx.await.unwrap_or_default());
+            }
+            RunnerMessage::AttemptsLoaded { attempts }
+        }));
+    }
+
+    /// Runs the SQL requirements of queued actions that haven't been
+    /// satisfied yet
+    fn check_queries(&mut self) {
+        let now = Utc::now();
+        let mut checks = Vec::new();
+        let mut any_queries = false;
+        for action in self.actions.values() {
+            let task = &self.tasks[action.task];
+            let queries: HashSet<(String, String)> =
+                task.requires.iter().flat_map(|req| req.queries()).collect();
+            any_queries |= !queries.is_empty();
+            if action.state != ActionState::Queued
+                || action.interval.end > now
+                || self.retired.contains(&action.task)
+            {
+                continue;
+            }
+            for (database, query) in queries {
+                let check = SqlCheck::new(&database, &query, action.interval, task.timezone);
+                if !self
+                    .queried
+                    .get(&check.key)
+                    .is_some_and(|is| is.has_subset(action.interval))
+                {
+                    checks.push(check);
+                }
+            }
+        }
+        if !any_queries {
+            return;
+        }
+        self.events.push(tokio::spawn(async move {
+            RunnerMessage::QueriesChecked {
+                satisfied: run_checks(checks).await,
+            }
+        }));
+    }
+
+    /// Advances the horizon to a day from now, generating the actions of
+    /// the intervals that end before it. Only each task's intervals since
+    /// the last horizon are generated, and actions that already exist
+    /// aren't added again, so it's cheap and safe to call every tick.
+    pub fn update_target(&mut self) {
+        let horizon = Utc::now() + Duration::try_days(1).unwrap();
+        if horizon <= self.last_horizon {
+            return;
+        }
+        let mut new_actions = Vec::new();
+        for tid in (0..self.tasks.len()).filter(|tid| self.is_active(*tid)) {
+            let task = &self.tasks[tid];
+            let gained = task
+                .timeline(horizon)
+                .difference(&task.timeline(self.last_horizon));
+            if gained.is_empty() {
+                continue;
+            }
+            let required: ResourceInterval = task
+                .provides
                 .iter()
-                .enumerate()
-                .fold(Vec::new(), |mut acc, (idx, task)| {
-                    let get_state = |intv: Interval| {
-                        if task.provides.iter().all(|res| {
-                            self.current.contains_key(res) && self.current[res].has_subset(intv)
-                        }) {
-                            ActionState::Completed
-                        } else {
-                            ActionState::Queued
-                        }
-                    };
-                    let res: Vec<Action> = task
-                        .generate_intervals(&new_required)
-                        .unwrap()
-                        .into_iter()
-                        .map({
-                            |interval| Action {
-                                task: idx,
-                                interval,
-                                state: get_state(interval),
-                            }
-                        })
-                        .collect();
-                    acc.extend(res);
-                    acc
+                .map(|res| (res.clone(), gained.clone()))
+                .collect::<HashMap<_, _>>()
+                .into();
+            new_actions.extend(self.generate_actions(&[tid], &required));
+        }
+        self.last_horizon = horizon;
+
+        let added = self.add_actions(new_actions);
+        if added > 0 {
+            info!("Generated {} new actions", added);
+        }
+    }
+
+    /// Appends the actions for any (task, interval) that doesn't have one
+    /// yet, returning how many were added. Completed actions older than
+    /// the action history would only be compacted away again, so they
+    /// aren't added.
+    fn add_actions(&mut self, actions: Vec<Action>) -> usize {
+        let cutoff = self.history_cutoff(Utc::now());
+        let mut added = 0;
+        for action in actions {
+            let key = (action.task, action.interval);
+            if self.registry.contains_key(&key)
+                || (action.state == ActionState::Completed && action.interval.end < cutoff)
+            {
+                continue;
+            }
+            self.insert_action(action);
+            added += 1;
+        }
+        added
+    }
+
+    /// Adds an action under a new id
+    fn insert_action(&mut self, action: Action) -> ActionId {
+        let action_id = ActionId(self.next_action);
+        self.next_action += 1;
+        self.registry
+            .insert((action.task, action.interval), action_id);
+        self.actions.insert(action_id, action);
+        self.event_stream.changed(action_id);
+        action_id
+    }
+
+    /// Completed actions for intervals that ended before this are compacted
+    fn history_cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now.checked_sub_signed(self.action_history)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC)
+    }
+
+    /// Keeps completed actions for intervals that ended within `history`.
+    /// Older ones are only kept as part of the current state.
+    pub fn set_action_history(&mut self, history: Duration) {
+        self.action_history = history;
+    }
+
+    /// Drops the completed actions that ended before the action history,
+    /// along with everything kept about them. They're already part of the
+    /// current state, so nothing is lost but the action itself.
+    fn compact_actions(&mut self, now: DateTime<Utc>) {
+        let cutoff = self.history_cutoff(now);
+        let compacted: Vec<ActionId> = self
+            .actions
+            .iter()
+            .filter(|(action_id, action)| {
+                action.state == ActionState::Completed
+                    && action.interval.end < cutoff
+                    && !self.action_cancels.contains_key(action_id)
+                    && !self.rechecking.contains(action_id)
+            })
+            .map(|(action_id, _)| *action_id)
+            .collect();
+        for action_id in &compacted {
+            let action = self.actions.remove(action_id).unwrap();
+            self.event_stream.changed(*action_id);
+            self.registry.remove(&(action.task, action.interval));
+            self.failures.remove(action_id);
+            self.retry_at.remove(action_id);
+            self.fallbacks.remove(action_id);
+            self.alerted.remove(action_id);
+            self.killed.remove(action_id);
+            self.outputs.remove(action_id);
+        }
+        self.compacted_at = now;
+        if !compacted.is_empty() {
+            info!(
+                "Compacted {} completed actions, keeping {}",
+                compacted.len(),
+                self.actions.len()
+            );
+        }
+    }
+
+    /// Drops retired tasks that have nothing running anymore, along with
+    /// their actions, renumbering the tasks that are left. A shard releases
+    /// the resources no task it runs provides anymore.
+    fn prune_retired(&mut self) {
+        let busy: HashSet<usize> = self
+            .actions
+            .iter()
+            .filter(|(action_id, _)| {
+                self.action_cancels.contains_key(action_id) || self.rechecking.contains(action_id)
+            })
+            .map(|(_, action)| action.task)
+            .collect();
+        let pruned: HashSet<usize> = self
+            .retired
+            .iter()
+            .filter(|tid| !busy.contains(tid))
+            .copied()
+            .collect();
+        if pruned.is_empty() {
+            return;
+        }
+
+        let dropped: Vec<ActionId> = self
+            .actions
+            .iter()
+            .filter(|(_, action)| pruned.contains(&action.task))
+            .map(|(action_id, _)| *action_id)
+            .collect();
+        for action_id in &dropped {
+            self.actions.remove(action_id);
+            self.event_stream.changed(*action_id);
+            self.failures.remove(action_id);
+            self.retry_at.remove(action_id);
+            self.fallbacks.remove(action_id);
+            self.alerted.remove(action_id);
+            self.killed.remove(action_id);
+            self.outputs.remove(action_id);
+        }
+
+        let mut renumbered = Vec::with_capacity(self.tasks.len());
+        let mut next = 0;
+        for tid in 0..self.tasks.len() {
+            if pruned.contains(&tid) {
+                renumbered.push(None);
+            } else {
+                renumbered.push(Some(next));
+                next += 1;
+            }
+        }
+        let released: HashSet<Resource> = pruned
+            .iter()
+            .flat_map(|tid| self.tasks[*tid].provides.iter().cloned())
+            .collect();
+        keep_renumbered(&mut self.tasks, &renumbered);
+        keep_renumbered(&mut self.owned, &renumbered);
+        keep_renumbered(&mut self.task_cancels, &renumbered);
+        for action in self.actions.values_mut() {
+            action.task = renumbered[action.task].unwrap();
+        }
+        self.registry = self
+            .actions
+            .iter()
+            .map(|(action_id, a)| ((a.task, a.interval), *action_id))
+            .collect();
+        self.retired = self
+            .retired
+            .iter()
+            .filter_map(|tid| renumbered[*tid])
+            .collect();
+        self.rechecked = self
+            .rechecked
+            .drain()
+            .filter_map(|(tid, at)| renumbered[tid].map(|tid| (tid, at)))
+            .collect();
+        if let Some(breaker) = &mut self.breaker {
+            breaker.renumber(&renumbered);
+        }
+        info!(
+            "Dropped {} retired tasks and {} of their actions",
+            pruned.len(),
+            dropped.len()
+        );
+
+        if let Some(shard) = &self.shard {
+            let provided: HashSet<&Resource> = (0..self.tasks.len())
+                .filter(|tid| self.is_active(*tid))
+                .flat_map(|tid| self.tasks[tid].provides.iter())
+                .collect();
+            let resources: HashSet<Resource> = released
+                .into_iter()
+                .filter(|res| !provided.contains(res))
+                .collect();
+            if !resources.is_empty() {
+                let storage = self.storage.clone();
+                let shard = shard.name.clone();
+                tokio::spawn(async move {
+                    storage
+                        .send(StorageMessage::ReleaseResources { shard, resources })
+                        .await
+                        .unwrap_or(());
+                });
+            }
+        }
+    }
+
+    /// Adds back the actions of `tid` over `is` that were compacted, so
+    /// they can be taken down or rerun like any other
+    fn restore_actions(&mut self, tid: usize, is: &IntervalSet) {
+        let required: ResourceInterval = self.tasks[tid]
+            .provides
+            .iter()
+            .map(|res| (res.clone(), is.clone()))
+            .collect::<HashMap<_, _>>()
+            .into();
+        for action in self.generate_actions(&[tid], &required) {
+            if !self.registry.contains_key(&(action.task, action.interval)) {
+                self.insert_action(action);
+            }
+        }
+    }
+
+    /// Tasks this runner runs: owned by its shard, and not retired
+    fn is_active(&self, tid: usize) -> bool {
+        self.owned[tid] && !self.retired.contains(&tid)
+    }
+
+    /// The current version of the named task
+    fn task_id(&self, task_name: &str) -> Option<usize> {
+        (0..self.tasks.len())
+            .rev()
+            .find(|tid| !self.retired.contains(tid) && self.tasks[*tid].name == task_name)
+    }
+
+    /// The tasks that haven't been retired, along with their indices
+    fn current_tasks(&self) -> (TaskSet, Vec<usize>) {
+        let tids: Vec<usize> = (0..self.tasks.len())
+            .filter(|tid| !self.retired.contains(tid))
+            .collect();
+        let tasks: Vec<Task> = tids.iter().map(|tid| self.tasks[*tid].clone()).collect();
+        (TaskSet::from(tasks), tids)
+    }
+
+    /// Actions for the intervals of the tasks covering `required`, ordered
+    /// by interval end
+    fn generate_actions(&self, tids: &[usize], required: &ResourceInterval) -> Vec<Action> {
+        let mut new_actions = tids.iter().map(|idx| (*idx, &self.tasks[*idx])).fold(
+            Vec::new(),
+            |mut acc, (idx, task)| {
+                let get_state = |intv: Interval| {
+                    if task.provides.iter().all(|res| {
+                        self.current.contains_key(res) && self.current[res].has_subset(intv)
+                    }) {
+                        ActionState::Completed
+                    } else {
+                        ActionState::Queued
+                    }
+                };
+                let intervals = task.generate_intervals(required).unwrap_or_else(|e| {
+                    error!(task_name = %task.name, error = %e, "Unable to generate intervals");
+                    Vec::new()
                 });
+                let res: Vec<Action> = intervals
+                    .into_iter()
+                    .map({
+                        |interval| Action {
+                            task: idx,
+                            interval,
+                            state: get_state(interval),
+                        }
+                    })
+                    .collect();
+                acc.extend(res);
+                acc
+            },
+        );
         new_actions.sort_unstable_by(|a, b| a.interval.end.partial_cmp(&b.interval.end).unwrap());
+        new_actions
+    }
+
+    async fn reload_world(&mut self, definition: WorldDefinition) -> Result<()> {
+        let tasks = definition.taskset()?;
+        for warning in definition.warnings() {
+            warn!("{}", warning);
+        }
+        for task in tasks.iter() {
+            validate_task(&self.executor, task).await?;
+        }
+        let owned = match &self.shard {
+            Some(shard) => shard.owned_tasks(&tasks)?,
+            None => vec![true; tasks.len()],
+        };
+
+        let mut existing: HashMap<String, usize> = (0..self.tasks.len())
+            .filter(|tid| !self.retired.contains(tid))
+            .map(|tid| (self.tasks[tid].name.clone(), tid))
+            .collect();
+        let mut retire = Vec::new();
+        let mut add = Vec::new();
+        let mut replaced = Vec::new();
+        for (task, owned) in tasks.iter().zip(owned) {
+            match existing.remove(&task.name) {
+                Some(tid) if self.tasks[tid] == *task => {}
+                Some(tid) => {
+                    retire.push(tid);
+                    replaced.push((tid, task.valid_over.clone()));
+                    add.push((task.clone(), owned));
+                }
+                None => add.push((task.clone(), owned)),
+            }
+        }
+        retire.extend(existing.into_values());
+
+        if let Some(shard) = &self.shard {
+            let resources: HashSet<Resource> = add
+                .iter()
+                .filter(|(_, owned)| *owned)
+                .flat_map(|(task, _)| task.provides.iter().cloned())
+                .collect();
+            let (response, rx) = oneshot::channel();
+            self.storage
+                .send(StorageMessage::ClaimResources {
+                    shard: shard.name.clone(),
+                    resources,
+                    response,
+                })
+                .await
+                .map_err(|e| Error::Channel(e.to_string()))?;
+            rx.await??;
+        }
+
+        info!(
+            "Reloading world: {} tasks added or changed, {} removed or changed",
+            add.len(),
+            retire.len()
+        );
+        for tid in retire {
+            self.retired.insert(tid);
+            self.task_cancels[tid].cancel();
+        }
+
+        // Completed intervals a task is no longer valid over are taken down
+        // and cleaned up with the down command that went with them
+        for (tid, valid_over) in &replaced {
+            if self.tasks[*tid].down.is_some() {
+                let lost = self.tasks[*tid]
+                    .timeline(self.last_horizon)
+                    .difference(valid_over);
+                self.restore_actions(*tid, &lost);
+            }
+        }
+        let stale: Vec<(usize, Interval)> = replaced
+            .iter()
+            .filter(|(tid, _)| self.tasks[*tid].down.is_some())
+            .flat_map(|(tid, valid_over)| {
+                self.actions
+                    .values()
+                    .filter(move |action| {
+                        action.task == *tid
+                            && action.state == ActionState::Completed
+                            && !valid_over.has_subset(action.interval)
+                    })
+                    .map(move |action| (*tid, action.interval))
+            })
+            .collect();
+        for (tid, interval) in &stale {
+            let is = IntervalSet::from(*interval);
+            for resource in &self.tasks[*tid].provides {
+                info!(resource, interval = %interval, "No longer valid, taking down");
+                if let Some(current) = self.current.get_mut(resource) {
+                    current.subtract(&is);
+                }
+            }
+            self.run_down(*tid, *interval, None);
+        }
+        if !stale.is_empty() {
+            self.store_state();
+        }
+        let first = self.tasks.len();
+        for (task, owned) in add {
+            self.tasks.push(task);
+            self.owned.push(owned);
+            self.task_cancels.push(self.cancel.child_token());
+        }
+        self.vars = definition.variables;
+        self.set_resource_aliases(definition.resource_aliases);
+        self.max_running = definition.max_running;
+        match definition.backfill {
+            Some(config) => self.set_backfill(config),
+            None => self.backfill = None,
+        }
+        self.action_history = Duration::try_days(
+            definition
+                .action_history_days
+                .unwrap_or(DEFAULT_ACTION_HISTORY_DAYS),
+        )
+        .unwrap();
+        self.end_state = self.current_tasks().0.coverage();
 
-        info!("Tick: Generated {} new actions", new_actions.len());
-        self.actions.extend(new_actions);
+        let tids: Vec<usize> = (first..self.tasks.len())
+            .filter(|tid| self.owned[*tid])
+            .collect();
+        let target = self
+            .tasks
+            .get_state(Utc::now() + Duration::try_days(1).unwrap());
+        let new_actions = self.generate_actions(&tids, &target);
+        self.add_actions(new_actions);
+        self.store_actions();
+        self.queue_actions();
+        Ok(())
     }
 
     fn tick(&mut self) {
@@ -393,7 +1951,37 @@ impl Runner {
         */
 
         // Perform maintenance
+        if let Some(breaker) = &mut self.breaker {
+            for action_id in breaker.release(Utc::now()) {
+                // Skipped while it was held
+                let Some(action) = self.actions.get_mut(&action_id) else {
+                    continue;
+                };
+                if action.state != ActionState::Errored {
+                    continue;
+                }
+                info!(%action_id, "Retrying held action");
+                action.state = ActionState::Queued;
+                self.event_stream.changed(action_id);
+                self.retry_at.remove(&action_id);
+            }
+        }
+        if Utc::now() - self.compacted_at >= Duration::try_seconds(COMPACTION_SECONDS).unwrap() {
+            self.compact_actions(Utc::now());
+        }
+        if !self.retired.is_empty() {
+            self.prune_retired();
+        }
+        if self.state_unstored {
+            self.store_state();
+        }
+        if self.actions_unstored {
+            self.store_actions();
+        }
+        self.update_target();
         self.queue_actions();
+        self.check_deadlines(Utc::now());
+        self.recheck(Utc::now());
 
         self.events.push(delayed_event(
             Duration::try_milliseconds(250).unwrap(),
@@ -415,37 +2003,86 @@ impl Runner {
     fn get_resource_state_details(
         &self,
         interval: Interval,
-        response: oneshot::Sender<ResourceStateDetails>,
-        max_intervals: Option<usize>,
-    ) {
-        // HashMap<Resource, HashMap<String, Vec<(DateTime<Utc>, DateTime<Utc>, ActionState)>>>;
-        let mut res: ResourceStateDetails = HashMap::new();
-
-        let all_resources: HashSet<Resource> =
-            self.tasks.iter().fold(HashSet::new(), |mut acc, t| {
-                acc.extend(t.provides.clone());
-                acc
-            });
+        options: &DetailsOptions,
+    ) -> DetailsPage {
+        let (tasks, tids) = self.current_tasks();
+        let wanted = |resource: &Resource| {
+            options
+                .resources
+                .as_ref()
+                .is_none_or(|resources| resources.contains(resource))
+        };
+        let grouped = |task: &Task| {
+            options
+                .groups
+                .as_ref()
+                .is_none_or(|groups| groups.iter().any(|group| task.in_group(group)))
+        };
 
         // Build out the hash
-        for resource in all_resources {
-            let mut res_ints = HashMap::new();
-            for task in self.tasks.iter() {
-                if task.provides.contains(&resource) {
-                    res_ints.insert(task.name.clone(), Vec::new());
-                }
+        let mut res: ResourceStateDetails = HashMap::new();
+        let mut groups = HashMap::new();
+        for task in tasks.iter().filter(|t| grouped(t)) {
+            if let Some(group) = &task.group {
+                groups.insert(task.name.clone(), group.clone());
+            }
+            for resource in task.provides.iter().filter(|r| wanted(r)) {
+                res.entry(resource.clone())
+                    .or_default()
+                    .insert(task.name.clone(), Vec::new());
             }
-            res.insert(resource.clone(), res_ints);
         }
 
         let mut actions: Vec<Action> = self
             .actions
-            .iter()
-            .filter(|x| interval.is_contiguous(x.interval))
+            .values()
+            .filter(|x| {
+                !self.retired.contains(&x.task)
+                    && interval.is_contiguous(x.interval)
+                    && grouped(&self.tasks[x.task])
+                    && self.tasks[x.task].provides.iter().any(wanted)
+            })
             .cloned()
             .collect();
 
-        if let Some(max_intv) = max_intervals {
+        // Compacted actions are rebuilt from the current state
+        let cutoff = self.history_cutoff(Utc::now());
+        for tid in tids {
+            let task = &self.tasks[tid];
+            if !grouped(task) || !task.provides.iter().any(wanted) {
+                continue;
+            }
+            let span = task
+                .timeline(cutoff)
+                .intersection(&IntervalSet::from(interval));
+            if span.is_empty() {
+                continue;
+            }
+            let required: ResourceInterval = task
+                .provides
+                .iter()
+                .map(|res| (res.clone(), span.clone()))
+                .collect::<HashMap<_, _>>()
+                .into();
+            actions.extend(
+                task.generate_intervals(&required)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|intv| {
+                        !self.registry.contains_key(&(tid, *intv)) && self.is_up(tid, *intv)
+                    })
+                    .map(|interval| Action {
+                        task: tid,
+                        interval,
+                        state: ActionState::Completed,
+                    }),
+            );
+        }
+
+        if let Some(seconds) = options.bucket_seconds.filter(|s| *s > 0) {
+            actions = bucket_actions(actions, seconds);
+        }
+        if let Some(max_intv) = options.max_intervals {
             if actions.len() > max_intv {
                 actions = coalesce_actions(actions);
             }
@@ -457,9 +2094,27 @@ impl Runner {
             actions.len()
         );
 
-        for action in actions {
-            let task = &self.tasks[action.task];
-            for resource in &task.provides {
+        let cursor = |action: &Action| DetailsCursor {
+            task_name: self.tasks[action.task].name.clone(),
+            start: action.interval.start,
+        };
+        let mut actions: Vec<(DetailsCursor, Action)> = actions
+            .into_iter()
+            .map(|action| (cursor(&action), action))
+            .filter(|(key, _)| options.cursor.as_ref().is_none_or(|after| key > after))
+            .collect();
+        actions.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut next_cursor = None;
+        if let Some(limit) = options.limit {
+            if actions.len() > limit {
+                actions.truncate(limit);
+                next_cursor = actions.last().map(|(key, _)| key.clone());
+            }
+        }
+
+        for (_, action) in actions {
+            let task = &self.tasks[action.task];
+            for resource in task.provides.iter().filter(|r| wanted(r)) {
                 res.get_mut(resource)
                     .unwrap()
                     .get_mut(&task.name)
@@ -468,163 +2123,1284 @@ impl Runner {
             }
         }
 
-        response.send(res).unwrap();
+        DetailsPage {
+            details: res,
+            groups,
+            next_cursor,
+        }
+    }
+
+    fn task_overview(&self, task_name: &str, max_intervals: usize) -> Option<TaskOverview> {
+        let tid = self.task_id(task_name)?;
+        let task = &self.tasks[tid];
+
+        let now = Utc::now();
+        let mut upcoming = Vec::new();
+        let mut offset = 0;
+        let valid_end = task.valid_over.end().unwrap_or(MIN_TIME);
+        while upcoming.len() < max_intervals {
+            let interval = task.schedule.interval(now, offset);
+            if interval.end > valid_end {
+                break;
+            }
+            // Skip over excluded windows
+            if task.valid_over.has_subset(interval) {
+                upcoming.push(interval);
+            }
+            offset += 1;
+        }
+
+        let available = self.available();
+        let mut pending: Vec<PendingInterval> = self
+            .actions
+            .iter()
+            .filter(|(_, a)| a.task == tid && a.state != ActionState::Completed)
+            .map(|(&action_id, a)| PendingInterval {
+                action_id,
+                interval: a.interval,
+                state: a.state,
+                unmet_requirements: task
+                    .requires
+                    .iter()
+                    .filter(|req| !req.is_satisfied(a.interval, &task.schedule, &available))
+                    .cloned()
+                    .collect(),
+            })
+            .collect();
+        pending.sort_unstable_by_key(|x| x.interval);
+        pending.truncate(max_intervals);
+
+        Some(TaskOverview {
+            name: task.name.clone(),
+            group: task.group.clone(),
+            provides: task.provides.clone(),
+            requires: task.requires.clone(),
+            timezone: task.timezone,
+            valid_over: task.valid_over.clone(),
+            upcoming,
+            pending,
+            paused: self.paused.contains(&task.name),
+        })
+    }
+
+    /// The root of the runner's kill switches. Cancelling it kills every
+    /// running action and stops the runner.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Kills the running actions of a task
+    fn cancel_task(&mut self, tid: usize) {
+        self.task_cancels[tid].cancel();
+        self.task_cancels[tid] = self.cancel.child_token();
+    }
+
+    fn set_paused(&mut self, task_name: &str, paused: bool) -> Result<()> {
+        if self.task_id(task_name).is_none() {
+            return Err(Error::Validation(format!("No such task {}", task_name)));
+        }
+        if paused {
+            info!(task_name, "Pausing task");
+            self.paused.insert(task_name.to_owned());
+        } else {
+            info!(task_name, "Resuming task");
+            self.paused.remove(task_name);
+        }
+        Ok(())
+    }
+
+    /// Pauses or resumes every task in a group, returning their names
+    fn set_group_paused(&mut self, group: &str, paused: bool) -> Result<Vec<String>> {
+        let mut names: Vec<String> = (0..self.tasks.len())
+            .filter(|tid| !self.retired.contains(tid) && self.tasks[*tid].in_group(group))
+            .map(|tid| self.tasks[tid].name.clone())
+            .collect();
+        if names.is_empty() {
+            return Err(Error::Validation(format!("No tasks in group {}", group)));
+        }
+        names.sort();
+        for name in &names {
+            self.set_paused(name, paused)?;
+        }
+        Ok(names)
+    }
+
+    /// The canonical names of the resources, and the tasks providing only
+    /// resources among them. Fails unless every resource is provided by a
+    /// task, and at least one task would be forced.
+    fn forced_tasks(
+        &self,
+        resources: HashSet<Resource>,
+        interval: Interval,
+    ) -> Result<(HashSet<Resource>, Vec<usize>)> {
+        if interval.start >= interval.end {
+            return Err(Error::Validation(format!(
+                "Interval {} is empty or backwards",
+                interval
+            )));
+        }
+        let resources: HashSet<Resource> =
+            resources.into_iter().map(|r| self.canonical(r)).collect();
+        let tids: Vec<usize> = (0..self.tasks.len())
+            .filter(|tid| self.is_active(*tid))
+            .collect();
+        if let Some(res) = resources.iter().find(|res| {
+            !tids
+                .iter()
+                .any(|tid| self.tasks[*tid].provides.contains(*res))
+        }) {
+            return Err(Error::Validation(format!("No task provides {}", res)));
+        }
+        let tids: Vec<usize> = tids
+            .into_iter()
+            .filter(|tid| self.tasks[*tid].provides.is_subset(&resources))
+            .collect();
+        if tids.is_empty() {
+            return Err(Error::Validation(
+                "Every task providing the resources also provides others, so none can be forced"
+                    .to_owned(),
+            ));
+        }
+        Ok((resources, tids))
+    }
+
+    fn force_up(
+        &mut self,
+        resources: HashSet<Resource>,
+        interval: Interval,
+    ) -> Result<Vec<ForcedInterval>> {
+        let (_, tids) = self.forced_tasks(resources, interval)?;
+        let mut forced = Vec::new();
+        for tid in tids {
+            let task = &self.tasks[tid];
+            let aligned = task.schedule.align_interval(interval);
+            let aligned_is = IntervalSet::from(aligned);
+            for resource in &task.provides {
+                info!(resource, interval = %aligned, "Forcing up");
+                self.current
+                    .entry(resource.clone())
+                    .or_default()
+                    .merge(&aligned_is);
+            }
+            for (action_id, action) in self.actions.iter_mut() {
+                if action.task == tid && aligned_is.has_subset(action.interval) {
+                    action.state = ActionState::Completed;
+                    self.event_stream.changed(*action_id);
+                }
+            }
+            forced.push(ForcedInterval {
+                task_name: task.name.clone(),
+                interval: aligned,
+            });
+        }
+        self.store_state();
+        self.store_actions();
+        Ok(forced)
+    }
+
+    fn force_down(
+        &mut self,
+        resources: HashSet<Resource>,
+        interval: Interval,
+    ) -> Result<Vec<ForcedInterval>> {
+        let (resources, tids) = self.forced_tasks(resources, interval)?;
+        let mut forced = Vec::new();
+        let mut downs = Vec::new();
+        for tid in tids {
+            let aligned = self.tasks[tid].schedule.align_interval(interval);
+            let aligned_is = IntervalSet::from(aligned);
+            // Compacted intervals need their actions back to run again
+            let restored = self.tasks[tid]
+                .timeline(self.last_horizon)
+                .intersection(&aligned_is);
+            self.restore_actions(tid, &restored);
+            let task = &self.tasks[tid];
+            for resource in &task.provides {
+                info!(resource, interval = %aligned, "Forcing down");
+                if let Some(current) = self.current.get_mut(resource) {
+                    current.subtract(&aligned_is);
+                }
+            }
+            let affected: Vec<ActionId> = self
+                .actions
+                .iter()
+                .filter(|(_, action)| action.task == tid && aligned_is.has_subset(action.interval))
+                .map(|(action_id, _)| *action_id)
+                .collect();
+            for action_id in affected {
+                let action = self.actions.get_mut(&action_id).unwrap();
+                self.event_stream.changed(action_id);
+                // Completed intervals are cleaned up before they run again
+                if action.state == ActionState::Completed && task.down.is_some() {
+                    action.state = ActionState::Running;
+                    downs.push((tid, action.interval, Some(action_id)));
+                } else {
+                    action.state = ActionState::Queued;
+                }
+            }
+            forced.push(ForcedInterval {
+                task_name: task.name.clone(),
+                interval: aligned,
+            });
+        }
+        for (tid, interval, action_id) in downs {
+            self.run_down(tid, interval, action_id);
+        }
+        self.store_state();
+        self.store_actions();
+        self.notify(Notification::ForcedDown {
+            resources,
+            interval,
+        });
+        Ok(forced)
+    }
+
+    /// Kills a single running action
+    fn kill_action(&mut self, action_id: ActionId) -> Result<()> {
+        let kill = self
+            .action_cancels
+            .get(&action_id)
+            .ok_or_else(|| Error::Validation(format!("Action {} isn't running", action_id)))?;
+        info!(%action_id, "Killing action");
+        kill.cancel();
+        self.killed.insert(action_id);
+        Ok(())
+    }
+
+    /// Requeues an action that errored or was given up on
+    fn retry_action(&mut self, action_id: ActionId) -> Result<()> {
+        let action = self
+            .actions
+            .get_mut(&action_id)
+            .ok_or_else(|| Error::Validation(format!("No such action {}", action_id)))?;
+        if !is_retryable(action.state) {
+            return Err(Error::Validation(format!(
+                "Action {} is {:?}, only errored, failed, and skipped actions can be retried",
+                action_id, action.state
+            )));
+        }
+        info!(%action_id, "Retrying action");
+        // Retrying a given up action by hand gives it a fresh budget
+        if matches!(action.state, ActionState::Failed | ActionState::Skipped) {
+            self.failures.remove(&action_id);
+        }
+        action.state = ActionState::Queued;
+        self.event_stream.changed(action_id);
+        self.retry_at.remove(&action_id);
+        Ok(())
+    }
+
+    /// Requeues every action of the runner's tasks in the state
+    fn retry_actions(&mut self, state: ActionState, group: Option<&str>) -> Result<Vec<ActionId>> {
+        if !is_retryable(state) {
+            return Err(Error::Validation(format!(
+                "{:?} actions can't be retried, only errored, failed, and skipped ones",
+                state
+            )));
+        }
+        let action_ids: Vec<ActionId> = self
+            .actions
+            .iter()
+            .filter(|(_, action)| {
+                action.state == state
+                    && self.is_active(action.task)
+                    && group.is_none_or(|group| self.tasks[action.task].in_group(group))
+            })
+            .map(|(action_id, _)| *action_id)
+            .collect();
+        for action_id in &action_ids {
+            self.retry_action(*action_id)?;
+        }
+        Ok(action_ids)
+    }
+
+    /// Gives up on an action, unlike forcing it up, which pretends it
+    /// succeeded
+    fn skip_action(&mut self, action_id: ActionId) -> Result<()> {
+        let action = self
+            .actions
+            .get_mut(&action_id)
+            .ok_or_else(|| Error::Validation(format!("No such action {}", action_id)))?;
+        match action.state {
+            ActionState::Running => {
+                return Err(Error::Validation(format!(
+                    "Action {} is running, kill it first",
+                    action_id
+                )))
+            }
+            ActionState::Completed => {
+                return Err(Error::Validation(format!(
+                    "Action {} has already completed",
+                    action_id
+                )))
+            }
+            _ => {}
+        }
+        info!(%action_id, "Skipping action");
+        action.state = ActionState::Skipped;
+        self.event_stream.changed(action_id);
+        self.retry_at.remove(&action_id);
+        Ok(())
+    }
+
+    /// Kills all running actions, leaving the runner up
+    fn cancel_all(&mut self) {
+        for tid in 0..self.task_cancels.len() {
+            self.cancel_task(tid);
+        }
+    }
+
+    fn upstream(
+        &self,
+        resource: &str,
+        interval: Interval,
+        max_depth: usize,
+    ) -> Option<Vec<UpstreamNode>> {
+        let mut nodes = if self.retired.is_empty() {
+            let states: HashMap<(usize, Interval), ActionState> = self
+                .actions
+                .values()
+                .map(|a| ((a.task, a.interval), a.state))
+                .collect();
+            upstream::trace(&self.tasks, &states, resource, interval, max_depth)?
+        } else {
+            // Trace over the current tasks only, re-indexed
+            let (tasks, tids) = self.current_tasks();
+            let index: HashMap<usize, usize> = tids
+                .iter()
+                .enumerate()
+                .map(|(idx, tid)| (*tid, idx))
+                .collect();
+            let states: HashMap<(usize, Interval), ActionState> = self
+                .actions
+                .values()
+                .filter_map(|a| index.get(&a.task).map(|idx| ((*idx, a.interval), a.state)))
+                .collect();
+            upstream::trace(&tasks, &states, resource, interval, max_depth)?
+        };
+
+        // Compacted actions were completed
+        let cutoff = self.history_cutoff(Utc::now());
+        for node in nodes.iter_mut().filter(|node| node.state.is_none()) {
+            let Some(tid) = self.task_id(&node.task_name) else {
+                continue;
+            };
+            if node.interval.end < cutoff && self.is_up(tid, node.interval) {
+                node.state = Some(ActionState::Completed);
+            }
+        }
+        Some(nodes)
+    }
+
+    /// Whether every resource the task provides is up over the interval
+    fn is_up(&self, tid: usize, interval: Interval) -> bool {
+        self.tasks[tid].provides.iter().all(|res| {
+            self.current
+                .get(res)
+                .is_some_and(|is| is.has_subset(interval))
+        })
     }
 
-    pub async fn run(&mut self, mut stay_up: bool) {
+    pub async fn run(&mut self, stay_up: bool) {
+        let recovered = std::mem::take(&mut self.recovered);
+        if !recovered.is_empty() {
+            self.recover_actions(recovered, Utc::now());
+            self.store_actions();
+        }
         self.tick();
         self.poll_messages();
+        if self.shard.is_some() {
+            self.refresh_shards();
+        }
+        self.refresh_remotes();
+        self.check_queries();
+        self.load_production();
 
         // Loop until the current state matches the end state
         while stay_up || !self.is_done() {
-            match self.events.next().await {
+            let event = tokio::select! {
+                event = self.events.next() => event,
+                _ = self.cancel.cancelled() => {
+                    info!("Cancelled, stopping");
+                    break;
+                }
+            };
+            match event {
                 Some(Ok(RunnerMessage::GetState { response })) => {
                     response
                         .send(RunnerState {
                             current: self.current.clone(),
                             coverage: self.end_state.clone(),
+                            failed: self.failed(),
                         })
                         .unwrap_or(());
                 }
+                Some(Ok(RunnerMessage::GetQueueDepths { response })) => {
+                    response.send(self.queue_depths()).unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::GetOutput {
+                    action_id,
+                    response,
+                })) => {
+                    let output = self.outputs.get(&action_id).cloned().ok_or_else(|| {
+                        Error::Validation(format!("Action {} isn't running", action_id))
+                    });
+                    response.send(output).unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::Subscribe { response })) => {
+                    response
+                        .send(self.event_stream.subscribe(&self.actions, &self.current))
+                        .unwrap_or(());
+                }
                 Some(Ok(RunnerMessage::PollMessages)) => {
                     self.poll_messages();
                 }
                 Some(Ok(RunnerMessage::Tick)) => {
                     self.tick();
                 }
+                Some(Ok(RunnerMessage::RefreshRemotes)) => {
+                    self.refresh_remotes();
+                }
+                Some(Ok(RunnerMessage::RemoteStatesLoaded { states })) => {
+                    for (remote, state) in states {
+                        self.remotes.entry(remote).or_default().update(state);
+                    }
+                    self.queue_actions();
+                    self.events.push(delayed_event(
+                        Duration::try_seconds(REMOTE_REFRESH_SECONDS).unwrap(),
+                        RunnerMessage::RefreshRemotes,
+                    ));
+                }
+                Some(Ok(RunnerMessage::RecheckCompleted {
+                    action_id,
+                    passed,
+                    error,
+                })) => {
+                    self.rechecking.remove(&action_id);
+                    if !passed {
+                        self.recheck_failed(action_id, error);
+                    }
+                }
+                Some(Ok(RunnerMessage::DownCompleted {
+                    action_id,
+                    succeeded,
+                })) => {
+                    debug!(?action_id, succeeded, "Down completed");
+                    if let Some(action_id) = action_id {
+                        self.action_cancels.remove(&action_id);
+                        if let Some(action) = self.actions.get_mut(&action_id) {
+                            action.state = ActionState::Queued;
+                            self.event_stream.changed(action_id);
+                        }
+                        self.store_actions();
+                    }
+                    self.queue_actions();
+                }
+                Some(Ok(RunnerMessage::AttemptsLoaded { attempts })) => {
+                    for (task_name, attempts) in attempts {
+                        let Some(tid) = self.task_id(&task_name) else {
+                            continue;
+                        };
+                        let task = &self.tasks[tid];
+                        for attempt in attempts.iter().filter(|a| a.succeeded && a.kind.is_up()) {
+                            let interval = task.schedule.interval(attempt.scheduled_time, 0);
+                            for res in &task.provides {
+                                self.produced.record(res, interval, attempt.stop_time);
+                            }
+                        }
+                    }
+                    self.queue_actions();
+                }
+                Some(Ok(RunnerMessage::CheckQueries)) => {
+                    self.check_queries();
+                }
+                Some(Ok(RunnerMessage::QueriesChecked { satisfied })) => {
+                    for (key, interval) in satisfied {
+                        self.queried.insert(&key, &IntervalSet::from(interval));
+                    }
+                    self.queue_actions();
+                    self.events.push(delayed_event(
+                        Duration::try_seconds(SQL_CHECK_SECONDS).unwrap(),
+                        RunnerMessage::CheckQueries,
+                    ));
+                }
+                Some(Ok(RunnerMessage::RefreshShards)) => {
+                    self.refresh_shards();
+                }
+                Some(Ok(RunnerMessage::ShardStatesLoaded { states })) => {
+                    // Tried again with the next refresh
+                    match states {
+                        Ok(states) => {
+                            self.merge_shard_states(states, false);
+                            self.queue_actions();
+                        }
+                        Err(e) => warn!("Unable to refresh the states of other shards: {}", e),
+                    }
+                    self.events.push(delayed_event(
+                        Duration::try_seconds(1).unwrap(),
+                        RunnerMessage::RefreshShards,
+                    ));
+                }
                 Some(Ok(RunnerMessage::GetResourceStateDetails {
                     interval,
+                    options,
                     response,
+                })) => {
+                    response
+                        .send(self.get_resource_state_details(interval, &options))
+                        .unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::GetTaskOverview {
+                    task_name,
                     max_intervals,
+                    response,
+                })) => {
+                    response
+                        .send(self.task_overview(&task_name, max_intervals))
+                        .unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::GetUpstream {
+                    resource,
+                    interval,
+                    max_depth,
+                    response,
                 })) => {
-                    self.get_resource_state_details(interval, response, max_intervals);
+                    let resource = self.canonical(resource);
+                    response
+                        .send(self.upstream(&resource, interval, max_depth))
+                        .unwrap_or(());
                 }
                 Some(Ok(RunnerMessage::ForceUp {
                     resources,
                     interval,
+                    response,
                 })) => {
-                    for (tid, task) in self.tasks.iter().enumerate() {
-                        if task.provides.is_subset(&resources) {
-                            let aligned_is =
-                                IntervalSet::from(task.schedule.align_interval(interval));
-                            for resource in &task.provides {
-                                self.current.get_mut(resource).unwrap().merge(&aligned_is);
-                            }
-                            for action in &mut self.actions {
-                                if action.task == tid && aligned_is.has_subset(action.interval) {
-                                    action.state = ActionState::Completed;
-                                }
-                            }
-                        }
-                    }
-                    self.store_state();
+                    response
+                        .send(self.force_up(resources, interval))
+                        .unwrap_or(());
                 }
                 Some(Ok(RunnerMessage::ForceDown {
                     resources,
                     interval,
+                    response,
                 })) => {
-                    // Use the interval to identify
-                    for (tid, task) in self.tasks.iter().enumerate() {
-                        if task.provides.is_subset(&resources) {
-                            let aligned_is =
-                                IntervalSet::from(task.schedule.align_interval(interval));
-                            for resource in &task.provides {
-                                self.current
-                                    .get_mut(resource)
-                                    .unwrap()
-                                    .subtract(&aligned_is);
-                            }
-                            for action in &mut self.actions {
-                                if action.task == tid && aligned_is.has_subset(action.interval) {
-                                    action.state = ActionState::Queued;
-                                }
-                            }
+                    response
+                        .send(self.force_down(resources, interval))
+                        .unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::ReloadState { response })) => {
+                    info!("Reloading state from storage");
+                    let res = self.reload_state().await;
+                    response.send(res).unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::CancelTask { task_name })) => {
+                    match self.task_id(&task_name) {
+                        Some(tid) => {
+                            info!(task_name, "Cancelling running actions");
+                            self.cancel_task(tid);
+                        }
+                        None => warn!(task_name, "Unable to cancel unknown task"),
+                    }
+                }
+                Some(Ok(RunnerMessage::GetAttempts {
+                    task_name,
+                    end,
+                    response,
+                })) => {
+                    let interval = match self.task_id(&task_name) {
+                        Some(tid) => self.tasks[tid].schedule.interval(end, 0),
+                        None => {
+                            response
+                                .send(Err(Error::Validation(format!(
+                                    "No such task {}",
+                                    task_name
+                                ))))
+                                .unwrap_or(());
+                            continue;
+                        }
+                    };
+                    let (storage_response, rx) = oneshot::channel();
+                    let msg = StorageMessage::GetAttempts {
+                        task_name,
+                        interval,
+                        response: storage_response,
+                    };
+                    let storage = self.storage.clone();
+                    // Don't hold up the event loop on storage
+                    tokio::spawn(async move {
+                        if let Err(e) = storage.send(msg).await {
+                            response
+                                .send(Err(Error::Channel(e.to_string())))
+                                .unwrap_or(());
+                            return;
+                        }
+                        response.send(rx.await.map_err(Error::from)).unwrap_or(());
+                    });
+                }
+                Some(Ok(RunnerMessage::GetArtifact {
+                    task_name,
+                    end,
+                    name,
+                    response,
+                })) => {
+                    let interval = match self.task_id(&task_name) {
+                        Some(tid) => self.tasks[tid].schedule.interval(end, 0),
+                        None => {
+                            response
+                                .send(Err(Error::Validation(format!(
+                                    "No such task {}",
+                                    task_name
+                                ))))
+                                .unwrap_or(());
+                            continue;
                         }
+                    };
+                    let (storage_response, rx) = oneshot::channel();
+                    let msg = StorageMessage::GetArtifact {
+                        task_name,
+                        interval,
+                        name,
+                        response: storage_response,
+                    };
+                    let storage = self.storage.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = storage.send(msg).await {
+                            response
+                                .send(Err(Error::Channel(e.to_string())))
+                                .unwrap_or(());
+                            return;
+                        }
+                        response
+                            .send(rx.await.map_err(Error::from).and_then(|res| res))
+                            .unwrap_or(());
+                    });
+                }
+                Some(Ok(RunnerMessage::GetStoredOutput {
+                    task_name,
+                    end,
+                    response,
+                })) => {
+                    let interval = match self.task_id(&task_name) {
+                        Some(tid) => self.tasks[tid].schedule.interval(end, 0),
+                        None => {
+                            response
+                                .send(Err(Error::Validation(format!(
+                                    "No such task {}",
+                                    task_name
+                                ))))
+                                .unwrap_or(());
+                            continue;
+                        }
+                    };
+                    let (storage_response, rx) = oneshot::channel();
+                    let msg = StorageMessage::GetOutput {
+                        task_name,
+                        interval,
+                        response: storage_response,
+                    };
+                    let storage = self.storage.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = storage.send(msg).await {
+                            response
+                                .send(Err(Error::Channel(e.to_string())))
+                                .unwrap_or(());
+                            return;
+                        }
+                        response
+                            .send(rx.await.map_err(Error::from).and_then(|res| res))
+                            .unwrap_or(());
+                    });
+                }
+                Some(Ok(RunnerMessage::ReplayAttempt {
+                    task_name,
+                    end,
+                    response,
+                })) => {
+                    let task = match self.task_id(&task_name) {
+                        Some(tid) => &self.tasks[tid],
+                        None => {
+                            response
+                                .send(Err(Error::Validation(format!(
+                                    "No such task {}",
+                                    task_name
+                                ))))
+                                .unwrap_or(());
+                            continue;
+                        }
+                    };
+                    let interval = task.schedule.interval(end, 0);
+                    let executor = self.executor.clone();
+                    let storage = self.storage.clone();
+                    let output_options = self.output_options;
+                    tokio::spawn(async move {
+                        let res = crate::replay::replay(
+                            &task_name,
+                            interval,
+                            &executor,
+                            &storage,
+                            output_options,
+                        )
+                        .await;
+                        response.send(res).unwrap_or(());
+                    });
+                }
+                Some(Ok(RunnerMessage::PauseTask {
+                    task_name,
+                    response,
+                })) => {
+                    let res = self.set_paused(&task_name, true);
+                    response.send(res).unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::ResumeTask {
+                    task_name,
+                    response,
+                })) => {
+                    let res = self.set_paused(&task_name, false);
+                    if res.is_ok() {
+                        self.queue_actions();
+                    }
+                    response.send(res).unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::PauseGroup { group, response })) => {
+                    let res = self.set_group_paused(&group, true);
+                    response.send(res).unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::ResumeGroup { group, response })) => {
+                    let res = self.set_group_paused(&group, false);
+                    if res.is_ok() {
+                        self.queue_actions();
                     }
-                    self.store_state();
+                    response.send(res).unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::CancelAll)) => {
+                    info!("Cancelling all running actions");
+                    self.cancel_all();
+                }
+                Some(Ok(RunnerMessage::ReloadWorld {
+                    definition,
+                    response,
+                })) => {
+                    let res = self.reload_world(*definition).await;
+                    if let Err(e) = &res {
+                        error!("Unable to reload world, keeping the current one: {}", e);
+                    }
+                    response.send(res).unwrap_or(());
                 }
                 Some(Ok(RunnerMessage::Stop)) => {
                     info!("Stopping");
-                    stay_up = false;
                     break;
                 }
-                Some(Ok(RunnerMessage::RetryAction { action_id })) => {
-                    info!("Retrying action {}", action_id);
-                    let action = &mut self.actions[action_id];
-                    action.state = ActionState::Queued;
+                Some(Ok(RunnerMessage::RetryAction {
+                    action_id,
+                    response,
+                })) => {
+                    let res = self.retry_action(action_id);
+                    if res.is_ok() {
+                        self.store_actions();
+                        self.queue_actions();
+                    }
+                    response.send(res).unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::RetryActions {
+                    state,
+                    group,
+                    response,
+                })) => {
+                    let res = self.retry_actions(state, group.as_deref());
+                    if res.as_ref().is_ok_and(|retried| !retried.is_empty()) {
+                        self.store_actions();
+                        self.queue_actions();
+                    }
+                    response.send(res).unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::RetryDue { action_id })) => {
+                    self.retry_due(action_id);
+                }
+                Some(Ok(RunnerMessage::SkipAction {
+                    action_id,
+                    response,
+                })) => {
+                    let res = self.skip_action(action_id);
+                    if res.is_ok() {
+                        self.store_actions();
+                    }
+                    response.send(res).unwrap_or(());
+                }
+                Some(Ok(RunnerMessage::KillAction {
+                    action_id,
+                    response,
+                })) => {
+                    response.send(self.kill_action(action_id)).unwrap_or(());
                 }
                 Some(Ok(RunnerMessage::ActionCompleted {
                     action_id,
                     succeeded,
+                    error,
+                    infra_failure,
                 })) => {
-                    self.complete_task(action_id, succeeded);
+                    self.complete_task(action_id, succeeded, error, infra_failure);
+                    self.store_actions();
                 }
                 Some(Err(e)) => {
-                    panic!("Something went wrong: {:?}", e)
+                    error!(error = %e, "A runner task failed");
                 }
                 None => {}
             }
-            // Log stuff
+            self.event_stream.publish(
+                &self.actions,
+                |action| &self.tasks[action.task].name,
+                &self.current,
+            );
         }
+        self.flush_snapshots().await;
     }
 
-    fn complete_task(&mut self, action_id: usize, succeeded: bool) {
-        info!("Completing action {}", action_id);
-        let action = &mut self.actions[action_id];
+    /// Requeues an errored action whose retry delay has passed, unless the
+    /// circuit breaker is holding retries
+    fn retry_due(&mut self, action_id: ActionId) {
+        // Retried or skipped by hand since
+        let Some(action) = self.actions.get_mut(&action_id) else {
+            return;
+        };
+        if action.state != ActionState::Errored || self.retry_at.remove(&action_id).is_none() {
+            return;
+        }
+        // Scheduled before the breaker tripped
+        if let Some(breaker) = &mut self.breaker {
+            if !breaker.on_retry_due(action.task, action_id) {
+                info!(%action_id, "Holding retry while the circuit breaker is open");
+                self.retry_at.insert(action_id, Utc::now());
+                return;
+            }
+        }
+        info!(%action_id, "Retrying action");
+        action.state = ActionState::Queued;
+        self.event_stream.changed(action_id);
+        self.store_actions();
+    }
+
+    fn complete_task(
+        &mut self,
+        action_id: ActionId,
+        succeeded: bool,
+        error: Option<String>,
+        infra_failure: bool,
+    ) {
+        self.action_cancels.remove(&action_id);
+        self.outputs.remove(&action_id);
+        let killed = self.killed.remove(&action_id);
+        let Some(action) = self.actions.get_mut(&action_id) else {
+            debug!(%action_id, "Attempt completed for an action that no longer exists");
+            return;
+        };
+        self.event_stream.changed(action_id);
+        let _span = info_span!(
+            "action",
+            %action_id,
+            task_name = %self.tasks[action.task].name,
+            interval = %action.interval
+        )
+        .entered();
+        info!(succeeded, "Completing");
+        if self.retired.contains(&action.task) {
+            // Killed by a world reload
+            return;
+        }
         if succeeded {
             let task = self.tasks.get(action.task).unwrap();
             action.state = ActionState::Completed;
             for res in &task.provides {
                 self.current
                     .entry(res.clone())
-                    .or_insert(IntervalSet::new())
+                    .or_default()
                     .insert(action.interval);
+                self.produced.record(res, action.interval, Utc::now());
+            }
+            if let Some(breaker) = &mut self.breaker {
+                breaker.on_success(action_id);
             }
+            self.failures.remove(&action_id);
+            self.retry_at.remove(&action_id);
+            self.fallbacks.remove(&action_id);
+            // Alert again if the interval ever goes late again
+            self.alerted.remove(&action_id);
             self.store_state();
             self.queue_actions();
         } else {
             action.state = ActionState::Errored;
-            self.events.push(delayed_event(
-                Duration::try_seconds(30).unwrap(),
-                RunnerMessage::RetryAction { action_id },
-            ));
-        }
-    }
-
-    fn store_state(&self) {
-        self.storage
-            .send(StorageMessage::StoreState {
+            if killed {
+                info!("Killed, leaving it until retried");
+                return;
+            }
+            if infra_failure {
+                // The command never got to run, so it doesn't count against
+                // the task's retries or escalation
+                let task = &self.tasks[action.task];
+                let failures = self.failures.get(&action_id).copied().unwrap_or(0);
+                let delay = task.retry.delay(failures.max(1));
+                warn!(
+                    error = error.as_deref().unwrap_or_default(),
+                    "Unable to run, retrying"
+                );
+                self.retry_at.insert(
+                    action_id,
+                    Utc::now().checked_add_signed(delay).unwrap_or(MAX_TIME),
+                );
+                self.events
+                    .push(delayed_event(delay, RunnerMessage::RetryDue { action_id }));
+                return;
+            }
+            let failures = self.failures.entry(action_id).or_default();
+            *failures += 1;
+            let task = &self.tasks[action.task];
+            post(
+                &self.notifications,
+                &self.event_stream,
+                Notification::ActionErrored {
+                    task_name: task.name.clone(),
+                    interval: action.interval,
+                    failures: *failures,
+                    error: error.clone(),
+                },
+            );
+            let gave_up = Notification::GaveUp {
+                task_name: task.name.clone(),
+                interval: action.interval,
+                failures: *failures,
+                error,
+            };
+            if let Some(policy) = &task.escalation {
+                if *failures == policy.after_failures {
+                    warn!(failures = *failures, "Escalating");
+                    if let Some(page) = &policy.page {
+                        let varmap =
+                            VarMap::from_schedule(&action.interval, task.timezone, &task.schedule)
+                                .with_vars(&self.vars);
+                        tokio::spawn(page_task(
+                            task.name.clone(),
+                            page.clone(),
+                            varmap,
+                            self.executor.clone(),
+                        ));
+                    }
+                    match &policy.then {
+                        EscalationAction::Retry => {}
+                        EscalationAction::Stop => {
+                            action.state = ActionState::Failed;
+                            post(&self.notifications, &self.event_stream, gave_up);
+                            return;
+                        }
+                        EscalationAction::Skip => {
+                            action.state = ActionState::Skipped;
+                            return;
+                        }
+                        EscalationAction::Fallback(_) => {
+                            self.fallbacks.insert(action_id);
+                        }
+                    }
+                }
+            }
+            let retry = match &mut self.breaker {
+                Some(breaker) => breaker.on_failure(Utc::now(), action.task, action_id),
+                None => true,
+            };
+            if !retry {
+                // Held by the breaker, which doesn't survive a restart
+                self.retry_at.insert(action_id, Utc::now());
+                return;
+            }
+            if !task.retry.should_retry(*failures) {
+                warn!(failures = *failures, "Giving up");
+                action.state = ActionState::Failed;
+                post(&self.notifications, &self.event_stream, gave_up);
+                return;
+            }
+            let delay = task.retry.delay(*failures);
+            self.retry_at.insert(
+                action_id,
+                Utc::now().checked_add_signed(delay).unwrap_or(MAX_TIME),
+            );
+            self.events
+                .push(delayed_event(delay, RunnerMessage::RetryDue { action_id }));
+        }
+    }
+
+    async fn reload_state(&mut self) -> Result<()> {
+        if self.shard.is_some() {
+            let (response, rx) = oneshot::channel();
+            self.storage
+                .send(StorageMessage::LoadShardStates { response })
+                .await
+                .map_err(|e| Error::Channel(e.to_string()))?;
+            self.current = ResourceInterval::new();
+            self.merge_shard_states(rx.await??, true);
+        } else {
+            let (response, rx) = oneshot::channel();
+            self.storage
+                .send(StorageMessage::LoadState { response })
+                .await
+                .map_err(|e| Error::Channel(e.to_string()))?;
+            self.current = rx.await?;
+            self.current.rename(&self.aliases);
+        }
+        self.rederive_action_states();
+        Ok(())
+    }
+
+    fn state_snapshot(&self) -> StorageMessage {
+        match &self.shard {
+            Some(shard) => StorageMessage::StoreShardState {
+                shard: shard.name.clone(),
+                state: shard.filter(&self.current),
+            },
+            None => StorageMessage::StoreState {
                 state: self.current.clone(),
+            },
+        }
+    }
+
+    fn store_state(&mut self) {
+        self.state_unstored = !self.try_store(self.state_snapshot());
+    }
+
+    /// Sends a snapshot to storage without waiting on it. Returns false if
+    /// storage is backed up, so a fresher snapshot is sent on a later tick.
+    fn try_store(&self, msg: StorageMessage) -> bool {
+        match self.storage.try_send(msg) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                debug!("Storage is backed up, deferring a snapshot");
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                storage_gone();
+                true
+            }
+        }
+    }
+
+    /// Waits to store the snapshots storage had no room for
+    async fn flush_snapshots(&mut self) {
+        if std::mem::take(&mut self.state_unstored) {
+            self.storage
+                .send(self.state_snapshot())
+                .await
+                .unwrap_or_else(|_| storage_gone());
+        }
+        if std::mem::take(&mut self.actions_unstored) {
+            self.storage
+                .send(StorageMessage::StoreActions {
+                    actions: self.ledger(),
+                })
+                .await
+                .unwrap_or_else(|_| storage_gone());
+        }
+    }
+
+    /// The actions whose progress can't be derived from the current state
+    fn ledger(&self) -> Vec<ActionRecord> {
+        self.actions
+            .iter()
+            .filter(|(action_id, action)| {
+                !self.retired.contains(&action.task)
+                    && (!matches!(action.state, ActionState::Queued | ActionState::Completed)
+                        || self.failures.contains_key(action_id)
+                        || self.fallbacks.contains(action_id))
             })
-            .unwrap();
+            .map(|(action_id, action)| ActionRecord {
+                task_name: self.tasks[action.task].name.clone(),
+                interval: action.interval,
+                state: action.state,
+                action_id: Some(*action_id),
+                failures: self.failures.get(action_id).copied().unwrap_or(0),
+                retry_at: self.retry_at.get(action_id).copied(),
+                fallback: self.fallbacks.contains(action_id),
+            })
+            .collect()
+    }
+
+    fn store_actions(&mut self) {
+        // The ledger isn't kept per shard
+        if self.shard.is_some() {
+            return;
+        }
+        let msg = StorageMessage::StoreActions {
+            actions: self.ledger(),
+        };
+        self.actions_unstored = !self.try_store(msg);
+    }
+
+    /// Restores the progress of actions from the ledger of a previous run.
+    /// Intervals that have come up since stay completed, and actions that
+    /// were running when it stopped are run again.
+    fn recover_actions(&mut self, records: Vec<ActionRecord>, now: DateTime<Utc>) {
+        for record in records {
+            let Some(tid) = self.task_id(&record.task_name) else {
+                continue;
+            };
+            let Some(&(mut action_id)) = self.registry.get(&(tid, record.interval)) else {
+                continue;
+            };
+            // Recorded ids are below those of actions added since
+            if let Some(recorded) = record.action_id {
+                if recorded != action_id && !self.actions.contains_key(&recorded) {
+                    let action = self.actions.remove(&action_id).unwrap();
+                    self.actions.insert(recorded, action);
+                    self.registry.insert((tid, record.interval), recorded);
+                    self.event_stream.changed(action_id);
+                    action_id = recorded;
+                }
+            }
+            let action = self.actions.get_mut(&action_id).unwrap();
+            self.event_stream.changed(action_id);
+            if action.state == ActionState::Completed {
+                continue;
+            }
+            let _span = info_span!(
+                "action",
+                %action_id,
+                task_name = %record.task_name,
+                interval = %record.interval
+            )
+            .entered();
+            if record.failures > 0 {
+                self.failures.insert(action_id, record.failures);
+            }
+            if record.fallback {
+                self.fallbacks.insert(action_id);
+            }
+            match record.state {
+                ActionState::Running => info!("Interrupted by a restart, running again"),
+                ActionState::Errored => {
+                    action.state = ActionState::Errored;
+                    if let Some(retry_at) = record.retry_at {
+                        info!(failures = record.failures, %retry_at, "Recovered, retrying");
+                        self.retry_at.insert(action_id, retry_at);
+                        self.events.push(delayed_event(
+                            (retry_at - now).max(Duration::zero()),
+                            RunnerMessage::RetryDue { action_id },
+                        ));
+                    }
+                }
+                ActionState::Failed | ActionState::Skipped => action.state = record.state,
+                ActionState::Queued | ActionState::Completed => {}
+            }
+        }
+    }
+
+    /// The queued actions that can run now, highest priority task first,
+    /// then oldest interval first
+    fn runnable_actions(&self) -> Vec<ActionId> {
+        let now = Utc::now();
+        let available = self.available();
+        let mut runnable: Vec<ActionId> = self
+            .actions
+            .iter()
+            .filter(|(_, x)| {
+                x.state == ActionState::Queued
+                    && x.interval.end <= now
+                    && !self.retired.contains(&x.task)
+                    && !self.paused.contains(&self.tasks[x.task].name)
+                    && self.tasks[x.task].can_run(x.interval, &available)
+            })
+            .map(|(action_id, _)| *action_id)
+            .collect();
+        runnable.sort_by_key(|action_id| {
+            let action = &self.actions[action_id];
+            (
+                std::cmp::Reverse(self.tasks[action.task].priority),
+                action.interval.end,
+            )
+        });
+        runnable
+    }
+
+    fn queue_depths(&self) -> QueueDepths {
+        QueueDepths {
+            runner: QueueDepth {
+                depth: self.messages.len(),
+                capacity: self.messages.max_capacity(),
+            },
+            storage: QueueDepth::of(&self.storage),
+            executor: QueueDepth::of(&self.executor),
+            lagging: self.lagging,
+        }
+    }
+
+    /// Holds back dispatch while storage's queue is over three quarters
+    /// full, as every attempt is stored, and lets it go once it's drained
+    /// to half full
+    fn update_lagging(&mut self) {
+        let free = self.storage.capacity();
+        let max = self.storage.max_capacity();
+        if !self.lagging && free < max / 4 {
+            warn!("Storage is backed up, holding back dispatch");
+            self.lagging = true;
+        } else if self.lagging && free >= max / 2 {
+            info!("Storage caught up, resuming dispatch");
+            self.lagging = false;
+        }
     }
 
     fn queue_actions(&mut self) {
+        self.update_lagging();
+        if self.lagging {
+            return;
+        }
+
+        // Every running action holds a kill switch until it completes, and
+        // no more are started than the executor has room to take
+        let slots = match self.max_running {
+            Some(limit) => limit.saturating_sub(self.action_cancels.len()),
+            None => usize::MAX,
+        }
+        .min(self.executor.capacity());
+
+        // Backfill past its launch budget waits, oldest first
         let now = Utc::now();
+        let mut runnable = Vec::new();
+        for action_id in self.runnable_actions() {
+            if runnable.len() == slots {
+                break;
+            }
+            let interval = self.actions[&action_id].interval;
+            if let Some(throttle) = &mut self.backfill {
+                if !throttle.admit(interval, now) {
+                    continue;
+                }
+            }
+            runnable.push(action_id);
+        }
 
         // Submit any elligible jobs
-        for (action_id, action) in self
-            .actions
-            .iter_mut()
-            .enumerate()
-            .filter(|(_, x)| x.state == ActionState::Queued && x.interval.end <= now)
-        {
+        if runnable.is_empty() {
+            return;
+        }
+        for action_id in runnable {
+            let action = self.actions.get_mut(&action_id).unwrap();
             let task = self.tasks.get(action.task).unwrap();
-            if !task.can_run(action.interval, &self.current) {
-                continue;
-            }
-            let (_kill_tx, kill) = oneshot::channel();
-            let varmap: VarMap = VarMap::from_interval(&action.interval, task.timezone)
-                .iter()
-                .chain(self.vars.iter())
-                .collect();
+            let kill = self.task_cancels[action.task].child_token();
+            self.action_cancels.insert(action_id, kill.clone());
+            let varmap = VarMap::from_schedule(&action.interval, task.timezone, &task.schedule)
+                .with_vars(&self.vars);
             let task_name = task.name.clone();
             let interval = action.interval;
-            let up = task.up.clone();
+            let up = match (&task.escalation, self.fallbacks.contains(&action_id)) {
+                (
+                    Some(EscalationPolicy {
+                        then: EscalationAction::Fallback(fallback),
+                        ..
+                    }),
+                    true,
+                ) => fallback.clone(),
+                _ => task.up.clone(),
+            };
             let check = task.check.clone();
-            let output_options = self.output_options.clone();
+            let output_options = self.output_options;
             let exe = self.executor.clone();
             let storage = self.storage.clone();
+            let output = OutputTap::new();
+            self.outputs.insert(action_id, output.clone());
             self.events.push(tokio::spawn(async move {
                 up_task(
                     action_id,
@@ -637,101 +3413,3207 @@ impl Runner {
                     output_options,
                     exe,
                     storage,
+                    output,
                 )
                 .await
             }));
-            // action.response = Some(response_rx);
-            // action.kill = Some(kill_tx);
             action.state = ActionState::Running;
+            self.event_stream.changed(action_id);
         }
+        self.store_actions();
     }
 
-    fn is_done(&self) -> bool {
-        self.end_state == self.current
-    }
-}
+    /// Re-runs the check of the most recently completed intervals of tasks
+    /// due a recheck
+    fn recheck(&mut self, now: DateTime<Utc>) {
+        for tid in 0..self.tasks.len() {
+            let task = &self.tasks[tid];
+            let (Some(every), Some(check)) = (task.recheck_every_seconds, &task.check) else {
+                continue;
+            };
+            if !self.is_active(tid) {
+                continue;
+            }
+            // The first recheck comes a full period after starting
+            let last = *self.rechecked.entry(tid).or_insert(now);
+            let due = Duration::try_seconds(every).and_then(|every| last.checked_add_signed(every));
+            if due.is_none_or(|due| now < due) {
+                continue;
+            }
+            self.rechecked.insert(tid, now);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::executors::local_executor;
+            let mut completed: Vec<ActionId> = self
+                .actions
+                .iter()
+                .filter(|(action_id, action)| {
+                    action.task == tid
+                        && action.state == ActionState::Completed
+                        && !self.rechecking.contains(action_id)
+                })
+                .map(|(action_id, _)| *action_id)
+                .collect();
+            completed
+                .sort_by_key(|action_id| std::cmp::Reverse(self.actions[action_id].interval.end));
+            for action_id in completed.into_iter().take(task.recheck_intervals) {
+                let interval = self.actions[&action_id].interval;
+                let varmap = VarMap::from_schedule(&interval, task.timezone, &task.schedule)
+                    .with_vars(&self.vars);
+                self.rechecking.insert(action_id);
+                self.events.push(tokio::spawn(recheck_task(
+                    action_id,
+                    task.name.clone(),
+                    interval,
+                    self.task_cancels[tid].child_token(),
+                    varmap,
+                    check.clone(),
+                    self.output_options,
+                    self.executor.clone(),
+                    self.storage.clone(),
+                )));
+            }
+        }
+    }
 
-    #[tokio::test]
-    async fn test_runner() {
-        let json_runner = r#"{
-            "variables": {
-                "HOME": "/tmp/world_test"
-            },
-            "calendars": {
-                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+    /// Marks a completed action's resources down to be produced again, as
+    /// its recheck failed
+    fn recheck_failed(&mut self, action_id: ActionId, error: Option<String>) {
+        // Anything could have happened to the action while it was checked,
+        // including being compacted away
+        let Some(action) = self.actions.get_mut(&action_id) else {
+            return;
+        };
+        if action.state != ActionState::Completed || self.retired.contains(&action.task) {
+            return;
+        }
+        self.event_stream.changed(action_id);
+        let task = &self.tasks[action.task];
+        let is = IntervalSet::from(action.interval);
+        for resource in &task.provides {
+            warn!(resource, interval = %action.interval, "Recheck failed, taking down");
+            if let Some(current) = self.current.get_mut(resource) {
+                current.subtract(&is);
+            }
+        }
+        action.state = ActionState::Queued;
+        post(
+            &self.notifications,
+            &self.event_stream,
+            Notification::RecheckFailed {
+                task_name: task.name.clone(),
+                interval: action.interval,
+                error,
             },
-            "tasks": {
-                "task_a": {
-                    "up": { "command": "/usr//bin/touch ${HOME}/task_a_${yyyymmdd}" },
-                    "down": { "command": "/bin/rm ${HOME}/task_a_${yyyymmdd}" },
-                    "check": { "command": "/bin/test -e ${HOME}/task_a_${yyyymmdd}" },
+        );
+        self.store_state();
+        self.queue_actions();
+    }
 
-                    "provides": [ "task_a" ],
+    /// Runs the down command of a task for an interval. With an action, the
+    /// action holds a kill switch while it runs, and is queued to run again
+    /// once it's done.
+    fn run_down(&mut self, tid: usize, interval: Interval, action_id: Option<ActionId>) {
+        let task = &self.tasks[tid];
+        let Some(down) = task.down.clone() else {
+            return;
+        };
+        let kill = match action_id {
+            Some(action_id) => {
+                let kill = self.task_cancels[tid].child_token();
+                self.action_cancels.insert(action_id, kill.clone());
+                kill
+            }
+            // The task's own kill switch is gone once it's retired
+            None => self.cancel.child_token(),
+        };
+        let varmap =
+            VarMap::from_schedule(&interval, task.timezone, &task.schedule).with_vars(&self.vars);
+        self.events.push(tokio::spawn(down_task(
+            action_id,
+            task.name.clone(),
+            interval,
+            kill,
+            varmap,
+            down,
+            self.output_options,
+            self.executor.clone(),
+            self.storage.clone(),
+        )));
+    }
 
-                    "calendar_name": "std",
-                    "times": [ "09:00:00", "12:00:00"],
-                    "timezone": "America/New_York",
+    /// Alerts once on every action that's still outstanding past its
+    /// task's alert deadline
+    fn check_deadlines(&mut self, now: DateTime<Utc>) {
+        let mut late = Vec::new();
+        let mut unbounded = Vec::new();
+        for (&action_id, action) in &self.actions {
+            if matches!(action.state, ActionState::Completed | ActionState::Skipped)
+                || self.retired.contains(&action.task)
+                || self.alerted.contains(&action_id)
+            {
+                continue;
+            }
+            let task = &self.tasks[action.task];
+            let Some(delay) = task.alert_delay_seconds else {
+                continue;
+            };
+            let Some(deadline) = Duration::try_seconds(delay)
+                .and_then(|delay| action.interval.end.checked_add_signed(delay))
+            else {
+                warn!(
+                    %action_id,
+                    task_name = %task.name,
+                    delay,
+                    "alert_delay_seconds is out of range, not alerting"
+                );
+                unbounded.push(action_id);
+                continue;
+            };
+            if deadline > now || deadline < self.started {
+                continue;
+            }
+            warn!(
+                %action_id,
+                task_name = %task.name,
+                interval = %action.interval,
+                state = ?action.state,
+                deadline = %deadline,
+                "Interval is late"
+            );
+            late.push((action_id, task.name.clone(), deadline));
+        }
+        // Only warned about once
+        self.alerted.extend(unbounded);
+        for (action_id, task_name, deadline) in late {
+            self.alerted.insert(action_id);
+            let action = &self.actions[&action_id];
+            self.notify(Notification::SlaBreached {
+                task_name,
+                interval: action.interval,
+                deadline,
+                state: action.state,
+            });
+        }
+    }
 
-                    "valid_from": "2022-01-01T09:00:00",
-                    "valid_to": "2022-01-08T09:00:00"
-                },
-                "task_b": {
-                    "up": { "command": "/usr//bin/touch ${HOME}/task_b_${yyyymmdd}" },
-                    "down": { "command": "/bin/rm ${HOME}/task_b_${yyyymmdd}" },
-                    "check": { "command": "/bin/test -e ${HOME}/task_b_${yyyymmdd}" },
+    /// The intervals of active tasks' resources that ran out of retries
+    fn failed(&self) -> ResourceInterval {
+        let mut failed = ResourceInterval::new();
+        for action in self
+            .actions
+            .values()
+            .filter(|a| a.state == ActionState::Failed && self.is_active(a.task))
+        {
+            for res in &self.tasks[action.task].provides {
+                failed
+                    .entry(res.clone())
+                    .or_default()
+                    .insert(action.interval);
+            }
+        }
+        failed
+    }
 
-                    "provides": [ "task_b" ],
-                    "requires": [ { "resource": "task_a", "offset": 0 } ],
+    fn is_done(&self) -> bool {
+        // Resources dropped by a world reload may linger in the state
+        self.end_state
+            .iter()
+            .all(|(res, is)| self.current.get(res) == Some(is))
+    }
+}
 
-                    "calendar_name": "std",
-                    "times": [ "17:00:00" ],
-                    "timezone": "America/New_York",
+/// A cloneable client for a runner started with [`Runner::spawn`], wrapping
+/// the message plumbing in typed async methods.
+#[derive(Clone)]
+pub struct RunnerHandle {
+    tx: mpsc::Sender<RunnerMessage>,
+    join: Arc<tokio::sync::Mutex<Option<tokio::task::JoinHandle<()>>>>,
+}
 
-                    "valid_from": "2022-01-04T09:00:00",
-                    "valid_to": "2022-01-07T00:00:00"
-                }
-            }
-        }"#;
+/// A handle for a runner driven elsewhere, such as by calling
+/// [`Runner::run`] directly. Shutting it down only asks the runner to stop.
+impl From<mpsc::Sender<RunnerMessage>> for RunnerHandle {
+    fn from(tx: mpsc::Sender<RunnerMessage>) -> Self {
+        RunnerHandle {
+            tx,
+            join: Arc::new(tokio::sync::Mutex::new(None)),
+        }
+    }
+}
 
-        // Some Deserializer.
-        let world_def: WorldDefinition = serde_json::from_str(json_runner).unwrap();
+impl RunnerHandle {
+    /// The raw channel to the runner, for messages without a typed wrapper
+    pub fn sender(&self) -> mpsc::Sender<RunnerMessage> {
+        self.tx.clone()
+    }
 
-        let tasks = world_def.taskset().unwrap();
+    /// Waits for room in the runner's queue if it's full
+    async fn send(&self, msg: RunnerMessage) -> Result<()> {
+        self.tx
+            .send(msg)
+            .await
+            .map_err(|_| Error::Channel("Runner is not running".to_owned()))
+    }
 
-        // Executor
-        let (tx, rx) = mpsc::unbounded_channel();
-        let executor = local_executor::start(10, rx);
+    /// The output of a running action's up command, as it's produced
+    pub async fn output(&self, action_id: ActionId) -> Result<OutputTap> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::GetOutput {
+            action_id,
+            response,
+        })
+        .await?;
+        rx.await?
+    }
 
-        // Storage
-        let (storage_tx, storage_rx) = mpsc::unbounded_channel();
-        let storage = storage::memory::start(storage_rx);
+    /// Subscribes to action state changes, resources coming up or going
+    /// down, and alerts, as they happen
+    pub async fn subscribe(&self) -> Result<tokio::sync::broadcast::Receiver<RunnerEvent>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::Subscribe { response }).await?;
+        Ok(rx.await?)
+    }
 
-        let (_runner_tx, runner_rx) = mpsc::unbounded_channel();
-        let mut runner = Runner::new(
-            tasks,
-            world_def.variables,
-            runner_rx,
-            tx.clone(),
-            storage_tx.clone(),
-            world_def.output_options,
-            true,
-        )
-        .await
-        .unwrap();
+    /// The current and end state of all resources
+    pub async fn state(&self) -> Result<RunnerState> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::GetState { response }).await?;
+        Ok(rx.await?)
+    }
 
-        runner.run(false).await;
+    /// How backed up the runner, storage, and executor channels are
+    pub async fn queue_depths(&self) -> Result<QueueDepths> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::GetQueueDepths { response })
+            .await?;
+        Ok(rx.await?)
+    }
 
-        tx.send(ExecutorMessage::Stop {}).unwrap();
-        executor.await.unwrap();
+    /// A task's upcoming schedule and what's blocking its outstanding
+    /// intervals, or None if there's no such task
+    pub async fn task_overview(
+        &self,
+        task_name: &str,
+        max_intervals: usize,
+    ) -> Result<Option<TaskOverview>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::GetTaskOverview {
+            task_name: task_name.to_owned(),
+            max_intervals,
+            response,
+        })
+        .await?;
+        Ok(rx.await?)
+    }
 
-        storage_tx.send(StorageMessage::Stop {}).unwrap();
-        storage.await.unwrap();
+    /// The task intervals upstream of the one providing `resource` at the
+    /// end of `interval`, up to `max_depth` requirements away, or None if
+    /// no task provides it
+    pub async fn upstream(
+        &self,
+        resource: &str,
+        interval: Interval,
+        max_depth: usize,
+    ) -> Result<Option<Vec<UpstreamNode>>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::GetUpstream {
+            resource: resource.to_owned(),
+            interval,
+            max_depth,
+            response,
+        })
+        .await?;
+        Ok(rx.await?)
+    }
 
-        assert_eq!(1, 1);
+    /// The actions over an interval, grouped by resource and task
+    pub async fn details(
+        &self,
+        interval: Interval,
+        max_intervals: Option<usize>,
+    ) -> Result<ResourceStateDetails> {
+        let options = DetailsOptions {
+            max_intervals,
+            ..DetailsOptions::default()
+        };
+        Ok(self.details_page(interval, options).await?.details)
+    }
+
+    /// A page of the actions over an interval, grouped by resource and task
+    pub async fn details_page(
+        &self,
+        interval: Interval,
+        options: DetailsOptions,
+    ) -> Result<DetailsPage> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::GetResourceStateDetails {
+            interval,
+            options,
+            response,
+        })
+        .await?;
+        Ok(rx.await?)
+    }
+
+    /// Marks the resources as available over the interval, returning the
+    /// task intervals forced up
+    pub async fn force_up(
+        &self,
+        resources: HashSet<Resource>,
+        interval: Interval,
+    ) -> Result<Vec<ForcedInterval>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::ForceUp {
+            resources,
+            interval,
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Marks the resources as down over at least the interval, returning
+    /// the task intervals forced down
+    pub async fn force_down(
+        &self,
+        resources: HashSet<Resource>,
+        interval: Interval,
+    ) -> Result<Vec<ForcedInterval>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::ForceDown {
+            resources,
+            interval,
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Requeues an errored, failed, or skipped action immediately
+    pub async fn retry(&self, action_id: ActionId) -> Result<()> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::RetryAction {
+            action_id,
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Requeues every action in the state, returning their ids
+    pub async fn retry_all(&self, state: ActionState) -> Result<Vec<ActionId>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::RetryActions {
+            state,
+            group: None,
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Requeues every action in the state of the tasks in a group
+    pub async fn retry_group(&self, state: ActionState, group: &str) -> Result<Vec<ActionId>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::RetryActions {
+            state,
+            group: Some(group.to_owned()),
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Gives up on an action that isn't running or completed, leaving its
+    /// interval down until it's retried
+    pub async fn skip(&self, action_id: ActionId) -> Result<()> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::SkipAction {
+            action_id,
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Kills a running action, which then stays errored until retried
+    pub async fn kill_action(&self, action_id: ActionId) -> Result<()> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::KillAction {
+            action_id,
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Kills the running actions of a task
+    pub async fn cancel_task(&self, task_name: &str) -> Result<()> {
+        self.send(RunnerMessage::CancelTask {
+            task_name: task_name.to_owned(),
+        })
+        .await
+    }
+
+    /// Kills all running actions
+    pub async fn cancel_all(&self) -> Result<()> {
+        self.send(RunnerMessage::CancelAll).await
+    }
+
+    /// Stops dispatching a task's actions until it's resumed
+    pub async fn pause_task(&self, task_name: &str) -> Result<()> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::PauseTask {
+            task_name: task_name.to_owned(),
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    pub async fn resume_task(&self, task_name: &str) -> Result<()> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::ResumeTask {
+            task_name: task_name.to_owned(),
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Pauses every task in a group, returning their names
+    pub async fn pause_group(&self, group: &str) -> Result<Vec<String>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::PauseGroup {
+            group: group.to_owned(),
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    pub async fn resume_group(&self, group: &str) -> Result<Vec<String>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::ResumeGroup {
+            group: group.to_owned(),
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Re-executes the most recent failed attempt of a task's interval
+    pub async fn replay(&self, task_name: &str, end: DateTime<Utc>) -> Result<TaskAttempt> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::ReplayAttempt {
+            task_name: task_name.to_owned(),
+            end,
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Every stored attempt of a task's interval, oldest first
+    pub async fn attempts(&self, task_name: &str, end: DateTime<Utc>) -> Result<Vec<TaskAttempt>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::GetAttempts {
+            task_name: task_name.to_owned(),
+            end,
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// The contents of an artifact of a task's interval, if it has one by
+    /// that name
+    pub async fn artifact(
+        &self,
+        task_name: &str,
+        end: DateTime<Utc>,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::GetArtifact {
+            task_name: task_name.to_owned(),
+            end,
+            name: name.to_owned(),
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// The output kept of the latest attempt of a task interval
+    pub async fn stored_output(
+        &self,
+        task_name: &str,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<OutputChunk>> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::GetStoredOutput {
+            task_name: task_name.to_owned(),
+            end,
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Reloads the resource state from storage
+    pub async fn reload(&self) -> Result<()> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::ReloadState { response }).await?;
+        rx.await?
+    }
+
+    /// Replaces the world definition, keeping the current one on error
+    pub async fn reload_world(&self, definition: WorldDefinition) -> Result<()> {
+        let (response, rx) = oneshot::channel();
+        self.send(RunnerMessage::ReloadWorld {
+            definition: Box::new(definition),
+            response,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Asks the runner to stop, without waiting for it to exit
+    pub async fn stop(&self) -> Result<()> {
+        self.send(RunnerMessage::Stop).await
+    }
+
+    /// Stops the runner and waits for it to exit
+    pub async fn shutdown(&self) -> Result<()> {
+        // The runner may have already exited on its own
+        self.send(RunnerMessage::Stop).await.unwrap_or(());
+        if let Some(join) = self.join.lock().await.take() {
+            join.await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "local-exec"))]
+mod tests {
+    use super::*;
+    use crate::executors::local_executor;
+
+    #[tokio::test]
+    async fn test_runner() {
+        let json_runner = r#"{
+            "variables": {
+                "HOME": "/tmp/world_test"
+            },
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/usr//bin/touch ${HOME}/task_a_${yyyymmdd}" },
+                    "down": { "command": "/bin/rm ${HOME}/task_a_${yyyymmdd}" },
+                    "check": { "command": "/bin/test -e ${HOME}/task_a_${yyyymmdd}" },
+
+                    "provides": [ "task_a" ],
+
+                    "calendar_name": "std",
+                    "times": [ "09:00:00", "12:00:00"],
+                    "timezone": "America/New_York",
+
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-01-08T09:00:00"
+                },
+                "task_b": {
+                    "up": { "command": "/usr//bin/touch ${HOME}/task_b_${yyyymmdd}" },
+                    "down": { "command": "/bin/rm ${HOME}/task_b_${yyyymmdd}" },
+                    "check": { "command": "/bin/test -e ${HOME}/task_b_${yyyymmdd}" },
+
+                    "provides": [ "task_b" ],
+                    "requires": [ { "resource": "task_a", "offset": 0 } ],
+
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+
+                    "valid_from": "2022-01-04T09:00:00",
+                    "valid_to": "2022-01-07T00:00:00"
+                }
+            }
+        }"#;
+
+        // Some Deserializer.
+        let world_def: WorldDefinition = serde_json::from_str(json_runner).unwrap();
+        std::fs::create_dir_all(&world_def.variables["HOME"]).unwrap();
+
+        let tasks = world_def.taskset().unwrap();
+
+        // Executor
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+
+        // Storage
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            tasks,
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+
+        runner.run(false).await;
+
+        // Everything is complete, and the validity window is in the past
+        let overview = runner.task_overview("task_b", 10).unwrap();
+        assert_eq!(overview.requires.len(), 1);
+        assert!(overview.pending.is_empty());
+        assert!(overview.upcoming.is_empty());
+        assert!(runner.task_overview("missing", 10).is_none());
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+
+        assert_eq!(1, 1);
+    }
+
+    #[tokio::test]
+    async fn test_runner_handle() {
+        let json_world = r#"{
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-07T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        let state = runner.state().await.unwrap();
+        assert!(state.coverage.contains_key("task_a"));
+        runner.reload().await.unwrap();
+        runner.shutdown().await.unwrap();
+
+        // Once shut down, the handle reports the runner is gone
+        assert!(runner.state().await.is_err());
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_attempts() {
+        let json_world = r#"{
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        // 17:00 in New York on the 3rd
+        let end = Utc.with_ymd_and_hms(2022, 1, 3, 22, 0, 0).unwrap();
+        let mut attempts = Vec::new();
+        for _ in 0..50 {
+            attempts = runner.attempts("task_a", end).await.unwrap();
+            if !attempts.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(attempts.len(), 1);
+        assert!(attempts[0].succeeded);
+        assert!(runner.attempts("missing", end).await.is_err());
+
+        runner.shutdown().await.unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_task() {
+        let json_world = r#"{
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/sleep 60" },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        let states = || async {
+            let details = runner
+                .details(Interval::new(MIN_TIME, MAX_TIME), None)
+                .await
+                .unwrap();
+            details["task_a"]["task_a"]
+                .iter()
+                .map(|a| a.state)
+                .collect::<Vec<ActionState>>()
+        };
+        let wait_for = |state: ActionState| async move {
+            for _ in 0..100 {
+                if states().await.iter().all(|s| *s == state) {
+                    return true;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            false
+        };
+
+        assert!(wait_for(ActionState::Running).await);
+        runner.cancel_task("task_a").await.unwrap();
+        assert!(wait_for(ActionState::Errored).await);
+
+        runner.shutdown().await.unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handle_from_sender() {
+        let json_world = r#"{
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                },
+                "task_b": {
+                    "up": { "command": "/bin/true" },
+                    "requires": [ { "resource": "task_a", "offset": 0 } ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        // Driven by the embedder rather than spawned
+        let (runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        let join = tokio::spawn(async move { runner.run(true).await });
+        let handle = RunnerHandle::from(runner_tx);
+
+        let overview = handle.task_overview("task_b", 5).await.unwrap().unwrap();
+        assert_eq!(overview.name, "task_b");
+        assert!(handle.task_overview("task_c", 5).await.unwrap().is_none());
+
+        let end = Utc.with_ymd_and_hms(2022, 1, 4, 22, 0, 0).unwrap();
+        let nodes = handle
+            .upstream("task_b", Interval::new(end, end), 5)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(nodes[0].task_name, "task_b");
+        assert!(nodes.iter().any(|node| node.task_name == "task_a"));
+
+        handle.stop().await.unwrap();
+        join.await.unwrap();
+        assert!(matches!(handle.state().await, Err(Error::Channel(_))));
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_kill_action() {
+        let json_world = r#"{
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/sleep 60" },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        let pending = || async {
+            let (response, rx) = oneshot::channel();
+            runner
+                .sender()
+                .send(RunnerMessage::GetTaskOverview {
+                    task_name: "task_a".to_owned(),
+                    max_intervals: 10,
+                    response,
+                })
+                .await
+                .unwrap();
+            rx.await.unwrap().unwrap().pending
+        };
+        let wait_for = |action_id: ActionId, state: ActionState| async move {
+            for _ in 0..100 {
+                if pending()
+                    .await
+                    .iter()
+                    .any(|p| p.action_id == action_id && p.state == state)
+                {
+                    return true;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            false
+        };
+
+        let action_ids: Vec<ActionId> = pending().await.iter().map(|p| p.action_id).collect();
+        assert_eq!(action_ids.len(), 2);
+        assert!(wait_for(action_ids[0], ActionState::Running).await);
+        runner.kill_action(action_ids[0]).await.unwrap();
+        assert!(wait_for(action_ids[0], ActionState::Errored).await);
+
+        // Only the killed action stops, and it can't be killed twice
+        assert!(wait_for(action_ids[1], ActionState::Running).await);
+        assert!(runner.kill_action(action_ids[0]).await.is_err());
+
+        runner.shutdown().await.unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy() {
+        let json_world = r#"{
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/false" },
+                    "retry": { "max_attempts": 3, "initial_delay_seconds": 0 },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-04T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        let end = Utc.with_ymd_and_hms(2022, 1, 3, 22, 0, 0).unwrap();
+        for _ in 0..50 {
+            if runner.attempts("task_a", end).await.unwrap().len() >= 3 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        // Retries stop once the attempts run out, leaving the action failed
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert_eq!(runner.attempts("task_a", end).await.unwrap().len(), 3);
+        let details = runner
+            .details(Interval::new(MIN_TIME, MAX_TIME), None)
+            .await
+            .unwrap();
+        assert_eq!(details["task_a"]["task_a"][0].state, ActionState::Failed);
+        let state = runner.state().await.unwrap();
+        assert!(state.failed["task_a"].has_subset(details["task_a"]["task_a"][0].interval));
+
+        // Retrying by hand starts a fresh budget
+        runner.retry(ActionId(0)).await.unwrap();
+        for _ in 0..50 {
+            if runner.attempts("task_a", end).await.unwrap().len() >= 6 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert_eq!(runner.attempts("task_a", end).await.unwrap().len(), 6);
+
+        // As does retrying in bulk
+        assert!(runner.retry_all(ActionState::Completed).await.is_err());
+        assert!(runner
+            .retry_all(ActionState::Errored)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            runner.retry_all(ActionState::Failed).await.unwrap(),
+            vec![ActionId(0)]
+        );
+        for _ in 0..50 {
+            if runner.attempts("task_a", end).await.unwrap().len() >= 9 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert_eq!(runner.attempts("task_a", end).await.unwrap().len(), 9);
+        assert!(runner.retry(ActionId(1)).await.is_err());
+
+        runner.shutdown().await.unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_persist_output() {
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        crate::storage::memory::start(storage_rx);
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        let stored = || {
+            let (response, rx) = oneshot::channel();
+            storage_tx
+                .try_send(StorageMessage::GetOutput {
+                    task_name: "task".to_owned(),
+                    interval,
+                    response,
+                })
+                .unwrap();
+            rx
+        };
+
+        // Left over from an earlier attempt
+        storage_tx
+            .send(StorageMessage::AppendOutput {
+                task_name: "task".to_owned(),
+                interval,
+                chunks: vec![OutputChunk {
+                    stream: OutputStream::Stdout,
+                    data: "stale\n".to_owned(),
+                }],
+                replace: false,
+            })
+            .await
+            .unwrap();
+
+        let tap = OutputTap::new();
+        let persister = tokio::spawn(persist_output(
+            "task".to_owned(),
+            interval,
+            tap.clone(),
+            storage_tx.clone(),
+        ));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        tap.push(OutputStream::Stdout, &vec![b'x'; OUTPUT_FLUSH_BYTES]);
+
+        // Stored while the command is still running
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let output = stored().await.unwrap().unwrap();
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0].data.len(), OUTPUT_FLUSH_BYTES);
+
+        tap.push(OutputStream::Stderr, b"oops\n");
+        tap.close();
+        persister.await.unwrap();
+        let output = stored().await.unwrap().unwrap();
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[1].data, "oops\n");
+    }
+
+    #[test]
+    fn check_output_cap() {
+        let chunk = |data: &str| OutputChunk {
+            stream: OutputStream::Stdout,
+            data: data.to_owned(),
+        };
+        let mut stored = MAX_STORED_OUTPUT_BYTES - 2;
+        assert_eq!(cap_output(chunk("a"), &mut stored), Some(chunk("a")));
+
+        // The chunk reaching the cap is cut on a character boundary
+        let cut = cap_output(chunk("éé"), &mut stored).unwrap();
+        assert!(cut.data.starts_with("\n[Output past"));
+        assert_eq!(stored, MAX_STORED_OUTPUT_BYTES);
+        assert_eq!(cap_output(chunk("b"), &mut stored), None);
+    }
+
+    #[tokio::test]
+    async fn test_infra_failures_not_counted() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "retry": { "max_attempts": 1, "initial_delay_seconds": 0 },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-04T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        // An executor that never reaches anywhere to run its tasks
+        let (tx, mut rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                match msg {
+                    ExecutorMessage::ValidateTask { response, .. } => {
+                        response.send(Ok(())).unwrap_or(())
+                    }
+                    ExecutorMessage::ExecuteTask { response, .. } => response
+                        .send(TaskAttempt {
+                            infra_failure: true,
+                            executor: vec!["Connection refused".to_owned()],
+                            ..TaskAttempt::new()
+                        })
+                        .unwrap_or(()),
+                    ExecutorMessage::Stop {} => break,
+                    _ => {}
+                }
+            }
+        });
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        let end = Utc.with_ymd_and_hms(2022, 1, 3, 22, 0, 0).unwrap();
+        for _ in 0..50 {
+            if runner.attempts("task_a", end).await.unwrap().len() >= 3 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        // Retried past max_attempts, since none of them got to run
+        assert!(runner.attempts("task_a", end).await.unwrap().len() >= 3);
+        let details = runner
+            .details(Interval::new(MIN_TIME, MAX_TIME), None)
+            .await
+            .unwrap();
+        assert_ne!(details["task_a"]["task_a"][0].state, ActionState::Failed);
+
+        runner.shutdown().await.unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_action_ledger() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "provides": [ "task_a" ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-04T00:00:00"
+                },
+                "task_b": {
+                    "up": { "command": "/bin/false" },
+                    "retry": { "max_attempts": 3, "initial_delay_seconds": 2 },
+                    "provides": [ "task_b" ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-04T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let spawn = |force_check| {
+            Runner::spawn(
+                world_def.taskset().unwrap(),
+                world_def.variables.clone(),
+                tx.clone(),
+                storage_tx.clone(),
+                world_def.output_options,
+                force_check,
+                true,
+            )
+        };
+        let end = Utc.with_ymd_and_hms(2022, 1, 3, 17, 0, 0).unwrap();
+        let wait_for = |runner: RunnerHandle, attempts: usize| async move {
+            tokio::time::timeout(std::time::Duration::from_secs(10), async {
+                while runner.attempts("task_b", end).await.unwrap().len() < attempts {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                }
+            })
+            .await
+            .unwrap()
+        };
+
+        // Stop while the second failure is waiting to be retried
+        let runner = spawn(true).await.unwrap();
+        wait_for(runner.clone(), 2).await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        runner.shutdown().await.unwrap();
+
+        // The retry and failure count survive the restart, so it only gets
+        // one more attempt
+        let runner = spawn(false).await.unwrap();
+        wait_for(runner.clone(), 3).await;
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        let state = runner.state().await.unwrap();
+        assert!(state.failed.contains_key("task_b"));
+        runner.shutdown().await.unwrap();
+
+        // So does giving up
+        let runner = spawn(false).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+        assert_eq!(runner.attempts("task_b", end).await.unwrap().len(), 3);
+        let state = runner.state().await.unwrap();
+        assert!(state.failed.contains_key("task_b"));
+        runner.shutdown().await.unwrap();
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recovered_action_ids() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        // A ledger from a run that had handed out more ids
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 3, 17, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 4, 17, 0, 0).unwrap(),
+        );
+        storage_tx
+            .send(StorageMessage::StoreActions {
+                actions: vec![ActionRecord {
+                    task_name: "task_a".to_owned(),
+                    interval,
+                    state: ActionState::Errored,
+                    action_id: Some(ActionId(41)),
+                    failures: 1,
+                    retry_at: None,
+                    fallback: false,
+                }],
+            })
+            .await
+            .unwrap();
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            false,
+        )
+        .await
+        .unwrap();
+        let recovered = std::mem::take(&mut runner.recovered);
+        runner.recover_actions(recovered, Utc::now());
+
+        // The action keeps its id, and new ones come after it
+        assert_eq!(runner.registry[&(0, interval)], ActionId(41));
+        assert_eq!(runner.actions[&ActionId(41)].state, ActionState::Errored);
+        assert!(runner.actions.len() > 1);
+        assert!(runner.actions.keys().all(|id| *id >= ActionId(41)));
+        assert!(runner.next_action > 42);
+        assert_eq!(runner.ledger()[0].action_id, Some(ActionId(41)));
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_skip_action() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/false" },
+                    "retry": { "initial_delay_seconds": 1 },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-04T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        let end = Utc.with_ymd_and_hms(2022, 1, 3, 17, 0, 0).unwrap();
+        let state = || async {
+            let details = runner
+                .details(Interval::new(MIN_TIME, MAX_TIME), None)
+                .await
+                .unwrap();
+            details["task_a"]["task_a"][0].state
+        };
+        for _ in 0..50 {
+            if state().await == ActionState::Errored {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+
+        // The pending retry never happens, and the interval stays down
+        runner.skip(ActionId(0)).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert_eq!(runner.attempts("task_a", end).await.unwrap().len(), 1);
+        assert_eq!(state().await, ActionState::Skipped);
+        let current = runner.state().await.unwrap().current;
+        assert!(current.get("task_a").is_none_or(|is| is.is_empty()));
+        assert!(runner.skip(ActionId(1)).await.is_err());
+
+        // Retrying picks it back up
+        runner.retry(ActionId(0)).await.unwrap();
+        for _ in 0..50 {
+            if runner.attempts("task_a", end).await.unwrap().len() >= 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert_eq!(runner.attempts("task_a", end).await.unwrap().len(), 2);
+
+        runner.shutdown().await.unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_escalation() {
+        let page_file = std::env::temp_dir().join("waterfall_escalation_page");
+        std::fs::remove_file(&page_file).unwrap_or(());
+        let json_world = format!(
+            r#"{{
+            "calendars": {{
+                "std": {{ "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }}
+            }},
+            "tasks": {{
+                "task_a": {{
+                    "up": {{ "command": "/bin/false" }},
+                    "escalation": {{
+                        "after_failures": 1,
+                        "page": {{ "command": "/usr/bin/touch {}" }},
+                        "then": "skip"
+                    }},
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                }}
+            }}
+        }}"#,
+            page_file.display()
+        );
+        let world_def: WorldDefinition = serde_json::from_str(&json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        // Skipped on the first failure, rather than retried
+        let mut skipped = false;
+        for _ in 0..100 {
+            let details = runner
+                .details(Interval::new(MIN_TIME, MAX_TIME), None)
+                .await
+                .unwrap();
+            if details["task_a"]["task_a"]
+                .iter()
+                .all(|a| a.state == ActionState::Skipped)
+            {
+                skipped = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(skipped);
+
+        // Paging runs alongside
+        for _ in 0..100 {
+            if page_file.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(page_file.exists());
+
+        runner.shutdown().await.unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resource_aliases() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "resource_aliases": { "prices": "prices_v2" },
+            "tasks": {
+                "prices_v2": {
+                    "up": { "command": "/bin/false" },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-06T00:00:00"
+                },
+                "report": {
+                    "up": { "command": "/bin/true" },
+                    "requires": [ { "resource": "prices", "offset": 0 } ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-06T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+        let tasks = world_def.taskset().unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        // The prices were produced under their old name
+        let mut state = tasks.coverage();
+        let prices = state.remove("prices_v2").unwrap();
+        state.remove("report");
+        state.insert(&"prices".to_owned(), &prices);
+        storage_tx
+            .send(StorageMessage::StoreState { state })
+            .await
+            .unwrap();
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            tasks,
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            false,
+        )
+        .await
+        .unwrap();
+        runner.set_resource_aliases(world_def.resource_aliases);
+        assert!(!runner.current.contains_key("prices"));
+
+        // prices_v2 would fail, so only the migrated state lets report run
+        tokio::time::timeout(std::time::Duration::from_secs(10), runner.run(false))
+            .await
+            .unwrap();
+
+        let (response, rx) = oneshot::channel();
+        storage_tx
+            .send(StorageMessage::LoadState { response })
+            .await
+            .unwrap();
+        let stored = rx.await.unwrap();
+        assert!(!stored.contains_key("prices"));
+        assert_eq!(stored["prices_v2"], prices);
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_world() {
+        let world = |task_a_up: &str, other: &str| -> WorldDefinition {
+            let json = format!(
+                r#"{{
+                "calendars": {{ "std": {{}} }},
+                "tasks": {{
+                    "task_a": {{
+                        "up": {{ "command": "{}" }},
+                        "calendar_name": "std",
+                        "times": [ "17:00:00" ],
+                        "timezone": "UTC",
+                        "valid_from": "2022-01-03T09:00:00",
+                        "valid_to": "2022-01-06T00:00:00"
+                    }},
+                    "{}": {{
+                        "up": {{ "command": "/bin/true" }},
+                        "requires": [ {{ "resource": "task_a", "offset": 0 }} ],
+                        "calendar_name": "std",
+                        "times": [ "17:00:00" ],
+                        "timezone": "UTC",
+                        "valid_from": "2022-01-03T09:00:00",
+                        "valid_to": "2022-01-06T00:00:00"
+                    }}
+                }}
+            }}"#,
+                task_a_up, other
+            );
+            serde_json::from_str(&json).unwrap()
+        };
+        let world_def = world("/bin/false", "task_b");
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables.clone(),
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+
+        // Invalid definitions are rejected, keeping the current world
+        let mut broken = world("/bin/true", "task_c");
+        broken.calendars.clear();
+        assert!(runner.reload_world(broken).await.is_err());
+        assert!(runner.end_state.contains_key("task_b"));
+
+        // task_a is fixed, task_b is replaced by task_c
+        runner
+            .reload_world(world("/bin/true", "task_c"))
+            .await
+            .unwrap();
+        assert!(!runner.end_state.contains_key("task_b"));
+        assert!(runner.task_overview("task_b", 1).is_none());
+
+        tokio::time::timeout(std::time::Duration::from_secs(10), runner.run(false))
+            .await
+            .unwrap();
+        assert_eq!(runner.current["task_c"], runner.end_state["task_c"]);
+        assert!(!runner.current.contains_key("task_b"));
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_action_output() {
+        let world_def: WorldDefinition = serde_json::from_value(serde_json::json!({
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": { "shell": "echo started; sleep 1; echo done" } },
+                    "provides": [ "task_a" ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-04T00:00:00"
+                }
+            }
+        }))
+        .unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables.clone(),
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        // The output of a running action can be followed to the end
+        let output = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                if let Ok(output) = runner.output(ActionId(0)).await {
+                    return output;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let lines: Vec<String> = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            output.json_lines(true).collect(),
+        )
+        .await
+        .unwrap();
+        let followed: String = lines
+            .iter()
+            .map(|line| serde_json::from_str::<OutputChunk>(line).unwrap().data)
+            .collect();
+        assert_eq!(followed, "started\ndone\n");
+
+        // Once it's done, its output is in its attempt
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(runner.output(ActionId(0)).await.is_err());
+
+        runner.shutdown().await.unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let world_def: WorldDefinition = serde_json::from_value(serde_json::json!({
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "provides": [ "task_a" ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-07T00:00:00"
+                }
+            }
+        }))
+        .unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables.clone(),
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            loop {
+                let state = runner.state().await.unwrap();
+                if state.current == state.coverage {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Forcing an interval down is seen going down, rerunning, and
+        // coming back up
+        let mut events = runner.subscribe().await.unwrap();
+        let forced = runner
+            .force_down(
+                HashSet::from(["task_a".to_owned()]),
+                Interval::new(
+                    Utc.with_ymd_and_hms(2022, 1, 5, 0, 0, 0).unwrap(),
+                    Utc.with_ymd_and_hms(2022, 1, 5, 1, 0, 0).unwrap(),
+                ),
+            )
+            .await
+            .unwrap();
+        let interval = forced[0].interval;
+        let mut seen = Vec::new();
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            loop {
+                let event = events.recv().await.unwrap();
+                let up = matches!(event, RunnerEvent::ResourceUp { .. });
+                seen.push(event);
+                if up {
+                    break;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let states: Vec<ActionState> = seen
+            .iter()
+            .filter_map(|event| match event {
+                RunnerEvent::ActionState {
+                    task_name, state, ..
+                } if task_name == "task_a" => Some(*state),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ActionState::Queued,
+                ActionState::Running,
+                ActionState::Completed
+            ]
+        );
+        assert!(matches!(
+            seen.first(),
+            Some(RunnerEvent::Alert {
+                notification: Notification::ForcedDown { .. }
+            })
+        ));
+        assert!(seen.contains(&RunnerEvent::ResourceDown {
+            resource: "task_a".to_owned(),
+            intervals: IntervalSet::from(interval),
+        }));
+        assert_eq!(
+            seen.last(),
+            Some(&RunnerEvent::ResourceUp {
+                resource: "task_a".to_owned(),
+                intervals: IntervalSet::from(interval),
+            })
+        );
+
+        runner.shutdown().await.unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_down_commands() {
+        let dir = std::env::temp_dir().join(format!("wf_down_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let downs = dir.join("downs");
+        let world = |valid_to: &str| -> WorldDefinition {
+            serde_json::from_value(serde_json::json!({
+                "variables": { "DIR": dir.to_str().unwrap() },
+                "calendars": { "std": {} },
+                "tasks": {
+                    "task_a": {
+                        "up": { "command": "/bin/true" },
+                        "down": { "command": { "shell": "echo ${yyyymmdd} >> ${DIR}/downs" } },
+                        "provides": [ "task_a" ],
+                        "calendar_name": "std",
+                        "times": [ "17:00:00" ],
+                        "timezone": "UTC",
+                        "valid_from": "2022-01-03T09:00:00",
+                        "valid_to": valid_to
+                    }
+                }
+            }))
+            .unwrap()
+        };
+        let world_def = world("2022-01-07T00:00:00");
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables.clone(),
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+        let until = |done: Box<dyn Fn(&RunnerState) -> bool>| {
+            let runner = runner.clone();
+            async move {
+                tokio::time::timeout(std::time::Duration::from_secs(10), async {
+                    while !done(&runner.state().await.unwrap()) {
+                        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    }
+                })
+                .await
+                .unwrap()
+            }
+        };
+        until(Box::new(|state| state.current == state.coverage)).await;
+        assert!(!downs.exists());
+
+        // Forcing an interval down cleans it up before it runs again
+        let downs_run = {
+            let downs = downs.clone();
+            move || {
+                std::fs::read_to_string(&downs)
+                    .map(|s| s.lines().count())
+                    .unwrap_or(0)
+            }
+        };
+        let forced = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 5, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 5, 1, 0, 0).unwrap(),
+        );
+        let task_a = HashSet::from(["task_a".to_owned()]);
+        assert!(runner
+            .force_down(HashSet::from(["task_b".to_owned()]), forced)
+            .await
+            .is_err());
+        assert!(runner
+            .force_down(task_a.clone(), Interval::new(forced.start, forced.start))
+            .await
+            .is_err());
+        let downed = runner.force_down(task_a, forced).await.unwrap();
+        assert_eq!(downed.len(), 1);
+        assert!(downed[0].interval.has_subset(forced));
+        let run = downs_run.clone();
+        until(Box::new(move |state| {
+            run() == 1 && state.current["task_a"].has_subset(forced)
+        }))
+        .await;
+
+        // So are intervals a reload leaves outside the task's validity
+        let dropped = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 5, 17, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 6, 17, 0, 0).unwrap(),
+        );
+        runner
+            .reload_world(world("2022-01-06T00:00:00"))
+            .await
+            .unwrap();
+        let run = downs_run.clone();
+        until(Box::new(move |state| {
+            run() == 2 && !state.current["task_a"].has_subset(dropped)
+        }))
+        .await;
+        runner.shutdown().await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&downs).unwrap(),
+            "20220105\n20220106\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recheck() {
+        let dir = std::env::temp_dir().join(format!("wf_recheck_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let world_def: WorldDefinition = serde_json::from_value(serde_json::json!({
+            "variables": { "DIR": dir.to_str().unwrap() },
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": { "shell": "touch ${DIR}/a_${yyyymmdd} && echo ${yyyymmdd} >> ${DIR}/ups" } },
+                    "check": { "command": "/bin/test -e ${DIR}/a_${yyyymmdd}" },
+                    "recheck_every_seconds": 1,
+                    "recheck_intervals": 2,
+                    "provides": [ "task_a" ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-07T00:00:00"
+                }
+            }
+        }))
+        .unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let runner = Runner::spawn(
+            world_def.taskset().unwrap(),
+            world_def.variables.clone(),
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+        let ups = || std::fs::read_to_string(dir.join("ups")).unwrap_or_default();
+        let wait_for = |lines: usize| {
+            let runner = runner.clone();
+            async move {
+                tokio::time::timeout(std::time::Duration::from_secs(10), async {
+                    loop {
+                        let state = runner.state().await.unwrap();
+                        if ups().lines().count() >= lines && state.current == state.coverage {
+                            break;
+                        }
+                        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    }
+                })
+                .await
+                .unwrap()
+            }
+        };
+        wait_for(4).await;
+
+        // Only the two most recent intervals are rechecked, and the one
+        // that's gone is produced again
+        std::fs::remove_file(dir.join("a_20220106")).unwrap();
+        std::fs::remove_file(dir.join("a_20220104")).unwrap();
+        wait_for(5).await;
+        assert_eq!(ups().lines().last(), Some("20220106"));
+        assert!(dir.join("a_20220106").exists());
+        assert!(!dir.join("a_20220104").exists());
+
+        runner.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_actions() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00", "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-10T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(1, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        let generated = runner.actions.len();
+        assert!(generated > 0);
+
+        // Regenerating, even from scratch as after a restart, adds nothing
+        runner.update_target();
+        runner.last_horizon = DateTime::<Utc>::MIN_UTC;
+        runner.update_target();
+        assert_eq!(runner.actions.len(), generated);
+
+        let keys: HashSet<(usize, Interval)> = runner
+            .actions
+            .values()
+            .map(|a| (a.task, a.interval))
+            .collect();
+        assert_eq!(keys.len(), generated);
+        assert_eq!(
+            runner.add_actions(runner.actions.values().cloned().collect()),
+            0
+        );
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_target_advances() {
+        let day =
+            |days| (Utc::now() + Duration::try_days(days).unwrap()).format("%Y-%m-%dT00:00:00");
+        let json_world = format!(
+            r#"{{
+            "calendars": {{ "std": {{ "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun" ] }} }},
+            "tasks": {{
+                "task_a": {{
+                    "up": {{ "command": "/bin/true" }},
+                    "calendar_name": "std",
+                    "times": [ "00:00:00", "06:00:00", "12:00:00", "18:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "{}",
+                    "valid_to": "{}"
+                }}
+            }}
+        }}"#,
+            day(-3),
+            day(30)
+        );
+        let world_def: WorldDefinition = serde_json::from_str(&json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(1, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        let generated: Vec<Interval> = runner.actions.values().map(|a| a.interval).collect();
+        let horizon = runner.last_horizon;
+        assert!(generated.iter().all(|intv| intv.end <= horizon));
+
+        // As though the horizon were a day behind, when the runner last
+        // generated actions
+        let behind = horizon - Duration::try_days(1).unwrap();
+        runner.actions.retain(|_, a| a.interval.end <= behind);
+        runner.registry.retain(|(_, intv), _| intv.end <= behind);
+        let kept = runner.actions.len();
+        assert!(kept < generated.len());
+        runner.last_horizon = behind;
+
+        // Only the intervals since then are added, in order
+        runner.update_target();
+        assert!(runner.last_horizon >= horizon);
+        let regenerated: Vec<Interval> = runner.actions.values().map(|a| a.interval).collect();
+        assert_eq!(regenerated[..generated.len()], generated[..]);
+        assert!(regenerated[generated.len()..]
+            .iter()
+            .all(|intv| intv.end > horizon));
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compact_actions() {
+        let day =
+            |days| (Utc::now() + Duration::try_days(days).unwrap()).format("%Y-%m-%dT00:00:00");
+        let json_world = format!(
+            r#"{{
+            "calendars": {{ "std": {{ "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun" ] }} }},
+            "tasks": {{
+                "task_a": {{
+                    "up": {{ "command": "/bin/true" }},
+                    "down": {{ "command": "/bin/true" }},
+                    "calendar_name": "std",
+                    "times": [ "00:00:00", "06:00:00", "12:00:00", "18:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "{}",
+                    "valid_to": "{}"
+                }}
+            }}
+        }}"#,
+            day(-30),
+            day(30)
+        );
+        let world_def: WorldDefinition = serde_json::from_str(&json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(1, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        let task_a = HashSet::from(["task_a".to_owned()]);
+        let now = Utc::now();
+        let midnight = now.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
+        let past = Interval::new(midnight - Duration::try_days(30).unwrap(), midnight);
+        runner.force_up(task_a.clone(), past).unwrap();
+        let generated = runner.actions.len();
+
+        // Only completed actions past the history are dropped
+        let cutoff = now - Duration::try_days(DEFAULT_ACTION_HISTORY_DAYS).unwrap();
+        runner.compact_actions(now);
+        assert!(runner.actions.len() < generated);
+        assert!(runner
+            .actions
+            .values()
+            .all(|a| a.interval.end >= cutoff || a.state != ActionState::Completed));
+        assert_eq!(runner.registry.len(), runner.actions.len());
+
+        // Regenerating them doesn't bring them back
+        let state = runner.tasks.get_state(now);
+        let actions = runner.generate_actions(&[0], &state);
+        assert_eq!(runner.add_actions(actions), 0);
+
+        // The timeline still lists them, from the current state
+        let everything = Interval::new(MIN_TIME, MAX_TIME);
+        let page = runner.get_resource_state_details(everything, &DetailsOptions::default());
+        let listed = &page.details["task_a"]["task_a"];
+        assert_eq!(listed.len(), generated);
+        assert!(listed
+            .iter()
+            .filter(|a| a.interval.end < cutoff)
+            .all(|a| a.state == ActionState::Completed));
+
+        // Forcing down a compacted interval takes it down and runs it again
+        let old = Interval::new(
+            midnight - Duration::try_days(20).unwrap(),
+            midnight - Duration::try_days(19).unwrap(),
+        );
+        runner.force_down(task_a, old).unwrap();
+        let restored: Vec<&Action> = runner
+            .actions
+            .values()
+            .filter(|a| old.has_subset(a.interval))
+            .collect();
+        assert_eq!(restored.len(), 4);
+        assert!(restored.iter().all(|a| a.state == ActionState::Running));
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let day =
+            |days| (Utc::now() + Duration::try_days(days).unwrap()).format("%Y-%m-%dT00:00:00");
+        let json_world = format!(
+            r#"{{
+            "calendars": {{ "std": {{ "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun" ] }} }},
+            "tasks": {{
+                "task_a": {{
+                    "up": {{ "command": "/bin/true" }},
+                    "calendar_name": "std",
+                    "times": [ "00:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "{}",
+                    "valid_to": "{}"
+                }}
+            }}
+        }}"#,
+            day(-3),
+            day(3)
+        );
+        let world_def: WorldDefinition = serde_json::from_str(&json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(1, rx);
+        // Storage that isn't keeping up
+        let (storage_tx, mut storage_rx) = mpsc::channel(8);
+        for _ in 0..7 {
+            storage_tx
+                .send(StorageMessage::StoreState {
+                    state: ResourceInterval::new(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        let running = |runner: &Runner| {
+            runner
+                .actions
+                .values()
+                .filter(|a| a.state == ActionState::Running)
+                .count()
+        };
+
+        // Nothing is dispatched while storage is backed up
+        runner.queue_actions();
+        assert_eq!(running(&runner), 0);
+        let depths = runner.queue_depths();
+        assert!(depths.lagging);
+        assert_eq!(
+            depths.storage,
+            QueueDepth {
+                depth: 7,
+                capacity: 8
+            }
+        );
+
+        // Snapshots that don't fit wait for a later tick
+        runner.store_state();
+        runner.store_actions();
+        assert!(!runner.state_unstored);
+        assert!(runner.actions_unstored);
+
+        // Once storage catches up, the snapshot is sent and dispatch resumes
+        while storage_rx.try_recv().is_ok() {}
+        runner.tick();
+        assert!(!runner.actions_unstored);
+        assert!(!runner.queue_depths().lagging);
+        assert!(running(&runner) > 0);
+        assert!(matches!(
+            storage_rx.try_recv(),
+            Ok(StorageMessage::StoreActions { .. })
+        ));
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_priorities() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "backfill": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-06T00:00:00"
+                },
+                "end_of_day": {
+                    "up": { "command": "/bin/true" },
+                    "priority": 10,
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-06T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(1, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+
+        // Every end_of_day interval goes first, each task oldest first
+        let order: Vec<(String, DateTime<Utc>)> = runner
+            .runnable_actions()
+            .iter()
+            .map(|&action_id| {
+                let action = &runner.actions[&action_id];
+                (runner.tasks[action.task].name.clone(), action.interval.end)
+            })
+            .collect();
+        let names: Vec<&str> = order.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "end_of_day",
+                "end_of_day",
+                "end_of_day",
+                "backfill",
+                "backfill",
+                "backfill"
+            ]
+        );
+        assert!(order[..3].windows(2).all(|w| w[0].1 < w[1].1));
+        assert!(order[3..].windows(2).all(|w| w[0].1 < w[1].1));
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_running() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/sleep 60" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-08T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        runner.set_max_running(2);
+
+        let running = |runner: &Runner| {
+            runner
+                .actions
+                .values()
+                .filter(|a| a.state == ActionState::Running)
+                .count()
+        };
+        assert_eq!(runner.runnable_actions().len(), 5);
+        runner.queue_actions();
+        assert_eq!(running(&runner), 2);
+
+        // Nothing more is dispatched until a slot frees up
+        runner.queue_actions();
+        assert_eq!(running(&runner), 2);
+
+        runner.cancellation_token().cancel();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backfill_throttle() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "backfill": { "max_launches_per_minute": 2 },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-08T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        runner.set_backfill(world_def.backfill.unwrap());
+
+        let launched = |runner: &Runner| {
+            runner
+                .actions
+                .values()
+                .filter(|a| a.state != ActionState::Queued)
+                .count()
+        };
+        assert_eq!(runner.runnable_actions().len(), 5);
+        runner.queue_actions();
+        assert_eq!(launched(&runner), 2);
+        assert_eq!(runner.actions[&ActionId(0)].state, ActionState::Running);
+        assert_eq!(runner.actions[&ActionId(1)].state, ActionState::Running);
+
+        // Nothing more launches until earlier launches age out of the window
+        runner.queue_actions();
+        assert_eq!(launched(&runner), 2);
+        assert_eq!(runner.runnable_actions().len(), 3);
+
+        runner.cancellation_token().cancel();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_details_paging() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "provides": [ "task_a" ],
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-08T00:00:00"
+                },
+                "task_b": {
+                    "up": { "command": "/bin/true" },
+                    "provides": [ "task_b" ],
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        let everything = Interval::new(MIN_TIME, MAX_TIME);
+        let count = |details: &ResourceStateDetails| -> usize {
+            details
+                .values()
+                .flat_map(|t| t.values())
+                .map(|a| a.len())
+                .sum()
+        };
+        let total = runner.actions.len();
+        assert!(total > 3);
+
+        // Only the resources asked for
+        let options = DetailsOptions {
+            resources: Some(HashSet::from(["task_b".to_owned()])),
+            ..DetailsOptions::default()
+        };
+        let page = runner.get_resource_state_details(everything, &options);
+        assert_eq!(page.details.keys().collect::<Vec<_>>(), vec!["task_b"]);
+        assert_eq!(
+            count(&page.details),
+            runner
+                .actions
+                .values()
+                .filter(|a| Some(a.task) == runner.task_id("task_b"))
+                .count()
+        );
+        assert!(page.next_cursor.is_none());
+
+        // Pages cover every action once, in order
+        let mut options = DetailsOptions {
+            limit: Some(3),
+            ..DetailsOptions::default()
+        };
+        let mut seen = Vec::new();
+        loop {
+            let page = runner.get_resource_state_details(everything, &options);
+            assert!(count(&page.details) <= 3);
+            for (task_name, actions) in page.details.values().flatten() {
+                seen.extend(actions.iter().map(|a| (task_name.clone(), a.interval)));
+            }
+            match page.next_cursor {
+                Some(cursor) => {
+                    let parsed: DetailsCursor = cursor.to_string().parse().unwrap();
+                    assert_eq!(parsed, cursor);
+                    options.cursor = Some(cursor);
+                }
+                None => break,
+            }
+        }
+        assert_eq!(seen.len(), total);
+        seen.sort_unstable();
+        seen.dedup();
+        assert_eq!(seen.len(), total);
+        assert!("task_a".parse::<DetailsCursor>().is_err());
+
+        // Buckets take the state of their least healthy action
+        let ids: Vec<ActionId> = runner
+            .actions
+            .iter()
+            .filter(|(_, a)| Some(a.task) == runner.task_id("task_a"))
+            .map(|(id, _)| *id)
+            .collect();
+        runner.actions.get_mut(&ids[1]).unwrap().state = ActionState::Errored;
+        runner.actions.get_mut(&ids[2]).unwrap().state = ActionState::Completed;
+        let options = DetailsOptions {
+            bucket_seconds: Some(30 * 86400),
+            ..DetailsOptions::default()
+        };
+        let page = runner.get_resource_state_details(everything, &options);
+        let task_a = &page.details["task_a"]["task_a"];
+        assert_eq!(task_a.len(), 1);
+        assert_eq!(task_a[0].state, ActionState::Errored);
+        let spanned: Vec<Interval> = runner
+            .actions
+            .values()
+            .filter(|a| Some(a.task) == runner.task_id("task_a"))
+            .map(|a| a.interval)
+            .collect();
+        assert_eq!(
+            task_a[0].interval,
+            Interval::new(spanned[0].start, spanned[spanned.len() - 1].end)
+        );
+
+        runner.cancellation_token().cancel();
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pause_task() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                },
+                "task_b": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(1, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+
+        let runnable = |runner: &Runner| -> HashSet<String> {
+            runner
+                .runnable_actions()
+                .iter()
+                .map(|&action_id| runner.tasks[runner.actions[&action_id].task].name.clone())
+                .collect()
+        };
+        runner.set_paused("task_a", true).unwrap();
+        assert_eq!(runnable(&runner), HashSet::from(["task_b".to_owned()]));
+        assert!(runner.task_overview("task_a", 10).unwrap().paused);
+        assert!(runner.set_paused("missing", true).is_err());
+
+        runner.set_paused("task_a", false).unwrap();
+        assert_eq!(runnable(&runner).len(), 2);
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_task_groups() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "pricing/eod/load": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                },
+                "fx": {
+                    "up": { "command": "/bin/true" },
+                    "group": "pricing",
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                },
+                "risk/var": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(1, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+
+        // Groups take in the groups nested under them
+        assert_eq!(
+            runner.set_group_paused("pricing", true).unwrap(),
+            vec!["fx".to_owned(), "pricing/eod/load".to_owned()]
+        );
+        let runnable: HashSet<String> = runner
+            .runnable_actions()
+            .iter()
+            .map(|&action_id| runner.tasks[runner.actions[&action_id].task].name.clone())
+            .collect();
+        assert_eq!(runnable, HashSet::from(["risk/var".to_owned()]));
+        assert!(runner.set_group_paused("pricing/intraday", true).is_err());
+        runner.set_group_paused("pricing/eod", false).unwrap();
+        assert!(!runner.task_overview("pricing/eod/load", 10).unwrap().paused);
+        assert!(runner.task_overview("fx", 10).unwrap().paused);
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 6, 0, 0, 0).unwrap(),
+        );
+        let page = runner.get_resource_state_details(
+            interval,
+            &DetailsOptions {
+                groups: Some(HashSet::from(["pricing/eod".to_owned()])),
+                ..DetailsOptions::default()
+            },
+        );
+        assert_eq!(
+            page.details.keys().collect::<Vec<_>>(),
+            vec!["pricing/eod/load"]
+        );
+        assert_eq!(
+            page.groups,
+            HashMap::from([("pricing/eod/load".to_owned(), "pricing/eod".to_owned())])
+        );
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sla_breaches() {
+        let json_world = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "alert_delay_seconds": 3600,
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                },
+                "task_b": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00",
+                    "valid_to": "2022-01-05T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(1, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::noop::start(storage_rx);
+
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables,
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        let (alert_tx, mut alert_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        runner.set_notifications(alert_tx);
+        let late_task = |notification: Notification| match notification {
+            Notification::SlaBreached {
+                task_name,
+                deadline,
+                state,
+                ..
+            } => (task_name, deadline, state),
+            other => panic!("Expected an SLA breach, got {:?}", other),
+        };
+
+        // Deadlines missed before the runner started are ignored
+        runner.check_deadlines(Utc::now());
+        assert!(alert_rx.try_recv().is_err());
+
+        // 09:00 on the 3rd, plus an hour
+        runner.started = MIN_TIME;
+        let deadline = Utc.with_ymd_and_hms(2022, 1, 3, 10, 0, 0).unwrap();
+        runner.check_deadlines(deadline - Duration::seconds(1));
+        assert!(alert_rx.try_recv().is_err());
+        runner.check_deadlines(deadline);
+        assert_eq!(
+            late_task(alert_rx.try_recv().unwrap()),
+            ("task_a".to_owned(), deadline, ActionState::Queued)
+        );
+        assert!(alert_rx.try_recv().is_err());
+
+        // Each interval is alerted on once
+        runner.check_deadlines(Utc::now());
+        assert_eq!(late_task(alert_rx.try_recv().unwrap()).0, "task_a");
+        runner.check_deadlines(Utc::now());
+        assert!(alert_rx.try_recv().is_err());
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sharded_runners() {
+        let json_world = r#"{
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-06T00:00:00"
+                },
+                "task_b": {
+                    "up": { "command": "/bin/true" },
+                    "requires": [ { "resource": "task_a", "offset": 0 } ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-06T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json_world).unwrap();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+
+        let make_runner = |name: &str, prefix: &str| {
+            let world_def = &world_def;
+            let tx = tx.clone();
+            let storage_tx = storage_tx.clone();
+            let shard = Shard {
+                name: name.to_owned(),
+                prefixes: vec![prefix.to_owned()],
+            };
+            async move {
+                let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+                let mut runner = Runner::new(
+                    world_def.taskset().unwrap(),
+                    world_def.variables.clone(),
+                    runner_rx,
+                    tx,
+                    storage_tx,
+                    world_def.output_options,
+                    true,
+                )
+                .await
+                .unwrap();
+                runner.set_shard(shard, true).await.map(|_| runner)
+            }
+        };
+
+        let mut runner_a = make_runner("a", "task_a").await.unwrap();
+        let mut runner_b = make_runner("b", "task_b").await.unwrap();
+
+        // Resources can only be claimed by one shard
+        assert!(make_runner("c", "task_").await.is_err());
+
+        // task_b can only complete once shard a's state is shared
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            tokio::join!(runner_a.run(false), runner_b.run(false));
+        })
+        .await
+        .unwrap();
+        assert_eq!(runner_b.current, runner_b.end_state);
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prune_retired_tasks() {
+        let world = |tasks: &[&str]| -> WorldDefinition {
+            let tasks: serde_json::Map<String, serde_json::Value> = tasks
+                .iter()
+                .map(|name| {
+                    (
+                        name.to_string(),
+                        serde_json::json!({
+                            "up": { "command": "/bin/true" },
+                            "calendar_name": "std",
+                            "times": [ "17:00:00" ],
+                            "timezone": "UTC",
+                            "valid_from": "2022-01-03T09:00:00",
+                            "valid_to": "2022-01-06T00:00:00"
+                        }),
+                    )
+                })
+                .collect();
+            serde_json::from_value(serde_json::json!({
+                "calendars": { "std": {} },
+                "tasks": tasks,
+            }))
+            .unwrap()
+        };
+        let world_def = world(&["task_a", "task_b"]);
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables.clone(),
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        runner
+            .set_shard(
+                Shard {
+                    name: "a".to_owned(),
+                    prefixes: vec!["task_".to_owned()],
+                },
+                true,
+            )
+            .await
+            .unwrap();
+
+        // Removed tasks are dropped with their actions, and the rest are
+        // renumbered
+        runner.reload_world(world(&["task_a"])).await.unwrap();
+        assert_eq!(runner.tasks.len(), 2);
+        runner.prune_retired();
+        assert_eq!(runner.tasks.len(), 1);
+        assert_eq!(runner.tasks[0].name, "task_a");
+        assert!(runner.retired.is_empty());
+        assert!(!runner.actions.is_empty());
+        assert!(runner.actions.values().all(|a| a.task == 0));
+        assert_eq!(runner.registry.len(), runner.actions.len());
+
+        // Only the resources of removed tasks are released
+        let claim = |shard: &str, resource: &str| {
+            let storage_tx = storage_tx.clone();
+            let shard = shard.to_owned();
+            let resources = HashSet::from([resource.to_owned()]);
+            async move {
+                let (response, rx) = oneshot::channel();
+                storage_tx
+                    .send(StorageMessage::ClaimResources {
+                        shard,
+                        resources,
+                        response,
+                    })
+                    .await
+                    .unwrap();
+                rx.await.unwrap()
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while claim("b", "task_b").await.is_err() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(claim("b", "task_a").await.is_err());
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_breaker_holds_due_retries() {
+        let world_def: WorldDefinition = serde_json::from_value(serde_json::json!({
+            "calendars": { "std": {} },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/false" },
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T09:00:00",
+                    "valid_to": "2022-01-06T00:00:00"
+                }
+            }
+        }))
+        .unwrap();
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let executor = local_executor::start(10, rx);
+        let (storage_tx, storage_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let storage = storage::memory::start(storage_rx);
+        let (_runner_tx, runner_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut runner = Runner::new(
+            world_def.taskset().unwrap(),
+            world_def.variables.clone(),
+            runner_rx,
+            tx.clone(),
+            storage_tx.clone(),
+            world_def.output_options,
+            true,
+        )
+        .await
+        .unwrap();
+        runner.set_circuit_breaker(CircuitBreakerConfig {
+            max_failures_per_minute: 0,
+            cooldown_seconds: 60,
+            initial_retries: 1,
+        });
+        let ids: Vec<ActionId> = runner.actions.keys().copied().collect();
+        for action_id in &ids[..2] {
+            runner.actions.get_mut(action_id).unwrap().state = ActionState::Errored;
+        }
+
+        // A retry scheduled before the breaker tripped is held, not run
+        let now = Utc::now();
+        runner.retry_at.insert(ids[0], now);
+        let breaker = runner.breaker.as_mut().unwrap();
+        assert!(!breaker.on_failure(now, 0, ids[1]));
+        runner.retry_due(ids[0]);
+        assert_eq!(runner.actions[&ids[0]].state, ActionState::Errored);
+        assert!(runner.retry_at.contains_key(&ids[0]));
+
+        // And released once the breaker recovers
+        let breaker = runner.breaker.as_mut().unwrap();
+        let later = now + Duration::try_seconds(61).unwrap();
+        assert_eq!(breaker.release(later), vec![ids[1]]);
+        breaker.on_success(ids[1]);
+        assert_eq!(breaker.release(later), vec![ids[0]]);
+
+        tx.send(ExecutorMessage::Stop {}).await.unwrap();
+        executor.await.unwrap();
+        storage_tx.send(StorageMessage::Stop {}).await.unwrap();
+        storage.await.unwrap();
     }
 }
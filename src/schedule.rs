@@ -1,26 +1,53 @@
 use super::*;
 use std::collections::HashSet;
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Schedule {
     calendar: Calendar,
+    #[serde(deserialize_with = "deserialize_times")]
     times: Vec<NaiveTime>,
     timezone: Tz,
 }
 
+/// Times are kept sorted and unique, regardless of how they were provided
+fn normalize_times(times: Vec<NaiveTime>) -> Vec<NaiveTime> {
+    let uniq: HashSet<NaiveTime> = HashSet::from_iter(times);
+    let mut times = Vec::from_iter(uniq);
+    times.sort();
+    times
+}
+
+fn deserialize_times<'de, D>(deserializer: D) -> std::result::Result<Vec<NaiveTime>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(normalize_times(Vec::deserialize(deserializer)?))
+}
+
 impl Schedule {
     pub fn new(calendar: Calendar, times: Vec<NaiveTime>, timezone: Tz) -> Self {
-        let uniq: HashSet<NaiveTime> = HashSet::from_iter(times.iter().cloned());
-        let mut times = Vec::from_iter(uniq.iter().cloned());
-        times.sort();
         Schedule {
             calendar,
-            times,
+            times: normalize_times(times),
             timezone,
         }
     }
 
+    pub fn calendar(&self) -> &Calendar {
+        &self.calendar
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    /// The position of `dt` among its day's scheduled times, if it's one
+    pub fn period_index<T: TimeZone>(&self, dt: DateTime<T>) -> Option<usize> {
+        let at = dt.with_timezone(&self.timezone);
+        self.times.iter().position(|x| *x == at.time())
+    }
+
     fn is_end_time<T: TimeZone>(&self, dt: DateTime<T>) -> bool {
         // Need to get the current interval, then offset it
         let at = dt.with_timezone(&self.timezone);
@@ -58,21 +85,17 @@ impl Schedule {
         //let et = interval.end.with_timezone(&self.timezone);
 
         let mut date = self.calendar.prev(st.date_naive());
-        let end_date = self.calendar.next(et.date_naive().succ_opt().unwrap());
+        let end_date = self
+            .calendar
+            .next(et.date_naive().succ_opt().unwrap_or(NaiveDate::MAX));
 
         let mut times = Vec::new();
         let mut prev_time = self
-            .timezone
-            .from_local_datetime(&date.and_time(self.times[0]))
-            .unwrap()
+            .localize(&date.and_time(self.times[0]))
             .with_timezone(&Utc);
         while date < end_date {
             for time in &self.times {
-                let dt = self
-                    .timezone
-                    .from_local_datetime(&date.and_time(*time))
-                    .unwrap()
-                    .with_timezone(&Utc);
+                let dt = self.localize(&date.and_time(*time)).with_timezone(&Utc);
                 if dt > interval.start && dt <= interval.end {
                     times.push(Interval::new(prev_time, dt));
                 } else if interval.end < dt {
@@ -108,52 +131,60 @@ impl Schedule {
     pub fn next_time<T: TimeZone>(&self, dt: DateTime<T>) -> DateTime<Tz> {
         let st = dt.with_timezone(&self.timezone);
 
-        let mut date = st.date_naive();
-        let mut time = st.time();
-
-        // Handle case where we're not on a valid date
-        if !self.calendar.includes(date) {
-            date = self.calendar.next(date);
-            time = self.times[0] - Duration::try_milliseconds(1).unwrap();
-        }
+        let date = st.date_naive();
+        let first = *self.times.first().unwrap();
 
-        // Figure out the time slot
-        let time = match self.times.iter().find(|x| **x > time) {
-            Some(t) => date.and_time(*t),
-            None => self
-                .calendar
-                .next(date)
-                .and_time(*self.times.first().unwrap()),
+        // Figure out the time slot. Off the calendar, that's the first time
+        // of the next valid date, which may be a month away.
+        let time = if !self.calendar.includes(date) {
+            self.calendar.next(date).and_time(first)
+        } else {
+            match self.times.iter().find(|x| **x > st.time()) {
+                Some(t) => date.and_time(*t),
+                None => self.calendar.next(date).and_time(first),
+            }
         };
 
         // Cast into a timezone
-        self.timezone.from_local_datetime(&time).unwrap()
+        self.localize(&time)
     }
 
     /// Given a time, generate the preceding interval according to the schedule
     pub fn prev_time<T: TimeZone>(&self, dt: DateTime<T>) -> DateTime<Tz> {
         let st = dt.with_timezone(&self.timezone);
 
-        let mut date = st.date_naive();
-        let mut time = st.time();
-
-        // Handle case where we're not on a valid date
-        if !self.calendar.includes(date) {
-            date = self.calendar.prev(date);
-            time = *self.times.last().unwrap() + Duration::try_milliseconds(1).unwrap();
-        }
+        let date = st.date_naive();
+        let last = *self.times.last().unwrap();
 
         // Figure out the time slot
-        let time = match self.times.iter().rev().find(|x| **x < time) {
-            Some(t) => date.and_time(*t),
-            None => self
-                .calendar
-                .prev(date)
-                .and_time(*self.times.last().unwrap()),
+        let time = if !self.calendar.includes(date) {
+            self.calendar.prev(date).and_time(last)
+        } else {
+            match self.times.iter().rev().find(|x| **x < st.time()) {
+                Some(t) => date.and_time(*t),
+                None => self.calendar.prev(date).and_time(last),
+            }
         };
 
         // Cast into a timezone
-        self.timezone.from_local_datetime(&time).unwrap()
+        self.localize(&time)
+    }
+
+    /// The local time in the schedule's timezone. A time repeated when the
+    /// clocks go back is its first occurrence, and one skipped when they go
+    /// forward is moved forward by the gap.
+    pub fn localize(&self, time: &NaiveDateTime) -> DateTime<Tz> {
+        use chrono::LocalResult;
+        match self.timezone.from_local_datetime(time) {
+            LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => dt,
+            LocalResult::None => {
+                let later = *time + Duration::try_hours(1).unwrap();
+                self.timezone
+                    .from_local_datetime(&later)
+                    .earliest()
+                    .unwrap_or_else(|| self.timezone.from_utc_datetime(time))
+            }
+        }
     }
 
     // Given a timestamp, return the scheduled time `offset`
@@ -176,6 +207,33 @@ impl Schedule {
 mod tests {
     use super::*;
 
+    #[test]
+    fn check_dst_gap() {
+        // 02:30 doesn't exist in New York on 2022-03-13
+        let timezone = chrono_tz::America::New_York;
+        let mut calendar = Calendar::new();
+        calendar.mask.extend([Weekday::Sat, Weekday::Sun]);
+        let sched = Schedule {
+            calendar,
+            times: vec![NaiveTime::from_hms_opt(2, 30, 0).unwrap()],
+            timezone,
+        };
+        let gap = NaiveDate::from_ymd_opt(2022, 3, 13)
+            .unwrap()
+            .and_hms_opt(2, 30, 0)
+            .unwrap();
+        assert_eq!(
+            sched.localize(&gap),
+            timezone.with_ymd_and_hms(2022, 3, 13, 3, 30, 0).unwrap()
+        );
+
+        let times = sched.generate(Interval::new(
+            Utc.with_ymd_and_hms(2022, 3, 12, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 3, 15, 0, 0, 0).unwrap(),
+        ));
+        assert_eq!(times.len(), 3);
+    }
+
     #[test]
     fn check_simple_generation() {
         let timezone = chrono_tz::America::Halifax;
@@ -450,4 +508,77 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn check_deserialize_normalizes_times() {
+        let json = r#"{
+            "calendar": { "mask": [ "Mon" ] },
+            "times": [ "12:00:00", "09:00:00", "12:00:00" ],
+            "timezone": "UTC"
+        }"#;
+        let sched: Schedule = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            sched.times,
+            vec![
+                NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+                NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+            ]
+        );
+
+        let round_trip: Schedule =
+            serde_json::from_str(&serde_json::to_string(&sched).unwrap()).unwrap();
+        assert_eq!(round_trip, sched);
+    }
+    #[test]
+    fn check_monthly() {
+        let utc = |m, d, h| Utc.with_ymd_and_hms(2022, m, d, h, 0, 0).unwrap();
+
+        // Month end reporting, at 18:00 on the last business day
+        let sched = Schedule::new(
+            Calendar {
+                rules: vec![CalendarRule::BusinessDay(-1)],
+                ..Calendar::new()
+            },
+            vec![NaiveTime::from_hms_opt(18, 0, 0).unwrap()],
+            chrono_tz::UTC,
+        );
+        assert_eq!(
+            sched.generate(Interval::new(utc(1, 1, 0), utc(4, 1, 0))),
+            vec![
+                Interval::new(
+                    Utc.with_ymd_and_hms(2021, 12, 31, 18, 0, 0).unwrap(),
+                    utc(1, 31, 18)
+                ),
+                Interval::new(utc(1, 31, 18), utc(2, 28, 18)),
+                Interval::new(utc(2, 28, 18), utc(3, 31, 18)),
+            ]
+        );
+        assert_eq!(
+            sched.interval(utc(2, 14, 0), 0),
+            Interval::new(utc(1, 31, 18), utc(2, 28, 18))
+        );
+        // 2022-04-30 is a Saturday
+        assert_eq!(
+            sched.interval(utc(2, 14, 0), 2),
+            Interval::new(utc(3, 31, 18), utc(4, 29, 18))
+        );
+
+        // Anchored at midnight on the first of the month
+        let sched = Schedule::new(
+            Calendar {
+                rules: vec![CalendarRule::MonthDay(1)],
+                ..Calendar::new()
+            },
+            vec![NaiveTime::from_hms_opt(0, 0, 0).unwrap()],
+            chrono_tz::UTC,
+        );
+        assert_eq!(
+            sched.interval(utc(1, 15, 12), 0),
+            Interval::new(utc(1, 1, 0), utc(2, 1, 0))
+        );
+        assert_eq!(
+            sched.align_interval(Interval::new(utc(1, 15, 0), utc(3, 10, 0))),
+            Interval::new(utc(1, 1, 0), utc(4, 1, 0))
+        );
+    }
 }
@@ -0,0 +1,105 @@
+use super::*;
+
+/// A partition of a world, run by one of several runners sharing a storage
+/// namespace. Every runner loads the whole world, but only runs the tasks
+/// providing resources its shard owns. Resources owned by other shards are
+/// read from their state in storage.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Shard {
+    pub name: String,
+
+    /// Resources starting with any of these prefixes belong to the shard
+    pub prefixes: Vec<String>,
+}
+
+/// The state of each shard, by shard name
+pub type ShardStates = HashMap<String, ResourceInterval>;
+
+impl Shard {
+    pub fn owns(&self, resource: &str) -> bool {
+        self.prefixes
+            .iter()
+            .any(|p| resource.starts_with(p.as_str()))
+    }
+
+    /// Flags the tasks run by this shard. A task providing resources both
+    /// inside and outside the shard can't be run by either, and is an error.
+    pub fn owned_tasks(&self, tasks: &TaskSet) -> Result<Vec<bool>> {
+        tasks
+            .iter()
+            .map(|task| {
+                let owned = task.provides.iter().filter(|r| self.owns(r)).count();
+                if owned == 0 {
+                    Ok(false)
+                } else if owned == task.provides.len() {
+                    Ok(true)
+                } else {
+                    Err(Error::Validation(format!(
+                        "Task {} provides resources both inside and outside of shard {}",
+                        task.name, self.name
+                    )))
+                }
+            })
+            .collect()
+    }
+
+    /// The subset of the state belonging to this shard
+    pub fn filter(&self, state: &ResourceInterval) -> ResourceInterval {
+        state
+            .iter()
+            .filter(|(res, _)| self.owns(res))
+            .map(|(res, is)| (res.clone(), is.clone()))
+            .collect::<HashMap<Resource, IntervalSet>>()
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_owned_tasks() {
+        let json = r#"{
+            "calendars": { "std": {} },
+            "tasks": {
+                "pricing/load": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-02-01T00:00:00"
+                },
+                "risk/load": {
+                    "up": { "command": "/bin/true" },
+                    "provides": [ "risk/load", "pricing/risk" ],
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-02-01T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json).unwrap();
+        let tasks = world_def.taskset().unwrap();
+
+        let shard = Shard {
+            name: "all".to_owned(),
+            prefixes: vec!["pricing/".to_owned(), "risk/".to_owned()],
+        };
+        assert!(shard.owned_tasks(&tasks).unwrap().iter().all(|x| *x));
+
+        // risk/load straddles the shard boundary
+        let shard = Shard {
+            name: "pricing".to_owned(),
+            prefixes: vec!["pricing/".to_owned()],
+        };
+        assert!(matches!(
+            shard.owned_tasks(&tasks),
+            Err(Error::Validation(_))
+        ));
+    }
+}
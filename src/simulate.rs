@@ -0,0 +1,331 @@
+use super::*;
+
+/// An action the runner would take, and when it would take it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimulatedAction {
+    /// Simulated time at which the action runs
+    pub time: DateTime<Utc>,
+    pub task_name: String,
+    pub interval: Interval,
+}
+
+/// Replays the runner's scheduling logic over `(from, to]` against a
+/// simulated clock and an executor where every action succeeds instantly.
+/// Resources due before `from` are assumed to be available.
+///
+/// Actions whose requirements can never be met within the window are left
+/// out of the result.
+pub fn simulate(
+    world: &WorldDefinition,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<SimulatedAction>> {
+    let tasks = world.taskset()?;
+
+    let mut current = tasks.get_state(from);
+    let required = tasks.get_state(to).difference(&current);
+
+    let mut queued: Vec<(usize, Interval)> = Vec::new();
+    for (tid, task) in tasks.iter().enumerate() {
+        queued.extend(
+            task.generate_intervals(&required)?
+                .into_iter()
+                .map(|interval| (tid, interval)),
+        );
+    }
+    queued.sort_unstable_by_key(|(tid, interval)| (interval.end, *tid));
+
+    let mut actions = Vec::new();
+    let mut now = from;
+    loop {
+        // Run everything that's eligible, until nothing else becomes eligible
+        let mut ran = true;
+        while ran {
+            ran = false;
+            let mut idx = 0;
+            while idx < queued.len() {
+                let (tid, interval) = queued[idx];
+                let task = &tasks[tid];
+                if interval.end <= now && task.can_run(interval, &current) {
+                    for res in &task.provides {
+                        current.entry(res.clone()).or_default().insert(interval);
+                    }
+                    actions.push(SimulatedAction {
+                        time: now,
+                        task_name: task.name.clone(),
+                        interval,
+                    });
+                    queued.remove(idx);
+                    ran = true;
+                } else {
+                    idx += 1;
+                }
+            }
+        }
+
+        // Advance the clock to the next time something becomes due
+        match queued.iter().map(|(_, i)| i.end).filter(|t| *t > now).min() {
+            Some(next) if next <= to => now = next,
+            _ => break,
+        }
+    }
+
+    Ok(actions)
+}
+
+/// Whether a planned action could start straight away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanStatus {
+    /// Its requirements are already available
+    Ready,
+    /// Its requirements are produced by other actions in the plan
+    Waiting,
+    /// Its requirements aren't met even once the rest of the plan has run
+    Blocked,
+}
+
+/// An action the runner would queue, and why
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedAction {
+    pub task_name: String,
+    pub interval: Interval,
+
+    /// The task's resources that don't exist yet for the interval
+    pub missing: Vec<Resource>,
+
+    /// All of the task's resources, to tell a partial interval from a new one
+    #[serde(skip)]
+    pub provides: usize,
+
+    pub status: PlanStatus,
+}
+
+impl std::fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: ", self.task_name, self.interval)?;
+        if self.missing.len() == self.provides {
+            write!(f, "not produced yet")?;
+        } else {
+            write!(f, "missing {}", self.missing.join(", "))?;
+        }
+        match self.status {
+            PlanStatus::Ready => write!(f, ", ready to run"),
+            PlanStatus::Waiting => write!(f, ", after other planned actions"),
+            PlanStatus::Blocked => write!(f, ", blocked on unmet requirements"),
+        }
+    }
+}
+
+/// The actions the runner would queue to bring `current` up to the state
+/// due by `until`, without running anything. Ordered by interval end, then
+/// task name.
+pub fn plan(
+    tasks: &TaskSet,
+    current: &ResourceInterval,
+    until: DateTime<Utc>,
+) -> Result<Vec<PlannedAction>> {
+    // As on the runner's startup, every interval due is considered, so a
+    // task with some of its resources already produced still lines up
+    let target = tasks.get_state(until);
+    let planned = current.union(&target);
+
+    let mut actions = Vec::new();
+    for task in tasks.iter() {
+        for interval in task.generate_intervals(&target)? {
+            let mut missing: Vec<Resource> = task
+                .provides
+                .iter()
+                .filter(|res| !current.get(*res).is_some_and(|is| is.has_subset(interval)))
+                .cloned()
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+            missing.sort();
+
+            let status = if task.can_run(interval, current) {
+                PlanStatus::Ready
+            } else if task.can_run(interval, &planned) {
+                PlanStatus::Waiting
+            } else {
+                PlanStatus::Blocked
+            };
+            actions.push(PlannedAction {
+                task_name: task.name.clone(),
+                interval,
+                missing,
+                provides: task.provides.len(),
+                status,
+            });
+        }
+    }
+    actions.sort_by(|a, b| (a.interval.end, &a.task_name).cmp(&(b.interval.end, &b.task_name)));
+    Ok(actions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_simulate() {
+        let json = r#"{
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00", "12:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-02-01T00:00:00"
+                },
+                "task_b": {
+                    "up": { "command": "/bin/true" },
+                    "requires": [ { "resource": "task_a", "offset": 0 } ],
+                    "calendar_name": "std",
+                    "times": [ "11:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-02-01T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json).unwrap();
+        let tz = chrono_tz::America::New_York;
+        let at = |d, h| tz.with_ymd_and_hms(2022, 1, d, h, 0, 0).unwrap();
+
+        let actions = simulate(
+            &world_def,
+            at(4, 13).with_timezone(&Utc),
+            at(5, 13).with_timezone(&Utc),
+        )
+        .unwrap();
+
+        let summary: Vec<(&str, DateTime<Utc>)> = actions
+            .iter()
+            .map(|a| (a.task_name.as_str(), a.time))
+            .collect();
+
+        // task_b's interval ending at 11:00 overlaps task_a's interval ending
+        // at 12:00, so it's held until then
+        assert_eq!(
+            summary,
+            vec![
+                ("task_a", at(5, 9).with_timezone(&Utc)),
+                ("task_a", at(5, 12).with_timezone(&Utc)),
+                ("task_b", at(5, 12).with_timezone(&Utc)),
+            ]
+        );
+        assert!(actions.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+
+    #[test]
+    fn check_plan() {
+        let json = r#"{
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00", "12:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-02-01T00:00:00"
+                },
+                "task_b": {
+                    "up": { "command": "/bin/true" },
+                    "requires": [ { "resource": "task_a", "offset": 0 } ],
+                    "calendar_name": "std",
+                    "times": [ "11:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-02-01T00:00:00"
+                },
+                "task_c": {
+                    "up": { "command": "/bin/true" },
+                    "provides": [ "c_out", "c_log" ],
+                    "requires": [ { "resource": "task_a", "offset": 1 } ],
+                    "calendar_name": "std",
+                    "times": [ "10:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-02-01T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json).unwrap();
+        let tasks = world_def.taskset().unwrap();
+        let tz = chrono_tz::America::New_York;
+        let at = |d, h| tz.with_ymd_and_hms(2022, 1, d, h, 0, 0).unwrap();
+
+        // Everything up to the 4th at 13:00 is done, as is task_c's log
+        // for the 5th. task_c needs task_a from the 6th, which isn't due.
+        let mut current = tasks.get_state(at(4, 13).with_timezone(&Utc));
+        let c_interval =
+            Interval::new(at(4, 10).with_timezone(&Utc), at(5, 10).with_timezone(&Utc));
+        current
+            .entry("c_log".to_owned())
+            .or_default()
+            .insert(c_interval);
+
+        let actions = plan(&tasks, &current, at(5, 13).with_timezone(&Utc)).unwrap();
+        let summary: Vec<(&str, DateTime<Utc>, Vec<&str>, PlanStatus)> = actions
+            .iter()
+            .map(|a| {
+                (
+                    a.task_name.as_str(),
+                    a.interval.end,
+                    a.missing.iter().map(String::as_str).collect(),
+                    a.status,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "task_a",
+                    at(5, 9).with_timezone(&Utc),
+                    vec!["task_a"],
+                    PlanStatus::Ready
+                ),
+                (
+                    "task_c",
+                    at(5, 10).with_timezone(&Utc),
+                    vec!["c_out"],
+                    PlanStatus::Blocked
+                ),
+                (
+                    "task_b",
+                    at(5, 11).with_timezone(&Utc),
+                    vec!["task_b"],
+                    PlanStatus::Waiting
+                ),
+                (
+                    "task_a",
+                    at(5, 12).with_timezone(&Utc),
+                    vec!["task_a"],
+                    PlanStatus::Ready
+                ),
+            ]
+        );
+        assert!(actions[0]
+            .to_string()
+            .ends_with("not produced yet, ready to run"));
+        assert!(actions[1]
+            .to_string()
+            .ends_with("missing c_out, blocked on unmet requirements"));
+
+        // Nothing is planned once the state is caught up
+        let caught_up = tasks.get_state(at(5, 13).with_timezone(&Utc));
+        assert!(plan(&tasks, &caught_up, at(5, 13).with_timezone(&Utc))
+            .unwrap()
+            .is_empty());
+    }
+}
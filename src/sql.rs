@@ -0,0 +1,185 @@
+//! Requirements satisfied by a SQL query, e.g. rows existing in an upstream
+//! table for the interval's date.
+//!
+//! Queries can't be run while requirements are evaluated, so the runner
+//! periodically runs the queries of queued actions, and records the
+//! intervals each was satisfied for under keys built by [`sql_key`], to be
+//! evaluated like any local resource.
+
+use super::*;
+
+/// How often the queries of queued actions are run
+pub const SQL_CHECK_SECONDS: i64 = 30;
+
+/// The key the intervals a query was satisfied for are recorded under
+pub fn sql_key(database: &str, query: &str) -> Resource {
+    format!("sql:{}#{}", database, query)
+}
+
+/// A query to run for an action's interval
+#[derive(Clone, Debug, PartialEq)]
+pub struct SqlCheck {
+    pub database: String,
+    /// The query with the interval's variables filled in
+    pub query: String,
+    pub key: Resource,
+    pub interval: Interval,
+}
+
+impl SqlCheck {
+    /// The check of a query for `interval`. Variables are filled in as
+    /// quoted literals, e.g. `WHERE loaded_at >= ${PERIOD_END}`.
+    pub fn new(database: &str, query: &str, interval: Interval, tz: Tz) -> Self {
+        SqlCheck {
+            database: database.to_owned(),
+            query: VarMap::from_interval(&interval, tz).apply_quoted(query),
+            key: sql_key(database, query),
+            interval,
+        }
+    }
+}
+
+/// Runs each check, returning the keys and intervals of those satisfied.
+/// Checks that fail to run aren't satisfied.
+pub async fn run_checks(checks: Vec<SqlCheck>) -> Vec<(Resource, Interval)> {
+    let mut by_database: HashMap<&str, Vec<&SqlCheck>> = HashMap::new();
+    for check in &checks {
+        by_database.entry(&check.database).or_default().push(check);
+    }
+
+    let mut satisfied = Vec::new();
+    for (database, checks) in by_database {
+        let queries: Vec<&str> = checks.iter().map(|c| c.query.as_str()).collect();
+        match query_all(database, &queries).await {
+            Ok(results) => {
+                for (check, result) in checks.into_iter().zip(results) {
+                    match result {
+                        Ok(true) => satisfied.push((check.key.clone(), check.interval)),
+                        Ok(false) => {}
+                        Err(e) => warn!(query = %check.query, "{}", e),
+                    }
+                }
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+    satisfied
+}
+
+/// Runs queries on one connection to `database`, with the driver for its
+/// URL scheme
+#[cfg_attr(
+    not(any(feature = "sql-postgres", feature = "sql-mysql")),
+    allow(unused_variables)
+)]
+async fn query_all(database: &str, queries: &[&str]) -> Result<Vec<Result<bool>>> {
+    let scheme = database.split("://").next().unwrap_or_default();
+    match scheme {
+        #[cfg(feature = "sql-postgres")]
+        "postgres" | "postgresql" => query_with::<sqlx::PgConnection>(database, queries).await,
+        #[cfg(feature = "sql-mysql")]
+        "mysql" | "mariadb" => query_with::<sqlx::MySqlConnection>(database, queries).await,
+        _ => Err(Error::Validation(format!(
+            "Unable to query {} databases, built without the sql-postgres or sql-mysql features",
+            scheme
+        ))),
+    }
+}
+
+/// A query is satisfied if it returns a row, unless the row's first
+/// column is false, zero, or NULL
+#[cfg(any(feature = "sql-postgres", feature = "sql-mysql"))]
+async fn query_with<C>(database: &str, queries: &[&str]) -> Result<Vec<Result<bool>>>
+where
+    C: sqlx::Connection,
+    for<'c> &'c mut C: sqlx::Executor<'c, Database = C::Database>,
+    for<'q> <C::Database as sqlx::Database>::Arguments<'q>: sqlx::IntoArguments<'q, C::Database>,
+    usize: sqlx::ColumnIndex<<C::Database as sqlx::Database>::Row>,
+    bool: sqlx::Type<C::Database> + for<'r> sqlx::Decode<'r, C::Database>,
+    i64: sqlx::Type<C::Database> + for<'r> sqlx::Decode<'r, C::Database>,
+    i32: sqlx::Type<C::Database> + for<'r> sqlx::Decode<'r, C::Database>,
+{
+    use sqlx::Row;
+
+    let mut conn = C::connect(database)
+        .await
+        .map_err(|e| Error::Remote(format!("Unable to connect to database: {}", e)))?;
+
+    let mut results = Vec::new();
+    for query in queries {
+        let result = sqlx::query(query)
+            .fetch_optional(&mut conn)
+            .await
+            .map_err(|e| Error::Remote(format!("Unable to run query: {}", e)))
+            .map(|row| match row {
+                None => false,
+                Some(row) if row.is_empty() => true,
+                Some(row) => row
+                    .try_get::<Option<bool>, _>(0)
+                    .or_else(|_| row.try_get::<Option<i64>, _>(0).map(|n| n.map(|n| n != 0)))
+                    .or_else(|_| row.try_get::<Option<i32>, _>(0).map(|n| n.map(|n| n != 0)))
+                    .map(|value| value.unwrap_or(false))
+                    .unwrap_or(true),
+            });
+        results.push(result);
+    }
+    conn.close().await.unwrap_or(());
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_sql_requirement() {
+        let schedule = Schedule::new(Calendar::new(), vec![NaiveTime::MIN], Tz::UTC);
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 4, 0, 0, 0).unwrap(),
+        );
+        let requirement: Requirement = serde_json::from_str(
+            r#"{ "database": "postgres://warehouse/prices",
+                 "query": "SELECT 1 FROM prices WHERE day = ${yyyy} LIMIT 1" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            requirement.queries(),
+            HashSet::from([(
+                "postgres://warehouse/prices".to_owned(),
+                "SELECT 1 FROM prices WHERE day = ${yyyy} LIMIT 1".to_owned()
+            )])
+        );
+        assert!(requirement.resources().is_empty());
+
+        let check = SqlCheck::new(
+            "postgres://warehouse/prices",
+            "SELECT 1 FROM prices WHERE day = ${yyyy} LIMIT 1",
+            interval,
+            Tz::UTC,
+        );
+        assert_eq!(
+            check.query,
+            "SELECT 1 FROM prices WHERE day = '2022' LIMIT 1"
+        );
+
+        // Only satisfied for intervals the query was satisfied for
+        let mut available = HashMap::new();
+        assert!(!requirement.is_satisfied(interval, &schedule, &available));
+        assert!(requirement.can_be_satisfied(interval, &schedule, &available));
+        available.insert(check.key.clone(), IntervalSet::from(interval));
+        assert!(requirement.is_satisfied(interval, &schedule, &available));
+        let next = Interval::new(interval.end, interval.end + Duration::days(1));
+        assert!(!requirement.is_satisfied(next, &schedule, &available));
+
+        // Unreachable databases satisfy nothing
+        let satisfied = run_checks(vec![SqlCheck::new(
+            "postgres://127.0.0.1:1/prices",
+            "SELECT true",
+            interval,
+            Tz::UTC,
+        )])
+        .await;
+        assert!(satisfied.is_empty());
+    }
+}
@@ -0,0 +1,498 @@
+//! Keeps state and attempts as JSON files in a directory. Files that are
+//! replaced are written to a temporary file that's synced and renamed into
+//! place, so a crash never leaves a half-written file behind. Attempts and
+//! output are appended as JSON lines. A partial last line left by a crash
+//! is skipped on reading and cut off before the next append; a bad line
+//! anywhere else is an error.
+
+use super::*;
+use std::path::{Path, PathBuf};
+
+pub struct FileStorage {
+    directory: PathBuf,
+}
+
+async fn read_json<T: serde::de::DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(payload) => Ok(serde_json::from_str(&payload)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads JSON lines. Only an unterminated last line, torn by a crash
+/// mid-append, may fail to parse.
+async fn read_lines<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    let payload = match tokio::fs::read_to_string(path).await {
+        Ok(payload) => payload,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let torn = !payload.is_empty() && !payload.ends_with('\n');
+    let count = payload.lines().count();
+    let mut values = Vec::with_capacity(count);
+    for (i, line) in payload.lines().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(value) => values.push(value),
+            Err(_) if torn && i + 1 == count => {
+                warn!(path = %path.display(), "Skipping partial last line");
+            }
+            Err(e) => {
+                return Err(Error::Storage(format!(
+                    "{} line {}: {}",
+                    path.display(),
+                    i + 1,
+                    e
+                )))
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Syncs a directory, so the entries just made in it survive a crash
+async fn sync_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    tokio::fs::File::open(path).await?.sync_all().await?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Replaces a file with `data`, through a synced temporary file
+async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let tmp = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await?;
+    sync_dir(path.parent().unwrap()).await
+}
+
+async fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    write_atomic(path, &serde_json::to_vec(value)?).await
+}
+
+fn to_lines<T: Serialize>(values: &[T]) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    for value in values {
+        serde_json::to_writer(&mut payload, value)?;
+        payload.push(b'\n');
+    }
+    Ok(payload)
+}
+
+/// Cuts off a partial last line left by a crash mid-append, so the next
+/// line doesn't run into it
+async fn trim_torn_line(file: &mut tokio::fs::File) -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let len = file.metadata().await?.len();
+    if len == 0 {
+        return Ok(());
+    }
+    let mut last = [0u8];
+    file.seek(std::io::SeekFrom::Start(len - 1)).await?;
+    file.read_exact(&mut last).await?;
+    if last[0] == b'\n' {
+        return Ok(());
+    }
+    let mut payload = Vec::new();
+    file.seek(std::io::SeekFrom::Start(0)).await?;
+    file.read_to_end(&mut payload).await?;
+    let keep = payload
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    file.set_len(keep as u64).await?;
+    Ok(())
+}
+
+/// Writes JSON lines to the end of a file
+async fn append_lines<T: Serialize>(path: &Path, values: &[T]) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let directory = path.parent().unwrap();
+    tokio::fs::create_dir_all(directory).await?;
+    let payload = to_lines(values)?;
+    let created = !tokio::fs::try_exists(path).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .await?;
+    trim_torn_line(&mut file).await?;
+    file.write_all(&payload).await?;
+    file.sync_all().await?;
+    if created {
+        sync_dir(directory).await?;
+    }
+    Ok(())
+}
+
+/// Replaces a file with JSON lines
+async fn write_lines<T: Serialize>(path: &Path, values: &[T]) -> Result<()> {
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    write_atomic(path, &to_lines(values)?).await
+}
+
+/// What the store keeps in its directory. Nothing else there is touched.
+const ENTRIES: [&str; 6] = [
+    "state.json",
+    "actions.json",
+    "annotations.json",
+    "attempts",
+    "artifacts",
+    "output",
+];
+
+impl FileStorage {
+    /// Stores files under `directory`, creating it if needed
+    pub async fn new(directory: impl Into<PathBuf>) -> Result<Self> {
+        let directory = directory.into();
+        tokio::fs::create_dir_all(directory.join("attempts")).await?;
+        Ok(FileStorage { directory })
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.directory.join("state.json")
+    }
+
+    fn actions_path(&self) -> PathBuf {
+        self.directory.join("actions.json")
+    }
+
+    fn attempts_path(&self, task_name: &str) -> PathBuf {
+        self.directory
+            .join("attempts")
+            .join(format!("{}.jsonl", encode_name(task_name)))
+    }
+
+    fn artifact_path(&self, task_name: &str, interval: Interval, name: &str) -> PathBuf {
+        self.directory
+            .join("artifacts")
+            .join(encode_name(task_name))
+            .join(interval.end.timestamp_millis().to_string())
+            .join(encode_name(name))
+    }
+
+    fn output_path(&self, task_name: &str, interval: Interval) -> PathBuf {
+        self.directory
+            .join("output")
+            .join(encode_name(task_name))
+            .join(format!("{}.jsonl", interval.end.timestamp_millis()))
+    }
+
+    fn annotations_path(&self) -> PathBuf {
+        self.directory.join("annotations.json")
+    }
+
+    /// All attempts of a task, oldest first
+    async fn history(&self, task_name: &str) -> Result<Vec<(Interval, TaskAttempt)>> {
+        read_lines(&self.attempts_path(task_name)).await
+    }
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    async fn clear(&mut self) -> Result<()> {
+        for entry in ENTRIES {
+            let path = self.directory.join(entry);
+            let res = if entry.ends_with(".json") {
+                tokio::fs::remove_file(&path).await
+            } else {
+                tokio::fs::remove_dir_all(&path).await
+            };
+            match res {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        tokio::fs::create_dir_all(self.directory.join("attempts")).await?;
+        sync_dir(&self.directory).await
+    }
+
+    async fn store_attempt(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        attempt: &TaskAttempt,
+    ) -> Result<()> {
+        append_lines(&self.attempts_path(task_name), &[(interval, attempt)]).await
+    }
+
+    async fn store_state(&mut self, state: &ResourceInterval) -> Result<()> {
+        write_json(&self.state_path(), state).await
+    }
+
+    async fn load_state(&mut self) -> Result<ResourceInterval> {
+        read_json(&self.state_path()).await
+    }
+
+    async fn store_actions(&mut self, actions: &[ActionRecord]) -> Result<()> {
+        write_json(&self.actions_path(), &actions).await
+    }
+
+    async fn load_actions(&mut self) -> Result<Vec<ActionRecord>> {
+        read_json(&self.actions_path()).await
+    }
+
+    async fn get_recent_attempts(
+        &mut self,
+        task_name: &str,
+        max_attempts: usize,
+    ) -> Result<Vec<TaskAttempt>> {
+        Ok(self
+            .history(task_name)
+            .await?
+            .into_iter()
+            .rev()
+            .filter(|(_, attempt)| attempt.kind.is_up())
+            .take(max_attempts)
+            .map(|(_, attempt)| attempt)
+            .collect())
+    }
+
+    async fn get_attempts(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<TaskAttempt>> {
+        Ok(self
+            .history(task_name)
+            .await?
+            .into_iter()
+            .filter(|(intv, _)| *intv == interval)
+            .map(|(_, attempt)| attempt)
+            .collect())
+    }
+
+    async fn store_artifact(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        artifact: &Artifact,
+    ) -> Result<()> {
+        let path = self.artifact_path(task_name, interval, &artifact.name);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        write_atomic(&path, &artifact.data).await
+    }
+
+    async fn get_artifact(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.artifact_path(task_name, interval, name)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn append_output(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        chunks: &[OutputChunk],
+        replace: bool,
+    ) -> Result<()> {
+        let path = self.output_path(task_name, interval);
+        if replace {
+            write_lines(&path, chunks).await
+        } else {
+            append_lines(&path, chunks).await
+        }
+    }
+
+    async fn get_output(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<OutputChunk>> {
+        read_lines(&self.output_path(task_name, interval)).await
+    }
+
+    async fn store_annotation(&mut self, annotation: &IntervalAnnotation) -> Result<()> {
+        let mut annotations: Vec<IntervalAnnotation> = read_json(&self.annotations_path()).await?;
+        annotations.push(annotation.clone());
+        write_json(&self.annotations_path(), &annotations).await
+    }
+
+    async fn get_annotations(&mut self, span: Interval) -> Result<Vec<IntervalAnnotation>> {
+        let annotations: Vec<IntervalAnnotation> = read_json(&self.annotations_path()).await?;
+        Ok(annotations
+            .into_iter()
+            .filter(|a| span.is_contiguous(a.interval))
+            .collect())
+    }
+}
+
+pub async fn start_file_storage(
+    msgs: mpsc::Receiver<StorageMessage>,
+    directory: PathBuf,
+) -> Result<()> {
+    serve(FileStorage::new(directory).await?, msgs).await
+}
+
+pub fn start(
+    msgs: mpsc::Receiver<StorageMessage>,
+    directory: PathBuf,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = start_file_storage(msgs, directory).await {
+            error!("Unable to start file storage: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_file_storage() {
+        let directory = std::env::temp_dir().join("waterfall_file_storage_test");
+        tokio::fs::remove_dir_all(&directory).await.unwrap_or(());
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        let state = ResourceInterval::from(HashMap::from([(
+            "resource".to_owned(),
+            IntervalSet::from(interval),
+        )]));
+
+        let mut storage = FileStorage::new(&directory).await.unwrap();
+        assert!(storage.load_state().await.unwrap().is_empty());
+        storage.store_state(&state).await.unwrap();
+        for exit_code in 0..3 {
+            let attempt = TaskAttempt {
+                exit_code,
+                ..TaskAttempt::new()
+            };
+            storage
+                .store_attempt("pricing/load", interval, &attempt)
+                .await
+                .unwrap();
+        }
+
+        // Everything survives reopening the directory
+        let mut storage = FileStorage::new(&directory).await.unwrap();
+        assert_eq!(storage.load_state().await.unwrap(), state);
+        let recent: Vec<i32> = storage
+            .get_recent_attempts("pricing/load", 2)
+            .await
+            .unwrap()
+            .iter()
+            .map(|a| a.exit_code)
+            .collect();
+        assert_eq!(recent, vec![2, 1]);
+        assert!(directory.join("attempts/pricing%2Fload.jsonl").exists());
+
+        let artifact = Artifact {
+            name: "out/report.csv".to_owned(),
+            size: 4,
+            data: b"a,b\n".to_vec(),
+        };
+        storage
+            .store_artifact("pricing/load", interval, &artifact)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .get_artifact("pricing/load", interval, "out/report.csv")
+                .await
+                .unwrap(),
+            Some(b"a,b\n".to_vec())
+        );
+        assert_eq!(
+            storage
+                .get_artifact("pricing/load", interval, "missing")
+                .await
+                .unwrap(),
+            None
+        );
+
+        let chunk = |data: &str| OutputChunk {
+            stream: OutputStream::Stdout,
+            data: data.to_owned(),
+        };
+        storage
+            .append_output("pricing/load", interval, &[chunk("stale\n")], false)
+            .await
+            .unwrap();
+        // A new attempt starts over, then adds to its own output
+        storage
+            .append_output("pricing/load", interval, &[chunk("one\n")], true)
+            .await
+            .unwrap();
+        storage
+            .append_output("pricing/load", interval, &[chunk("two\n")], false)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_output("pricing/load", interval).await.unwrap(),
+            vec![chunk("one\n"), chunk("two\n")]
+        );
+
+        // A line torn by a crash is skipped, and cut off by the next append
+        let output = storage.output_path("pricing/load", interval);
+        let mut torn = tokio::fs::read(&output).await.unwrap();
+        torn.extend_from_slice(br#"{"stream":"#);
+        tokio::fs::write(&output, &torn).await.unwrap();
+        assert_eq!(
+            storage.get_output("pricing/load", interval).await.unwrap(),
+            vec![chunk("one\n"), chunk("two\n")]
+        );
+        storage
+            .append_output("pricing/load", interval, &[chunk("three\n")], false)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_output("pricing/load", interval).await.unwrap(),
+            vec![chunk("one\n"), chunk("two\n"), chunk("three\n")]
+        );
+
+        // A bad line anywhere else is an error
+        let mut corrupt = b"garbage\n".to_vec();
+        corrupt.extend(tokio::fs::read(&output).await.unwrap());
+        tokio::fs::write(&output, &corrupt).await.unwrap();
+        assert!(storage.get_output("pricing/load", interval).await.is_err());
+
+        // Names can't climb out of the store's directories
+        storage
+            .store_attempt("..", interval, &TaskAttempt::new())
+            .await
+            .unwrap();
+        assert!(directory.join("attempts/%2E..jsonl").exists());
+        assert_eq!(storage.get_attempts("..", interval).await.unwrap().len(), 1);
+
+        // No temporary files are left behind
+        let mut entries = tokio::fs::read_dir(&directory).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            assert!(!entry.file_name().to_string_lossy().ends_with(".tmp"));
+        }
+
+        // Only what the store keeps is cleared from its directory
+        tokio::fs::write(directory.join("notes.txt"), "kept")
+            .await
+            .unwrap();
+        storage.clear().await.unwrap();
+        assert!(directory.join("notes.txt").exists());
+        assert!(!directory.join("output").exists());
+        assert!(storage.load_state().await.unwrap().is_empty());
+        assert!(storage
+            .get_attempts("pricing/load", interval)
+            .await
+            .unwrap()
+            .is_empty());
+
+        tokio::fs::remove_dir_all(&directory).await.unwrap();
+    }
+}
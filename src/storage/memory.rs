@@ -1,47 +1,336 @@
 use super::*;
+use std::collections::{BTreeMap, VecDeque};
 
-use futures::prelude::*;
+/// How many attempts of each task are kept by default
+pub const DEFAULT_MAX_ATTEMPTS: usize = 1000;
 
-/// The mpsc channel can be sized to fit max parallelism
-pub async fn start_memory_storage(mut msgs: mpsc::UnboundedReceiver<StorageMessage>) -> Result<()> {
-    let mut system_state = HashMap::<String, String>::new();
-    while let Some(msg) = msgs.recv().await {
-        use StorageMessage::*;
-        match msg {
-            Clear {} => {
-                system_state.clear();
-            }
-            StoreAttempt {
-                task_name,
-                interval,
-                attempt,
-            } => {
-                let tag = format!("{}_{}", task_name, interval.end);
-                let payload = serde_json::to_string(&attempt).unwrap();
-                system_state.insert(tag, payload);
-            }
-            StoreState { state } => {
-                let payload = serde_json::to_string(&state).unwrap();
-                system_state.insert("state".to_owned(), payload);
-            }
-            LoadState { response } => {
-                let is: ResourceInterval =
-                    serde_json::from_str(&system_state.get(&"state".to_owned()).unwrap()).unwrap();
-                response.send(is).unwrap();
-            }
-            Stop {} => {
+/// Keeps state and attempts in memory, for development and testing. Only
+/// the latest attempts of each task are kept, so long runs don't grow
+/// without bound.
+pub struct MemoryStorage {
+    state: Option<ResourceInterval>,
+    actions: Vec<ActionRecord>,
+    /// Oldest first
+    attempts: HashMap<String, VecDeque<(Interval, TaskAttempt)>>,
+    max_attempts: usize,
+    annotations: Vec<IntervalAnnotation>,
+    artifacts: HashMap<(String, Interval, String), Vec<u8>>,
+    outputs: HashMap<(String, Interval), Vec<OutputChunk>>,
+    owners: HashMap<Resource, String>,
+    shard_states: ShardStates,
+    snapshots: BTreeMap<DateTime<Utc>, ResourceInterval>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::with_max_attempts(DEFAULT_MAX_ATTEMPTS)
+    }
+
+    /// Keeps only the latest `max_attempts` attempts of each task
+    pub fn with_max_attempts(max_attempts: usize) -> Self {
+        MemoryStorage {
+            state: None,
+            actions: Vec::new(),
+            attempts: HashMap::new(),
+            max_attempts,
+            annotations: Vec::new(),
+            artifacts: HashMap::new(),
+            outputs: HashMap::new(),
+            owners: HashMap::new(),
+            shard_states: ShardStates::new(),
+            snapshots: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn clear(&mut self) -> Result<()> {
+        self.state = None;
+        self.actions.clear();
+        self.attempts.clear();
+        self.annotations.clear();
+        self.artifacts.clear();
+        self.outputs.clear();
+        self.owners.clear();
+        self.shard_states.clear();
+        self.snapshots.clear();
+        Ok(())
+    }
+
+    async fn store_attempt(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        attempt: &TaskAttempt,
+    ) -> Result<()> {
+        let history = self.attempts.entry(task_name.to_owned()).or_default();
+        history.push_back((interval, attempt.clone()));
+        while history.len() > self.max_attempts {
+            let Some((pruned, _)) = history.pop_front() else {
                 break;
+            };
+            // Output and artifacts go with the last of their interval's
+            // attempts
+            if !history.iter().any(|(intv, _)| *intv == pruned) {
+                self.outputs.remove(&(task_name.to_owned(), pruned));
+                self.artifacts
+                    .retain(|(task, intv, _), _| task != task_name || *intv != pruned);
             }
         }
+        Ok(())
+    }
+
+    async fn store_state(&mut self, state: &ResourceInterval) -> Result<()> {
+        self.state = Some(state.clone());
+        Ok(())
+    }
+
+    /// Empty until a state is stored, as with a fresh redis
+    async fn load_state(&mut self) -> Result<ResourceInterval> {
+        Ok(self.state.clone().unwrap_or_default())
+    }
+
+    async fn store_actions(&mut self, actions: &[ActionRecord]) -> Result<()> {
+        self.actions = actions.to_vec();
+        Ok(())
+    }
+
+    async fn load_actions(&mut self) -> Result<Vec<ActionRecord>> {
+        Ok(self.actions.clone())
+    }
+
+    async fn get_recent_attempts(
+        &mut self,
+        task_name: &str,
+        max_attempts: usize,
+    ) -> Result<Vec<TaskAttempt>> {
+        Ok(match self.attempts.get(task_name) {
+            Some(history) => history
+                .iter()
+                .rev()
+                .filter(|(_, attempt)| attempt.kind.is_up())
+                .take(max_attempts)
+                .map(|(_, attempt)| attempt.clone())
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+
+    async fn get_attempts(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<TaskAttempt>> {
+        Ok(match self.attempts.get(task_name) {
+            Some(history) => history
+                .iter()
+                .filter(|(intv, _)| *intv == interval)
+                .map(|(_, attempt)| attempt.clone())
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+
+    async fn store_artifact(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        artifact: &Artifact,
+    ) -> Result<()> {
+        self.artifacts.insert(
+            (task_name.to_owned(), interval, artifact.name.clone()),
+            artifact.data.clone(),
+        );
+        Ok(())
+    }
+
+    async fn get_artifact(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .artifacts
+            .get(&(task_name.to_owned(), interval, name.to_owned()))
+            .cloned())
+    }
+
+    async fn append_output(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        chunks: &[OutputChunk],
+        replace: bool,
+    ) -> Result<()> {
+        let output = self
+            .outputs
+            .entry((task_name.to_owned(), interval))
+            .or_default();
+        if replace {
+            output.clear();
+        }
+        output.extend_from_slice(chunks);
+        Ok(())
     }
 
-    Ok(())
+    async fn get_output(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<OutputChunk>> {
+        Ok(self
+            .outputs
+            .get(&(task_name.to_owned(), interval))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn store_annotation(&mut self, annotation: &IntervalAnnotation) -> Result<()> {
+        self.annotations.push(annotation.clone());
+        Ok(())
+    }
+
+    async fn get_annotations(&mut self, span: Interval) -> Result<Vec<IntervalAnnotation>> {
+        Ok(self
+            .annotations
+            .iter()
+            .filter(|a| span.is_contiguous(a.interval))
+            .cloned()
+            .collect())
+    }
+
+    async fn claim_resources(&mut self, shard: &str, resources: &HashSet<Resource>) -> Result<()> {
+        claim(&mut self.owners, shard, resources)
+    }
+
+    async fn release_resources(
+        &mut self,
+        shard: &str,
+        resources: &HashSet<Resource>,
+    ) -> Result<()> {
+        release(&mut self.owners, shard, resources);
+        Ok(())
+    }
+
+    async fn store_shard_state(&mut self, shard: &str, state: &ResourceInterval) -> Result<()> {
+        self.shard_states.insert(shard.to_owned(), state.clone());
+        Ok(())
+    }
+
+    async fn load_shard_states(&mut self) -> Result<ShardStates> {
+        Ok(self.shard_states.clone())
+    }
+
+    async fn take_snapshot(&mut self, time: DateTime<Utc>, keep: usize) -> Result<()> {
+        let state = self
+            .state
+            .clone()
+            .ok_or_else(|| Error::Storage("No state has been stored".to_owned()))?;
+        self.snapshots.insert(time, state);
+        while self.snapshots.len() > keep {
+            self.snapshots.pop_first();
+        }
+        Ok(())
+    }
+
+    async fn list_snapshots(&mut self) -> Result<Vec<DateTime<Utc>>> {
+        Ok(self.snapshots.keys().cloned().collect())
+    }
+
+    async fn load_snapshot(&mut self, time: DateTime<Utc>) -> Result<ResourceInterval> {
+        self.snapshots
+            .get(&time)
+            .cloned()
+            .ok_or_else(|| Error::Storage(format!("No snapshot taken at {}", time)))
+    }
+}
+
+/// The mpsc channel can be sized to fit max parallelism
+pub async fn start_memory_storage(msgs: mpsc::Receiver<StorageMessage>) -> Result<()> {
+    serve(MemoryStorage::new(), msgs).await
 }
 
-pub fn start(msgs: mpsc::UnboundedReceiver<StorageMessage>) -> tokio::task::JoinHandle<()> {
+pub fn start(msgs: mpsc::Receiver<StorageMessage>) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        start_memory_storage(msgs)
-            .await
-            .expect("Unable to start memory storage");
+        if let Err(e) = start_memory_storage(msgs).await {
+            error!("Unable to start memory storage: {}", e);
+        }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_retention() {
+        let mut storage = MemoryStorage::with_max_attempts(3);
+        assert_eq!(storage.load_state().await.unwrap(), ResourceInterval::new());
+
+        let day = |d| Utc.with_ymd_and_hms(2022, 1, d, 0, 0, 0).unwrap();
+        let chunk = OutputChunk {
+            stream: OutputStream::Stdout,
+            data: "out\n".to_owned(),
+        };
+        for d in 1..6 {
+            let interval = Interval::new(day(d), day(d + 1));
+            storage
+                .append_output("task", interval, std::slice::from_ref(&chunk), true)
+                .await
+                .unwrap();
+            let attempt = TaskAttempt {
+                exit_code: d as i32,
+                ..TaskAttempt::new()
+            };
+            storage
+                .store_attempt("task", interval, &attempt)
+                .await
+                .unwrap();
+        }
+
+        // Only the latest three are kept
+        let recent: Vec<i32> = storage
+            .get_recent_attempts("task", 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|a| a.exit_code)
+            .collect();
+        assert_eq!(recent, vec![5, 4, 3]);
+        assert!(storage
+            .get_attempts("task", Interval::new(day(1), day(2)))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage
+                .get_attempts("task", Interval::new(day(4), day(5)))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Output goes with its attempts
+        assert!(storage
+            .get_output("task", Interval::new(day(1), day(2)))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage
+                .get_output("task", Interval::new(day(4), day(5)))
+                .await
+                .unwrap(),
+            vec![chunk]
+        );
+    }
+}
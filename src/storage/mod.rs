@@ -1,15 +1,102 @@
 use super::*;
 use crate::executors::TaskAttempt;
-use crate::runner::ActionState;
+use crate::runner::{ActionId, ActionState};
+use async_trait::async_trait;
+
+/// A free-text note on a task interval, recording operational context like
+/// why it was forced up
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Annotation {
+    #[serde(default = "chrono::Utc::now")]
+    pub time: DateTime<Utc>,
+
+    #[serde(default)]
+    pub author: Option<String>,
+
+    pub text: String,
+}
+
+/// An annotation, along with the task interval it's attached to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IntervalAnnotation {
+    pub task_name: String,
+    pub interval: Interval,
+
+    #[serde(flatten)]
+    pub annotation: Annotation,
+}
+
+/// The progress of an action that can't be derived from the resource
+/// state, persisted so retries and failure counts survive a restart
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ActionRecord {
+    pub task_name: String,
+    pub interval: Interval,
+    pub state: ActionState,
+
+    /// Kept so the action has the same id after a restart. Ledgers written
+    /// before ids were kept don't have one.
+    #[serde(default)]
+    pub action_id: Option<ActionId>,
+
+    /// Consecutive failures
+    #[serde(default)]
+    pub failures: usize,
+
+    /// When an errored action is due to be retried. Errored actions
+    /// without one wait to be retried by hand.
+    #[serde(default)]
+    pub retry_at: Option<DateTime<Utc>>,
+
+    /// Escalated to running its task's fallback
+    #[serde(default)]
+    pub fallback: bool,
+}
+
+/// Whether a backend can currently be reached, and what's waiting on it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StorageHealth {
+    pub available: bool,
+
+    /// When the backend became unreachable, if it is
+    #[serde(default)]
+    pub down_since: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub last_error: Option<String>,
+
+    /// Writes held until the backend can be reached again
+    #[serde(default)]
+    pub buffered: usize,
+
+    /// Writes given up on since starting
+    #[serde(default)]
+    pub dropped: usize,
+}
+
+impl Default for StorageHealth {
+    fn default() -> Self {
+        StorageHealth {
+            available: true,
+            down_since: None,
+            last_error: None,
+            buffered: 0,
+            dropped: 0,
+        }
+    }
+}
 
 /// Messages for interacting with an Executor
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum StorageMessage {
     Clear {},
     StoreAttempt {
         task_name: String,
         interval: Interval,
         attempt: TaskAttempt,
+        /// The caller's span, so storing shows up as part of its trace
+        span: tracing::Span,
     },
     StoreState {
         state: ResourceInterval,
@@ -17,16 +104,776 @@ pub enum StorageMessage {
     LoadState {
         response: oneshot::Sender<ResourceInterval>,
     },
-    /*
+    /// Replaces the stored action ledger
+    StoreActions {
+        actions: Vec<ActionRecord>,
+    },
+    LoadActions {
+        response: oneshot::Sender<Vec<ActionRecord>>,
+    },
+    /// Retrieve the most recent up attempts of a task, newest first
+    GetRecentAttempts {
+        task_name: String,
+        max_attempts: usize,
+        response: oneshot::Sender<Vec<TaskAttempt>>,
+    },
+    /// Retrieve all attempts of a task for an interval, oldest first
     GetAttempts {
         task_name: String,
         interval: Interval,
-        response: oneshot::Sender<TaskAttempt>,
+        response: oneshot::Sender<Vec<TaskAttempt>>,
+    },
+    /// Keeps a file collected from an attempt of the task interval,
+    /// replacing any earlier one of the same name
+    StoreArtifact {
+        task_name: String,
+        interval: Interval,
+        artifact: Artifact,
+    },
+    /// Retrieve the contents of an artifact of a task interval, or None if
+    /// there isn't one by that name
+    GetArtifact {
+        task_name: String,
+        interval: Interval,
+        name: String,
+        response: oneshot::Sender<Result<Option<Vec<u8>>>>,
+    },
+    /// Adds to the output of the task interval's latest attempt, kept as
+    /// it's produced. With `replace`, the chunks replace what was kept,
+    /// starting a new attempt's output.
+    AppendOutput {
+        task_name: String,
+        interval: Interval,
+        chunks: Vec<OutputChunk>,
+        replace: bool,
+    },
+    /// Retrieve the kept output of the task interval's latest attempt
+    GetOutput {
+        task_name: String,
+        interval: Interval,
+        response: oneshot::Sender<Result<Vec<OutputChunk>>>,
+    },
+    StoreAnnotation {
+        annotation: IntervalAnnotation,
+        response: oneshot::Sender<Result<()>>,
+    },
+    /// Retrieve the annotations of all task intervals overlapping or
+    /// adjacent to the span, oldest first
+    GetAnnotations {
+        span: Interval,
+        response: oneshot::Sender<Vec<IntervalAnnotation>>,
+    },
+    /// Records the shard as the owner of the resources. Fails if any are
+    /// owned by another shard.
+    ClaimResources {
+        shard: String,
+        resources: HashSet<Resource>,
+        response: oneshot::Sender<Result<()>>,
+    },
+    /// Gives up the shard's ownership of the resources, so other shards
+    /// can claim them
+    ReleaseResources {
+        shard: String,
+        resources: HashSet<Resource>,
+    },
+    StoreShardState {
+        shard: String,
+        state: ResourceInterval,
+    },
+    LoadShardStates {
+        response: oneshot::Sender<Result<ShardStates>>,
+    },
+    /// Copies the current state into a snapshot taken at `time`, keeping
+    /// only the newest `keep` snapshots
+    TakeSnapshot {
+        time: DateTime<Utc>,
+        keep: usize,
+    },
+    /// The times of the stored snapshots, oldest first
+    ListSnapshots {
+        response: oneshot::Sender<Vec<DateTime<Utc>>>,
+    },
+    LoadSnapshot {
+        time: DateTime<Utc>,
+        response: oneshot::Sender<Result<ResourceInterval>>,
+    },
+    GetHealth {
+        response: oneshot::Sender<StorageHealth>,
     },
-    */
     Stop {},
 }
 
+/// A backend for persisting runner state and attempt history.
+///
+/// Implementations don't need to deal with channels: [`serve`] adapts any
+/// `Storage` to the `StorageMessage` actor model used by the runner and
+/// daemons, so an in-house metadata service can be plugged in by
+/// implementing this trait and handing it to [`start`].
+#[async_trait]
+pub trait Storage: Send {
+    /// Remove all stored state and attempts
+    async fn clear(&mut self) -> Result<()>;
+
+    async fn store_attempt(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        attempt: &TaskAttempt,
+    ) -> Result<()>;
+
+    async fn store_state(&mut self, state: &ResourceInterval) -> Result<()>;
+
+    async fn load_state(&mut self) -> Result<ResourceInterval>;
+
+    /// Replaces the action ledger. Backends that can't keep one lose it,
+    /// and runners start with a clean slate.
+    async fn store_actions(&mut self, _actions: &[ActionRecord]) -> Result<()> {
+        Ok(())
+    }
+
+    async fn load_actions(&mut self) -> Result<Vec<ActionRecord>> {
+        Ok(Vec::new())
+    }
+
+    /// The most recent up attempts of a task, newest first. Rechecks and
+    /// down commands aren't runs of the task, so they're left out.
+    async fn get_recent_attempts(
+        &mut self,
+        task_name: &str,
+        max_attempts: usize,
+    ) -> Result<Vec<TaskAttempt>>;
+
+    /// All attempts of a task for an interval, oldest first
+    async fn get_attempts(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<TaskAttempt>>;
+
+    /// Keeps an artifact of the task interval, replacing any earlier one
+    /// of the same name
+    async fn store_artifact(
+        &mut self,
+        _task_name: &str,
+        _interval: Interval,
+        _artifact: &Artifact,
+    ) -> Result<()> {
+        Err(Error::Storage(
+            "Artifacts aren't supported by this backend".to_owned(),
+        ))
+    }
+
+    /// The contents of an artifact of the task interval, if there is one
+    async fn get_artifact(
+        &mut self,
+        _task_name: &str,
+        _interval: Interval,
+        _name: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Adds output of the task interval's latest attempt, or replaces the
+    /// kept output with it
+    async fn append_output(
+        &mut self,
+        _task_name: &str,
+        _interval: Interval,
+        _chunks: &[OutputChunk],
+        _replace: bool,
+    ) -> Result<()> {
+        Err(Error::Storage(
+            "Output isn't kept by this backend".to_owned(),
+        ))
+    }
+
+    /// The kept output of the task interval's latest attempt
+    async fn get_output(
+        &mut self,
+        _task_name: &str,
+        _interval: Interval,
+    ) -> Result<Vec<OutputChunk>> {
+        Ok(Vec::new())
+    }
+
+    async fn store_annotation(&mut self, _annotation: &IntervalAnnotation) -> Result<()> {
+        Err(Error::Storage(
+            "Annotations aren't supported by this backend".to_owned(),
+        ))
+    }
+
+    /// The annotations of all task intervals overlapping or adjacent to the
+    /// span, oldest first
+    async fn get_annotations(&mut self, _span: Interval) -> Result<Vec<IntervalAnnotation>> {
+        Ok(Vec::new())
+    }
+
+    /// Records `shard` as the owner of the resources, failing if any are
+    /// already owned by another shard
+    async fn claim_resources(
+        &mut self,
+        _shard: &str,
+        _resources: &HashSet<Resource>,
+    ) -> Result<()> {
+        Err(Error::Storage(
+            "Sharding isn't supported by this backend".to_owned(),
+        ))
+    }
+
+    /// Gives up ownership of the resources `shard` owns
+    async fn release_resources(
+        &mut self,
+        _shard: &str,
+        _resources: &HashSet<Resource>,
+    ) -> Result<()> {
+        Err(Error::Storage(
+            "Sharding isn't supported by this backend".to_owned(),
+        ))
+    }
+
+    async fn store_shard_state(&mut self, _shard: &str, _state: &ResourceInterval) -> Result<()> {
+        Err(Error::Storage(
+            "Sharding isn't supported by this backend".to_owned(),
+        ))
+    }
+
+    async fn load_shard_states(&mut self) -> Result<ShardStates> {
+        Err(Error::Storage(
+            "Sharding isn't supported by this backend".to_owned(),
+        ))
+    }
+
+    /// Copies the current state into a snapshot taken at `time`, then
+    /// removes all but the newest `keep` snapshots
+    async fn take_snapshot(&mut self, _time: DateTime<Utc>, _keep: usize) -> Result<()> {
+        Err(Error::Storage(
+            "Snapshots aren't supported by this backend".to_owned(),
+        ))
+    }
+
+    /// The times of the stored snapshots, oldest first
+    async fn list_snapshots(&mut self) -> Result<Vec<DateTime<Utc>>> {
+        Ok(Vec::new())
+    }
+
+    async fn load_snapshot(&mut self, time: DateTime<Utc>) -> Result<ResourceInterval> {
+        Err(Error::Storage(format!("No snapshot taken at {}", time)))
+    }
+
+    /// Backends that are always reachable are always healthy
+    fn health(&self) -> StorageHealth {
+        StorageHealth::default()
+    }
+
+    /// Called every [`MAINTENANCE_INTERVAL`] between messages, e.g. to
+    /// reconnect and flush writes buffered during an outage
+    async fn maintain(&mut self) {}
+}
+
+/// How often [`serve`] gives the backend a chance to catch up
+pub const MAINTENANCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Sends the result of a read, or logs why it failed. Dropping the
+/// response tells the caller the read failed.
+fn respond<T>(what: &str, res: Result<T>, response: oneshot::Sender<T>) {
+    match res {
+        Ok(value) => response.send(value).unwrap_or(()),
+        Err(e) => warn!("Unable to {}: {}", what, e),
+    }
+}
+
+/// Logs why a write failed
+fn written(what: &str, res: Result<()>) {
+    if let Err(e) = res {
+        error!("Unable to {}: {}", what, e);
+    }
+}
+
+/// Claims resources in an in-memory ownership map, for backends without
+/// anything better
+fn claim(
+    owners: &mut HashMap<Resource, String>,
+    shard: &str,
+    resources: &HashSet<Resource>,
+) -> Result<()> {
+    if let Some((res, owner)) = resources
+        .iter()
+        .filter_map(|r| owners.get(r).map(|o| (r, o)))
+        .find(|(_, owner)| *owner != shard)
+    {
+        return Err(Error::Validation(format!(
+            "Resource {} is owned by shard {}",
+            res, owner
+        )));
+    }
+    for res in resources {
+        owners.insert(res.clone(), shard.to_owned());
+    }
+    Ok(())
+}
+
+/// Releases the resources `shard` owns in an in-memory ownership map
+fn release(owners: &mut HashMap<Resource, String>, shard: &str, resources: &HashSet<Resource>) {
+    owners.retain(|res, owner| owner != shard || !resources.contains(res));
+}
+
+/// Task names can contain path separators, so backends keying files or
+/// objects by name percent-encode anything outside a safe set. A leading
+/// `.` is encoded too, so names like `..` don't leave their directory.
+fn encode_name(task_name: &str) -> String {
+    task_name
+        .bytes()
+        .enumerate()
+        .map(|(i, b)| match b {
+            b'.' if i == 0 => "%2E".to_owned(),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Services `StorageMessage`s with the given backend until a `Stop` is
+/// received or the channel closes. Failed writes are logged, and failed
+/// reads fail their caller, so a storage outage never stops the service.
+pub async fn serve<S: Storage>(
+    mut storage: S,
+    mut msgs: mpsc::Receiver<StorageMessage>,
+) -> Result<()> {
+    let mut maintenance = tokio::time::interval(MAINTENANCE_INTERVAL);
+    maintenance.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        let msg = tokio::select! {
+            msg = msgs.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = maintenance.tick() => {
+                storage.maintain().await;
+                continue;
+            }
+        };
+        use StorageMessage::*;
+        match msg {
+            Clear {} => written("clear storage", storage.clear().await),
+            StoreAttempt {
+                task_name,
+                interval,
+                attempt,
+                span,
+            } => {
+                let span = info_span!(
+                    parent: &span,
+                    "store_attempt",
+                    task_name = %task_name,
+                    interval = %interval
+                );
+                let res = storage
+                    .store_attempt(&task_name, interval, &attempt)
+                    .instrument(span)
+                    .await;
+                written("store attempt", res);
+            }
+            StoreState { state } => written("store state", storage.store_state(&state).await),
+            LoadState { response } => respond("load state", storage.load_state().await, response),
+            StoreActions { actions } => {
+                written("store actions", storage.store_actions(&actions).await)
+            }
+            LoadActions { response } => {
+                respond("load actions", storage.load_actions().await, response)
+            }
+            GetRecentAttempts {
+                task_name,
+                max_attempts,
+                response,
+            } => {
+                let span = info_span!("get_recent_attempts", task_name = %task_name);
+                let attempts = storage
+                    .get_recent_attempts(&task_name, max_attempts)
+                    .instrument(span)
+                    .await;
+                respond("load attempts", attempts, response);
+            }
+            GetAttempts {
+                task_name,
+                interval,
+                response,
+            } => {
+                let span = info_span!("get_attempts", task_name = %task_name, interval = %interval);
+                let attempts = storage
+                    .get_attempts(&task_name, interval)
+                    .instrument(span)
+                    .await;
+                respond("load attempts", attempts, response);
+            }
+            StoreArtifact {
+                task_name,
+                interval,
+                artifact,
+            } => {
+                // Losing an artifact shouldn't take storage down with it
+                if let Err(e) = storage
+                    .store_artifact(&task_name, interval, &artifact)
+                    .await
+                {
+                    warn!(
+                        task_name = %task_name,
+                        interval = %interval,
+                        "Unable to store artifact {}: {}",
+                        artifact.name,
+                        e
+                    );
+                }
+            }
+            GetArtifact {
+                task_name,
+                interval,
+                name,
+                response,
+            } => {
+                let res = storage.get_artifact(&task_name, interval, &name).await;
+                response.send(res).unwrap_or(());
+            }
+            AppendOutput {
+                task_name,
+                interval,
+                chunks,
+                replace,
+            } => {
+                // Losing output shouldn't take storage down with it
+                if let Err(e) = storage
+                    .append_output(&task_name, interval, &chunks, replace)
+                    .await
+                {
+                    warn!(
+                        task_name = %task_name,
+                        interval = %interval,
+                        "Unable to store output: {}",
+                        e
+                    );
+                }
+            }
+            GetOutput {
+                task_name,
+                interval,
+                response,
+            } => {
+                let res = storage.get_output(&task_name, interval).await;
+                response.send(res).unwrap_or(());
+            }
+            StoreAnnotation {
+                annotation,
+                response,
+            } => {
+                let res = storage.store_annotation(&annotation).await;
+                response.send(res).unwrap_or(());
+            }
+            GetAnnotations { span, response } => respond(
+                "load annotations",
+                storage.get_annotations(span).await,
+                response,
+            ),
+            ClaimResources {
+                shard,
+                resources,
+                response,
+            } => {
+                // Conflicts are the caller's problem, not a storage failure
+                let res = storage.claim_resources(&shard, &resources).await;
+                response.send(res).unwrap_or(());
+            }
+            ReleaseResources { shard, resources } => written(
+                "release resources",
+                storage.release_resources(&shard, &resources).await,
+            ),
+            StoreShardState { shard, state } => written(
+                "store shard state",
+                storage.store_shard_state(&shard, &state).await,
+            ),
+            LoadShardStates { response } => {
+                // Failures are left to the runner, which can retry them
+                let res = storage.load_shard_states().await;
+                response.send(res).unwrap_or(());
+            }
+            TakeSnapshot { time, keep } => {
+                // A missed snapshot shouldn't take storage down with it
+                if let Err(e) = storage.take_snapshot(time, keep).await {
+                    warn!("Unable to snapshot state: {}", e);
+                }
+            }
+            ListSnapshots { response } => {
+                respond("list snapshots", storage.list_snapshots().await, response)
+            }
+            LoadSnapshot { time, response } => {
+                let res = storage.load_snapshot(time).await;
+                response.send(res).unwrap_or(());
+            }
+            GetHealth { response } => response.send(storage.health()).unwrap_or(()),
+            Stop {} => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Spawns a task servicing `msgs` with the given backend
+pub fn start<S: Storage + 'static>(
+    storage: S,
+    msgs: mpsc::Receiver<StorageMessage>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = serve(storage, msgs).await {
+            error!("Storage failed: {}", e);
+        }
+    })
+}
+
+pub mod file;
 pub mod memory;
 pub mod noop;
+#[cfg(feature = "redis-storage")]
 pub mod redis;
+#[cfg(feature = "s3-storage")]
+pub mod s3;
+pub mod snapshots;
+#[cfg(feature = "sqlite-storage")]
+pub mod sqlite;
+pub use snapshots::SnapshotConfig;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_serve_memory() {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = start(memory::MemoryStorage::new(), rx);
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        for exit_code in 0..3 {
+            tx.send(StorageMessage::StoreAttempt {
+                task_name: "task".to_owned(),
+                interval,
+                attempt: TaskAttempt {
+                    exit_code,
+                    ..TaskAttempt::new()
+                },
+                span: tracing::Span::none(),
+            })
+            .await
+            .unwrap();
+        }
+
+        let (response, rx) = oneshot::channel();
+        tx.send(StorageMessage::GetRecentAttempts {
+            task_name: "task".to_owned(),
+            max_attempts: 2,
+            response,
+        })
+        .await
+        .unwrap();
+        let recent: Vec<i32> = rx.await.unwrap().iter().map(|a| a.exit_code).collect();
+        assert_eq!(recent, vec![2, 1]);
+
+        let (response, rx) = oneshot::channel();
+        tx.send(StorageMessage::GetAttempts {
+            task_name: "task".to_owned(),
+            interval,
+            response,
+        })
+        .await
+        .unwrap();
+        assert_eq!(rx.await.unwrap().len(), 3);
+
+        tx.send(StorageMessage::Stop {}).await.unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_artifacts() {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = start(memory::MemoryStorage::new(), rx);
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        for data in [&b"first"[..], &b"second"[..]] {
+            tx.send(StorageMessage::StoreArtifact {
+                task_name: "task".to_owned(),
+                interval,
+                artifact: Artifact {
+                    name: "out/report.csv".to_owned(),
+                    size: data.len() as u64,
+                    data: data.to_vec(),
+                },
+            })
+            .await
+            .unwrap();
+        }
+
+        let get = |name: &str| {
+            let (response, rx) = oneshot::channel();
+            tx.try_send(StorageMessage::GetArtifact {
+                task_name: "task".to_owned(),
+                interval,
+                name: name.to_owned(),
+                response,
+            })
+            .unwrap();
+            rx
+        };
+        // The latest attempt's artifact wins
+        assert_eq!(
+            get("out/report.csv").await.unwrap().unwrap(),
+            Some(b"second".to_vec())
+        );
+        assert_eq!(get("missing").await.unwrap().unwrap(), None);
+
+        tx.send(StorageMessage::Stop {}).await.unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_output() {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = start(memory::MemoryStorage::new(), rx);
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        let chunk = |stream, data: &str| OutputChunk {
+            stream,
+            data: data.to_owned(),
+        };
+        let append = |chunks, replace| {
+            tx.try_send(StorageMessage::AppendOutput {
+                task_name: "task".to_owned(),
+                interval,
+                chunks,
+                replace,
+            })
+            .unwrap();
+        };
+        let get = || {
+            let (response, rx) = oneshot::channel();
+            tx.try_send(StorageMessage::GetOutput {
+                task_name: "task".to_owned(),
+                interval,
+                response,
+            })
+            .unwrap();
+            rx
+        };
+
+        append(vec![chunk(OutputStream::Stdout, "first attempt\n")], true);
+        append(vec![chunk(OutputStream::Stdout, "loading\n")], true);
+        append(vec![chunk(OutputStream::Stderr, "oops\n")], false);
+        assert_eq!(
+            get().await.unwrap().unwrap(),
+            vec![
+                chunk(OutputStream::Stdout, "loading\n"),
+                chunk(OutputStream::Stderr, "oops\n")
+            ]
+        );
+
+        tx.send(StorageMessage::Stop {}).await.unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_annotations() {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = start(memory::MemoryStorage::new(), rx);
+
+        let day = |d| Utc.with_ymd_and_hms(2022, 1, d, 0, 0, 0).unwrap();
+        for d in [1, 5] {
+            let (response, rx) = oneshot::channel();
+            tx.send(StorageMessage::StoreAnnotation {
+                annotation: IntervalAnnotation {
+                    task_name: "task".to_owned(),
+                    interval: Interval::new(day(d), day(d + 1)),
+                    annotation: serde_json::from_str(
+                        r#"{ "author": "ops", "text": "vendor resent file, forced up manually" }"#,
+                    )
+                    .unwrap(),
+                },
+                response,
+            })
+            .await
+            .unwrap();
+            rx.await.unwrap().unwrap();
+        }
+
+        let (response, rx) = oneshot::channel();
+        tx.send(StorageMessage::GetAnnotations {
+            span: Interval::new(day(3), day(8)),
+            response,
+        })
+        .await
+        .unwrap();
+        let annotations = rx.await.unwrap();
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(annotations[0].interval, Interval::new(day(5), day(6)));
+        assert_eq!(annotations[0].annotation.author.as_deref(), Some("ops"));
+
+        tx.send(StorageMessage::Stop {}).await.unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_snapshots() {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = start(memory::MemoryStorage::new(), rx);
+
+        let day = |d| Utc.with_ymd_and_hms(2022, 1, d, 0, 0, 0).unwrap();
+        for d in 1..4 {
+            let state = ResourceInterval::from(HashMap::from([(
+                "resource".to_owned(),
+                IntervalSet::from(Interval::new(day(1), day(d + 1))),
+            )]));
+            tx.send(StorageMessage::StoreState { state }).await.unwrap();
+            tx.send(StorageMessage::TakeSnapshot {
+                time: day(d),
+                keep: 2,
+            })
+            .await
+            .unwrap();
+        }
+
+        let (response, rx) = oneshot::channel();
+        tx.send(StorageMessage::ListSnapshots { response })
+            .await
+            .unwrap();
+        assert_eq!(rx.await.unwrap(), vec![day(2), day(3)]);
+
+        // Each snapshot is the state as of when it was taken
+        let (response, rx) = oneshot::channel();
+        tx.send(StorageMessage::LoadSnapshot {
+            time: day(2),
+            response,
+        })
+        .await
+        .unwrap();
+        let snapshot = rx.await.unwrap().unwrap();
+        assert!(snapshot["resource"].has_subset(Interval::new(day(1), day(3))));
+        assert!(!snapshot["resource"].has_subset(Interval::new(day(1), day(4))));
+
+        let (response, rx) = oneshot::channel();
+        tx.send(StorageMessage::LoadSnapshot {
+            time: day(1),
+            response,
+        })
+        .await
+        .unwrap();
+        assert!(rx.await.unwrap().is_err());
+
+        tx.send(StorageMessage::Stop {}).await.unwrap();
+        handle.await.unwrap();
+    }
+}
@@ -1,31 +1,105 @@
 use super::*;
 
+/// Keeps only the latest state, and discards all attempts and annotations
+#[derive(Default)]
+pub struct NoopStorage {
+    state: ResourceInterval,
+    owners: HashMap<Resource, String>,
+    shard_states: ShardStates,
+}
+
+impl NoopStorage {
+    pub fn new() -> Self {
+        NoopStorage::default()
+    }
+}
+
+#[async_trait]
+impl Storage for NoopStorage {
+    async fn clear(&mut self) -> Result<()> {
+        self.state = ResourceInterval::new();
+        self.owners.clear();
+        self.shard_states.clear();
+        Ok(())
+    }
+
+    async fn store_attempt(
+        &mut self,
+        _task_name: &str,
+        _interval: Interval,
+        _attempt: &TaskAttempt,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn store_state(&mut self, state: &ResourceInterval) -> Result<()> {
+        self.state = state.clone();
+        Ok(())
+    }
+
+    async fn load_state(&mut self) -> Result<ResourceInterval> {
+        Ok(self.state.clone())
+    }
+
+    async fn get_recent_attempts(
+        &mut self,
+        _task_name: &str,
+        _max_attempts: usize,
+    ) -> Result<Vec<TaskAttempt>> {
+        Ok(Vec::new())
+    }
+
+    async fn get_attempts(
+        &mut self,
+        _task_name: &str,
+        _interval: Interval,
+    ) -> Result<Vec<TaskAttempt>> {
+        Ok(Vec::new())
+    }
+
+    async fn append_output(
+        &mut self,
+        _task_name: &str,
+        _interval: Interval,
+        _chunks: &[OutputChunk],
+        _replace: bool,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn store_annotation(&mut self, _annotation: &IntervalAnnotation) -> Result<()> {
+        Ok(())
+    }
+
+    async fn claim_resources(&mut self, shard: &str, resources: &HashSet<Resource>) -> Result<()> {
+        claim(&mut self.owners, shard, resources)
+    }
+
+    async fn release_resources(
+        &mut self,
+        shard: &str,
+        resources: &HashSet<Resource>,
+    ) -> Result<()> {
+        release(&mut self.owners, shard, resources);
+        Ok(())
+    }
+
+    async fn store_shard_state(&mut self, shard: &str, state: &ResourceInterval) -> Result<()> {
+        self.shard_states.insert(shard.to_owned(), state.clone());
+        Ok(())
+    }
+
+    async fn load_shard_states(&mut self) -> Result<ShardStates> {
+        Ok(self.shard_states.clone())
+    }
+}
+
 /// The mpsc channel can be sized to fit max parallelism
-pub async fn start_storage(mut msgs: mpsc::UnboundedReceiver<StorageMessage>) -> Result<()> {
-    let mut current_state = ResourceInterval::new();
-    while let Some(msg) = msgs.recv().await {
-        use StorageMessage::*;
-        match msg {
-            Clear {} => {
-                current_state = ResourceInterval::new();
-            }
-            StoreAttempt { .. } => {}
-            StoreState { state } => {
-                current_state = state;
-            }
-            LoadState { response } => {
-                response.send(current_state.clone()).unwrap();
-            }
-            Stop {} => {
-                break;
-            }
-        }
-    }
-
-    Ok(())
+pub async fn start_storage(msgs: mpsc::Receiver<StorageMessage>) -> Result<()> {
+    serve(NoopStorage::new(), msgs).await
 }
 
-pub fn start(msgs: mpsc::UnboundedReceiver<StorageMessage>) -> tokio::task::JoinHandle<()> {
+pub fn start(msgs: mpsc::Receiver<StorageMessage>) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
         start_storage(msgs).await.expect("Unable to start storage");
     })
@@ -3,82 +3,1266 @@ use super::*;
 extern crate redis;
 
 use futures::prelude::*;
-use redis::AsyncCommands;
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+use redis::cluster::ClusterClient;
+use redis::cluster_async::ClusterConnection;
+use redis::sentinel::{SentinelClient, SentinelServerType};
+use redis::{AsyncCommands, RedisFuture};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::time::Instant;
 
-/// The mpsc channel can be sized to fit max parallelism
-pub async fn start_redis_storage(
-    mut msgs: mpsc::UnboundedReceiver<StorageMessage>,
-    url: String,
-    prefix: String,
-) -> Result<()> {
-    let client = redis::Client::open(url)?;
-    let mut conn = client.get_multiplexed_async_connection().await?;
-
-    while let Some(msg) = msgs.recv().await {
-        use StorageMessage::*;
-        match msg {
-            Clear {} => {
-                let mut keys = Vec::new();
-                {
-                    let mut iter: redis::AsyncIter<String> =
-                        conn.scan_match(format!("{}:*", prefix)).await?;
-                    while let Some(key) = iter.next_item().await {
-                        keys.push(key);
-                    }
-                }
-                for key in keys {
-                    conn.del(key).await?;
-                }
+/// Where and how to keep state and attempts in redis. Exactly one of
+/// `url`, `sentinel`, or `cluster_nodes` says where.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RedisConfig {
+    /// A single server, e.g. `redis://localhost:6379`
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// The master of a Sentinel-monitored deployment, found again on every
+    /// reconnect, so failovers are followed
+    #[serde(default)]
+    pub sentinel: Option<SentinelConfig>,
+
+    /// Some nodes of a Redis Cluster, from which the rest are discovered
+    #[serde(default)]
+    pub cluster_nodes: Option<Vec<String>>,
+
+    /// Prepended to every key, so several worlds can share a redis
+    pub prefix: String,
+
+    /// How long attempts are kept after they finish. Kept forever if unset.
+    #[serde(default)]
+    pub attempt_ttl_seconds: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SentinelConfig {
+    /// The name the sentinels know the master by
+    pub master_name: String,
+
+    /// The sentinels' URLs, e.g. `redis://sentinel-1:26379`
+    pub nodes: Vec<String>,
+}
+
+/// How connections are made, for each kind of deployment
+enum Client {
+    Single(redis::Client),
+    Sentinel(SentinelClient),
+    Cluster(ClusterClient),
+}
+
+impl Client {
+    fn open(config: &RedisConfig) -> Result<Self> {
+        match (&config.url, &config.sentinel, &config.cluster_nodes) {
+            (Some(url), None, None) => Ok(Client::Single(redis::Client::open(url.as_str())?)),
+            (None, Some(sentinel), None) => Ok(Client::Sentinel(SentinelClient::build(
+                sentinel.nodes.clone(),
+                sentinel.master_name.clone(),
+                None,
+                SentinelServerType::Master,
+            )?)),
+            (None, None, Some(nodes)) => Ok(Client::Cluster(ClusterClient::new(nodes.clone())?)),
+            _ => Err(Error::Validation(
+                "Redis storage needs exactly one of url, sentinel, or cluster_nodes".to_owned(),
+            )),
+        }
+    }
+
+    async fn connect(&mut self) -> redis::RedisResult<Connection> {
+        match self {
+            Client::Single(client) => client
+                .get_multiplexed_async_connection()
+                .await
+                .map(Connection::Single),
+            Client::Sentinel(client) => client.get_async_connection().await.map(Connection::Single),
+            Client::Cluster(client) => client.get_async_connection().await.map(Connection::Cluster),
+        }
+    }
+}
+
+/// A connection to a single server, or to a whole cluster
+#[derive(Clone)]
+enum Connection {
+    Single(MultiplexedConnection),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> RedisFuture<'a, redis::Value> {
+        match self {
+            Connection::Single(conn) => conn.req_packed_command(cmd),
+            Connection::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<redis::Value>> {
+        match self {
+            Connection::Single(conn) => conn.req_packed_commands(pipeline, offset, count),
+            Connection::Cluster(conn) => conn.req_packed_commands(pipeline, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Single(conn) => conn.get_db(),
+            Connection::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
+
+/// The most writes held while redis is unreachable. The oldest attempts
+/// and annotations are dropped beyond it.
+pub const MAX_BUFFERED_WRITES: usize = 10_000;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Appends an attempt to its interval's field of the task's attempt hash,
+/// indexes the interval by when it last ran, and prunes intervals that last
+/// ran more than the TTL ago.
+///
+/// KEYS: the attempt hash, the interval index. ARGV: the interval's field,
+/// the attempt, when it stopped in ms, the TTL in ms or 0 for none.
+static APPEND_ATTEMPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        local attempts = redis.call('HGET', KEYS[1], ARGV[1])
+        if attempts then
+            attempts = string.sub(attempts, 1, -2) .. ',' .. ARGV[2] .. ']'
+        else
+            attempts = '[' .. ARGV[2] .. ']'
+        end
+        redis.call('HSET', KEYS[1], ARGV[1], attempts)
+        redis.call('ZADD', KEYS[2], ARGV[3], ARGV[1])
+        local ttl = tonumber(ARGV[4])
+        if ttl > 0 then
+            local cutoff = '(' .. (tonumber(ARGV[3]) - ttl)
+            local expired = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', cutoff)
+            for _, field in ipairs(expired) do
+                redis.call('HDEL', KEYS[1], field)
+            end
+            redis.call('ZREMRANGEBYSCORE', KEYS[2], '-inf', cutoff)
+            redis.call('PEXPIRE', KEYS[1], ttl)
+            redis.call('PEXPIRE', KEYS[2], ttl)
+        end
+        return 1
+        ",
+    )
+});
+
+/// Claims resources for a shard all at once, or none of them if any is
+/// owned by another shard.
+///
+/// KEYS: the owners hash. ARGV: the shard, then the resources. Returns the
+/// first resource owned elsewhere and its owner, or nothing.
+static CLAIM_RESOURCES: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        for i = 2, #ARGV do
+            local owner = redis.call('HGET', KEYS[1], ARGV[i])
+            if owner and owner ~= ARGV[1] then
+                return { ARGV[i], owner }
+            end
+        end
+        for i = 2, #ARGV do
+            redis.call('HSET', KEYS[1], ARGV[i], ARGV[1])
+        end
+        return {}
+        ",
+    )
+});
+
+/// Releases the resources a shard owns, leaving any owned by others.
+///
+/// KEYS: the owners hash. ARGV: the shard, then the resources.
+static RELEASE_RESOURCES: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        for i = 2, #ARGV do
+            if redis.call('HGET', KEYS[1], ARGV[i]) == ARGV[1] then
+                redis.call('HDEL', KEYS[1], ARGV[i])
+            end
+        end
+        return 1
+        ",
+    )
+});
+
+/// The hash of a task's attempts, with a field of each interval's attempts.
+/// The task's keys share a hash tag, so a cluster keeps them on one node.
+fn attempts_key(prefix: &str, task_name: &str) -> String {
+    format!("{}:task:{{{}}}:attempts", prefix, task_name)
+}
+
+/// The fields of a task's attempt hash, scored by when they last ran
+fn intervals_key(prefix: &str, task_name: &str) -> String {
+    format!("{}:task:{{{}}}:intervals", prefix, task_name)
+}
+
+/// The list of an interval's output chunks
+fn output_key(prefix: &str, task_name: &str, interval: &Interval) -> String {
+    format!(
+        "{}:task:{{{}}}:output:{}",
+        prefix,
+        task_name,
+        interval_field(interval)
+    )
+}
+
+/// The hash of an interval's artifacts, with a field of each's contents
+fn artifacts_key(prefix: &str, task_name: &str, interval: &Interval) -> String {
+    format!(
+        "{}:task:{{{}}}:artifacts:{}",
+        prefix,
+        task_name,
+        interval_field(interval)
+    )
+}
+
+fn interval_field(interval: &Interval) -> String {
+    interval.end.to_rfc3339()
+}
+
+/// Escapes the glob characters of a key, for SCAN and KEYS patterns
+fn glob_escape(key: &str) -> String {
+    let mut escaped = String::with_capacity(key.len());
+    for c in key.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The keys matching a pattern, on every primary of a cluster
+async fn scan_keys(conn: &mut Connection, pattern: &str) -> redis::RedisResult<Vec<String>> {
+    if let Connection::Cluster(_) = conn {
+        // SCAN only covers one node, but KEYS is sent to every primary of a
+        // cluster
+        return conn.keys(pattern).await;
+    }
+    let mut keys = Vec::new();
+    let mut iter: redis::AsyncIter<String> = conn.scan_match(pattern).await?;
+    while let Some(key) = iter.next_item().await {
+        keys.push(key?);
+    }
+    Ok(keys)
+}
+
+/// The interval end of a key of the first layout, as `DateTime`'s display
+fn legacy_interval_end(suffix: &str) -> Option<DateTime<Utc>> {
+    let naive = suffix.strip_suffix(" UTC")?;
+    chrono::NaiveDateTime::parse_from_str(naive, "%Y-%m-%d %H:%M:%S%.f")
+        .ok()
+        .map(|end| end.and_utc())
+}
+
+/// An interval's attempts with those moved from an earlier layout placed
+/// first, skipping any already there from an interrupted migration, and
+/// when the latest of them stopped in ms
+fn merge_attempts(
+    existing: Option<&str>,
+    moved: Vec<serde_json::Value>,
+) -> serde_json::Result<(String, i64)> {
+    let existing: Vec<serde_json::Value> = match existing {
+        Some(payload) => serde_json::from_str(payload)?,
+        None => Vec::new(),
+    };
+    let mut attempts: Vec<serde_json::Value> = moved
+        .into_iter()
+        .filter(|attempt| !existing.contains(attempt))
+        .collect();
+    attempts.extend(existing);
+    let stopped = attempts
+        .iter()
+        .filter_map(|attempt| serde_json::from_value::<TaskAttempt>(attempt.clone()).ok())
+        .map(|attempt| attempt.stop_time.timestamp_millis())
+        .max()
+        .unwrap_or(0);
+    Ok((serde_json::to_string(&attempts)?, stopped))
+}
+
+/// Adds attempts of an interval from an earlier layout to the task's hash
+async fn move_attempts(
+    conn: &mut Connection,
+    prefix: &str,
+    task_name: &str,
+    field: &str,
+    moved: Vec<serde_json::Value>,
+) -> redis::RedisResult<()> {
+    let attempts = attempts_key(prefix, task_name);
+    let existing: Option<String> = conn.hget(&attempts, field).await?;
+    let (payload, stopped) = merge_attempts(existing.as_deref(), moved).map_err(|e| {
+        redis::RedisError::from((
+            redis::ErrorKind::UnexpectedReturnType,
+            "Unreadable attempts",
+            e.to_string(),
+        ))
+    })?;
+    redis::pipe()
+        .hset(&attempts, field, payload)
+        .ignore()
+        .zadd(intervals_key(prefix, task_name), field, stopped)
+        .ignore()
+        .query_async(conn)
+        .await
+}
+
+/// Moves attempts kept by earlier versions into the task hashes, returning
+/// how many intervals were moved. The first layout kept a list of each
+/// interval's attempts at `{prefix}:{task}_{end}`, with the task's history
+/// at `{prefix}:attempts:{task}`. The next kept the task hashes without a
+/// hash tag.
+async fn migrate_attempts(conn: &mut Connection, prefix: &str) -> redis::RedisResult<usize> {
+    let mut moved = 0;
+
+    let history_prefix = format!("{}:attempts:", prefix);
+    let histories = scan_keys(conn, &format!("{}*", glob_escape(&history_prefix))).await?;
+    for history in histories {
+        let Some(task_name) = history.strip_prefix(&history_prefix) else {
+            continue;
+        };
+        let interval_prefix = format!("{}:{}_", prefix, task_name);
+        let keys = scan_keys(conn, &format!("{}*", glob_escape(&interval_prefix))).await?;
+        for key in keys {
+            // Other tasks' names can start with this one's
+            let Some(end) = key
+                .strip_prefix(&interval_prefix)
+                .and_then(legacy_interval_end)
+            else {
+                continue;
+            };
+            let payloads: Vec<String> = conn.lrange(&key, 0, -1).await?;
+            let attempts = payloads
+                .iter()
+                .filter_map(|payload| serde_json::from_str(payload).ok())
+                .collect();
+            move_attempts(conn, prefix, task_name, &end.to_rfc3339(), attempts).await?;
+            conn.del::<_, ()>(&key).await?;
+            moved += 1;
+        }
+        conn.del::<_, ()>(&history).await?;
+    }
+
+    let hash_prefix = format!("{}:task:", prefix);
+    let hashes = scan_keys(conn, &format!("{}*:attempts", glob_escape(&hash_prefix))).await?;
+    for hash in hashes {
+        let Some(task_name) = hash
+            .strip_prefix(&hash_prefix)
+            .and_then(|key| key.strip_suffix(":attempts"))
+        else {
+            continue;
+        };
+        if task_name.starts_with('{') && task_name.ends_with('}') {
+            continue;
+        }
+        let fields: HashMap<String, String> = conn.hgetall(&hash).await?;
+        for (field, payload) in fields {
+            let attempts = serde_json::from_str(&payload).unwrap_or_default();
+            move_attempts(conn, prefix, task_name, &field, attempts).await?;
+            moved += 1;
+        }
+        conn.del::<_, ()>(&hash).await?;
+        conn.del::<_, ()>(format!("{}{}:intervals", hash_prefix, task_name))
+            .await?;
+    }
+    Ok(moved)
+}
+
+/// A write, serialized and ready to be sent once redis can be reached
+#[derive(Debug)]
+enum PendingWrite {
+    Attempt {
+        task_name: String,
+        interval: Interval,
+        stopped: i64,
+        payload: String,
+    },
+    Output {
+        task_name: String,
+        interval: Interval,
+        chunks: Vec<String>,
+        replace: bool,
+    },
+    Artifact {
+        task_name: String,
+        interval: Interval,
+        name: String,
+        data: Vec<u8>,
+    },
+    State(String),
+    Actions(String),
+    Annotation {
+        score: i64,
+        payload: String,
+    },
+    ShardState {
+        shard: String,
+        payload: String,
+    },
+}
+
+impl PendingWrite {
+    /// Whether `self` makes an earlier buffered `other` pointless to send
+    fn supersedes(&self, other: &PendingWrite) -> bool {
+        use PendingWrite::*;
+        match (self, other) {
+            (State(_), State(_)) | (Actions(_), Actions(_)) => true,
+            (ShardState { shard, .. }, ShardState { shard: other, .. }) => shard == other,
+            (
+                Output {
+                    task_name,
+                    interval,
+                    replace: true,
+                    ..
+                },
+                Output {
+                    task_name: other_task,
+                    interval: other_interval,
+                    ..
+                },
+            ) => task_name == other_task && interval == other_interval,
+            _ => false,
+        }
+    }
+
+    async fn apply(
+        &self,
+        conn: &mut Connection,
+        prefix: &str,
+        attempt_ttl: Option<Duration>,
+    ) -> redis::RedisResult<()> {
+        use PendingWrite::*;
+        match self {
+            Attempt {
+                task_name,
+                interval,
+                stopped,
+                payload,
+            } => {
+                APPEND_ATTEMPT
+                    .key(attempts_key(prefix, task_name))
+                    .key(intervals_key(prefix, task_name))
+                    .arg(interval_field(interval))
+                    .arg(payload)
+                    .arg(*stopped)
+                    .arg(attempt_ttl.map_or(0, |ttl| ttl.as_millis() as u64))
+                    .invoke_async(conn)
+                    .await
             }
-            StoreAttempt {
+            Output {
                 task_name,
                 interval,
-                attempt,
+                chunks,
+                replace,
             } => {
-                let tag = format!("{}:{}_{}", prefix, task_name, interval.end);
-                let payload = serde_json::to_string(&attempt).unwrap();
-                conn.rpush(&tag, &payload).await?;
+                let key = output_key(prefix, task_name, interval);
+                let mut pipe = redis::pipe();
+                pipe.atomic();
+                if *replace {
+                    pipe.del(&key).ignore();
+                }
+                if !chunks.is_empty() {
+                    pipe.rpush(&key, chunks).ignore();
+                }
+                // Output lasts as long as the attempts it's from
+                if let Some(ttl) = attempt_ttl {
+                    pipe.pexpire(&key, ttl.as_millis() as i64).ignore();
+                }
+                pipe.query_async(conn).await
             }
-            /*
-            SetTaskIntervalState {
+            Artifact {
                 task_name,
                 interval,
-                state,
+                name,
+                data,
             } => {
-                let map = format!("{}:task_interval_states", prefix);
-                let key = format!("{}_{}-{}", task_name, interval.start, interval.end);
-                let value = serde_json::to_string(&state).unwrap();
-                conn.hset(&map, &key, &value).await?;
+                let key = artifacts_key(prefix, task_name, interval);
+                let mut pipe = redis::pipe();
+                pipe.atomic().hset(&key, name, data).ignore();
+                if let Some(ttl) = attempt_ttl {
+                    pipe.pexpire(&key, ttl.as_millis() as i64).ignore();
+                }
+                pipe.query_async(conn).await
+            }
+            State(payload) => conn.set(format!("{}:state", prefix), payload).await,
+            Actions(payload) => conn.set(format!("{}:actions", prefix), payload).await,
+            Annotation { score, payload } => {
+                // Scored by interval end, for range queries
+                conn.zadd(format!("{}:annotations", prefix), payload, *score)
+                    .await
             }
-            */
-            StoreState { state } => {
-                let tag = format!("{}:state", prefix);
-                let payload = serde_json::to_string(&state).unwrap();
-                conn.set(&tag, &payload).await?;
+            ShardState { shard, payload } => {
+                conn.hset(format!("{}:shard_states", prefix), shard, payload)
+                    .await
             }
-            LoadState { response } => {
-                let tag = format!("{}:state", prefix);
-                let payload: String = conn.get(&tag).await.unwrap_or("{}".to_owned());
-                let is: ResourceInterval = serde_json::from_str(&payload).unwrap();
-                response.send(is).unwrap();
+        }
+    }
+}
+
+/// Whether the error means redis couldn't be reached, rather than that it
+/// refused the command
+fn is_connection_error(e: &redis::RedisError) -> bool {
+    e.is_io_error()
+        || e.is_connection_refusal()
+        || e.is_connection_dropped()
+        || e.is_timeout()
+        || e.is_unrecoverable_error()
+}
+
+/// Persists state and attempts to redis, with all keys under `prefix`.
+///
+/// Each task's attempts are kept in a hash with a field per interval, and
+/// the intervals are indexed by when they last ran, so recent attempts and
+/// pruning only touch the task's own keys.
+///
+/// Losing the connection doesn't stop it: writes are buffered, up to
+/// [`MAX_BUFFERED_WRITES`], and sent once it reconnects, retrying with
+/// backoff. Reads fail until then.
+pub struct RedisStorage {
+    client: Client,
+    conn: Option<Connection>,
+    prefix: String,
+    attempt_ttl: Option<Duration>,
+    pending: VecDeque<PendingWrite>,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+    health: StorageHealth,
+
+    /// Whether attempts in earlier layouts have been moved
+    migrated: bool,
+}
+
+impl RedisStorage {
+    /// Fails if redis can't be reached at first
+    pub async fn new(config: &RedisConfig) -> Result<Self> {
+        let mut storage = RedisStorage::disconnected(config)?;
+        let mut conn = storage.client.connect().await?;
+        storage.migrate(&mut conn).await?;
+        storage.conn = Some(conn);
+        storage.health = StorageHealth::default();
+        Ok(storage)
+    }
+
+    /// Storage that connects on first use
+    pub fn disconnected(config: &RedisConfig) -> Result<Self> {
+        Ok(RedisStorage {
+            client: Client::open(config)?,
+            conn: None,
+            prefix: config.prefix.clone(),
+            attempt_ttl: config.attempt_ttl_seconds.map(Duration::from_secs),
+            pending: VecDeque::new(),
+            backoff: MIN_BACKOFF,
+            retry_at: None,
+            health: StorageHealth {
+                available: false,
+                down_since: Some(Utc::now()),
+                ..StorageHealth::default()
+            },
+            migrated: false,
+        })
+    }
+
+    /// Moves attempts in earlier layouts, once, before anything is read or
+    /// written
+    async fn migrate(&mut self, conn: &mut Connection) -> redis::RedisResult<()> {
+        if self.migrated {
+            return Ok(());
+        }
+        match migrate_attempts(conn, &self.prefix).await {
+            Ok(moved) => {
+                if moved > 0 {
+                    info!(moved, "Moved attempts of intervals to the current layout");
+                }
+            }
+            Err(e) if is_connection_error(&e) => return Err(e),
+            // Left where they are, rather than holding up storage
+            Err(e) => warn!("Unable to move attempts to the current layout: {}", e),
+        }
+        self.migrated = true;
+        Ok(())
+    }
+
+    fn unavailable(&self) -> Error {
+        Error::Storage(format!(
+            "Redis is unavailable: {}",
+            self.health.last_error.as_deref().unwrap_or("not connected")
+        ))
+    }
+
+    /// Forgets the connection, and waits out the backoff before the next
+    fn lost_connection(&mut self, e: &redis::RedisError) {
+        if self.health.available {
+            warn!("Lost connection to redis, buffering writes: {}", e);
+            self.health.available = false;
+            self.health.down_since = Some(Utc::now());
+        }
+        self.health.last_error = Some(e.to_string());
+        self.conn = None;
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    /// The connection, reconnecting if the backoff has passed
+    async fn connection(&mut self) -> Result<Connection> {
+        if let Some(conn) = &self.conn {
+            return Ok(conn.clone());
+        }
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return Err(self.unavailable());
+        }
+        let connected = match self.client.connect().await {
+            Ok(mut conn) => self.migrate(&mut conn).await.map(|()| conn),
+            Err(e) => Err(e),
+        };
+        match connected {
+            Ok(conn) => {
+                info!(buffered = self.pending.len(), "Connected to redis");
+                self.conn = Some(conn.clone());
+                self.backoff = MIN_BACKOFF;
+                self.retry_at = None;
+                self.health.available = true;
+                self.health.down_since = None;
+                Ok(conn)
+            }
+            Err(e) => {
+                self.lost_connection(&e);
+                Err(self.unavailable())
             }
-            Stop {} => {
+        }
+    }
+
+    fn buffer(&mut self, write: PendingWrite) {
+        self.pending.retain(|w| !write.supersedes(w));
+        self.pending.push_back(write);
+        if self.pending.len() > MAX_BUFFERED_WRITES {
+            // Superseding keeps state writes few, so there's always an
+            // attempt or annotation to drop
+            let oldest = self
+                .pending
+                .iter()
+                .position(|w| {
+                    matches!(
+                        w,
+                        PendingWrite::Attempt { .. }
+                            | PendingWrite::Output { .. }
+                            | PendingWrite::Artifact { .. }
+                            | PendingWrite::Annotation { .. }
+                    )
+                })
+                .unwrap_or(0);
+            self.pending.remove(oldest);
+            self.health.dropped += 1;
+            warn!("Redis write buffer is full, dropping the oldest write");
+        }
+        self.health.buffered = self.pending.len();
+    }
+
+    /// Sends buffered writes, oldest first, until they're done or redis
+    /// can't be reached
+    async fn flush(&mut self) {
+        while !self.pending.is_empty() {
+            let Ok(mut conn) = self.connection().await else {
                 break;
+            };
+            let write = &self.pending[0];
+            match write.apply(&mut conn, &self.prefix, self.attempt_ttl).await {
+                Ok(()) => {
+                    self.pending.pop_front();
+                }
+                Err(e) if is_connection_error(&e) => {
+                    self.lost_connection(&e);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Redis refused a write, dropping it: {}", e);
+                    self.pending.pop_front();
+                    self.health.dropped += 1;
+                }
+            }
+        }
+        self.health.buffered = self.pending.len();
+    }
+
+    async fn write(&mut self, write: PendingWrite) -> Result<()> {
+        self.buffer(write);
+        self.flush().await;
+        Ok(())
+    }
+
+    /// Runs `op` once buffered writes are sent, so it sees them
+    async fn read<T, F>(&mut self, op: impl FnOnce(Connection) -> F) -> Result<T>
+    where
+        F: Future<Output = redis::RedisResult<T>>,
+    {
+        self.flush().await;
+        if !self.pending.is_empty() {
+            return Err(self.unavailable());
+        }
+        let conn = self.connection().await?;
+        op(conn).await.map_err(|e| {
+            if is_connection_error(&e) {
+                self.lost_connection(&e);
+            }
+            e.into()
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    async fn clear(&mut self) -> Result<()> {
+        let pattern = format!("{}:*", glob_escape(&self.prefix));
+        self.read(|mut conn| async move {
+            for key in scan_keys(&mut conn, &pattern).await? {
+                conn.del::<_, ()>(key).await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn store_attempt(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        attempt: &TaskAttempt,
+    ) -> Result<()> {
+        self.write(PendingWrite::Attempt {
+            task_name: task_name.to_owned(),
+            interval,
+            stopped: attempt.stop_time.timestamp_millis(),
+            payload: serde_json::to_string(attempt)?,
+        })
+        .await
+    }
+
+    async fn store_state(&mut self, state: &ResourceInterval) -> Result<()> {
+        self.write(PendingWrite::State(serde_json::to_string(state)?))
+            .await
+    }
+
+    async fn load_state(&mut self) -> Result<ResourceInterval> {
+        let tag = format!("{}:state", self.prefix);
+        let payload: Option<String> = self
+            .read(|mut conn| async move { conn.get(&tag).await })
+            .await?;
+        Ok(serde_json::from_str(payload.as_deref().unwrap_or("{}"))?)
+    }
+
+    async fn store_actions(&mut self, actions: &[ActionRecord]) -> Result<()> {
+        self.write(PendingWrite::Actions(serde_json::to_string(actions)?))
+            .await
+    }
+
+    async fn load_actions(&mut self) -> Result<Vec<ActionRecord>> {
+        let tag = format!("{}:actions", self.prefix);
+        let payload: Option<String> = self
+            .read(|mut conn| async move { conn.get(&tag).await })
+            .await?;
+        match payload {
+            Some(payload) => Ok(serde_json::from_str(&payload)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn get_recent_attempts(
+        &mut self,
+        task_name: &str,
+        max_attempts: usize,
+    ) -> Result<Vec<TaskAttempt>> {
+        if max_attempts == 0 {
+            return Ok(Vec::new());
+        }
+        // The most recent attempts are among those of the intervals run
+        // most recently
+        let attempts = attempts_key(&self.prefix, task_name);
+        let intervals = intervals_key(&self.prefix, task_name);
+        let payloads: Vec<Option<String>> = self
+            .read(|mut conn| async move {
+                let fields: Vec<String> = conn
+                    .zrevrange(&intervals, 0, max_attempts as isize - 1)
+                    .await?;
+                if fields.is_empty() {
+                    return Ok(Vec::new());
+                }
+                redis::cmd("HMGET")
+                    .arg(&attempts)
+                    .arg(&fields)
+                    .query_async(&mut conn)
+                    .await
+            })
+            .await?;
+        let mut recent: Vec<TaskAttempt> = payloads
+            .iter()
+            .flatten()
+            .filter_map(|x| serde_json::from_str::<Vec<TaskAttempt>>(x).ok())
+            .flatten()
+            .filter(|a| a.kind.is_up())
+            .collect();
+        recent.sort_by_key(|a| std::cmp::Reverse(a.stop_time));
+        recent.truncate(max_attempts);
+        Ok(recent)
+    }
+
+    async fn get_attempts(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<TaskAttempt>> {
+        let attempts = attempts_key(&self.prefix, task_name);
+        let field = interval_field(&interval);
+        let payload: Option<String> = self
+            .read(|mut conn| async move { conn.hget(&attempts, &field).await })
+            .await?;
+        match payload {
+            Some(payload) => Ok(serde_json::from_str(&payload)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn store_artifact(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        artifact: &Artifact,
+    ) -> Result<()> {
+        self.write(PendingWrite::Artifact {
+            task_name: task_name.to_owned(),
+            interval,
+            name: artifact.name.clone(),
+            data: artifact.data.clone(),
+        })
+        .await
+    }
+
+    async fn get_artifact(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let key = artifacts_key(&self.prefix, task_name, &interval);
+        let name = name.to_owned();
+        self.read(|mut conn| async move { conn.hget(&key, &name).await })
+            .await
+    }
+
+    async fn append_output(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        chunks: &[OutputChunk],
+        replace: bool,
+    ) -> Result<()> {
+        self.write(PendingWrite::Output {
+            task_name: task_name.to_owned(),
+            interval,
+            chunks: chunks
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<serde_json::Result<_>>()?,
+            replace,
+        })
+        .await
+    }
+
+    async fn get_output(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<OutputChunk>> {
+        let key = output_key(&self.prefix, task_name, &interval);
+        let payloads: Vec<String> = self
+            .read(|mut conn| async move { conn.lrange(&key, 0, -1).await })
+            .await?;
+        Ok(payloads
+            .iter()
+            .filter_map(|x| serde_json::from_str(x).ok())
+            .collect())
+    }
+
+    async fn store_annotation(&mut self, annotation: &IntervalAnnotation) -> Result<()> {
+        self.write(PendingWrite::Annotation {
+            score: annotation.interval.end.timestamp_millis(),
+            payload: serde_json::to_string(annotation)?,
+        })
+        .await
+    }
+
+    async fn get_annotations(&mut self, span: Interval) -> Result<Vec<IntervalAnnotation>> {
+        let tag = format!("{}:annotations", self.prefix);
+        let payloads: Vec<String> = self
+            .read(|mut conn| async move {
+                conn.zrangebyscore(&tag, span.start.timestamp_millis(), "+inf")
+                    .await
+            })
+            .await?;
+        let mut annotations = Vec::new();
+        for payload in payloads {
+            let annotation: IntervalAnnotation = serde_json::from_str(&payload)?;
+            if span.is_contiguous(annotation.interval) {
+                annotations.push(annotation);
+            }
+        }
+        annotations.sort_by_key(|a| a.annotation.time);
+        Ok(annotations)
+    }
+
+    async fn claim_resources(&mut self, shard: &str, resources: &HashSet<Resource>) -> Result<()> {
+        let tag = format!("{}:owners", self.prefix);
+        let shard = shard.to_owned();
+        let mut resources: Vec<Resource> = resources.iter().cloned().collect();
+        resources.sort_unstable();
+        let conflict: Vec<String> = self
+            .read(|mut conn| async move {
+                CLAIM_RESOURCES
+                    .key(&tag)
+                    .arg(&shard)
+                    .arg(&resources)
+                    .invoke_async(&mut conn)
+                    .await
+            })
+            .await?;
+        match conflict.as_slice() {
+            [res, owner] => Err(Error::Validation(format!(
+                "Resource {} is owned by shard {}",
+                res, owner
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    async fn release_resources(
+        &mut self,
+        shard: &str,
+        resources: &HashSet<Resource>,
+    ) -> Result<()> {
+        let tag = format!("{}:owners", self.prefix);
+        let shard = shard.to_owned();
+        let resources: Vec<Resource> = resources.iter().cloned().collect();
+        self.read(|mut conn| async move {
+            RELEASE_RESOURCES
+                .key(&tag)
+                .arg(&shard)
+                .arg(&resources)
+                .invoke_async::<()>(&mut conn)
+                .await
+        })
+        .await
+    }
+
+    async fn store_shard_state(&mut self, shard: &str, state: &ResourceInterval) -> Result<()> {
+        self.write(PendingWrite::ShardState {
+            shard: shard.to_owned(),
+            payload: serde_json::to_string(state)?,
+        })
+        .await
+    }
+
+    async fn take_snapshot(&mut self, time: DateTime<Utc>, keep: usize) -> Result<()> {
+        let prefix = self.prefix.clone();
+        let payload: Option<String> = self
+            .read(|mut conn| async move { conn.get(format!("{}:state", prefix)).await })
+            .await?;
+        let payload =
+            payload.ok_or_else(|| Error::Storage("No state has been stored".to_owned()))?;
+
+        let prefix = self.prefix.clone();
+        self.read(|mut conn| async move {
+            let index = format!("{}:snapshots", prefix);
+            let stamp = time.to_rfc3339();
+            conn.set::<_, _, ()>(format!("{}:snapshot:{}", prefix, stamp), &payload)
+                .await?;
+            conn.zadd::<_, _, _, ()>(&index, &stamp, time.timestamp_millis())
+                .await?;
+
+            // Everything but the newest `keep`, oldest first
+            let expired: Vec<String> = conn.zrange(&index, 0, -(keep as isize) - 1).await?;
+            for stamp in expired {
+                conn.del::<_, ()>(format!("{}:snapshot:{}", prefix, stamp))
+                    .await?;
+                conn.zrem::<_, _, ()>(&index, &stamp).await?;
             }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_snapshots(&mut self) -> Result<Vec<DateTime<Utc>>> {
+        let index = format!("{}:snapshots", self.prefix);
+        let stamps: Vec<String> = self
+            .read(|mut conn| async move { conn.zrange(&index, 0, -1).await })
+            .await?;
+        Ok(stamps
+            .iter()
+            .filter_map(|s| DateTime::parse_from_rfc3339(s).ok())
+            .map(|t| t.with_timezone(&Utc))
+            .collect())
+    }
+
+    async fn load_snapshot(&mut self, time: DateTime<Utc>) -> Result<ResourceInterval> {
+        let tag = format!("{}:snapshot:{}", self.prefix, time.to_rfc3339());
+        let payload: Option<String> = self
+            .read(|mut conn| async move { conn.get(&tag).await })
+            .await?;
+        match payload {
+            Some(payload) => Ok(serde_json::from_str(&payload)?),
+            None => Err(Error::Storage(format!("No snapshot taken at {}", time))),
         }
     }
 
-    Ok(())
+    async fn load_shard_states(&mut self) -> Result<ShardStates> {
+        let tag = format!("{}:shard_states", self.prefix);
+        let payloads: HashMap<String, String> = self
+            .read(|mut conn| async move { conn.hgetall(&tag).await })
+            .await?;
+        payloads
+            .into_iter()
+            .map(|(shard, payload)| Ok((shard, serde_json::from_str(&payload)?)))
+            .collect()
+    }
+
+    fn health(&self) -> StorageHealth {
+        self.health.clone()
+    }
+
+    async fn maintain(&mut self) {
+        if self.conn.is_none() || !self.pending.is_empty() {
+            // Reconnecting with nothing to send still updates the health
+            if self.connection().await.is_ok() {
+                self.flush().await;
+            }
+        }
+    }
+}
+
+/// The mpsc channel can be sized to fit max parallelism
+pub async fn start_redis_storage(
+    msgs: mpsc::Receiver<StorageMessage>,
+    config: RedisConfig,
+) -> Result<()> {
+    serve(RedisStorage::new(&config).await?, msgs).await
 }
 
 pub fn start(
-    msgs: mpsc::UnboundedReceiver<StorageMessage>,
-    url: String,
-    prefix: String,
+    msgs: mpsc::Receiver<StorageMessage>,
+    config: RedisConfig,
 ) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        start_redis_storage(msgs, url, prefix)
-            .await
-            .expect("Unable to start redis storage");
+        if let Err(e) = start_redis_storage(msgs, config).await {
+            error!("Unable to start redis storage: {}", e);
+        }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_deployments() {
+        let config = |json| serde_json::from_value::<RedisConfig>(json).unwrap();
+        for json in [
+            serde_json::json!({ "url": "redis://localhost", "prefix": "prod" }),
+            serde_json::json!({
+                "sentinel": {
+                    "master_name": "waterfall",
+                    "nodes": [ "redis://sentinel-1:26379", "redis://sentinel-2:26379" ]
+                },
+                "prefix": "prod"
+            }),
+            serde_json::json!({
+                "cluster_nodes": [ "redis://node-1:6379", "redis://node-2:6379" ],
+                "prefix": "prod"
+            }),
+        ] {
+            assert!(RedisStorage::disconnected(&config(json)).is_ok());
+        }
+
+        // Where redis is has to be unambiguous
+        for json in [
+            serde_json::json!({ "prefix": "prod" }),
+            serde_json::json!({
+                "url": "redis://localhost",
+                "cluster_nodes": [ "redis://node-1:6379" ],
+                "prefix": "prod"
+            }),
+        ] {
+            assert!(matches!(
+                RedisStorage::disconnected(&config(json)),
+                Err(Error::Validation(_))
+            ));
+        }
+    }
+
+    fn attempt(stopped: DateTime<Utc>) -> TaskAttempt {
+        TaskAttempt {
+            stop_time: stopped,
+            ..TaskAttempt::new()
+        }
+    }
+
+    #[test]
+    fn check_legacy_layout() {
+        let end = Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap();
+
+        // Interval lists were keyed by the end's display
+        let suffix = end.to_string();
+        assert_eq!(legacy_interval_end(&suffix), Some(end));
+        assert_eq!(legacy_interval_end("b_2022-01-02 00:00:00 UTC"), None);
+
+        // A list of the interval's attempts, oldest first
+        let first = serde_json::to_value(attempt(end)).unwrap();
+        let second = serde_json::to_value(attempt(end + Duration::from_secs(60))).unwrap();
+        let (payload, stopped) = merge_attempts(None, vec![first.clone(), second.clone()]).unwrap();
+        let attempts: Vec<TaskAttempt> = serde_json::from_str(&payload).unwrap();
+        assert_eq!(attempts.len(), 2);
+        assert_eq!(attempts[0].stop_time, end);
+        assert_eq!(stopped, (end + Duration::from_secs(60)).timestamp_millis());
+
+        // Moved attempts go before newer ones, once, even if the move is
+        // run again
+        let third = serde_json::to_value(attempt(end + Duration::from_secs(120))).unwrap();
+        let existing = serde_json::to_string(&vec![first.clone(), third]).unwrap();
+        let (payload, stopped) = merge_attempts(Some(&existing), vec![first, second]).unwrap();
+        let attempts: Vec<TaskAttempt> = serde_json::from_str(&payload).unwrap();
+        assert_eq!(attempts.len(), 3);
+        assert_eq!(attempts[0].stop_time, end + Duration::from_secs(60));
+        assert_eq!(stopped, (end + Duration::from_secs(120)).timestamp_millis());
+
+        assert_eq!(glob_escape("a*b[c]"), "a\\*b\\[c\\]");
+    }
+
+    /// Against a live redis at `WATERFALL_TEST_REDIS_URL`, if set
+    #[tokio::test]
+    async fn check_migration() {
+        let Ok(url) = std::env::var("WATERFALL_TEST_REDIS_URL") else {
+            return;
+        };
+        let config = RedisConfig {
+            url: Some(url.clone()),
+            sentinel: None,
+            cluster_nodes: None,
+            prefix: format!("migration-{:x}", rand::random::<u32>()),
+            attempt_ttl_seconds: None,
+        };
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        let payload = |stopped| serde_json::to_string(&attempt(stopped)).unwrap();
+
+        // Seed the first layout for one task and the untagged hashes for
+        // another
+        let mut conn = redis::Client::open(url.as_str())
+            .unwrap()
+            .get_multiplexed_async_connection()
+            .await
+            .unwrap();
+        let prefix = &config.prefix;
+        for stopped in [interval.end, interval.end + Duration::from_secs(60)] {
+            redis::pipe()
+                .rpush(format!("{}:old_{}", prefix, interval.end), payload(stopped))
+                .lpush(format!("{}:attempts:old", prefix), payload(stopped))
+                .query_async::<()>(&mut conn)
+                .await
+                .unwrap();
+        }
+        conn.hset::<_, _, _, ()>(
+            format!("{}:task:untagged:attempts", prefix),
+            interval_field(&interval),
+            format!("[{}]", payload(interval.end)),
+        )
+        .await
+        .unwrap();
+        conn.zadd::<_, _, _, ()>(
+            format!("{}:task:untagged:intervals", prefix),
+            interval_field(&interval),
+            interval.end.timestamp_millis(),
+        )
+        .await
+        .unwrap();
+
+        let mut storage = RedisStorage::new(&config).await.unwrap();
+        assert_eq!(
+            storage.get_attempts("old", interval).await.unwrap().len(),
+            2
+        );
+        let recent = storage.get_recent_attempts("old", 10).await.unwrap();
+        assert_eq!(recent[0].stop_time, interval.end + Duration::from_secs(60));
+        assert_eq!(
+            storage
+                .get_attempts("untagged", interval)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        let left: Vec<String> = conn.keys(format!("{}:attempts:*", prefix)).await.unwrap();
+        assert!(left.is_empty());
+
+        storage.clear().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_outage_buffering() {
+        // Nothing listens on port 1
+        let mut storage = RedisStorage::disconnected(&RedisConfig {
+            url: Some("redis://127.0.0.1:1".to_owned()),
+            sentinel: None,
+            cluster_nodes: None,
+            prefix: "test".to_owned(),
+            attempt_ttl_seconds: None,
+        })
+        .unwrap();
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+
+        for _ in 0..3 {
+            storage
+                .store_attempt("task", interval, &TaskAttempt::new())
+                .await
+                .unwrap();
+            storage.store_state(&ResourceInterval::new()).await.unwrap();
+        }
+
+        // Only the latest state is worth sending
+        let health = storage.health();
+        assert!(!health.available);
+        assert!(health.down_since.is_some());
+        assert!(health.last_error.is_some());
+        assert_eq!(health.buffered, 4);
+
+        // Reads can't be answered until the writes are sent
+        assert!(matches!(storage.load_state().await, Err(Error::Storage(_))));
+        assert_eq!(storage.health().buffered, 4);
+
+        for _ in 0..MAX_BUFFERED_WRITES {
+            storage
+                .store_attempt("task", interval, &TaskAttempt::new())
+                .await
+                .unwrap();
+        }
+        let health = storage.health();
+        assert_eq!(health.buffered, MAX_BUFFERED_WRITES);
+        assert_eq!(health.dropped, 4);
+        assert!(storage
+            .pending
+            .iter()
+            .any(|w| matches!(w, PendingWrite::State(_))));
+    }
+}
@@ -0,0 +1,510 @@
+//! Keeps state, snapshots, and attempts as JSON objects in an S3-compatible
+//! bucket. Every attempt and annotation is its own object, so writes never
+//! need to read back what's already stored, and history grows in cheap
+//! object storage instead of memory.
+
+use super::*;
+use futures::TryStreamExt;
+use object_store::{aws::AmazonS3Builder, path::Path, ObjectStore, PutPayload};
+use std::sync::Arc;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct S3Config {
+    pub bucket: String,
+
+    /// Prepended to every key, so several worlds can share a bucket
+    #[serde(default)]
+    pub prefix: String,
+
+    /// For S3-compatible services other than AWS. Plain `http://`
+    /// endpoints are allowed.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+
+    #[serde(default)]
+    pub region: Option<String>,
+}
+
+/// The objects and directories the store keeps under its prefix. Nothing
+/// else there is touched, so prefixes can nest.
+const ENTRIES: [&str; 7] = [
+    "state.json",
+    "actions.json",
+    "annotations",
+    "artifacts",
+    "attempts",
+    "output",
+    "snapshots",
+];
+
+pub struct S3Storage {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+    /// Object names of attempts and annotations are the microseconds at
+    /// which they were stored, kept increasing so they sort in write order
+    last_write: i64,
+}
+
+/// Zero-padded so keys sort in time order
+fn millis_key(time: DateTime<Utc>) -> String {
+    format!("{:020}", time.timestamp_millis())
+}
+
+fn parse_millis_key(key: &str) -> Option<DateTime<Utc>> {
+    let millis: i64 = key.strip_suffix(".json").unwrap_or(key).parse().ok()?;
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+impl S3Storage {
+    /// Connects to the configured bucket. Credentials are taken from the
+    /// usual `AWS_*` environment variables.
+    pub fn new(config: &S3Config) -> Result<Self> {
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(&config.bucket);
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+        if let Some(region) = &config.region {
+            builder = builder.with_region(region);
+        }
+        Ok(S3Storage::with_store(
+            Arc::new(builder.build()?),
+            &config.prefix,
+        ))
+    }
+
+    /// Stores objects under `prefix` in an arbitrary object store
+    pub fn with_store(store: Arc<dyn ObjectStore>, prefix: &str) -> Self {
+        S3Storage {
+            store,
+            prefix: Path::from(prefix),
+            last_write: 0,
+        }
+    }
+
+    fn key<'a>(&self, parts: impl IntoIterator<Item = &'a str>) -> Path {
+        self.prefix
+            .parts()
+            .chain(parts.into_iter().map(|p| p.into()))
+            .collect()
+    }
+
+    fn next_write(&mut self) -> String {
+        self.last_write = Utc::now().timestamp_micros().max(self.last_write + 1);
+        format!("{:020}.json", self.last_write)
+    }
+
+    async fn put_json<T: Serialize>(&self, key: &Path, value: &T) -> Result<()> {
+        let payload = PutPayload::from(serde_json::to_vec(value)?);
+        self.store.put(key, payload).await?;
+        Ok(())
+    }
+
+    /// Reads an object, or `None` if it doesn't exist
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, key: &Path) -> Result<Option<T>> {
+        match self.store.get(key).await {
+            Ok(result) => Ok(Some(serde_json::from_slice(&result.bytes().await?)?)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Every key under `prefix`, in key order
+    async fn list(&self, prefix: &Path) -> Result<Vec<Path>> {
+        let mut keys: Vec<Path> = self
+            .store
+            .list(Some(prefix))
+            .map_ok(|meta| meta.location)
+            .try_collect()
+            .await?;
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn get_all<T: serde::de::DeserializeOwned>(&self, keys: &[Path]) -> Result<Vec<T>> {
+        let mut values = Vec::new();
+        for key in keys {
+            if let Some(value) = self.get_json(key).await? {
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Keys of a task's attempts, in the order they were stored
+    async fn attempt_keys(&self, task_name: &str) -> Result<Vec<Path>> {
+        let mut keys = self
+            .list(&self.key(["attempts", &encode_name(task_name)]))
+            .await?;
+        keys.sort_by_key(|key| key.filename().map(|f| f.to_owned()));
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn clear(&mut self) -> Result<()> {
+        for entry in ENTRIES {
+            let key = self.key([entry]);
+            let keys = if entry.ends_with(".json") {
+                vec![key]
+            } else {
+                self.list(&key).await?
+            };
+            for key in keys {
+                match self.store.delete(&key).await {
+                    Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn store_attempt(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        attempt: &TaskAttempt,
+    ) -> Result<()> {
+        // Rechecks and down commands are named for their kind, so recent
+        // up attempts can be picked out without reading them
+        let mut name = self.next_write();
+        if !attempt.kind.is_up() {
+            let kind = serde_json::to_value(attempt.kind)?;
+            name = name.replace(
+                ".json",
+                &format!(".{}.json", kind.as_str().unwrap_or("other")),
+            );
+        }
+        let key = self.key([
+            "attempts",
+            &encode_name(task_name),
+            &millis_key(interval.end),
+            &name,
+        ]);
+        self.put_json(&key, attempt).await
+    }
+
+    async fn store_state(&mut self, state: &ResourceInterval) -> Result<()> {
+        self.put_json(&self.key(["state.json"]), state).await
+    }
+
+    async fn load_state(&mut self) -> Result<ResourceInterval> {
+        Ok(self
+            .get_json(&self.key(["state.json"]))
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn store_actions(&mut self, actions: &[ActionRecord]) -> Result<()> {
+        self.put_json(&self.key(["actions.json"]), &actions).await
+    }
+
+    async fn load_actions(&mut self) -> Result<Vec<ActionRecord>> {
+        Ok(self
+            .get_json(&self.key(["actions.json"]))
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn get_recent_attempts(
+        &mut self,
+        task_name: &str,
+        max_attempts: usize,
+    ) -> Result<Vec<TaskAttempt>> {
+        let keys: Vec<Path> = self
+            .attempt_keys(task_name)
+            .await?
+            .into_iter()
+            .rev()
+            .filter(|key| key.filename().is_some_and(|f| f.matches('.').count() == 1))
+            .take(max_attempts)
+            .collect();
+        self.get_all(&keys).await
+    }
+
+    async fn get_attempts(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<TaskAttempt>> {
+        let keys = self
+            .list(&self.key([
+                "attempts",
+                &encode_name(task_name),
+                &millis_key(interval.end),
+            ]))
+            .await?;
+        self.get_all(&keys).await
+    }
+
+    async fn store_artifact(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        artifact: &Artifact,
+    ) -> Result<()> {
+        let key = self.key([
+            "artifacts",
+            &encode_name(task_name),
+            &millis_key(interval.end),
+            &encode_name(&artifact.name),
+        ]);
+        self.store
+            .put(&key, PutPayload::from(artifact.data.clone()))
+            .await?;
+        Ok(())
+    }
+
+    async fn get_artifact(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let key = self.key([
+            "artifacts",
+            &encode_name(task_name),
+            &millis_key(interval.end),
+            &encode_name(name),
+        ]);
+        match self.store.get(&key).await {
+            Ok(result) => Ok(Some(result.bytes().await?.to_vec())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Objects can't be appended to, so each batch of chunks is its own
+    async fn append_output(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        chunks: &[OutputChunk],
+        replace: bool,
+    ) -> Result<()> {
+        let dir = self.key(["output", &encode_name(task_name), &millis_key(interval.end)]);
+        if replace {
+            for key in self.list(&dir).await? {
+                self.store.delete(&key).await?;
+            }
+        }
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        let name = self.next_write();
+        self.put_json(&dir.child(name), &chunks).await
+    }
+
+    async fn get_output(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<OutputChunk>> {
+        let dir = self.key(["output", &encode_name(task_name), &millis_key(interval.end)]);
+        let keys = self.list(&dir).await?;
+        let batches: Vec<Vec<OutputChunk>> = self.get_all(&keys).await?;
+        Ok(batches.into_iter().flatten().collect())
+    }
+
+    async fn store_annotation(&mut self, annotation: &IntervalAnnotation) -> Result<()> {
+        let name = self.next_write();
+        let key = self.key(["annotations", &millis_key(annotation.interval.end), &name]);
+        self.put_json(&key, annotation).await
+    }
+
+    async fn get_annotations(&mut self, span: Interval) -> Result<Vec<IntervalAnnotation>> {
+        // Keys start with the interval end, so skip straight past anything
+        // ending before the span
+        let offset = self.key(["annotations", &millis_key(span.start)]);
+        let mut keys: Vec<Path> = self
+            .store
+            .list_with_offset(Some(&self.key(["annotations"])), &offset)
+            .map_ok(|meta| meta.location)
+            .try_collect()
+            .await?;
+        keys.sort();
+        let annotations: Vec<IntervalAnnotation> = self.get_all(&keys).await?;
+        Ok(annotations
+            .into_iter()
+            .filter(|a| span.is_contiguous(a.interval))
+            .collect())
+    }
+
+    async fn take_snapshot(&mut self, time: DateTime<Utc>, keep: usize) -> Result<()> {
+        let state = self.load_state().await?;
+        let key = self.key(["snapshots", &format!("{}.json", millis_key(time))]);
+        self.put_json(&key, &state).await?;
+
+        let keys = self.list(&self.key(["snapshots"])).await?;
+        for key in &keys[..keys.len().saturating_sub(keep)] {
+            self.store.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_snapshots(&mut self) -> Result<Vec<DateTime<Utc>>> {
+        Ok(self
+            .list(&self.key(["snapshots"]))
+            .await?
+            .iter()
+            .filter_map(|key| key.filename().and_then(parse_millis_key))
+            .collect())
+    }
+
+    async fn load_snapshot(&mut self, time: DateTime<Utc>) -> Result<ResourceInterval> {
+        let key = self.key(["snapshots", &format!("{}.json", millis_key(time))]);
+        self.get_json(&key)
+            .await?
+            .ok_or_else(|| Error::Storage(format!("No snapshot taken at {}", time)))
+    }
+}
+
+pub async fn start_s3_storage(
+    msgs: mpsc::Receiver<StorageMessage>,
+    config: S3Config,
+) -> Result<()> {
+    serve(S3Storage::new(&config)?, msgs).await
+}
+
+pub fn start(
+    msgs: mpsc::Receiver<StorageMessage>,
+    config: S3Config,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = start_s3_storage(msgs, config).await {
+            error!("Unable to start S3 storage: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn check_s3_storage() {
+        let store = Arc::new(InMemory::new());
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        let state = ResourceInterval::from(HashMap::from([(
+            "resource".to_owned(),
+            IntervalSet::from(interval),
+        )]));
+
+        let mut storage = S3Storage::with_store(store.clone(), "worlds/prod");
+        assert!(storage.load_state().await.unwrap().is_empty());
+        storage.store_state(&state).await.unwrap();
+        for exit_code in 0..3 {
+            let attempt = TaskAttempt {
+                exit_code,
+                ..TaskAttempt::new()
+            };
+            storage
+                .store_attempt("pricing/load", interval, &attempt)
+                .await
+                .unwrap();
+        }
+        // Rechecks are kept, but aren't recent runs of the task
+        let check = TaskAttempt {
+            kind: AttemptKind::Check,
+            exit_code: 9,
+            ..TaskAttempt::new()
+        };
+        storage
+            .store_attempt("pricing/load", interval, &check)
+            .await
+            .unwrap();
+
+        // Output is kept in batches, and replaced by a new attempt
+        let chunk = |data: &str| OutputChunk {
+            stream: OutputStream::Stdout,
+            data: data.to_owned(),
+        };
+        for (data, replace) in [("stale\n", false), ("one\n", true), ("two\n", false)] {
+            storage
+                .append_output("pricing/load", interval, &[chunk(data)], replace)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            storage.get_output("pricing/load", interval).await.unwrap(),
+            vec![chunk("one\n"), chunk("two\n")]
+        );
+
+        // Snapshots roll over, keeping the newest
+        for hour in 0..3 {
+            let time = Utc.with_ymd_and_hms(2022, 1, 2, hour, 0, 0).unwrap();
+            storage.take_snapshot(time, 2).await.unwrap();
+        }
+        let snapshots = storage.list_snapshots().await.unwrap();
+        assert_eq!(
+            snapshots,
+            vec![
+                Utc.with_ymd_and_hms(2022, 1, 2, 1, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, 2, 2, 0, 0).unwrap(),
+            ]
+        );
+        assert_eq!(storage.load_snapshot(snapshots[0]).await.unwrap(), state);
+
+        // Everything is read back from the bucket
+        let mut storage = S3Storage::with_store(store.clone(), "worlds/prod");
+        assert_eq!(storage.load_state().await.unwrap(), state);
+        let recent: Vec<i32> = storage
+            .get_recent_attempts("pricing/load", 2)
+            .await
+            .unwrap()
+            .iter()
+            .map(|a| a.exit_code)
+            .collect();
+        assert_eq!(recent, vec![2, 1]);
+        assert_eq!(
+            storage
+                .get_attempts("pricing/load", interval)
+                .await
+                .unwrap()
+                .len(),
+            4
+        );
+
+        let annotation = IntervalAnnotation {
+            task_name: "pricing/load".to_owned(),
+            interval,
+            annotation: Annotation {
+                time: Utc::now(),
+                author: None,
+                text: "Vendor file arrived late".to_owned(),
+            },
+        };
+        storage.store_annotation(&annotation).await.unwrap();
+        let later = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 4, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(storage.get_annotations(interval).await.unwrap().len(), 1);
+        assert!(storage.get_annotations(later).await.unwrap().is_empty());
+
+        // Other prefixes are left alone, even those nested under this one
+        let mut other = S3Storage::with_store(store.clone(), "worlds/dev");
+        assert!(other.load_state().await.unwrap().is_empty());
+        other.store_state(&state).await.unwrap();
+        let mut nested = S3Storage::with_store(store.clone(), "worlds/prod/staging");
+        nested.store_state(&state).await.unwrap();
+
+        storage.clear().await.unwrap();
+        assert!(storage.load_state().await.unwrap().is_empty());
+        assert!(storage.list_snapshots().await.unwrap().is_empty());
+        assert!(storage
+            .get_attempts("pricing/load", interval)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(other.load_state().await.unwrap(), state);
+        assert_eq!(nested.load_state().await.unwrap(), state);
+    }
+}
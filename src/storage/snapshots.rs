@@ -0,0 +1,56 @@
+//! Periodic point-in-time copies of the stored state
+
+use super::*;
+
+fn default_every_seconds() -> u64 {
+    3600
+}
+
+fn default_keep() -> usize {
+    24
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// How often to snapshot the state
+    #[serde(default = "default_every_seconds")]
+    pub every_seconds: u64,
+
+    /// How many snapshots to keep. Older ones are removed.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            every_seconds: default_every_seconds(),
+            keep: default_keep(),
+        }
+    }
+}
+
+/// Spawns a task asking the storage backend to snapshot its state on the
+/// configured schedule. Exits once the storage channel closes.
+pub fn schedule(
+    storage: mpsc::Sender<StorageMessage>,
+    config: SnapshotConfig,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker =
+            tokio::time::interval(std::time::Duration::from_secs(config.every_seconds.max(1)));
+        // The first tick completes immediately
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let msg = StorageMessage::TakeSnapshot {
+                time: Utc::now(),
+                keep: config.keep,
+            };
+            if storage.send(msg).await.is_err() {
+                break;
+            }
+        }
+    })
+}
@@ -0,0 +1,447 @@
+use super::*;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS state (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        payload TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS actions (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        payload TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS attempts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        task_name TEXT NOT NULL,
+        interval_end INTEGER NOT NULL,
+        payload TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS attempts_by_interval ON attempts (task_name, interval_end);
+    CREATE TABLE IF NOT EXISTS outputs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        task_name TEXT NOT NULL,
+        interval_end INTEGER NOT NULL,
+        payload TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS outputs_by_interval ON outputs (task_name, interval_end);
+    CREATE TABLE IF NOT EXISTS artifacts (
+        task_name TEXT NOT NULL,
+        interval_end INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (task_name, interval_end, name)
+    );
+    CREATE TABLE IF NOT EXISTS annotations (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        interval_end INTEGER NOT NULL,
+        payload TEXT NOT NULL
+    );
+";
+
+/// Persists state and attempts to a local SQLite file, for single-node
+/// runs without any external service. Queries block, so [`start`] serves
+/// the storage from a blocking thread rather than a runtime worker.
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStorage { conn })
+    }
+
+    fn payloads(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<String>>>()?)
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn clear(&mut self) -> Result<()> {
+        self.conn
+            .execute_batch("DELETE FROM state; DELETE FROM actions; DELETE FROM attempts; DELETE FROM outputs; DELETE FROM artifacts; DELETE FROM annotations;")?;
+        Ok(())
+    }
+
+    async fn store_attempt(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        attempt: &TaskAttempt,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO attempts (task_name, interval_end, payload) VALUES (?1, ?2, ?3)",
+            params![
+                task_name,
+                interval.end.timestamp_millis(),
+                serde_json::to_string(attempt)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn store_state(&mut self, state: &ResourceInterval) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO state (id, payload) VALUES (0, ?1)",
+            params![serde_json::to_string(state)?],
+        )?;
+        Ok(())
+    }
+
+    async fn load_state(&mut self) -> Result<ResourceInterval> {
+        let payload: Option<String> = self
+            .conn
+            .query_row("SELECT payload FROM state WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        match payload {
+            Some(payload) => Ok(serde_json::from_str(&payload)?),
+            None => Ok(ResourceInterval::new()),
+        }
+    }
+
+    async fn store_actions(&mut self, actions: &[ActionRecord]) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO actions (id, payload) VALUES (0, ?1)",
+            params![serde_json::to_string(actions)?],
+        )?;
+        Ok(())
+    }
+
+    async fn load_actions(&mut self) -> Result<Vec<ActionRecord>> {
+        let payload: Option<String> = self
+            .conn
+            .query_row("SELECT payload FROM actions WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        match payload {
+            Some(payload) => Ok(serde_json::from_str(&payload)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn get_recent_attempts(
+        &mut self,
+        task_name: &str,
+        max_attempts: usize,
+    ) -> Result<Vec<TaskAttempt>> {
+        let payloads = self.payloads(
+            "SELECT payload FROM attempts
+             WHERE task_name = ?1 AND json_extract(payload, '$.kind') IS NULL
+             ORDER BY id DESC LIMIT ?2",
+            params![task_name, max_attempts as i64],
+        )?;
+        Ok(payloads
+            .iter()
+            .filter_map(|x| serde_json::from_str(x).ok())
+            .collect())
+    }
+
+    async fn get_attempts(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<TaskAttempt>> {
+        let payloads = self.payloads(
+            "SELECT payload FROM attempts WHERE task_name = ?1 AND interval_end = ?2 ORDER BY id",
+            params![task_name, interval.end.timestamp_millis()],
+        )?;
+        Ok(payloads
+            .iter()
+            .filter_map(|x| serde_json::from_str(x).ok())
+            .collect())
+    }
+
+    async fn store_artifact(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        artifact: &Artifact,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO artifacts (task_name, interval_end, name, data)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                task_name,
+                interval.end.timestamp_millis(),
+                artifact.name,
+                artifact.data
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn get_artifact(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT data FROM artifacts WHERE task_name = ?1 AND interval_end = ?2 AND name = ?3",
+                params![task_name, interval.end.timestamp_millis(), name],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Each batch of chunks is a row, so appending doesn't rewrite what's
+    /// already kept
+    async fn append_output(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+        chunks: &[OutputChunk],
+        replace: bool,
+    ) -> Result<()> {
+        let end = interval.end.timestamp_millis();
+        let tx = self.conn.transaction()?;
+        if replace {
+            tx.execute(
+                "DELETE FROM outputs WHERE task_name = ?1 AND interval_end = ?2",
+                params![task_name, end],
+            )?;
+        }
+        if !chunks.is_empty() {
+            tx.execute(
+                "INSERT INTO outputs (task_name, interval_end, payload) VALUES (?1, ?2, ?3)",
+                params![task_name, end, serde_json::to_string(chunks)?],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn get_output(
+        &mut self,
+        task_name: &str,
+        interval: Interval,
+    ) -> Result<Vec<OutputChunk>> {
+        let payloads = self.payloads(
+            "SELECT payload FROM outputs WHERE task_name = ?1 AND interval_end = ?2 ORDER BY id",
+            params![task_name, interval.end.timestamp_millis()],
+        )?;
+        Ok(payloads
+            .iter()
+            .filter_map(|x| serde_json::from_str::<Vec<OutputChunk>>(x).ok())
+            .flatten()
+            .collect())
+    }
+
+    async fn store_annotation(&mut self, annotation: &IntervalAnnotation) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO annotations (interval_end, payload) VALUES (?1, ?2)",
+            params![
+                annotation.interval.end.timestamp_millis(),
+                serde_json::to_string(annotation)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn get_annotations(&mut self, span: Interval) -> Result<Vec<IntervalAnnotation>> {
+        let payloads = self.payloads(
+            "SELECT payload FROM annotations WHERE interval_end >= ?1 ORDER BY id",
+            params![span.start.timestamp_millis()],
+        )?;
+        let mut annotations = Vec::new();
+        for payload in payloads {
+            let annotation: IntervalAnnotation = serde_json::from_str(&payload)?;
+            if span.is_contiguous(annotation.interval) {
+                annotations.push(annotation);
+            }
+        }
+        Ok(annotations)
+    }
+}
+
+pub async fn start_sqlite_storage(
+    msgs: mpsc::Receiver<StorageMessage>,
+    path: String,
+) -> Result<()> {
+    serve(SqliteStorage::open(path)?, msgs).await
+}
+
+pub fn start(msgs: mpsc::Receiver<StorageMessage>, path: String) -> tokio::task::JoinHandle<()> {
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = handle.block_on(start_sqlite_storage(msgs, path)) {
+            error!("Unable to start sqlite storage: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_sqlite_storage() {
+        let path = std::env::temp_dir().join("waterfall_sqlite_test.db");
+        std::fs::remove_file(&path).unwrap_or(());
+
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+        );
+        let state = ResourceInterval::from(HashMap::from([(
+            "resource".to_owned(),
+            IntervalSet::from(interval),
+        )]));
+
+        let actions = vec![ActionRecord {
+            task_name: "task".to_owned(),
+            interval,
+            state: ActionState::Errored,
+            action_id: Some(ActionId(3)),
+            failures: 2,
+            retry_at: Some(interval.end),
+            fallback: false,
+        }];
+
+        let mut storage = SqliteStorage::open(&path).unwrap();
+        assert!(storage.load_state().await.unwrap().is_empty());
+        assert!(storage.load_actions().await.unwrap().is_empty());
+        storage.store_state(&state).await.unwrap();
+        storage.store_actions(&actions).await.unwrap();
+        for exit_code in 0..3 {
+            let attempt = TaskAttempt {
+                exit_code,
+                ..TaskAttempt::new()
+            };
+            storage
+                .store_attempt("task", interval, &attempt)
+                .await
+                .unwrap();
+        }
+        // Rechecks are kept, but aren't recent runs of the task
+        let check = TaskAttempt {
+            kind: AttemptKind::Check,
+            exit_code: 9,
+            ..TaskAttempt::new()
+        };
+        storage
+            .store_attempt("task", interval, &check)
+            .await
+            .unwrap();
+        drop(storage);
+
+        // Everything survives reopening the file
+        let mut storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.load_state().await.unwrap(), state);
+        assert_eq!(storage.load_actions().await.unwrap(), actions);
+        let recent: Vec<i32> = storage
+            .get_recent_attempts("task", 2)
+            .await
+            .unwrap()
+            .iter()
+            .map(|a| a.exit_code)
+            .collect();
+        assert_eq!(recent, vec![2, 1]);
+        assert_eq!(
+            storage.get_attempts("task", interval).await.unwrap().len(),
+            4
+        );
+
+        // Artifacts are replaced by later ones of the same name
+        for data in [b"old".to_vec(), b"new".to_vec()] {
+            let artifact = Artifact {
+                name: "out/report.csv".to_owned(),
+                size: data.len() as u64,
+                data,
+            };
+            storage
+                .store_artifact("task", interval, &artifact)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            storage
+                .get_artifact("task", interval, "out/report.csv")
+                .await
+                .unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(
+            storage
+                .get_artifact("task", interval, "nope")
+                .await
+                .unwrap(),
+            None
+        );
+
+        // Output is appended in batches, and replaced by a new attempt
+        let chunk = |data: &str| OutputChunk {
+            stream: OutputStream::Stdout,
+            data: data.to_owned(),
+        };
+        for (data, replace) in [("stale\n", false), ("one\n", true), ("two\n", false)] {
+            storage
+                .append_output("task", interval, &[chunk(data)], replace)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            storage.get_output("task", interval).await.unwrap(),
+            vec![chunk("one\n"), chunk("two\n")]
+        );
+
+        storage.clear().await.unwrap();
+        assert!(storage
+            .get_output("task", interval)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage.load_state().await.unwrap().is_empty());
+        assert!(storage.load_actions().await.unwrap().is_empty());
+        assert!(storage
+            .get_attempts("task", interval)
+            .await
+            .unwrap()
+            .is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_served_off_runtime() {
+        let path = std::env::temp_dir().join("waterfall_sqlite_served_test.db");
+        std::fs::remove_file(&path).unwrap_or(());
+        let state = ResourceInterval::from(HashMap::from([(
+            "resource".to_owned(),
+            IntervalSet::from(Interval::new(
+                Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, 2, 0, 0, 0).unwrap(),
+            )),
+        )]));
+
+        // Served from a blocking thread, even on a single threaded runtime
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = start(rx, path.to_string_lossy().into_owned());
+        tx.send(StorageMessage::StoreState {
+            state: state.clone(),
+        })
+        .await
+        .unwrap();
+        let (response, loaded) = oneshot::channel();
+        tx.send(StorageMessage::LoadState { response })
+            .await
+            .unwrap();
+        assert_eq!(loaded.await.unwrap(), state);
+
+        drop(tx);
+        handle.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,11 +1,44 @@
 use super::*;
 use std::ops::{Deref, DerefMut};
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
-pub struct TaskResources(HashMap<String, i64>);
+/// Quantities of named resources (cores, memory_mb, ...), which may be
+/// fractional
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct TaskResources(#[serde(serialize_with = "serialize_quantities")] HashMap<String, f64>);
+
+/// Tolerance for float error accumulated by repeated `sub` / `add`
+const QUANTITY_EPSILON: f64 = 1e-9;
+
+/// The longest a task can go between rechecks, a hundred years
+const MAX_RECHECK_EVERY_SECONDS: i64 = 36500 * 86400;
+
+/// The longest an interval can be late before it's alerted on, a hundred
+/// years
+const MAX_ALERT_DELAY_SECONDS: i64 = 36500 * 86400;
+
+/// Whole quantities are written as integers, so the output stays readable
+/// by consumers expecting integer resources
+fn serialize_quantities<S>(
+    quantities: &HashMap<String, f64>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    use serde::ser::SerializeMap;
+    let mut map = serializer.serialize_map(Some(quantities.len()))?;
+    for (k, v) in quantities {
+        if v.fract() == 0.0 && v.abs() < i64::MAX as f64 {
+            map.serialize_entry(k, &(*v as i64))?;
+        } else {
+            map.serialize_entry(k, v)?;
+        }
+    }
+    map.end()
+}
 
 impl Deref for TaskResources {
-    type Target = HashMap<String, i64>;
+    type Target = HashMap<String, f64>;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
@@ -17,6 +50,12 @@ impl DerefMut for TaskResources {
     }
 }
 
+impl From<HashMap<String, f64>> for TaskResources {
+    fn from(data: HashMap<String, f64>) -> Self {
+        TaskResources(data)
+    }
+}
+
 impl TaskResources {
     #[must_use]
     pub fn new() -> Self {
@@ -27,7 +66,7 @@ impl TaskResources {
     pub fn can_satisfy(&self, requirements: &TaskResources) -> bool {
         requirements
             .iter()
-            .all(|(k, v)| self.contains_key(k) && self[k] >= *v)
+            .all(|(k, v)| self.contains_key(k) && self[k] + QUANTITY_EPSILON >= *v)
     }
 
     /// Subtracts resources from available resources.
@@ -42,7 +81,9 @@ impl TaskResources {
             }
             Ok(())
         } else {
-            Err(anyhow!("Cannot satisfy requested resources"))
+            Err(Error::Executor(
+                "Cannot satisfy requested resources".to_owned(),
+            ))
         }
     }
 
@@ -59,6 +100,20 @@ impl TaskResources {
     }
 }
 
+/// A span of local time, open-ended if `to` isn't set
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ValidityWindow {
+    pub from: NaiveDateTime,
+
+    #[serde(default)]
+    pub to: Option<NaiveDateTime>,
+}
+
+fn default_recheck_intervals() -> usize {
+    1
+}
+
 /// Defines the struct to parse for tasks
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 #[serde(deny_unknown_fields)]
@@ -78,10 +133,40 @@ pub struct TaskDefinition {
     #[serde(default)]
     pub check: Option<TaskDetails>,
 
-    /// Number of seconds
+    /// Alert when an interval's resources are still down this many seconds
+    /// after its scheduled end
     #[serde(default)]
     pub alert_delay_seconds: Option<i64>,
 
+    /// Re-runs `check` on recently completed intervals this often. An
+    /// interval that fails it is marked down and produced again, catching
+    /// data deleted or corrupted after the fact.
+    #[serde(default)]
+    pub recheck_every_seconds: Option<i64>,
+
+    /// How many of the most recently completed intervals are rechecked
+    #[serde(default = "default_recheck_intervals")]
+    pub recheck_intervals: usize,
+
+    /// What to do once an interval keeps failing. Without it, failed
+    /// intervals are retried forever.
+    #[serde(default)]
+    pub escalation: Option<EscalationPolicy>,
+
+    /// How failed intervals are retried
+    #[serde(default)]
+    pub retry: RetryPolicy,
+
+    /// Runnable intervals of higher priority tasks are dispatched first
+    #[serde(default)]
+    pub priority: i32,
+
+    /// The group the task is listed and operated on with, e.g.
+    /// `pricing/eod`. Defaults to the path of a name like
+    /// `pricing/eod/load`.
+    #[serde(default)]
+    pub group: Option<String>,
+
     #[serde(default)]
     pub provides: HashSet<String>,
 
@@ -96,21 +181,127 @@ pub struct TaskDefinition {
 
     #[serde(default)]
     pub valid_to: Option<NaiveDateTime>,
+
+    /// Further windows the task is valid over, beyond `valid_from` to
+    /// `valid_to`
+    #[serde(default)]
+    pub valid_windows: Vec<ValidityWindow>,
+
+    /// Windows the task deliberately doesn't cover, e.g. before it existed
+    /// or during a migration. Excluded intervals aren't run, and don't
+    /// count towards coverage.
+    #[serde(default)]
+    pub exclusions: Vec<ValidityWindow>,
 }
 
 impl TaskDefinition {
+    /// Problems that would stop the task's schedule being built
+    pub fn problems(&self, name: &str) -> Vec<Error> {
+        let mut problems = Vec::new();
+        if self.times.is_empty() {
+            problems.push(Error::Validation(format!(
+                "Task {} has no scheduled times",
+                name
+            )));
+        }
+        if let Some(seconds) = self.recheck_every_seconds {
+            if !(1..=MAX_RECHECK_EVERY_SECONDS).contains(&seconds) {
+                problems.push(Error::Validation(format!(
+                    "Task {} must be rechecked every 1 to {} seconds, not {}",
+                    name, MAX_RECHECK_EVERY_SECONDS, seconds
+                )));
+            }
+        }
+        if let Some(seconds) = self.alert_delay_seconds {
+            if !(0..=MAX_ALERT_DELAY_SECONDS).contains(&seconds) {
+                problems.push(Error::Validation(format!(
+                    "Task {} alert_delay_seconds of {} must be between 0 and {}",
+                    name, seconds, MAX_ALERT_DELAY_SECONDS
+                )));
+            }
+        }
+        if let Err(Error::Validation(e)) = self.retry.validate() {
+            problems.push(Error::Validation(format!("Task {}: {}", name, e)));
+        }
+        problems
+    }
+
+    /// Local times skipped or repeated by daylight saving changes. The
+    /// schedule moves skipped times past the gap and takes the first of
+    /// repeated ones, which may not be what was meant.
+    pub fn warnings(&self, name: &str) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        // A year covers all of a timezone's changes
+        let today = Utc::now().date_naive();
+        for time in &self.times {
+            let clash = today.iter_days().take(366).find(|date| {
+                self.timezone
+                    .from_local_datetime(&date.and_time(*time))
+                    .single()
+                    .is_none()
+            });
+            if let Some(date) = clash {
+                warnings.push(format!(
+                    "Task {} is scheduled at {}, which is skipped or repeated on {} in {}",
+                    name, time, date, self.timezone
+                ));
+            }
+        }
+
+        let bounds = [
+            ("valid_from", Some(self.valid_from)),
+            ("valid_to", self.valid_to),
+        ]
+        .into_iter()
+        .chain(self.valid_windows.iter().flat_map(|w| {
+            [
+                ("valid_windows from", Some(w.from)),
+                ("valid_windows to", w.to),
+            ]
+        }))
+        .chain(
+            self.exclusions
+                .iter()
+                .flat_map(|w| [("exclusions from", Some(w.from)), ("exclusions to", w.to)]),
+        );
+        for (field, bound) in bounds {
+            let Some(bound) = bound else {
+                continue;
+            };
+            if self.timezone.from_local_datetime(&bound).single().is_none() {
+                warnings.push(format!(
+                    "Task {} has {} of {}, which is skipped or repeated in {}",
+                    name, field, bound, self.timezone
+                ));
+            }
+        }
+        warnings
+    }
+
     pub fn to_task(&self, name: &str, calendar: &Calendar) -> Task {
         let schedule = Schedule::new(calendar.clone(), self.times.clone(), self.timezone);
         /*
             The valid_{from,to} interval must be aligned to the actual schedule.
             They will be adjusted to include any interval who's
         */
-        let start = schedule
-            .interval(
-                self.timezone.from_local_datetime(&self.valid_from).unwrap(),
-                0,
-            )
-            .start;
+        let align = |from: &NaiveDateTime, to: &Option<NaiveDateTime>| {
+            let start = schedule.interval(schedule.localize(from), 0).start;
+            // Without an end, the task is valid forever
+            let end = match to {
+                Some(nt) => schedule.interval(schedule.localize(nt), 0).start,
+                None => MAX_TIME,
+            };
+            IntervalSet::from(Interval::new(start, end))
+        };
+
+        let mut valid_over = align(&self.valid_from, &self.valid_to);
+        for window in &self.valid_windows {
+            valid_over.merge(&align(&window.from, &window.to));
+        }
+        for window in &self.exclusions {
+            valid_over.subtract(&align(&window.from, &window.to));
+        }
 
         let provides = if self.provides.is_empty() {
             HashSet::from([name.to_owned()])
@@ -118,40 +309,57 @@ impl TaskDefinition {
             self.provides.clone()
         };
 
-        let end = match self.valid_to {
-            Some(nt) => self.timezone.from_local_datetime(&nt).unwrap(),
-            None => MAX_TIME.with_timezone(&self.timezone),
-        };
-
-        let actual_end = schedule.interval(end, 0).start;
+        let group = self
+            .group
+            .clone()
+            .or_else(|| name.rsplit_once('/').map(|(path, _)| path.to_owned()));
 
         Task {
             name: name.to_owned(),
+            group,
             up: self.up.clone(),
             down: self.down.clone(),
             check: self.check.clone(),
+            escalation: self.escalation.clone(),
+            retry: self.retry.clone(),
+            priority: self.priority,
+            alert_delay_seconds: self.alert_delay_seconds,
+            recheck_every_seconds: self.recheck_every_seconds,
+            recheck_intervals: self.recheck_intervals,
 
             provides,
             requires: self.requires.clone(),
 
             schedule,
-            valid_over: IntervalSet::from(Interval::new(start, actual_end)),
+            valid_over,
             timezone: self.timezone,
         }
     }
 }
 
-/*
-   No need for serialize / deserialize here, since we don't
-   need to transmit it anywhere. It is reconstituted by the
-   definition
-*/
-#[derive(Clone, Serialize, Debug)]
+/// A task resolved against its calendar. Round-trips through serde, so a
+/// resolved task can be cached or handed to another process as-is.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Task {
     pub name: String,
+    #[serde(default)]
+    pub group: Option<String>,
     pub up: TaskDetails,
     pub down: Option<TaskDetails>,
     pub check: Option<TaskDetails>,
+    #[serde(default)]
+    pub escalation: Option<EscalationPolicy>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub alert_delay_seconds: Option<i64>,
+    #[serde(default)]
+    pub recheck_every_seconds: Option<i64>,
+    #[serde(default = "default_recheck_intervals")]
+    pub recheck_intervals: usize,
 
     pub provides: HashSet<Resource>,
     pub requires: Vec<Requirement>,
@@ -164,6 +372,33 @@ pub struct Task {
 // Really need to rethink this valid_over and scheduling times. When generating
 
 impl Task {
+    /// Whether the task is in `group`, or a group nested under it
+    pub fn in_group(&self, group: &str) -> bool {
+        let group = group.trim_end_matches('/');
+        self.group.as_deref().is_some_and(|own| {
+            own == group
+                || own
+                    .strip_prefix(group)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+
+    /// Every command the task may run, with what it's for, for validation
+    /// against an executor
+    pub fn commands(&self) -> Vec<(&'static str, &TaskDetails)> {
+        let mut cmds = vec![("up", &self.up)];
+        if let Some(cmd) = &self.down {
+            cmds.push(("down", cmd));
+        }
+        if let Some(cmd) = &self.check {
+            cmds.push(("check", cmd));
+        }
+        for cmd in self.escalation.iter().flat_map(|e| e.commands()) {
+            cmds.push(("escalation", cmd));
+        }
+        cmds
+    }
+
     pub fn generate_intervals(&self, required: &ResourceInterval) -> Result<Vec<Interval>> {
         // Ensure that all intervals that are required are provided by this instance
         let reqs: Vec<IntervalSet> = self
@@ -184,8 +419,9 @@ impl Task {
             let ris = &reqs[0];
             // Ensure that all intervals are the same
             if !reqs[1..].iter().all(|is| is == ris) {
-                Err(anyhow!(
+                Err(Error::Schedule(
                     "Task produces multiple resources, but intervals are not consistent across needs"
+                        .to_owned(),
                 ))
             } else {
                 Ok(ris.iter().fold(Vec::new(), |mut acc, intv| {
@@ -201,6 +437,26 @@ impl Task {
         res
     }
 
+    /// The task's valid intervals that have ended by `time`, up to the
+    /// end of its last interval ending by then
+    pub fn timeline<T: TimeZone>(&self, time: DateTime<T>) -> IntervalSet {
+        if time <= MIN_TIME {
+            return IntervalSet::new();
+        }
+        // Need to align each of these intervals with a scheduled time
+        let timeline = if time < MAX_TIME {
+            let cur_intv = self.schedule.interval(time.clone(), 0);
+            if cur_intv.end > time {
+                IntervalSet::from(Interval::new(MIN_TIME, cur_intv.start))
+            } else {
+                IntervalSet::from(Interval::new(MIN_TIME, cur_intv.end))
+            }
+        } else {
+            IntervalSet::from(Interval::new(MIN_TIME, time.with_timezone(&Utc)))
+        };
+        self.valid_over.intersection(&timeline)
+    }
+
     pub fn validity(&self, max_time: DateTime<Utc>) -> IntervalSet {
         if self.valid_over.is_empty() {
             IntervalSet::new()
@@ -269,6 +525,40 @@ mod tests {
     use super::*;
     use chrono_tz::America::{Halifax, New_York};
 
+    #[test]
+    fn check_fractional_resources() {
+        let mut available: TaskResources =
+            serde_json::from_str(r#"{ "cores": 1, "gpu_mem": 1.0 }"#).unwrap();
+        let request: TaskResources =
+            serde_json::from_str(r#"{ "cores": 0.1, "gpu_mem": 0.5 }"#).unwrap();
+
+        for _ in 0..2 {
+            available.sub(&request).unwrap();
+        }
+        assert!(!available.can_satisfy(&request));
+        assert!(available.sub(&request).is_err());
+
+        // Accumulated float error doesn't prevent using the last of a resource
+        for _ in 0..8 {
+            available
+                .sub(&TaskResources::from(HashMap::from([(
+                    "cores".to_owned(),
+                    0.1,
+                )])))
+                .unwrap();
+        }
+
+        available.add(&request);
+        assert_eq!(
+            serde_json::to_value(&available).unwrap()["gpu_mem"],
+            serde_json::json!(0.5)
+        );
+
+        // Whole quantities serialize as integers for older consumers
+        let whole = TaskResources::from(HashMap::from([("cores".to_owned(), 4.0)]));
+        assert_eq!(serde_json::to_string(&whole).unwrap(), r#"{"cores":4}"#);
+    }
+
     macro_rules! intv {
         ( $x:literal, $y:literal ) => {
             Interval::new(
@@ -295,6 +585,46 @@ mod tests {
         };
     }
 
+    #[test]
+    fn check_problems() {
+        let task = |extra: &str| {
+            serde_json::from_str::<TaskDefinition>(&format!(
+                r#"{{
+                    "up": "/bin/true",
+                    "calendar_name": "std",
+                    "times": [ "09:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-03T00:00:00"
+                    {}
+                }}"#,
+                extra
+            ))
+            .unwrap()
+        };
+        assert!(task("").problems("a").is_empty());
+        assert!(task(r#", "recheck_every_seconds": 60"#)
+            .problems("a")
+            .is_empty());
+        assert_eq!(
+            task(r#", "recheck_every_seconds": 0"#).problems("a").len(),
+            1
+        );
+        assert_eq!(
+            task(r#", "recheck_every_seconds": 9223372036854775807"#)
+                .problems("a")
+                .len(),
+            1
+        );
+        assert_eq!(
+            task(r#", "alert_delay_seconds": -1"#).problems("a").len(),
+            1
+        );
+        assert_eq!(
+            task(r#", "retry": { "jitter": -0.5 }"#).problems("a").len(),
+            1
+        );
+    }
+
     #[test]
     fn check_task_can_parse() {
         // Spans a weekend
@@ -451,4 +781,142 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn check_validity_windows() {
+        let task_json = r#"
+        {
+            "up": "/usr/bin/true",
+            "calendar_name": "std",
+            "times": [ "17:00:00" ],
+            "timezone": "America/New_York",
+            "valid_from": "2022-01-04T09:00:00",
+            "valid_to": "2022-01-07T00:00:00",
+            "valid_windows": [
+                { "from": "2022-02-01T09:00:00", "to": "2022-02-03T00:00:00" }
+            ],
+            "exclusions": [
+                { "from": "2022-01-05T09:00:00", "to": "2022-01-06T00:00:00" }
+            ]
+        }
+        "#;
+        let task_def: TaskDefinition = serde_json::from_str(task_json).unwrap();
+        let task = task_def.to_task("task", &Calendar::new());
+
+        let at = |m, d| New_York.with_ymd_and_hms(2022, m, d, 17, 0, 0).unwrap();
+        assert_eq!(
+            task.valid_over,
+            IntervalSet::from(vec![
+                Interval::new(at(1, 3), at(1, 4)),
+                Interval::new(at(1, 5), at(1, 6)),
+                Interval::new(at(1, 31), at(2, 2)),
+            ])
+        );
+
+        // Excluded intervals don't count towards coverage
+        let tasks = TaskSet::from(vec![task]);
+        assert_eq!(tasks.coverage()["task"], tasks[0].valid_over);
+    }
+
+    #[test]
+    fn check_task_round_trip() {
+        let task_json = r#"
+        {
+            "up": "/usr/bin/touch /tmp/a_${yyyymmdd}_${hhmmss}",
+            "provides": [ "resource_a" ],
+            "requires": [
+                { "resource": "alpha", "offset": 0 },
+                { "all": [
+                    { "resource": "beta", "offset": -1 },
+                    { "path": "/tmp/ready_${yyyymmdd}" }
+                ] }
+            ],
+            "calendar_name": "std",
+            "times": [ "15:00:00", "09:00:00" ],
+            "timezone": "America/Halifax",
+            "valid_from": "2022-01-05T12:30:00",
+            "valid_to": "2022-01-11T00:00:00"
+        }
+        "#;
+
+        let task_def: TaskDefinition = serde_json::from_str(task_json).unwrap();
+        let cal = Calendar {
+            exclude: HashSet::from([NaiveDate::from_ymd_opt(2022, 1, 6).unwrap()]),
+            ..Calendar::new()
+        };
+        let task = task_def.to_task("test", &cal);
+
+        let json = serde_json::to_string(&task).unwrap();
+        let parsed: Task = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, task);
+
+        let interval = Interval::new(
+            Halifax.with_ymd_and_hms(2022, 1, 5, 0, 0, 0).unwrap(),
+            Halifax.with_ymd_and_hms(2022, 1, 10, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            parsed.schedule.generate(interval),
+            task.schedule.generate(interval)
+        );
+
+        let tasks = TaskSet::from(vec![task]);
+        let json = serde_json::to_string(&tasks).unwrap();
+        assert_eq!(serde_json::from_str::<TaskSet>(&json).unwrap(), tasks);
+    }
+
+    #[test]
+    fn check_groups() {
+        let task_def: TaskDefinition = serde_json::from_value(serde_json::json!({
+            "up": "/bin/true",
+            "calendar_name": "std",
+            "times": [ "17:00:00" ],
+            "timezone": "America/New_York",
+            "valid_from": "2022-01-03T00:00:00",
+            "valid_to": "2022-02-01T00:00:00"
+        }))
+        .unwrap();
+        let cal = Calendar::new();
+
+        // Hierarchical names are grouped by their path
+        let task = task_def.to_task("pricing/eod/load", &cal);
+        assert_eq!(task.group.as_deref(), Some("pricing/eod"));
+        assert!(task.in_group("pricing"));
+        assert!(task.in_group("pricing/eod/"));
+        assert!(!task.in_group("pricing/eo"));
+        assert!(!task.in_group("risk"));
+        assert!(task_def.to_task("load", &cal).group.is_none());
+
+        // Unless given a group of their own
+        let task_def = TaskDefinition {
+            group: Some("risk".to_owned()),
+            ..task_def
+        };
+        let task = task_def.to_task("pricing/eod/load", &cal);
+        assert!(task.in_group("risk"));
+        assert!(!task.in_group("pricing"));
+    }
+
+    #[test]
+    fn check_open_ended_validity() {
+        // No valid_to, starting in a DST gap
+        let task_def: TaskDefinition = serde_json::from_value(serde_json::json!({
+            "up": "/bin/true",
+            "calendar_name": "std",
+            "times": [ "17:00:00" ],
+            "timezone": "America/New_York",
+            "valid_from": "2022-03-13T02:30:00"
+        }))
+        .unwrap();
+        let task = task_def.to_task("open", &Calendar::new());
+
+        assert_eq!(task.valid_over.end(), Some(MAX_TIME));
+        let timeline = task.timeline(New_York.with_ymd_and_hms(2022, 3, 16, 12, 0, 0).unwrap());
+        assert_eq!(
+            timeline,
+            IntervalSet::from(vec![Interval::new(
+                New_York.with_ymd_and_hms(2022, 3, 11, 17, 0, 0).unwrap(),
+                New_York.with_ymd_and_hms(2022, 3, 15, 17, 0, 0).unwrap()
+            )])
+        );
+    }
 }
@@ -2,7 +2,7 @@ use super::*;
 use std::convert::From;
 use std::ops::{Deref, DerefMut};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct TaskSet(Vec<Task>);
 
 impl TaskSet {
@@ -15,22 +15,38 @@ impl TaskSet {
     }
 
     pub fn validate(&self) -> Result<()> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(()),
+        }
+    }
+
+    /// Every problem with the task set, rather than just the first
+    pub fn problems(&self) -> Vec<Error> {
         let state = self.coverage();
+        let mut problems = Vec::new();
 
         // Ensures that all requirements are met
         for task in &self.0 {
+            for req in &task.requires {
+                if let Err(Error::Validation(msg)) = req.validate() {
+                    problems.push(Error::Validation(format!(
+                        "Task {} has a bad requirement: {}",
+                        task.name, msg
+                    )));
+                }
+            }
             for resource in task.requires_resources() {
                 if !state.contains_key(&resource) {
-                    return Err(anyhow!(
+                    problems.push(Error::Validation(format!(
                         "Task {} requires resource {}, which isn't produced.",
-                        task.name,
-                        resource
-                    ));
+                        task.name, resource
+                    )));
                 }
             }
         }
 
-        // TODO Ensure that all resources will be produced over the valid_over interval
+        problems.extend(self.uncovered_requirements(&state));
 
         // validate that no task generates the same resource on overlapping times
         let providers: HashMap<Resource, Vec<usize>> =
@@ -43,21 +59,111 @@ impl TaskSet {
                     }
                     acc
                 });
+        let mut providers: Vec<(Resource, Vec<usize>)> = providers.into_iter().collect();
+        providers.sort();
         for (res, tids) in providers {
             let mut is = IntervalSet::new();
-            for tid in tids {
-                let already_provided = is.intersection(&self.0[tid].valid_over);
+            for tid in &tids {
+                let already_provided = is.intersection(&self.0[*tid].valid_over);
                 if !already_provided.is_empty() {
-                    return Err(anyhow!(
+                    problems.push(Error::Validation(format!(
                         "Task set invalid: multiple tasks provide resource {} on the intervals {:?}",
                         res,
                         already_provided
-                    ));
+                    )));
+                }
+                is.merge(&self.0[*tid].valid_over);
+            }
+            if let Err(problem) = self.validate_handovers(&res, &tids) {
+                problems.push(problem);
+            }
+        }
+
+        problems
+    }
+
+    /// Requirements that can never be met at the edges of a task's
+    /// validity, e.g. requiring the previous interval of a resource whose
+    /// provider starts on the same day as the task. Such intervals would
+    /// wait forever. Required intervals that are partly produced, as when
+    /// tasks sharing a `valid_from` run at different times of day, are left
+    /// alone.
+    fn uncovered_requirements(&self, coverage: &ResourceInterval) -> Vec<Error> {
+        let mut problems = Vec::new();
+        for task in &self.0 {
+            let mut edges = Vec::new();
+            for window in task.valid_over.iter() {
+                edges.push(task.schedule.interval(window.start, 1));
+                if window.end < MAX_TIME {
+                    edges.push(task.schedule.interval(window.end, 0));
+                }
+            }
+            for req in &task.requires {
+                for interval in &edges {
+                    let (touched, missing): (Vec<_>, Vec<_>) = req
+                        .required_intervals(*interval, &task.schedule)
+                        .into_iter()
+                        .partition(|(res, intv)| {
+                            // Resources nothing produces are reported above
+                            coverage
+                                .get(res)
+                                .is_none_or(|is| !is.is_disjoint(&IntervalSet::from(*intv)))
+                        });
+                    if missing.is_empty() {
+                        continue;
+                    }
+                    let mut available = coverage.clone();
+                    for (res, intv) in touched {
+                        available.entry(res).or_default().insert(intv);
+                    }
+                    if req.can_be_satisfied(*interval, &task.schedule, &available) {
+                        continue;
+                    }
+                    let missing: Vec<String> = missing
+                        .iter()
+                        .map(|(res, intv)| format!("{} over {}", res, intv))
+                        .collect();
+                    problems.push(Error::Validation(format!(
+                        "Task {} can never run its interval {}, as nothing produces {}",
+                        task.name,
+                        interval,
+                        missing.join(", ")
+                    )));
+                    break;
                 }
-                is.merge(&self.0[tid].valid_over);
             }
         }
+        problems
+    }
 
+    /// When one task takes over a resource from another, the cutover has to
+    /// fall on a boundary of both schedules. Otherwise neither task produces
+    /// the sliver between their last and first intervals, and anything
+    /// requiring the resource across the cutover waits forever.
+    fn validate_handovers(&self, resource: &Resource, tids: &[usize]) -> Result<()> {
+        for from in tids {
+            for to in tids {
+                let (from, to) = (&self.0[*from], &self.0[*to]);
+                for end in from.valid_over.iter().map(|x| x.end) {
+                    for start in to.valid_over.iter().map(|x| x.start) {
+                        if end >= start || end == MAX_TIME {
+                            continue;
+                        }
+                        let misaligned = to.schedule.interval(start, 0).start < end
+                            || start < from.schedule.interval(end, 1).end;
+                        if misaligned {
+                            return Err(Error::Validation(format!(
+                                "Handover of resource {} from {} to {} leaves a gap over {}. The cutover must fall on a scheduled time of both tasks.",
+                                resource,
+                                from.name,
+                                to.name,
+                                Interval::new(end, start)
+                            )));
+                        }
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
@@ -66,21 +172,10 @@ impl TaskSet {
 
         // Insert all of the covered items
         for task in &self.0 {
-            // Need to align each of these intervals with a scheduled time
-            let timeline = if time < MAX_TIME {
-                let cur_intv = task.schedule.interval(time.clone(), 0);
-                if cur_intv.end > time {
-                    IntervalSet::from(Interval::new(MIN_TIME, cur_intv.start))
-                } else {
-                    IntervalSet::from(Interval::new(MIN_TIME, cur_intv.end))
-                }
-            } else {
-                IntervalSet::from(Interval::new(MIN_TIME, time.with_timezone(&Utc)))
-            };
-            let task_timeline = task.valid_over.intersection(&timeline);
+            let task_timeline = task.timeline(time.clone());
             for resource in &task.provides {
                 res.entry(resource.clone())
-                    .or_insert(IntervalSet::new())
+                    .or_default()
                     .merge(&task_timeline);
             }
         }
@@ -107,3 +202,104 @@ impl From<Vec<Task>> for TaskSet {
         Self(data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world(v2_times: &str, cutover: &str, report_from: &str) -> Result<TaskSet> {
+        let json = format!(
+            r#"{{
+            "calendars": {{ "std": {{}} }},
+            "tasks": {{
+                "load_v1": {{
+                    "up": "/bin/true",
+                    "provides": [ "prices" ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "2022-01-01T00:00:00",
+                    "valid_to": "{cutover}"
+                }},
+                "load_v2": {{
+                    "up": "/bin/true",
+                    "provides": [ "prices" ],
+                    "calendar_name": "std",
+                    "times": [ {v2_times} ],
+                    "timezone": "UTC",
+                    "valid_from": "{cutover}",
+                    "valid_to": "2022-01-20T00:00:00"
+                }},
+                "report": {{
+                    "up": "/bin/true",
+                    "requires": [ {{ "resource": "prices", "offset": -1 }} ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "UTC",
+                    "valid_from": "{report_from}",
+                    "valid_to": "2022-01-20T00:00:00"
+                }}
+            }}
+        }}"#
+        );
+        let world_def: WorldDefinition = serde_json::from_str(&json).unwrap();
+        world_def.taskset()
+    }
+
+    #[test]
+    fn check_handover() {
+        let tasks = world(
+            r#""17:00:00""#,
+            "2022-01-10T00:00:00",
+            "2022-01-04T00:00:00",
+        )
+        .unwrap();
+        let coverage = tasks.coverage();
+
+        // The resource is covered continuously across the cutover, so
+        // requirements spanning it resolve
+        let report = tasks.iter().find(|t| t.name == "report").unwrap();
+        let cutover = Utc.with_ymd_and_hms(2022, 1, 7, 17, 0, 0).unwrap();
+        for offset in 0..3 {
+            let interval = report.schedule.interval(cutover, offset);
+            assert!(report.can_run(interval, &coverage));
+        }
+
+        // Shifting load_v2 to 18:00 leaves an hour nothing produces
+        assert!(matches!(
+            world(
+                r#""18:00:00""#,
+                "2022-01-10T00:00:00",
+                "2022-01-04T00:00:00"
+            ),
+            Err(Error::Validation(_))
+        ));
+
+        // Unless the cutover falls on a time both run at
+        assert!(world(
+            r#""17:00:00", "18:00:00""#,
+            "2022-01-10T17:30:00",
+            "2022-01-04T00:00:00"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn check_uncovered_requirements() {
+        // The report's first interval needs the day before, which is before
+        // prices were first loaded
+        let problems = world(
+            r#""17:00:00""#,
+            "2022-01-10T00:00:00",
+            "2022-01-02T00:00:00",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            problems.contains("Task report can never run its interval"),
+            "{}",
+            problems
+        );
+        assert!(problems.contains("prices over (2021-12-30 17:00:00 UTC, 2021-12-31 17:00:00 UTC]"));
+    }
+}
@@ -0,0 +1,196 @@
+//! Sets up logging for the binaries, and, with the `otel` feature, exports
+//! spans as OpenTelemetry traces over OTLP, so each action's
+//! lifecycle, from dispatch through execution on an agent to its attempt
+//! being stored, is one distributed trace. Trace context crosses the hop to
+//! `wfw` as W3C `traceparent` headers on the `/run` request.
+//!
+//! Without the `otel` feature, spans stay local and the header helpers do
+//! nothing.
+
+use super::*;
+
+#[cfg(feature = "otel")]
+use opentelemetry::trace::TracerProvider;
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+#[cfg(feature = "otel")]
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+
+/// Logging options shared by the binaries
+#[cfg(feature = "server")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct LogArgs {
+    /// Enable verbose logging
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// Log as JSON lines, with each span's fields, for log aggregators
+    #[clap(long)]
+    pub log_json: bool,
+
+    /// Export traces to this OTLP/HTTP endpoint, e.g.
+    /// http://localhost:4318/v1/traces
+    #[cfg(feature = "otel")]
+    #[clap(long)]
+    pub otlp_endpoint: Option<String>,
+}
+
+/// Flushes exported spans when dropped
+#[cfg(feature = "server")]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<SdkTracerProvider>,
+}
+
+#[cfg(feature = "server")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Unable to flush traces: {}", e);
+            }
+        }
+    }
+}
+
+/// Sets up logging, and trace export if asked for. `RUST_LOG` overrides
+/// the default filter.
+#[cfg(feature = "server")]
+pub fn init(args: &LogArgs, service_name: &str) -> Result<Telemetry> {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        tracing_subscriber::EnvFilter::new(if args.verbose { "debug" } else { "info" })
+    });
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            args.log_json
+                .then(|| tracing_subscriber::fmt::layer().json()),
+        )
+        .with((!args.log_json).then(tracing_subscriber::fmt::layer));
+
+    #[cfg(feature = "otel")]
+    {
+        let provider = args
+            .otlp_endpoint
+            .as_deref()
+            .map(|endpoint| otlp_provider(endpoint, service_name))
+            .transpose()?;
+        registry.with(provider.as_ref().map(layer)).init();
+        Ok(Telemetry { provider })
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = service_name;
+        registry.init();
+        Ok(Telemetry {})
+    }
+}
+
+/// Batches spans up for export to an OTLP/HTTP collector
+#[cfg(feature = "otel")]
+pub fn otlp_provider(endpoint: &str, service_name: &str) -> Result<SdkTracerProvider> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| Error::Validation(format!("Bad OTLP endpoint {}: {}", endpoint, e)))?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name(service_name.to_owned())
+                .build(),
+        )
+        .build())
+}
+
+/// A tracing layer exporting spans through `provider`. Also installs the
+/// W3C trace context propagator.
+#[cfg(feature = "otel")]
+pub fn layer<S>(provider: &SdkTracerProvider) -> OpenTelemetryLayer<S, SdkTracer>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    opentelemetry::global::set_text_map_propagator(
+        opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+    );
+    tracing_opentelemetry::layer().with_tracer(provider.tracer("waterfall"))
+}
+
+/// Headers carrying the trace context of `span`, to continue its trace on
+/// the other side of a request
+pub fn trace_headers(span: &tracing::Span) -> HashMap<String, String> {
+    #[cfg(feature = "otel")]
+    {
+        let mut headers = HashMap::new();
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&span.context(), &mut headers)
+        });
+        headers
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = span;
+        HashMap::new()
+    }
+}
+
+/// Makes `span` part of the trace carried by `headers`, if there is one
+pub fn set_remote_parent(span: &tracing::Span, headers: &HashMap<String, String>) {
+    #[cfg(feature = "otel")]
+    {
+        let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(headers)
+        });
+        // Only fails if the span has already started its own trace
+        span.set_parent(context).unwrap_or(());
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, headers);
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn check_propagation() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer(&provider));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let action = info_span!("action", task_name = "task_a");
+            let headers = action.in_scope(|| trace_headers(&tracing::Span::current()));
+            assert!(headers.contains_key("traceparent"));
+
+            // As an agent would, on receiving the request
+            let remote = info_span!("agent_run");
+            set_remote_parent(&remote, &headers);
+            remote.in_scope(|| info!("Running"));
+        });
+        provider.force_flush().unwrap();
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let action = spans.iter().find(|s| s.name == "action").unwrap();
+        let remote = spans.iter().find(|s| s.name == "agent_run").unwrap();
+        assert_eq!(
+            remote.span_context.trace_id(),
+            action.span_context.trace_id()
+        );
+        assert_eq!(remote.parent_span_id, action.span_context.span_id());
+
+        // Nothing to continue without a trace
+        assert!(trace_headers(&tracing::Span::none()).is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use super::*;
+use crate::runner::ActionState;
+
+/// A single task interval in the chain of work feeding a resource interval
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamNode {
+    pub task_name: String,
+    pub interval: Interval,
+
+    /// State of the runner's action for the interval, if there is one
+    pub state: Option<ActionState>,
+
+    /// Indices of the nodes this node requires
+    pub requires: Vec<usize>,
+}
+
+fn provider(tasks: &TaskSet, resource: &str, interval: Interval) -> Option<usize> {
+    tasks
+        .iter()
+        .position(|t| t.provides.contains(resource) && t.valid_over.contains(interval.end))
+}
+
+/// Walks the requirements of the task interval providing `resource` at the
+/// end of `interval`, collecting every upstream task interval up to
+/// `max_depth` requirements away. The first node is always the target.
+/// Returns None if no task provides the resource at that time.
+pub fn trace(
+    tasks: &TaskSet,
+    states: &HashMap<(usize, Interval), ActionState>,
+    resource: &str,
+    interval: Interval,
+    max_depth: usize,
+) -> Option<Vec<UpstreamNode>> {
+    let tid = provider(tasks, resource, interval)?;
+    let target = tasks[tid].schedule.interval(interval.end, 0);
+
+    let mut nodes = vec![UpstreamNode {
+        task_name: tasks[tid].name.clone(),
+        interval: target,
+        state: states.get(&(tid, target)).copied(),
+        requires: Vec::new(),
+    }];
+    let mut node_tasks = vec![tid];
+    let mut index = HashMap::from([((tid, target), 0usize)]);
+
+    let mut frontier = vec![0];
+    for _ in 0..max_depth {
+        let mut next = Vec::new();
+        for nid in frontier {
+            let task = &tasks[node_tasks[nid]];
+            let mut requires = Vec::new();
+            for req in &task.requires {
+                for (res, req_intv) in req.required_intervals(nodes[nid].interval, &task.schedule) {
+                    let ptid = match provider(tasks, &res, req_intv) {
+                        Some(ptid) => ptid,
+                        None => continue,
+                    };
+                    for pintv in tasks[ptid].schedule.generate(req_intv) {
+                        let id = match index.get(&(ptid, pintv)) {
+                            Some(id) => *id,
+                            None => {
+                                let id = nodes.len();
+                                nodes.push(UpstreamNode {
+                                    task_name: tasks[ptid].name.clone(),
+                                    interval: pintv,
+                                    state: states.get(&(ptid, pintv)).copied(),
+                                    requires: Vec::new(),
+                                });
+                                node_tasks.push(ptid);
+                                index.insert((ptid, pintv), id);
+                                next.push(id);
+                                id
+                            }
+                        };
+                        if !requires.contains(&id) {
+                            requires.push(id);
+                        }
+                    }
+                }
+            }
+            nodes[nid].requires = requires;
+        }
+        if next.is_empty() {
+            break;
+        }
+        frontier = next;
+    }
+
+    Some(nodes)
+}
+
+/// Given the time each node finished, follows the latest-finishing
+/// requirement from the target (node 0) upstream. That chain is the one
+/// that determined when the target could start.
+pub fn critical_path(nodes: &[UpstreamNode], finished: &[Option<DateTime<Utc>>]) -> Vec<usize> {
+    let mut path = Vec::new();
+    if nodes.is_empty() {
+        return path;
+    }
+
+    let mut cur = 0;
+    path.push(cur);
+    while let Some(next) = nodes[cur]
+        .requires
+        .iter()
+        .filter(|id| finished[**id].is_some())
+        .max_by_key(|id| finished[**id])
+    {
+        if path.contains(next) {
+            break;
+        }
+        path.push(*next);
+        cur = *next;
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world() -> TaskSet {
+        let json = r#"{
+            "calendars": {
+                "std": { "mask": [ "Mon", "Tue", "Wed", "Thu", "Fri" ] }
+            },
+            "tasks": {
+                "task_a": {
+                    "up": { "command": "/bin/true" },
+                    "calendar_name": "std",
+                    "times": [ "09:00:00", "12:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-02-01T00:00:00"
+                },
+                "task_b": {
+                    "up": { "command": "/bin/true" },
+                    "requires": [ { "resource": "task_a", "offset": 0 } ],
+                    "calendar_name": "std",
+                    "times": [ "17:00:00" ],
+                    "timezone": "America/New_York",
+                    "valid_from": "2022-01-01T09:00:00",
+                    "valid_to": "2022-02-01T00:00:00"
+                }
+            }
+        }"#;
+        let world_def: WorldDefinition = serde_json::from_str(json).unwrap();
+        world_def.taskset().unwrap()
+    }
+
+    #[test]
+    fn check_trace() {
+        let tasks = world();
+        let tz = chrono_tz::America::New_York;
+        let end = tz.with_ymd_and_hms(2022, 1, 5, 17, 0, 0).unwrap();
+        let nodes = trace(
+            &tasks,
+            &HashMap::new(),
+            "task_b",
+            Interval::new(end, end),
+            5,
+        )
+        .unwrap();
+
+        // task_b's day requires both of task_a's intervals for the day
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[0].task_name, "task_b");
+        assert_eq!(nodes[0].requires, vec![1, 2]);
+        assert!(nodes[1..].iter().all(|n| n.task_name == "task_a"));
+
+        assert!(trace(
+            &tasks,
+            &HashMap::new(),
+            "missing",
+            Interval::new(end, end),
+            5
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn check_critical_path() {
+        let tasks = world();
+        let tz = chrono_tz::America::New_York;
+        let end = tz.with_ymd_and_hms(2022, 1, 5, 17, 0, 0).unwrap();
+        let nodes = trace(
+            &tasks,
+            &HashMap::new(),
+            "task_b",
+            Interval::new(end, end),
+            5,
+        )
+        .unwrap();
+
+        let at = |h| Some(Utc.with_ymd_and_hms(2022, 1, 5, h, 0, 0).unwrap());
+        assert_eq!(critical_path(&nodes, &[at(22), at(20), at(15)]), vec![0, 1]);
+        assert_eq!(critical_path(&nodes, &[at(22), None, at(15)]), vec![0, 2]);
+        assert_eq!(critical_path(&nodes, &[None, None, None]), vec![0]);
+    }
+}
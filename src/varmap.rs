@@ -1,63 +1,344 @@
 use super::*;
+use chrono::format::{Item, StrftimeItems};
 use std::ops::{Deref, DerefMut};
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
-pub struct VarMap(HashMap<String, String>);
+#[serde(transparent)]
+pub struct VarMap {
+    vars: HashMap<String, String>,
+
+    /// The schedule behind the interval variables, for business day and
+    /// period offsets
+    #[serde(skip)]
+    schedule: Option<Box<Schedule>>,
+}
 
 impl Deref for VarMap {
     type Target = HashMap<String, String>;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.vars
     }
 }
 
 impl DerefMut for VarMap {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.vars
     }
 }
 
+/// Parses an offset like `-1d` or `+2bd` into its count and unit
+fn parse_offset(offset: &str) -> Option<(i64, &str)> {
+    let sign = match offset.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let body = &offset[1..];
+    let digits = body.find(|c: char| !c.is_ascii_digit())?;
+    let count: i64 = body[..digits].parse().ok()?;
+    Some((sign * count, &body[digits..]))
+}
+
 impl VarMap {
     pub fn new() -> Self {
-        VarMap(HashMap::new())
+        VarMap::default()
     }
 
-    // Derive variables from a given interval
+    /// Derive variables from a given interval. `start` and `end` are the
+    /// interval's bounds in the task's timezone, as RFC 3339, and can be
+    /// formatted like `${end:%Y-%m-%d}`.
     pub fn from_interval(int: &Interval, tz: Tz) -> Self {
         let start = int.start.with_timezone(&tz);
         let end = int.end.with_timezone(&tz);
 
-        VarMap(HashMap::from([
-            ("PERIOD_START".to_owned(), format!("{}", start)),
-            ("PERIOD_END".to_owned(), format!("{}", end)),
-            ("yyyy".to_owned(), format!("{}", end.year())),
-            ("mm".to_owned(), format!("{}", end.month())),
-            ("dd".to_owned(), format!("{}", end.day())),
-            (
-                "yyyymmdd".to_owned(),
-                format!("{}{}{}", end.year(), end.month(), end.day()),
+        VarMap {
+            vars: HashMap::from([
+                ("PERIOD_START".to_owned(), format!("{}", start)),
+                ("PERIOD_END".to_owned(), format!("{}", end)),
+                ("start".to_owned(), start.to_rfc3339()),
+                ("end".to_owned(), end.to_rfc3339()),
+                ("yyyy".to_owned(), end.format("%Y").to_string()),
+                ("mm".to_owned(), end.format("%m").to_string()),
+                ("dd".to_owned(), end.format("%d").to_string()),
+                ("yyyymmdd".to_owned(), end.format("%Y%m%d").to_string()),
+                ("hhmmss".to_owned(), end.format("%H%M%S").to_string()),
+                ("yyyy_ww".to_owned(), end.format("%G_%V").to_string()),
+            ]),
+            schedule: None,
+        }
+    }
+
+    /// Derive variables from an interval of `schedule`: those of
+    /// [`VarMap::from_interval`], plus ones that depend on the schedule's
+    /// calendar. Offsets like `${end-1bd}` and `${end-1p}` count business
+    /// days and scheduled periods.
+    pub fn from_schedule(int: &Interval, tz: Tz, schedule: &Schedule) -> Self {
+        let mut varmap = VarMap::from_interval(int, tz);
+        let date = int.end.with_timezone(&schedule.timezone()).date_naive();
+        let calendar = schedule.calendar();
+        // Calendars that run out of business days leave these unset
+        if let Ok(prev) = calendar.business_day_offset(date, -1) {
+            varmap.insert("prev_business_day".to_owned(), prev.to_string());
+        }
+        if let Ok(next) = calendar.business_day_offset(date, 1) {
+            varmap.insert("next_business_day".to_owned(), next.to_string());
+        }
+        if let Some(index) = schedule.period_index(int.end) {
+            varmap.insert("period_index".to_owned(), index.to_string());
+        }
+        varmap.schedule = Some(Box::new(schedule.clone()));
+        varmap
+    }
+
+    /// The schedule behind the interval variables, if there is one. It
+    /// isn't serialized with the variables, so it's sent alongside them.
+    pub fn schedule(&self) -> Option<&Schedule> {
+        self.schedule.as_deref()
+    }
+
+    /// Counts business day and period offsets on `schedule`
+    pub fn with_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = Some(Box::new(schedule));
+        self
+    }
+
+    /// Adds `other`'s variables, replacing any of the same name
+    pub fn with_vars(mut self, other: &VarMap) -> Self {
+        for (k, v) in other.iter() {
+            self.vars.insert(k.clone(), v.clone());
+        }
+        self
+    }
+
+    /// A time variable's value, either RFC 3339 or a `%Y-%m-%d` date, which
+    /// is taken as midnight. The flag is whether it's a date.
+    fn time_of(&self, value: &str) -> Option<(DateTime<FixedOffset>, bool)> {
+        if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+            return Some((time, false));
+        }
+        let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+        let time = self.localize(date.and_hms_opt(0, 0, 0)?, Utc.fix())?;
+        Some((time, true))
+    }
+
+    /// Places a local time in the schedule's timezone, or at `offset` if
+    /// there's no schedule
+    fn localize(&self, local: NaiveDateTime, offset: FixedOffset) -> Option<DateTime<FixedOffset>> {
+        match &self.schedule {
+            Some(schedule) => {
+                let time = schedule.timezone().from_local_datetime(&local).earliest()?;
+                Some(time.with_timezone(&time.offset().fix()))
+            }
+            None => offset.from_local_datetime(&local).single(),
+        }
+    }
+
+    /// Moves `time` by `count` units: `m`inutes, `h`ours, `d`ays, `w`eeks,
+    /// `bd` business days, or `p`eriods of the schedule. Days and weeks keep
+    /// the time of day across daylight saving changes.
+    fn shift(
+        &self,
+        time: DateTime<FixedOffset>,
+        count: i64,
+        unit: &str,
+    ) -> Option<DateTime<FixedOffset>> {
+        let local = time.naive_local();
+        match unit {
+            "m" => time.checked_add_signed(Duration::try_minutes(count)?),
+            "h" => time.checked_add_signed(Duration::try_hours(count)?),
+            "d" => self.localize(
+                local.checked_add_signed(Duration::try_days(count)?)?,
+                *time.offset(),
             ),
-            (
-                "hhmmss".to_owned(),
-                format!("{}{}{}", end.hour(), end.minute(), end.second()),
+            "w" => self.localize(
+                local.checked_add_signed(Duration::try_weeks(count)?)?,
+                *time.offset(),
             ),
-        ]))
+            "bd" => {
+                let calendar = match &self.schedule {
+                    Some(schedule) => schedule.calendar().clone(),
+                    None => Calendar::new(),
+                };
+                let date = calendar.business_day_offset(local.date(), count).ok()?;
+                self.localize(date.and_time(local.time()), *time.offset())
+            }
+            "p" => {
+                let end = self
+                    .schedule
+                    .as_ref()?
+                    .interval(time, i32::try_from(count).ok()?)
+                    .end;
+                Some(end.with_timezone(&time.offset().fix()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The value a `${...}` reference stands for: a variable, or a time
+    /// variable with an offset like `-1d` and a strftime format after a
+    /// colon, e.g. `${end-1d:%Y%m%d}`
+    fn resolve(&self, reference: &str) -> Option<String> {
+        if let Some(value) = self.get(reference) {
+            return Some(value.clone());
+        }
+        let (head, format) = match reference.split_once(':') {
+            Some((head, format)) => (head, Some(format)),
+            None => (reference, None),
+        };
+        let (name, offset) = match head.find(['+', '-']) {
+            Some(at) => (&head[..at], Some(parse_offset(&head[at..])?)),
+            None => (head, None),
+        };
+        let (mut time, is_date) = self.time_of(self.get(name)?)?;
+        if let Some((count, unit)) = offset {
+            time = self.shift(time, count, unit)?;
+        }
+        match format {
+            Some(format) => {
+                let items: Vec<Item> = StrftimeItems::new(format).collect();
+                if items.iter().any(|item| matches!(item, Item::Error)) {
+                    return None;
+                }
+                Some(time.format_with_items(items.into_iter()).to_string())
+            }
+            None if is_date => Some(time.date_naive().to_string()),
+            None => Some(time.to_rfc3339()),
+        }
+    }
+
+    /// Replaces every `${...}` reference in one pass, so values are never
+    /// expanded themselves. References that can't be resolved are left as
+    /// they are.
+    fn expand(&self, s: &str, wrap: impl Fn(String) -> String) -> String {
+        let mut expanded = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(open) = rest.find("${") {
+            expanded.push_str(&rest[..open]);
+            rest = &rest[open..];
+            let Some(close) = rest.find('}') else {
+                break;
+            };
+            match self.resolve(&rest[2..close]) {
+                Some(value) => expanded.push_str(&wrap(value)),
+                None => expanded.push_str(&rest[..=close]),
+            }
+            rest = &rest[close + 1..];
+        }
+        expanded.push_str(rest);
+        expanded
     }
 
     /// Interpolate values into a string, assuming string has variables
     /// as ${varname}
     pub fn apply_to(&self, s: &str) -> String {
-        let mut expanded = s.to_string();
-        for (key, value) in self.0.iter() {
-            expanded = expanded.replace(&format!("${{{}}}", key), value);
+        self.expand(s, |value| value)
+    }
+
+    /// Interpolate values into a shell command, single-quoting each value
+    /// so it's passed to the shell as a single literal word
+    pub fn apply_quoted(&self, s: &str) -> String {
+        self.expand(s, |value| shell_quote(&value))
+    }
+
+    /// Renders every string in `details` as a Tera template, if the
+    /// details opt in with `"templates": true`. Variables are available by
+    /// name, and secrets as `secret.NAME`. `${...}` references are left for
+    /// [`VarMap::apply_to`] and [`VarMap::apply_quoted`], so they still work.
+    /// Rendered values aren't quoted; the `shell_quote` filter quotes them
+    /// for commands run by a shell.
+    pub fn render(&self, details: &TaskDetails) -> Result<TaskDetails> {
+        if details.get("templates") != Some(&serde_json::Value::Bool(true)) {
+            return Ok(details.clone());
         }
-        expanded
+        self.render_templates(details)
+    }
+
+    #[cfg(feature = "templates")]
+    fn render_templates(&self, details: &TaskDetails) -> Result<TaskDetails> {
+        let mut context = tera::Context::new();
+        let mut secrets = HashMap::new();
+        for (name, value) in self.iter() {
+            match name.strip_prefix("secret:") {
+                Some(secret) => {
+                    secrets.insert(secret, value);
+                }
+                None => context.insert(name, value),
+            }
+        }
+        context.insert("secret", &secrets);
+        let mut tera = tera::Tera::default();
+        tera.register_filter("shell_quote", shell_quote_filter);
+        render_value(details, &mut tera, &context)
+    }
+
+    #[cfg(not(feature = "templates"))]
+    fn render_templates(&self, _details: &TaskDetails) -> Result<TaskDetails> {
+        Err(Error::Validation(
+            "Task details use templates, but waterfall was built without them".to_owned(),
+        ))
     }
 }
 
+/// Single-quotes a value, so a shell takes it as a single literal word
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// `{{ name | shell_quote }}` in templates
+#[cfg(feature = "templates")]
+fn shell_quote_filter(
+    value: &tera::Value,
+    _: &HashMap<String, tera::Value>,
+) -> tera::Result<tera::Value> {
+    let quoted = match value.as_str() {
+        Some(s) => shell_quote(s),
+        None => shell_quote(&value.to_string()),
+    };
+    Ok(tera::Value::String(quoted))
+}
+
+/// Renders the strings of a JSON value, leaving its keys as they are
+#[cfg(feature = "templates")]
+fn render_value(
+    value: &serde_json::Value,
+    tera: &mut tera::Tera,
+    context: &tera::Context,
+) -> Result<serde_json::Value> {
+    use serde_json::Value;
+    Ok(match value {
+        Value::String(s) => Value::String(tera.render_str(s, context).map_err(|e| {
+            // Tera's own message only names the template, not what's
+            // wrong with it
+            let mut message = e.to_string();
+            let mut source = std::error::Error::source(&e);
+            while let Some(cause) = source {
+                message = format!("{}: {}", message, cause);
+                source = cause.source();
+            }
+            Error::Validation(format!("Unable to render {:?}: {}", s, message))
+        })?),
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| render_value(item, tera, context))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), render_value(v, tera, context)?)))
+                .collect::<Result<_>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
 impl From<HashMap<String, String>> for VarMap {
-    fn from(data: HashMap<String, String>) -> Self {
-        VarMap(data)
+    fn from(vars: HashMap<String, String>) -> Self {
+        VarMap {
+            vars,
+            schedule: None,
+        }
     }
 }
 
@@ -67,7 +348,7 @@ impl<'a> FromIterator<(&'a String, &'a String)> for VarMap {
         for (k, v) in iter {
             data.insert(k.clone(), v.clone());
         }
-        VarMap(data)
+        VarMap::from(data)
     }
 }
 
@@ -78,11 +359,145 @@ mod tests {
     #[test]
     fn check_simple_apply() {
         let s = "This is a ${test} of home and ${test} of away ${beep}";
-        let vm = VarMap(HashMap::from([("test".to_owned(), "alpha".to_owned())]));
+        let vm = VarMap::from(HashMap::from([("test".to_owned(), "alpha".to_owned())]));
 
         assert_eq!(
             &vm.apply_to(s),
             "This is a alpha of home and alpha of away ${beep}"
         );
     }
+
+    #[test]
+    fn check_interval_vars() {
+        let interval = Interval::new(
+            Utc.with_ymd_and_hms(2022, 1, 4, 14, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 1, 5, 14, 7, 3).unwrap(),
+        );
+        let vm = VarMap::from_interval(&interval, chrono_tz::America::New_York);
+
+        // Legacy names are zero-padded
+        assert_eq!(
+            vm.apply_to("${yyyy}/${mm}/${dd} ${yyyymmdd}T${hhmmss}"),
+            "2022/01/05 20220105T090703"
+        );
+        assert_eq!(vm.apply_to("${end}"), "2022-01-05T09:07:03-05:00");
+        assert_eq!(
+            vm.apply_to("${start:%Y-%m-%d} to ${end:%Y-%m-%d %H%M}"),
+            "2022-01-04 to 2022-01-05 0907"
+        );
+
+        // Bad formats and formats of things that aren't times stay put
+        assert_eq!(vm.apply_to("${end:%Q} ${yyyy:%Y}"), "${end:%Q} ${yyyy:%Y}");
+
+        assert_eq!(vm.apply_quoted("ls ${end:%b %d}"), "ls 'Jan 05'");
+    }
+
+    #[test]
+    fn check_calendar_vars() {
+        let tz = chrono_tz::America::New_York;
+        let schedule = Schedule::new(
+            Calendar::new(),
+            vec![
+                NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+                NaiveTime::from_hms_opt(17, 0, 0).unwrap(),
+            ],
+            tz,
+        );
+        let monday = tz.with_ymd_and_hms(2022, 1, 3, 9, 0, 0).unwrap();
+        let vm = VarMap::from_schedule(&schedule.interval(monday, 0), tz, &schedule);
+
+        assert_eq!(
+            vm.apply_to("${prev_business_day} ${next_business_day} ${period_index} ${yyyy_ww}"),
+            "2021-12-31 2022-01-04 0 2022_01"
+        );
+        assert_eq!(
+            vm.apply_to("${end-1d:%Y%m%d} ${end-1bd:%Y%m%d} ${end+2h:%H%M}"),
+            "20220102 20211231 1100"
+        );
+        assert_eq!(vm.apply_to("${end-1p}"), "2021-12-31T17:00:00-05:00");
+        assert_eq!(vm.apply_to("${prev_business_day+1bd}"), "2022-01-03");
+
+        // Days keep the time of day across daylight saving changes
+        let after_dst = tz.with_ymd_and_hms(2022, 3, 14, 9, 0, 0).unwrap();
+        let vm = VarMap::from_schedule(&schedule.interval(after_dst, 0), tz, &schedule);
+        assert_eq!(vm.apply_to("${end-1d}"), "2022-03-13T09:00:00-04:00");
+        assert_eq!(vm.apply_to("${end-1w}"), "2022-03-07T09:00:00-05:00");
+
+        // Without a schedule, business days are weekdays and periods are
+        // unknown
+        let vm = VarMap::from_interval(&schedule.interval(monday, 0), tz);
+        assert_eq!(
+            vm.apply_to("${end-1bd:%a} ${end-1p} ${end-1x} ${end-d}"),
+            "Fri ${end-1p} ${end-1x} ${end-d}"
+        );
+    }
+
+    #[cfg(feature = "templates")]
+    #[test]
+    fn check_templates() {
+        let mut vm = VarMap::from_interval(
+            &Interval::new(
+                Utc.with_ymd_and_hms(2022, 1, 4, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2022, 1, 5, 0, 0, 0).unwrap(),
+            ),
+            Tz::UTC,
+        );
+        vm.insert("env".to_owned(), "prod".to_owned());
+        vm.insert("secret:token".to_owned(), "hunter2".to_owned());
+
+        let details = serde_json::json!({
+            "templates": true,
+            "command": [
+                "./load.sh",
+                "{% if env == 'prod' %}--live{% else %}--dry-run{% endif %}",
+                "--day={{ end | date(format='%a') | lower }}",
+                "--token={{ secret.token }}",
+                "--file=${yyyymmdd}.csv",
+            ],
+            "timeout": 60,
+        });
+        assert_eq!(
+            vm.render(&details).unwrap()["command"],
+            serde_json::json!([
+                "./load.sh",
+                "--live",
+                "--day=wed",
+                "--token=hunter2",
+                "--file=${yyyymmdd}.csv",
+            ])
+        );
+
+        // Values are rendered as they are, unless quoted for a shell
+        vm.insert("note".to_owned(), "it's; rm -rf /".to_owned());
+        let shell = serde_json::json!({
+            "templates": true,
+            "command": "echo {{ note | shell_quote }} {{ note }}",
+        });
+        assert_eq!(
+            vm.render(&shell).unwrap()["command"],
+            serde_json::json!(r"echo 'it'\''s; rm -rf /' it's; rm -rf /")
+        );
+
+        // Without opting in, nothing is rendered
+        let plain = serde_json::json!({ "command": "docker inspect -f '{{.State}}' x" });
+        assert_eq!(vm.render(&plain).unwrap(), plain);
+
+        let broken = serde_json::json!({ "templates": true, "command": "{{ nope }}" });
+        match vm.render(&broken) {
+            Err(Error::Validation(msg)) => assert!(msg.contains("nope")),
+            other => panic!("Expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_single_pass() {
+        let vm = VarMap::from(HashMap::from([
+            ("a".to_owned(), "${b}".to_owned()),
+            ("b".to_owned(), "beta".to_owned()),
+        ]));
+        assert_eq!(
+            vm.apply_to("${a} ${b} ${c} ${unclosed"),
+            "${b} beta ${c} ${unclosed"
+        );
+    }
 }
@@ -0,0 +1,153 @@
+//! Hot reloading of the world file. Edits are validated before being handed
+//! to the runner, so a broken edit is logged and the previous definition
+//! keeps running.
+
+use super::*;
+use crate::runner::RunnerMessage;
+use notify::{RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+
+/// How long to wait for a burst of writes to settle before reloading
+const DEBOUNCE_MILLIS: u64 = 500;
+
+/// Reads, parses, and validates a world definition, in JSON or YAML
+pub fn load_world(path: &Path) -> Result<WorldDefinition> {
+    let definition: WorldDefinition = config_file::load(path)?;
+    definition.taskset()?;
+    Ok(definition)
+}
+
+/// Spawns a task reloading the runner's world whenever the file changes.
+/// Exits once the runner goes away.
+pub fn watch_world(
+    path: impl Into<PathBuf>,
+    runner: mpsc::Sender<RunnerMessage>,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let path: PathBuf = path.into();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        tx.send(event).unwrap_or(());
+    })
+    .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+
+    // Editors often replace the file rather than write to it, so watch the
+    // directory and pick out events on the file
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+    let file_name = path.file_name().map(|f| f.to_owned());
+
+    Ok(tokio::spawn(async move {
+        // Dropping the watcher stops the events
+        let _watcher = watcher;
+        let touches_world = |event: &notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                !event.kind.is_access()
+                    && event
+                        .paths
+                        .iter()
+                        .any(|p| p.file_name().map(|f| f.to_owned()) == file_name)
+            }
+            Err(e) => {
+                warn!("Error watching {}: {}", path.display(), e);
+                false
+            }
+        };
+
+        while let Some(event) = rx.recv().await {
+            if !touches_world(&event) {
+                continue;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(DEBOUNCE_MILLIS)).await;
+            while rx.try_recv().is_ok() {}
+
+            let definition = match load_world(&path) {
+                Ok(definition) => definition,
+                Err(e) => {
+                    error!(
+                        "Rejecting invalid edit to {}, keeping the current world: {}",
+                        path.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            info!("{} changed, reloading world", path.display());
+            let (response, res_rx) = oneshot::channel();
+            let msg = RunnerMessage::ReloadWorld {
+                definition: Box::new(definition),
+                response,
+            };
+            if runner.send(msg).await.is_err() {
+                break;
+            }
+            match res_rx.await {
+                Ok(Ok(())) => info!("Reloaded world from {}", path.display()),
+                // The runner logs its own failures
+                Ok(Err(_)) => {}
+                Err(_) => break,
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_watch_world() {
+        let world = |times: &str| {
+            format!(
+                r#"{{
+                "calendars": {{ "std": {{}} }},
+                "tasks": {{
+                    "task_a": {{
+                        "up": {{ "command": "/bin/true" }},
+                        "calendar_name": "std",
+                        "times": [ {} ],
+                        "timezone": "UTC",
+                        "valid_from": "2022-01-03T09:00:00",
+                        "valid_to": "2022-01-06T00:00:00"
+                    }}
+                }}
+            }}"#,
+                times
+            )
+        };
+        let dir = std::env::temp_dir().join("waterfall_watch_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("world.json");
+        std::fs::write(&path, world(r#""17:00:00""#)).unwrap();
+
+        let (tx, mut rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let handle = watch_world(&path, tx).unwrap();
+        let timeout = std::time::Duration::from_secs(2);
+
+        // Invalid edits never reach the runner
+        std::fs::write(&path, world(r#""25:00:00""#)).unwrap();
+        assert!(tokio::time::timeout(timeout, rx.recv()).await.is_err());
+
+        std::fs::write(&path, world(r#""18:00:00""#)).unwrap();
+        match tokio::time::timeout(timeout, rx.recv()).await {
+            Ok(Some(RunnerMessage::ReloadWorld {
+                definition,
+                response,
+            })) => {
+                assert_eq!(
+                    definition.tasks["task_a"].times,
+                    vec![NaiveTime::from_hms_opt(18, 0, 0).unwrap()]
+                );
+                response.send(Ok(())).unwrap();
+            }
+            other => panic!("Expected a reload, got {:?}", other),
+        }
+
+        handle.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,6 +5,11 @@ use super::*;
 pub struct WorldDefinition {
     pub tasks: HashMap<String, TaskDefinition>,
 
+    /// Task templates by name, e.g. `load_${region}`, each expanded into a
+    /// task for every combination of its parameters
+    #[serde(default)]
+    pub foreach: HashMap<String, TaskTemplate>,
+
     pub calendars: HashMap<String, Calendar>,
 
     #[serde(default)]
@@ -12,29 +17,289 @@ pub struct WorldDefinition {
 
     #[serde(default)]
     pub output_options: TaskOutputOptions,
+
+    /// Holds retries when failures spike across the world
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    /// Caps how many actions run at once across the world, however many
+    /// the executors could take
+    #[serde(default)]
+    pub max_running: Option<usize>,
+
+    /// Limits how quickly intervals that ended a while ago are launched,
+    /// so catching up doesn't swamp downstream systems
+    #[serde(default)]
+    pub backfill: Option<BackfillConfig>,
+
+    /// How many days completed actions stay listed after their interval
+    /// ends. Older intervals are only kept in the resource state.
+    #[serde(default)]
+    pub action_history_days: Option<i64>,
+
+    /// Old resource names, mapped to what they're called now. Tasks and
+    /// requirements may use either, and stored state under an old name is
+    /// migrated to the new one.
+    #[serde(default)]
+    pub resource_aliases: HashMap<Resource, Resource>,
 }
 
 impl WorldDefinition {
     pub fn taskset(&self) -> Result<TaskSet> {
-        // Ensure all tasks reference a valid calendar
-        for (name, def) in self.tasks.iter() {
+        let ts = self.unchecked_taskset()?;
+        ts.validate()?;
+        Ok(ts)
+    }
+
+    /// The task set, if the tasks can be built, without checking that they
+    /// fit together
+    pub fn unchecked_taskset(&self) -> Result<TaskSet> {
+        match self.definition_problems().into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(self.build_taskset()),
+        }
+    }
+
+    /// Every problem with the world, rather than just the first, for
+    /// checking a world before it's run
+    pub fn problems(&self) -> Vec<Error> {
+        let mut problems = self.definition_problems();
+        // Tasks that can't be built would only add noise
+        if problems.is_empty() {
+            problems.extend(self.build_taskset().problems());
+        }
+        problems
+    }
+
+    /// Things about the world that are likely mistakes, but don't stop it
+    /// from running
+    pub fn warnings(&self) -> Vec<String> {
+        let (tasks, _) = self.expand_tasks();
+        tasks
+            .iter()
+            .flat_map(|(name, def)| def.warnings(name))
+            .collect()
+    }
+
+    /// Every task of the world, those in `tasks` and those expanded from
+    /// `foreach`, ordered by name
+    pub fn task_definitions(&self) -> Result<Vec<(String, TaskDefinition)>> {
+        let (tasks, problems) = self.expand_tasks();
+        match problems.into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(tasks),
+        }
+    }
+
+    /// The world's tasks, with any problems expanding the templates
+    fn expand_tasks(&self) -> (Vec<(String, TaskDefinition)>, Vec<Error>) {
+        let mut tasks: Vec<(String, TaskDefinition)> = self
+            .tasks
+            .iter()
+            .map(|(name, def)| (name.clone(), def.clone()))
+            .collect();
+        let mut problems = Vec::new();
+        let mut templates: Vec<(&String, &TaskTemplate)> = self.foreach.iter().collect();
+        templates.sort_by_key(|(name, _)| *name);
+        for (name, template) in templates {
+            match template.expand(name) {
+                Ok(expanded) => tasks.extend(expanded),
+                Err(e) => problems.push(e),
+            }
+        }
+
+        tasks.sort_by(|a, b| a.0.cmp(&b.0));
+        for pair in tasks.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                problems.push(Error::Validation(format!(
+                    "Task {} is defined more than once across tasks and foreach",
+                    pair[0].0
+                )));
+            }
+        }
+        tasks.dedup_by(|a, b| a.0 == b.0);
+        (tasks, problems)
+    }
+
+    /// Problems with the definitions themselves, which stop the task set
+    /// from being built
+    fn definition_problems(&self) -> Vec<Error> {
+        let mut problems = Vec::new();
+        let mut calendars: Vec<(&String, &Calendar)> = self.calendars.iter().collect();
+        calendars.sort_by_key(|(name, _)| *name);
+        for (name, calendar) in calendars {
+            if let Err(e) = calendar.validate() {
+                let msg = match e {
+                    Error::Validation(msg) => msg,
+                    e => e.to_string(),
+                };
+                problems.push(Error::Validation(format!("Calendar {}: {}", name, msg)));
+            }
+        }
+
+        let (tasks, expansion_problems) = self.expand_tasks();
+        problems.extend(expansion_problems);
+        for (name, def) in &tasks {
+            // Ensure all tasks reference a valid calendar
             if !self.calendars.contains_key(&def.calendar_name) {
-                return Err(anyhow!(
+                problems.push(Error::Validation(format!(
                     "Task {} references calendar {}, which is not defined",
-                    name,
-                    def.calendar_name
-                ));
+                    name, def.calendar_name
+                )));
+            }
+            if def.recheck_every_seconds.is_some() && def.check.is_none() {
+                problems.push(Error::Validation(format!(
+                    "Task {} is rechecked, but has no check command",
+                    name
+                )));
             }
+            problems.extend(def.problems(name));
         }
+
+        let mut aliases: Vec<(&Resource, &Resource)> = self.resource_aliases.iter().collect();
+        aliases.sort();
+        for (alias, canonical) in aliases {
+            if self.resource_aliases.contains_key(canonical) {
+                problems.push(Error::Validation(format!(
+                    "Resource alias {} refers to {}, which is itself an alias",
+                    alias, canonical
+                )));
+            }
+        }
+        if let Some(days) = self.action_history_days {
+            if !(0..=36500).contains(&days) {
+                problems.push(Error::Validation(format!(
+                    "Action history of {} days must be between 0 and 36500",
+                    days
+                )));
+            }
+        }
+        if let Some(Err(e)) = self.backfill.as_ref().map(|b| b.validate()) {
+            problems.push(e);
+        }
+        if let Some(Err(e)) = self.circuit_breaker.as_ref().map(|b| b.validate()) {
+            problems.push(e);
+        }
+        problems
+    }
+
+    fn build_taskset(&self) -> TaskSet {
         let tasks: Vec<Task> = self
-            .tasks
+            .expand_tasks()
+            .0
             .iter()
-            .map(|(tn, td)| td.to_task(tn, self.calendars.get(&td.calendar_name).unwrap()))
+            .map(|(tn, td)| {
+                let mut task = td.to_task(tn, self.calendars.get(&td.calendar_name).unwrap());
+                task.provides = task
+                    .provides
+                    .into_iter()
+                    .map(|res| self.resource_aliases.get(&res).cloned().unwrap_or(res))
+                    .collect();
+                for req in task.requires.iter_mut() {
+                    req.rename(&self.resource_aliases);
+                }
+                task
+            })
             .collect();
-        let ts = TaskSet::from(tasks);
+        TaskSet::from(tasks)
+    }
+}
 
-        ts.validate()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(ts)
+    fn world(tasks: serde_json::Value) -> WorldDefinition {
+        serde_json::from_value(serde_json::json!({
+            "calendars": {
+                "std": {},
+                "never": { "mask": [] }
+            },
+            "tasks": tasks,
+        }))
+        .unwrap()
+    }
+
+    fn task(calendar: &str, times: &[&str], requires: &[&str]) -> serde_json::Value {
+        let requires: Vec<serde_json::Value> = requires
+            .iter()
+            .map(|res| serde_json::json!({ "resource": res, "offset": 0 }))
+            .collect();
+        serde_json::json!({
+            "up": "/bin/true",
+            "requires": requires,
+            "calendar_name": calendar,
+            "times": times,
+            "timezone": "America/New_York",
+            "valid_from": "2022-01-03T00:00:00",
+            "valid_to": "2022-02-01T00:00:00"
+        })
+    }
+
+    #[test]
+    fn check_problems() {
+        // Every problem with the definitions is listed
+        let def = world(serde_json::json!({
+            "a": task("nope", &["09:00:00"], &[]),
+            "b": task("std", &["02:30:00"], &[]),
+            "c": task("std", &[], &[]),
+        }));
+        let problems: Vec<String> = def.problems().iter().map(|e| e.to_string()).collect();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].contains("Calendar never"));
+        assert!(problems[1].contains("calendar nope"));
+        assert!(problems[2].contains("Task c has no scheduled times"));
+
+        // Times lost to daylight saving only warrant a warning
+        let warnings = def.warnings();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("02:30:00, which is skipped or repeated"));
+        assert!(def.taskset().is_err());
+        assert!(def.unchecked_taskset().is_err());
+
+        // As is every problem between tasks, once they can be built
+        let mut def = world(serde_json::json!({
+            "a": task("std", &["09:00:00"], &["x"]),
+            "b": task("std", &["17:00:00"], &["y", "a"]),
+        }));
+        def.calendars.remove("never");
+        let problems = def.problems();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(def.unchecked_taskset().is_ok());
+        assert!(matches!(def.taskset(), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn check_foreach() {
+        let mut def = world(serde_json::json!({
+            "report": task("std", &["18:00:00"], &["load_us", "load_eu"]),
+        }));
+        def.calendars.remove("never");
+        def.foreach.insert(
+            "load_${region}".to_owned(),
+            serde_json::from_value(serde_json::json!({
+                "parameters": { "region": [ "us", "eu" ] },
+                "task": task("std", &["17:00:00"], &[]),
+            }))
+            .unwrap(),
+        );
+        let names: Vec<String> = def
+            .taskset()
+            .unwrap()
+            .iter()
+            .map(|t| t.name.clone())
+            .collect();
+        assert_eq!(names, vec!["load_eu", "load_us", "report"]);
+
+        // Expanded names can't clash with other tasks
+        def.tasks.insert(
+            "load_us".to_owned(),
+            serde_json::from_value(task("std", &["17:00:00"], &[])).unwrap(),
+        );
+        let problems = def.problems();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0]
+            .to_string()
+            .contains("Task load_us is defined more than once"));
     }
 }
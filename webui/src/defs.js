@@ -6,6 +6,8 @@ export const ALL_STATES = [
   { name: 'ERRORED', display: 'Errored' },
   { name: 'COMPLETED', display: 'Completed' },
   { name: 'KILLED', display: 'Killed' },
+  { name: 'FAILED', display: 'Failed' },
+  { name: 'SKIPPED', display: 'Skipped' },
 ];
 
 export const defaultCountHandler = {